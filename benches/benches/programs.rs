@@ -0,0 +1,30 @@
+// Times each program in `programs/` in a fresh VM, the same programs that
+// `lox-bytecode --bench-suite` runs. Run with `cargo bench` from `benches/`.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use lox_bytecode::VmBuilder;
+
+const PROGRAMS: [(&str, &str); 5] = [
+    ("fib", include_str!("../programs/fib.lox")),
+    ("arithmetic", include_str!("../programs/arithmetic.lox")),
+    ("strings", include_str!("../programs/strings.lox")),
+    ("methods", include_str!("../programs/methods.lox")),
+    ("closures", include_str!("../programs/closures.lox")),
+];
+
+fn programs(c: &mut Criterion) {
+    let mut group = c.benchmark_group("programs");
+    group.sample_size(20);
+    for (name, source) in PROGRAMS {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let mut vm = VmBuilder::new().output(std::io::sink()).build();
+                vm.interpret(source).unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, programs);
+criterion_main!(benches);
@@ -0,0 +1,638 @@
+use std::collections::HashMap;
+
+use crate::chunk::*;
+use crate::diagnostic::*;
+use crate::function::*;
+use crate::globals::*;
+use crate::native::*;
+use crate::shared::*;
+use crate::value::*;
+
+// Assembles the textual form of bytecode. It is laid out like the
+// disassembler's output, but operands are symbolic so that a file doesn't
+// depend on constant table or global slot numbers:
+//
+//   ; Prints 3.
+//   == add ==
+//   .arity 2
+//       OP_GET_LOCAL 1
+//       OP_GET_LOCAL 2
+//       OP_ADD
+//       OP_RETURN
+//
+//   == <script> ==
+//       OP_CLOSURE add
+//       OP_DEFINE_GLOBAL add
+//       OP_GET_GLOBAL add
+//       OP_CONSTANT 1
+//       OP_CONSTANT 2
+//       OP_CALL 2
+//       OP_PRINT
+//       OP_NIL
+//       OP_RETURN
+//
+// Each `== name ==` starts a function, and `<script>` is the one that runs.
+// `.arity N` and `.upvalues N` describe the function being defined.
+// `label:` marks a jump target. `;` starts a comment. Offsets and line
+// numbers at the start of a line, as the disassembler prints them, are
+// skipped.
+//
+// Operands, by instruction:
+// - OP_CONSTANT: a number, a "string", nil, true or false.
+// - Globals, classes, properties and methods: a name.
+// - Invokes: a name and an argument count.
+// - OP_CONSTANT_CALL: a native's name and an argument count.
+// - Jumps and loops: a label.
+// - OP_CLOSURE: a function name. Each of its upvalues follows on its own
+//   line as `local N` or `upvalue N`.
+// - Everything else: plain numbers.
+//
+// Instructions get the line they are on in the file, so runtime errors
+// point back into it.
+pub fn assemble(
+    text: &str,
+    file: Option<Rc<str>>,
+    globals: &mut GlobalTable,
+    natives: &NativeTable,
+) -> Result<Function, Vec<Diagnostic>> {
+    let mut parser = AsmParser::default();
+    for (index, line) in text.lines().enumerate() {
+        parser.line(index + 1, line);
+    }
+    parser.finish_function();
+    if !parser.diagnostics.is_empty() {
+        return Err(parser.diagnostics);
+    }
+
+    let mut assembler = Assembler {
+        functions: parser.functions,
+        file,
+        globals,
+        natives,
+        built: HashMap::new(),
+        building: Vec::new(),
+        diagnostics: Vec::new(),
+    };
+    let function = assembler.build("<script>", 1, 1);
+    match function {
+        Some(function) if assembler.diagnostics.is_empty() => Ok((*function).clone()),
+        _ => Err(assembler.diagnostics),
+    }
+}
+
+fn error(line: usize, column: usize, message: String) -> Diagnostic {
+    Diagnostic {
+        severity: Severity::Error,
+        message,
+        at: None,
+        line,
+        column,
+        span: Span::default(),
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Word {
+    text: String,
+    column: usize,
+    // Quoted in the source, so it's a string rather than a name.
+    quoted: bool,
+}
+
+// Splits a line into whitespace-separated words, keeping "quoted strings"
+// together and dropping everything after a `;`.
+fn words(line: &str) -> Result<Vec<Word>, (usize, String)> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().enumerate().peekable();
+    while let Some(&(index, c)) = chars.peek() {
+        if c == ';' {
+            break;
+        }
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let column = index + 1;
+        let mut text = String::new();
+        if c == '"' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some((_, '"')) => break,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, 'n')) => text.push('\n'),
+                        Some((_, 't')) => text.push('\t'),
+                        Some((_, c)) => text.push(c),
+                        None => return Err((column, "Unterminated string.".to_string())),
+                    },
+                    Some((_, c)) => text.push(c),
+                    None => return Err((column, "Unterminated string.".to_string())),
+                }
+            }
+            words.push(Word {
+                text,
+                column,
+                quoted: true,
+            });
+        } else {
+            while let Some(&(_, c)) = chars.peek() {
+                if c.is_whitespace() || c == ';' {
+                    break;
+                }
+                text.push(c);
+                chars.next();
+            }
+            words.push(Word {
+                text,
+                column,
+                quoted: false,
+            });
+        }
+    }
+    Ok(words)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operands {
+    None,
+    Byte,
+    TwoBytes,
+    Index,
+    Constant,
+    Name,
+    Global,
+    Property,
+    Invoke,
+    SuperInvoke,
+    ConstantCall,
+    Jump,
+    Closure,
+}
+
+fn operands(op: OpCode) -> Operands {
+    use OpCode::*;
+    match op {
+        Call | GetUpvalue | SetUpvalue | PopN => Operands::Byte,
+        AddLocals => Operands::TwoBytes,
+        GetLocal | GetLocalLong | SetLocal | SetLocalLong => Operands::Index,
+        Constant | ConstantLong => Operands::Constant,
+        Class | ClassLong | SetProperty | SetPropertyLong | Method | MethodLong | GetSuper
+        | GetSuperLong => Operands::Name,
+        DefineGlobal | DefineGlobalLong | GetGlobal | GetGlobalLong | SetGlobal | SetGlobalLong => {
+            Operands::Global
+        }
+        GetProperty | GetPropertyLong => Operands::Property,
+        Invoke | InvokeLong => Operands::Invoke,
+        SuperInvoke | SuperInvokeLong => Operands::SuperInvoke,
+        ConstantCall => Operands::ConstantCall,
+        Jump | JumpIfFalse | JumpIfFalsePop | Loop | JumpLong | JumpIfFalseLong | LoopLong => {
+            Operands::Jump
+        }
+        Closure | ClosureLong => Operands::Closure,
+        _ => Operands::None,
+    }
+}
+
+fn is_column(text: &str) -> bool {
+    text == "|" || text.bytes().all(|b| b.is_ascii_digit())
+}
+
+fn from_mnemonic(name: &str) -> Option<OpCode> {
+    (0..=u8::MAX)
+        .filter_map(|code| OpCode::try_from(code).ok())
+        .find(|op| op.mnemonic() == name)
+}
+
+#[derive(Debug)]
+struct Instruction {
+    op: OpCode,
+    args: Vec<Word>,
+    // `(is_local, index)` for each upvalue a closure captures.
+    captures: Vec<(bool, usize)>,
+    line: usize,
+    column: usize,
+}
+
+impl Instruction {
+    fn size(&self) -> usize {
+        let index = if self.op.is_long() { 3 } else { 1 };
+        match operands(self.op) {
+            Operands::None => 1,
+            Operands::Byte => 2,
+            Operands::TwoBytes | Operands::ConstantCall => 3,
+            Operands::Index | Operands::Constant | Operands::Name | Operands::Global => 1 + index,
+            Operands::Property => 3 + index,
+            Operands::Invoke => 4 + index,
+            Operands::SuperInvoke => 2 + index,
+            Operands::Jump => match self.op {
+                OpCode::JumpLong | OpCode::JumpIfFalseLong | OpCode::LoopLong => 5,
+                _ => 3,
+            },
+            Operands::Closure => 1 + index + 3 * self.captures.len(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct AsmFunction {
+    name: String,
+    line: usize,
+    arity: usize,
+    upvalues: usize,
+    instructions: Vec<Instruction>,
+    labels: HashMap<String, usize>,
+}
+
+#[derive(Default)]
+struct AsmParser {
+    functions: HashMap<String, AsmFunction>,
+    current: Option<AsmFunction>,
+    // Labels waiting for the instruction they mark.
+    pending_labels: Vec<(String, usize)>,
+    // Inside a `-- constants --` listing, which is skipped.
+    in_constants: bool,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl AsmParser {
+    fn line(&mut self, line: usize, text: &str) {
+        let trimmed = text.trim();
+        if let Some(name) = trimmed
+            .strip_prefix("==")
+            .and_then(|rest| rest.strip_suffix("=="))
+        {
+            self.finish_function();
+            self.in_constants = false;
+            self.current = Some(AsmFunction {
+                name: name.trim().to_string(),
+                line,
+                arity: 0,
+                upvalues: 0,
+                instructions: Vec::new(),
+                labels: HashMap::new(),
+            });
+            return;
+        }
+        if trimmed == "-- constants --" {
+            self.in_constants = true;
+        }
+        if self.in_constants {
+            return;
+        }
+
+        let words = match words(text) {
+            Ok(words) => words,
+            Err((column, message)) => {
+                self.diagnostics.push(error(line, column, message));
+                return;
+            }
+        };
+        // Skip the offset and line columns the disassembler prints.
+        let start = words
+            .iter()
+            .position(|word| word.quoted || !is_column(&word.text))
+            .unwrap_or(words.len());
+        let words = &words[start..];
+        let Some(first) = words.first() else {
+            return;
+        };
+        let Some(function) = self.current.as_mut() else {
+            let message = "Expect a '== name ==' function header.".to_string();
+            self.diagnostics.push(error(line, first.column, message));
+            return;
+        };
+
+        if let Some(label) = first.text.strip_suffix(':').filter(|_| words.len() == 1) {
+            self.pending_labels.push((label.to_string(), line));
+            return;
+        }
+
+        match first.text.as_str() {
+            ".arity" | ".upvalues" => {
+                match words.get(1).and_then(|word| word.text.parse().ok()) {
+                    Some(n) if words.len() == 2 => {
+                        if first.text == ".arity" {
+                            function.arity = n;
+                        } else {
+                            function.upvalues = n;
+                        }
+                    }
+                    _ => {
+                        let message = format!("Expect a number after '{}'.", first.text);
+                        self.diagnostics.push(error(line, first.column, message));
+                    }
+                }
+                return;
+            }
+            "local" | "upvalue" => {
+                let closure = function
+                    .instructions
+                    .last_mut()
+                    .filter(|instruction| operands(instruction.op) == Operands::Closure);
+                let index = words.get(1).and_then(|word| word.text.parse().ok());
+                match (closure, index) {
+                    (Some(closure), Some(index)) if words.len() == 2 && index <= 0xffff => {
+                        closure.captures.push((first.text == "local", index));
+                    }
+                    (None, _) => {
+                        let message = "Upvalues must follow OP_CLOSURE.".to_string();
+                        self.diagnostics.push(error(line, first.column, message));
+                    }
+                    _ => {
+                        let message = format!("Expect a slot after '{}'.", first.text);
+                        self.diagnostics.push(error(line, first.column, message));
+                    }
+                }
+                return;
+            }
+            _ => {}
+        }
+
+        let mnemonic = first;
+        let Some(op) = from_mnemonic(&mnemonic.text) else {
+            let message = format!("Unknown instruction '{}'.", mnemonic.text);
+            self.diagnostics.push(error(line, mnemonic.column, message));
+            return;
+        };
+
+        let args = words[1..].to_vec();
+        let expected = match operands(op) {
+            Operands::None => 0,
+            Operands::TwoBytes | Operands::Invoke | Operands::SuperInvoke => 2,
+            Operands::ConstantCall => 2,
+            _ => 1,
+        };
+        if args.len() != expected {
+            let message = format!("{} takes {expected} operand(s).", mnemonic.text);
+            self.diagnostics.push(error(line, mnemonic.column, message));
+            return;
+        }
+
+        let offset = function.instructions.len();
+        for (label, label_line) in self.pending_labels.drain(..) {
+            if function.labels.insert(label.clone(), offset).is_some() {
+                let message = format!("Label '{label}' is defined twice.");
+                self.diagnostics.push(error(label_line, 1, message));
+            }
+        }
+        function.instructions.push(Instruction {
+            op,
+            args,
+            captures: Vec::new(),
+            line,
+            column: mnemonic.column,
+        });
+    }
+
+    fn finish_function(&mut self) {
+        let Some(mut function) = self.current.take() else {
+            return;
+        };
+        // A label at the very end marks the offset just past the code.
+        let end = function.instructions.len();
+        for (label, _) in self.pending_labels.drain(..) {
+            function.labels.insert(label, end);
+        }
+        if function.instructions.is_empty() {
+            let message = format!("Function '{}' has no instructions.", function.name);
+            self.diagnostics.push(error(function.line, 1, message));
+        }
+        if let Some(previous) = self.functions.get(&function.name) {
+            let message = format!(
+                "Function '{}' is already defined on line {}.",
+                function.name, previous.line
+            );
+            self.diagnostics.push(error(function.line, 1, message));
+            return;
+        }
+        self.functions.insert(function.name.clone(), function);
+    }
+}
+
+struct Assembler<'a> {
+    functions: HashMap<String, AsmFunction>,
+    file: Option<Rc<str>>,
+    globals: &'a mut GlobalTable,
+    natives: &'a NativeTable,
+    built: HashMap<String, Rc<Function>>,
+    // Functions being assembled, to catch a function that contains itself.
+    building: Vec<String>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Assembler<'_> {
+    fn build(&mut self, name: &str, line: usize, column: usize) -> Option<Rc<Function>> {
+        if let Some(function) = self.built.get(name) {
+            return Some(Rc::clone(function));
+        }
+        if self.building.iter().any(|f| f == name) {
+            let message = format!("Function '{name}' can't contain a closure of itself.");
+            self.diagnostics.push(error(line, column, message));
+            return None;
+        }
+        let Some(function) = self.functions.remove(name) else {
+            self.diagnostics
+                .push(error(line, column, format!("No function named '{name}'.")));
+            return None;
+        };
+
+        self.building.push(name.to_string());
+        let chunk = self.chunk(&function);
+        self.building.pop();
+
+        let function = Rc::new(Function::new(
+            function.arity,
+            &Rc::new(chunk),
+            if name == "<script>" { "" } else { name },
+            function.upvalues,
+        ));
+        self.built.insert(name.to_string(), Rc::clone(&function));
+        Some(function)
+    }
+
+    fn chunk(&mut self, function: &AsmFunction) -> Chunk {
+        let mut offsets = Vec::new();
+        let mut offset = 0;
+        for instruction in &function.instructions {
+            offsets.push(offset);
+            offset += instruction.size();
+        }
+        offsets.push(offset);
+
+        let mut chunk = Chunk::new();
+        chunk.set_file(self.file.clone());
+        for (i, instruction) in function.instructions.iter().enumerate() {
+            let mut bytes = vec![u8::from(instruction.op)];
+            if let Err(message) =
+                self.operands(&mut chunk, function, instruction, &offsets, i, &mut bytes)
+            {
+                self.diagnostics
+                    .push(error(instruction.line, instruction.column, message));
+                continue;
+            }
+            for byte in bytes {
+                chunk.write(byte, instruction.line, instruction.column);
+            }
+        }
+        chunk
+    }
+
+    // Appends the encoded operands of the `i`th instruction to `bytes`.
+    fn operands(
+        &mut self,
+        chunk: &mut Chunk,
+        function: &AsmFunction,
+        instruction: &Instruction,
+        offsets: &[usize],
+        i: usize,
+        bytes: &mut Vec<u8>,
+    ) -> Result<(), String> {
+        let op = instruction.op;
+        let args = &instruction.args;
+        let long = op.is_long();
+        let number = |word: &Word| -> Result<usize, String> {
+            word.text
+                .parse()
+                .map_err(|_| format!("Expect a number, not '{}'.", word.text))
+        };
+        let byte = |word: &Word| -> Result<u8, String> {
+            u8::try_from(number(word)?)
+                .map_err(|_| format!("'{}' doesn't fit in a byte.", word.text))
+        };
+        let index = |bytes: &mut Vec<u8>, n: usize| -> Result<(), String> {
+            if long && n <= MAX_LONG_INDEX {
+                bytes.extend_from_slice(&(n as u32).to_be_bytes()[1..]);
+            } else if let (false, Ok(n)) = (long, u8::try_from(n)) {
+                bytes.push(n);
+            } else {
+                return Err(format!("Index {n} is too large for {}.", op.mnemonic()));
+            }
+            Ok(())
+        };
+        let constant = |chunk: &mut Chunk, value: Value| {
+            chunk
+                .add_constant(value)
+                .ok_or_else(|| "Too many constants in one chunk.".to_string())
+        };
+
+        match operands(op) {
+            Operands::None => {}
+            Operands::Byte => bytes.push(byte(&args[0])?),
+            Operands::TwoBytes => {
+                bytes.push(byte(&args[0])?);
+                bytes.push(byte(&args[1])?);
+            }
+            Operands::Index => index(bytes, number(&args[0])?)?,
+            Operands::Constant => {
+                let value = literal(&args[0])?;
+                index(bytes, constant(chunk, value)?)?;
+            }
+            Operands::Name => {
+                let name = Value::Str(args[0].text.clone());
+                index(bytes, constant(chunk, name)?)?;
+            }
+            Operands::Global => {
+                let slot = self.globals.resolve(&args[0].text);
+                if matches!(
+                    op,
+                    OpCode::DefineGlobal
+                        | OpCode::DefineGlobalLong
+                        | OpCode::SetGlobal
+                        | OpCode::SetGlobalLong
+                ) {
+                    self.globals.mark_assigned(slot);
+                }
+                index(bytes, slot)?;
+            }
+            Operands::Property => {
+                let name = Value::Str(args[0].text.clone());
+                index(bytes, constant(chunk, name)?)?;
+                bytes.extend_from_slice(&(chunk.add_cache() as u16).to_be_bytes());
+            }
+            Operands::Invoke | Operands::SuperInvoke => {
+                let name = Value::Str(args[0].text.clone());
+                index(bytes, constant(chunk, name)?)?;
+                bytes.push(byte(&args[1])?);
+                if operands(op) == Operands::Invoke {
+                    bytes.extend_from_slice(&(chunk.add_cache() as u16).to_be_bytes());
+                }
+            }
+            Operands::ConstantCall => {
+                let Some(native) = self.natives.get(&args[0].text) else {
+                    return Err(format!("No native function named '{}'.", args[0].text));
+                };
+                let native = constant(chunk, Value::Native(Rc::clone(native)))?;
+                let native =
+                    u8::try_from(native).map_err(|_| "Too many constants for OP_CONSTANT_CALL.")?;
+                bytes.push(native);
+                bytes.push(byte(&args[1])?);
+            }
+            Operands::Jump => {
+                let Some(&target) = function.labels.get(&args[0].text) else {
+                    return Err(format!("No label named '{}'.", args[0].text));
+                };
+                let target = offsets[target];
+                let after = offsets[i + 1];
+                let distance = if matches!(op, OpCode::Loop | OpCode::LoopLong) {
+                    after.checked_sub(target)
+                } else {
+                    target.checked_sub(after)
+                };
+                let Some(distance) = distance else {
+                    let direction = if matches!(op, OpCode::Loop | OpCode::LoopLong) {
+                        "backwards"
+                    } else {
+                        "forwards"
+                    };
+                    return Err(format!("{} only jumps {direction}.", op.mnemonic()));
+                };
+                match op {
+                    OpCode::JumpLong | OpCode::JumpIfFalseLong | OpCode::LoopLong => {
+                        let distance = u32::try_from(distance).map_err(|_| "Jump too large.")?;
+                        bytes.extend_from_slice(&distance.to_be_bytes());
+                    }
+                    _ => {
+                        let distance = u16::try_from(distance)
+                            .map_err(|_| format!("Jump too large; use {}_LONG.", op.mnemonic()))?;
+                        bytes.extend_from_slice(&distance.to_be_bytes());
+                    }
+                }
+            }
+            Operands::Closure => {
+                let Some(nested) = self.build(&args[0].text, instruction.line, instruction.column)
+                else {
+                    return Ok(());
+                };
+                if nested.upvalues() != instruction.captures.len() {
+                    return Err(format!(
+                        "'{}' has {} upvalue(s) but the closure captures {}.",
+                        args[0].text,
+                        nested.upvalues(),
+                        instruction.captures.len()
+                    ));
+                }
+                index(bytes, constant(chunk, Value::Func(nested))?)?;
+                for &(is_local, slot) in &instruction.captures {
+                    bytes.push(u8::from(is_local));
+                    bytes.extend_from_slice(&(slot as u16).to_be_bytes());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn literal(word: &Word) -> Result<Value, String> {
+    if word.quoted {
+        return Ok(Value::Str(word.text.clone()));
+    }
+    match word.text.as_str() {
+        "nil" => Ok(Value::Nil),
+        "true" => Ok(Value::Boolean(true)),
+        "false" => Ok(Value::Boolean(false)),
+        text => text
+            .parse()
+            .map(Value::Number)
+            .map_err(|_| format!("Expect a number, string, nil, true or false, not '{text}'.")),
+    }
+}
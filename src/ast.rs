@@ -0,0 +1,910 @@
+use crate::diagnostic::*;
+use crate::json::*;
+use crate::scanner::*;
+use crate::token::*;
+
+// The syntax tree of a whole script. Every node keeps the tokens it was
+// built from, so tools can map it back to positions in the source.
+#[derive(Clone, Debug, Default)]
+pub struct Ast {
+    pub statements: Vec<Stmt>,
+}
+
+#[derive(Clone, Debug)]
+pub enum Stmt {
+    Expression(Expr),
+    Print(Expr),
+    Var {
+        name: Token,
+        initializer: Option<Expr>,
+    },
+    Block {
+        brace: Token,
+        statements: Vec<Stmt>,
+    },
+    If {
+        condition: Expr,
+        then_branch: Box<Stmt>,
+        else_branch: Option<Box<Stmt>>,
+    },
+    While {
+        condition: Expr,
+        body: Box<Stmt>,
+    },
+    For {
+        initializer: Option<Box<Stmt>>,
+        condition: Option<Expr>,
+        increment: Option<Expr>,
+        body: Box<Stmt>,
+    },
+    // `for (var name in iterable) body`.
+    ForIn {
+        name: Token,
+        iterable: Expr,
+        body: Box<Stmt>,
+    },
+    Function(FunctionDecl),
+    Return {
+        keyword: Token,
+        value: Option<Expr>,
+    },
+    Class {
+        name: Token,
+        superclass: Option<Token>,
+        methods: Vec<FunctionDecl>,
+        statics: Vec<StaticField>,
+    },
+    Extend {
+        name: Token,
+        methods: Vec<FunctionDecl>,
+        statics: Vec<StaticField>,
+    },
+}
+
+// `static name = value;` in a class body.
+#[derive(Clone, Debug)]
+pub struct StaticField {
+    pub name: Token,
+    pub value: Option<Expr>,
+}
+
+#[derive(Clone, Debug)]
+pub struct FunctionDecl {
+    pub name: Token,
+    pub params: Vec<Token>,
+    pub body: Vec<Stmt>,
+}
+
+// Literals keep their token; its type says which kind of literal it is and
+// its lexeme is the source text, quotes included for strings.
+#[derive(Clone, Debug)]
+pub enum Expr {
+    Literal(Token),
+    Variable(Token),
+    Assign {
+        name: Token,
+        value: Box<Expr>,
+    },
+    Unary {
+        operator: Token,
+        right: Box<Expr>,
+    },
+    Binary {
+        left: Box<Expr>,
+        operator: Token,
+        right: Box<Expr>,
+    },
+    Logical {
+        left: Box<Expr>,
+        operator: Token,
+        right: Box<Expr>,
+    },
+    Grouping(Box<Expr>),
+    Call {
+        callee: Box<Expr>,
+        paren: Token,
+        arguments: Vec<Expr>,
+    },
+    Get {
+        object: Box<Expr>,
+        name: Token,
+    },
+    Set {
+        object: Box<Expr>,
+        name: Token,
+        value: Box<Expr>,
+    },
+    This(Token),
+    Super {
+        keyword: Token,
+        method: Token,
+    },
+}
+
+impl Ast {
+    // Every node becomes an object whose "type" names its variant.
+    pub fn to_json(&self) -> Json {
+        Json::object([
+            ("type", "Script".into()),
+            ("statements", json_list(&self.statements)),
+        ])
+    }
+}
+
+fn json_list<'a, T: 'a>(items: &'a [T]) -> Json
+where
+    Json: From<&'a T>,
+{
+    Json::Array(items.iter().map(Json::from).collect())
+}
+
+impl From<&Token> for Json {
+    fn from(token: &Token) -> Self {
+        Json::object([
+            ("lexeme", token.lexeme.as_str().into()),
+            ("line", token.line.into()),
+            ("column", token.column.into()),
+        ])
+    }
+}
+
+impl From<&Box<Stmt>> for Json {
+    fn from(stmt: &Box<Stmt>) -> Self {
+        Json::from(stmt.as_ref())
+    }
+}
+
+impl From<&Box<Expr>> for Json {
+    fn from(expr: &Box<Expr>) -> Self {
+        Json::from(expr.as_ref())
+    }
+}
+
+impl From<&FunctionDecl> for Json {
+    fn from(function: &FunctionDecl) -> Self {
+        Json::object([
+            ("type", "Function".into()),
+            ("name", (&function.name).into()),
+            ("params", json_list(&function.params)),
+            ("body", json_list(&function.body)),
+        ])
+    }
+}
+
+impl From<&StaticField> for Json {
+    fn from(field: &StaticField) -> Self {
+        Json::object([
+            ("type", "StaticField".into()),
+            ("name", (&field.name).into()),
+            ("value", field.value.as_ref().into()),
+        ])
+    }
+}
+
+impl From<&Stmt> for Json {
+    fn from(stmt: &Stmt) -> Self {
+        match stmt {
+            Stmt::Expression(expr) => {
+                Json::object([("type", "Expression".into()), ("expression", expr.into())])
+            }
+            Stmt::Print(expr) => {
+                Json::object([("type", "Print".into()), ("expression", expr.into())])
+            }
+            Stmt::Var { name, initializer } => Json::object([
+                ("type", "Var".into()),
+                ("name", name.into()),
+                ("initializer", initializer.as_ref().into()),
+            ]),
+            Stmt::Block { brace, statements } => Json::object([
+                ("type", "Block".into()),
+                ("brace", brace.into()),
+                ("statements", json_list(statements)),
+            ]),
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => Json::object([
+                ("type", "If".into()),
+                ("condition", condition.into()),
+                ("then", then_branch.into()),
+                ("else", else_branch.as_ref().into()),
+            ]),
+            Stmt::While { condition, body } => Json::object([
+                ("type", "While".into()),
+                ("condition", condition.into()),
+                ("body", body.into()),
+            ]),
+            Stmt::For {
+                initializer,
+                condition,
+                increment,
+                body,
+            } => Json::object([
+                ("type", "For".into()),
+                ("initializer", initializer.as_ref().into()),
+                ("condition", condition.as_ref().into()),
+                ("increment", increment.as_ref().into()),
+                ("body", body.into()),
+            ]),
+            Stmt::ForIn {
+                name,
+                iterable,
+                body,
+            } => Json::object([
+                ("type", "ForIn".into()),
+                ("name", name.into()),
+                ("iterable", iterable.into()),
+                ("body", body.into()),
+            ]),
+            Stmt::Function(function) => function.into(),
+            Stmt::Return { keyword, value } => Json::object([
+                ("type", "Return".into()),
+                ("keyword", keyword.into()),
+                ("value", value.as_ref().into()),
+            ]),
+            Stmt::Class {
+                name,
+                superclass,
+                methods,
+                statics,
+            } => Json::object([
+                ("type", "Class".into()),
+                ("name", name.into()),
+                ("superclass", superclass.as_ref().into()),
+                ("methods", json_list(methods)),
+                ("statics", json_list(statics)),
+            ]),
+            Stmt::Extend {
+                name,
+                methods,
+                statics,
+            } => Json::object([
+                ("type", "Extend".into()),
+                ("name", name.into()),
+                ("methods", json_list(methods)),
+                ("statics", json_list(statics)),
+            ]),
+        }
+    }
+}
+
+impl From<&Expr> for Json {
+    fn from(expr: &Expr) -> Self {
+        match expr {
+            Expr::Literal(token) => Json::object([
+                ("type", "Literal".into()),
+                ("kind", format!("{:?}", token.ttype).as_str().into()),
+                ("token", token.into()),
+            ]),
+            Expr::Variable(name) => {
+                Json::object([("type", "Variable".into()), ("name", name.into())])
+            }
+            Expr::Assign { name, value } => Json::object([
+                ("type", "Assign".into()),
+                ("name", name.into()),
+                ("value", value.into()),
+            ]),
+            Expr::Unary { operator, right } => Json::object([
+                ("type", "Unary".into()),
+                ("operator", operator.into()),
+                ("right", right.into()),
+            ]),
+            Expr::Binary {
+                left,
+                operator,
+                right,
+            } => Json::object([
+                ("type", "Binary".into()),
+                ("left", left.into()),
+                ("operator", operator.into()),
+                ("right", right.into()),
+            ]),
+            Expr::Logical {
+                left,
+                operator,
+                right,
+            } => Json::object([
+                ("type", "Logical".into()),
+                ("left", left.into()),
+                ("operator", operator.into()),
+                ("right", right.into()),
+            ]),
+            Expr::Grouping(expr) => {
+                Json::object([("type", "Grouping".into()), ("expression", expr.into())])
+            }
+            Expr::Call {
+                callee,
+                paren,
+                arguments,
+            } => Json::object([
+                ("type", "Call".into()),
+                ("callee", callee.into()),
+                ("paren", paren.into()),
+                ("arguments", json_list(arguments)),
+            ]),
+            Expr::Get { object, name } => Json::object([
+                ("type", "Get".into()),
+                ("object", object.into()),
+                ("name", name.into()),
+            ]),
+            Expr::Set {
+                object,
+                name,
+                value,
+            } => Json::object([
+                ("type", "Set".into()),
+                ("object", object.into()),
+                ("name", name.into()),
+                ("value", value.into()),
+            ]),
+            Expr::This(keyword) => {
+                Json::object([("type", "This".into()), ("keyword", keyword.into())])
+            }
+            Expr::Super { keyword, method } => Json::object([
+                ("type", "Super".into()),
+                ("keyword", keyword.into()),
+                ("method", method.into()),
+            ]),
+        }
+    }
+}
+
+// Parses `source` into a tree without generating any bytecode. Syntax
+// errors are reported with the same messages the compiler uses; after each
+// one the parser skips to the next statement and keeps going.
+pub fn parse_to_ast(source: &str) -> Result<Ast, Vec<Diagnostic>> {
+    let (ast, diagnostics) = parse_partial(source);
+    if diagnostics.is_empty() {
+        Ok(ast)
+    } else {
+        Err(diagnostics)
+    }
+}
+
+// Like `parse_to_ast`, but also returns the tree when there are errors. It
+// holds every statement that did parse, which is what the language server
+// works from while a document is half typed.
+pub fn parse_partial(source: &str) -> (Ast, Vec<Diagnostic>) {
+    let mut parser = AstParser::new(source);
+    let mut statements = Vec::new();
+    while !parser.is_match(TokenType::Eof) {
+        if let Some(statement) = parser.declaration() {
+            statements.push(statement);
+        }
+    }
+    (Ast { statements }, parser.diagnostics)
+}
+
+// Unwinds the parser to the enclosing declaration once the error has been
+// recorded.
+struct ParseError;
+
+type ParseResult<T> = Result<T, ParseError>;
+
+struct AstParser {
+    scanner: Scanner,
+    current: Token,
+    previous: Token,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl AstParser {
+    fn new(source: &str) -> Self {
+        let mut parser = Self {
+            scanner: Scanner::new(source),
+            current: Token::default(),
+            previous: Token::default(),
+            diagnostics: Vec::new(),
+        };
+        parser.advance();
+        parser
+    }
+
+    fn advance(&mut self) {
+        self.previous = self.current.clone();
+        loop {
+            self.current = self.scanner.scan_token();
+            if self.current.ttype != TokenType::Error {
+                break;
+            }
+            let token = self.current.clone();
+            self.diagnostics
+                .push(Diagnostic::error_at(&token, &token.lexeme));
+        }
+    }
+
+    fn check(&self, ttype: TokenType) -> bool {
+        self.current.ttype == ttype
+    }
+
+    fn is_match(&mut self, ttype: TokenType) -> bool {
+        if self.check(ttype) {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn consume(&mut self, ttype: TokenType, message: &str) -> ParseResult<Token> {
+        if self.check(ttype) {
+            self.advance();
+            Ok(self.previous.clone())
+        } else {
+            Err(self.error_at_current(message))
+        }
+    }
+
+    fn error_at_current(&mut self, message: &str) -> ParseError {
+        let token = self.current.clone();
+        self.error_at(&token, message)
+    }
+
+    fn error_at(&mut self, token: &Token, message: &str) -> ParseError {
+        self.diagnostics.push(Diagnostic::error_at(token, message));
+        ParseError
+    }
+
+    fn synchronize(&mut self) {
+        while self.current.ttype != TokenType::Eof {
+            if self.previous.ttype == TokenType::SemiColon {
+                return;
+            }
+            if matches!(
+                self.current.ttype,
+                TokenType::Class
+                    | TokenType::Fun
+                    | TokenType::Var
+                    | TokenType::For
+                    | TokenType::If
+                    | TokenType::While
+                    | TokenType::Print
+                    | TokenType::Return
+            ) {
+                return;
+            }
+            self.advance();
+        }
+    }
+
+    fn declaration(&mut self) -> Option<Stmt> {
+        let result = if self.is_match(TokenType::Class) {
+            self.class_declaration()
+        } else if self.check(TokenType::Identifier)
+            && self.current.lexeme == "extend"
+            && self.scanner.peek_token().ttype == TokenType::Identifier
+        {
+            self.advance();
+            self.extend_declaration()
+        } else if self.is_match(TokenType::Fun) {
+            self.function("Expect function name.").map(Stmt::Function)
+        } else if self.is_match(TokenType::Var) {
+            self.var_declaration()
+        } else {
+            self.statement()
+        };
+
+        match result {
+            Ok(statement) => Some(statement),
+            Err(ParseError) => {
+                self.synchronize();
+                None
+            }
+        }
+    }
+
+    fn class_declaration(&mut self) -> ParseResult<Stmt> {
+        let name = self.consume(TokenType::Identifier, "Expect class name.")?;
+        let superclass = if self.is_match(TokenType::Less) {
+            let superclass = self.consume(TokenType::Identifier, "Expect superclass name.")?;
+            if superclass.lexeme == name.lexeme {
+                self.error_at(&superclass, "A class can't inherit from itself.");
+            }
+            Some(superclass)
+        } else {
+            None
+        };
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.")?;
+        let (methods, statics) = self.class_members()?;
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.")?;
+
+        Ok(Stmt::Class {
+            name,
+            superclass,
+            methods,
+            statics,
+        })
+    }
+
+    fn extend_declaration(&mut self) -> ParseResult<Stmt> {
+        let name = self.consume(TokenType::Identifier, "Expect class name.")?;
+        self.consume(TokenType::LeftBrace, "Expect '{' before extension body.")?;
+        let (methods, statics) = self.class_members()?;
+        self.consume(TokenType::RightBrace, "Expect '}' after extension body.")?;
+
+        Ok(Stmt::Extend {
+            name,
+            methods,
+            statics,
+        })
+    }
+
+    fn class_members(&mut self) -> ParseResult<(Vec<FunctionDecl>, Vec<StaticField>)> {
+        let mut methods = Vec::new();
+        let mut statics = Vec::new();
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            let member = self.consume(TokenType::Identifier, "Expect method name.")?;
+            if member.lexeme == "static" && self.check(TokenType::Identifier) {
+                statics.push(self.static_field()?);
+            } else {
+                methods.push(self.function_body(member)?);
+            }
+        }
+        Ok((methods, statics))
+    }
+
+    fn static_field(&mut self) -> ParseResult<StaticField> {
+        let name = self.consume(TokenType::Identifier, "Expect static field name.")?;
+        let value = if self.is_match(TokenType::Assign) {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+        self.consume(TokenType::SemiColon, "Expect ';' after static field.")?;
+        Ok(StaticField { name, value })
+    }
+
+    fn function(&mut self, name_message: &str) -> ParseResult<FunctionDecl> {
+        let name = self.consume(TokenType::Identifier, name_message)?;
+        self.function_body(name)
+    }
+
+    fn function_body(&mut self, name: Token) -> ParseResult<FunctionDecl> {
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.")?;
+        let mut params = Vec::new();
+        if !self.check(TokenType::RightParen) {
+            loop {
+                if params.len() == 255 {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+                params.push(self.consume(TokenType::Identifier, "Expect parameter name.")?);
+                if !self.is_match(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.")?;
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.")?;
+        let body = self.block()?;
+
+        Ok(FunctionDecl { name, params, body })
+    }
+
+    fn var_declaration(&mut self) -> ParseResult<Stmt> {
+        let name = self.consume(TokenType::Identifier, "Expect variable name.")?;
+        let initializer = if self.is_match(TokenType::Assign) {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+        self.consume(
+            TokenType::SemiColon,
+            "Expect ';' after variable declaration.",
+        )?;
+
+        Ok(Stmt::Var { name, initializer })
+    }
+
+    fn statement(&mut self) -> ParseResult<Stmt> {
+        if self.is_match(TokenType::Print) {
+            let value = self.expression()?;
+            self.consume(TokenType::SemiColon, "Expect ';' after value.")?;
+            Ok(Stmt::Print(value))
+        } else if self.is_match(TokenType::For) {
+            self.for_statement()
+        } else if self.is_match(TokenType::If) {
+            self.if_statement()
+        } else if self.is_match(TokenType::Return) {
+            self.return_statement()
+        } else if self.is_match(TokenType::While) {
+            self.while_statement()
+        } else if self.is_match(TokenType::LeftBrace) {
+            let brace = self.previous.clone();
+            Ok(Stmt::Block {
+                brace,
+                statements: self.block()?,
+            })
+        } else {
+            self.expression_statement()
+        }
+    }
+
+    // Declarations that fail to parse are dropped from the block; their
+    // diagnostics are already recorded.
+    fn block(&mut self) -> ParseResult<Vec<Stmt>> {
+        let mut statements = Vec::new();
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            if let Some(statement) = self.declaration() {
+                statements.push(statement);
+            }
+        }
+        if self.check(TokenType::Eof) {
+            // There is nothing left to resynchronize on, so keep what the
+            // unclosed block has instead of dropping its whole declaration.
+            self.error_at_current("Expect '}' after block.");
+        } else {
+            self.consume(TokenType::RightBrace, "Expect '}' after block.")?;
+        }
+        Ok(statements)
+    }
+
+    fn expression_statement(&mut self) -> ParseResult<Stmt> {
+        let expr = self.expression()?;
+        self.consume(TokenType::SemiColon, "Expect ';' after expression.")?;
+        Ok(Stmt::Expression(expr))
+    }
+
+    fn for_statement(&mut self) -> ParseResult<Stmt> {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.")?;
+        let initializer = if self.is_match(TokenType::SemiColon) {
+            None
+        } else if self.is_match(TokenType::Var) {
+            let next = self.scanner.peek_token();
+            if self.check(TokenType::Identifier)
+                && next.ttype == TokenType::Identifier
+                && next.lexeme == "in"
+            {
+                return self.for_in_statement();
+            }
+            Some(Box::new(self.var_declaration()?))
+        } else {
+            Some(Box::new(self.expression_statement()?))
+        };
+
+        let condition = if self.check(TokenType::SemiColon) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.consume(TokenType::SemiColon, "Expect ';' after loop condition.")?;
+
+        let increment = if self.check(TokenType::RightParen) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.consume(TokenType::RightParen, "Expect ')' after for clauses.")?;
+
+        Ok(Stmt::For {
+            initializer,
+            condition,
+            increment,
+            body: Box::new(self.statement()?),
+        })
+    }
+
+    fn for_in_statement(&mut self) -> ParseResult<Stmt> {
+        let name = self.consume(TokenType::Identifier, "Expect variable name.")?;
+        self.advance(); // in
+        let iterable = self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after for clauses.")?;
+
+        Ok(Stmt::ForIn {
+            name,
+            iterable,
+            body: Box::new(self.statement()?),
+        })
+    }
+
+    fn if_statement(&mut self) -> ParseResult<Stmt> {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.")?;
+        let condition = self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after condition.")?;
+
+        let then_branch = Box::new(self.statement()?);
+        let else_branch = if self.is_match(TokenType::Else) {
+            Some(Box::new(self.statement()?))
+        } else {
+            None
+        };
+
+        Ok(Stmt::If {
+            condition,
+            then_branch,
+            else_branch,
+        })
+    }
+
+    fn return_statement(&mut self) -> ParseResult<Stmt> {
+        let keyword = self.previous.clone();
+        let value = if self.check(TokenType::SemiColon) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.consume(TokenType::SemiColon, "Expect ';' after return value.")?;
+        Ok(Stmt::Return { keyword, value })
+    }
+
+    fn while_statement(&mut self) -> ParseResult<Stmt> {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.")?;
+        let condition = self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after 'while'.")?;
+        Ok(Stmt::While {
+            condition,
+            body: Box::new(self.statement()?),
+        })
+    }
+
+    fn expression(&mut self) -> ParseResult<Expr> {
+        self.assignment()
+    }
+
+    fn assignment(&mut self) -> ParseResult<Expr> {
+        let expr = self.or()?;
+        if !self.is_match(TokenType::Assign) {
+            return Ok(expr);
+        }
+
+        let equals = self.previous.clone();
+        let value = Box::new(self.assignment()?);
+        match expr {
+            Expr::Variable(name) => Ok(Expr::Assign { name, value }),
+            Expr::Get { object, name } => Ok(Expr::Set {
+                object,
+                name,
+                value,
+            }),
+            _ => Err(self.error_at(&equals, "Invalid assignment target.")),
+        }
+    }
+
+    fn or(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.and()?;
+        while self.is_match(TokenType::Or) {
+            let operator = self.previous.clone();
+            let right = self.and()?;
+            expr = Expr::Logical {
+                left: Box::new(expr),
+                operator,
+                right: Box::new(right),
+            };
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.binary(0)?;
+        while self.is_match(TokenType::And) {
+            let operator = self.previous.clone();
+            let right = self.binary(0)?;
+            expr = Expr::Logical {
+                left: Box::new(expr),
+                operator,
+                right: Box::new(right),
+            };
+        }
+        Ok(expr)
+    }
+
+    // Equality, comparison, term and factor, loosest first. Each level is
+    // left associative.
+    fn binary(&mut self, level: usize) -> ParseResult<Expr> {
+        const LEVELS: [&[TokenType]; 4] = [
+            &[TokenType::BangEqual, TokenType::Equals],
+            &[
+                TokenType::Greater,
+                TokenType::GreaterEqual,
+                TokenType::Less,
+                TokenType::LessEqual,
+            ],
+            &[TokenType::Minus, TokenType::Plus],
+            &[TokenType::Slash, TokenType::Star],
+        ];
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+
+        let mut expr = self.binary(level + 1)?;
+        while LEVELS[level].contains(&self.current.ttype) {
+            self.advance();
+            let operator = self.previous.clone();
+            let right = self.binary(level + 1)?;
+            expr = Expr::Binary {
+                left: Box::new(expr),
+                operator,
+                right: Box::new(right),
+            };
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> ParseResult<Expr> {
+        if self.is_match(TokenType::Bang) || self.is_match(TokenType::Minus) {
+            let operator = self.previous.clone();
+            let right = Box::new(self.unary()?);
+            return Ok(Expr::Unary { operator, right });
+        }
+        self.call()
+    }
+
+    fn call(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.primary()?;
+        loop {
+            if self.is_match(TokenType::LeftParen) {
+                let paren = self.previous.clone();
+                let arguments = self.arguments()?;
+                expr = Expr::Call {
+                    callee: Box::new(expr),
+                    paren,
+                    arguments,
+                };
+            } else if self.is_match(TokenType::Dot) {
+                let name =
+                    self.consume(TokenType::Identifier, "Expect property name after '.'.")?;
+                expr = Expr::Get {
+                    object: Box::new(expr),
+                    name,
+                };
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn arguments(&mut self) -> ParseResult<Vec<Expr>> {
+        let mut arguments = Vec::new();
+        if !self.check(TokenType::RightParen) {
+            loop {
+                let argument = self.expression()?;
+                if arguments.len() == 255 {
+                    let token = self.previous.clone();
+                    self.error_at(&token, "Can't have more than 255 arguments.");
+                }
+                arguments.push(argument);
+                if !self.is_match(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.")?;
+        Ok(arguments)
+    }
+
+    fn primary(&mut self) -> ParseResult<Expr> {
+        match self.current.ttype {
+            TokenType::False
+            | TokenType::True
+            | TokenType::Nil
+            | TokenType::Number
+            | TokenType::String => {
+                self.advance();
+                Ok(Expr::Literal(self.previous.clone()))
+            }
+            TokenType::Identifier => {
+                self.advance();
+                Ok(Expr::Variable(self.previous.clone()))
+            }
+            TokenType::This => {
+                self.advance();
+                Ok(Expr::This(self.previous.clone()))
+            }
+            TokenType::Super => {
+                self.advance();
+                let keyword = self.previous.clone();
+                self.consume(TokenType::Dot, "Expect '.' after 'super'.")?;
+                let method =
+                    self.consume(TokenType::Identifier, "Expect superclass method name.")?;
+                Ok(Expr::Super { keyword, method })
+            }
+            TokenType::LeftParen => {
+                self.advance();
+                let expr = self.expression()?;
+                self.consume(TokenType::RightParen, "Expect ')' after expression.")?;
+                Ok(Expr::Grouping(Box::new(expr)))
+            }
+            _ => Err(self.error_at_current("Expect expression.")),
+        }
+    }
+}
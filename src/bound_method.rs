@@ -1,13 +1,20 @@
 use std::fmt::Display;
-use std::rc::Rc;
 
 use crate::closure::*;
+use crate::error::*;
+use crate::memory::*;
+use crate::shared::*;
 use crate::value::*;
 
 #[derive(Debug)]
 pub struct BoundMethod {
     receiver: Value,
     method: Rc<Closure>,
+    // Set for `Class.method`, which hasn't been bound to an instance yet:
+    // `receiver` holds the class, and calls take the instance as their first
+    // argument.
+    unbound: bool,
+    allocation: Option<Allocation>,
 }
 
 impl BoundMethod {
@@ -15,9 +22,25 @@ impl BoundMethod {
         Self {
             receiver: receiver.clone(),
             method: Rc::clone(method),
+            unbound: false,
+            allocation: None,
         }
     }
 
+    pub fn unbound(class: &Value, method: &Rc<Closure>) -> Self {
+        Self {
+            receiver: class.clone(),
+            method: Rc::clone(method),
+            unbound: true,
+            allocation: None,
+        }
+    }
+
+    pub fn with_allocation(mut self, allocation: Allocation) -> Self {
+        self.allocation = Some(allocation);
+        self
+    }
+
     pub fn get_closure(&self) -> Rc<Closure> {
         Rc::clone(&self.method)
     }
@@ -25,6 +48,35 @@ impl BoundMethod {
     pub fn get_receiver(&self) -> Value {
         self.receiver.clone()
     }
+
+    pub fn is_unbound(&self) -> bool {
+        self.unbound
+    }
+
+    // Binds an unbound method to `receiver`, which must be an instance of
+    // the class it came from or of a subclass.
+    pub fn bind(&self, receiver: &Value) -> Result<Self, NativeError> {
+        match (&self.receiver, receiver) {
+            (Value::Class(class), Value::Instance(instance))
+                if instance.get_class().is_subclass_of(class) =>
+            {
+                Ok(Self::new(receiver, &self.method))
+            }
+            (Value::Class(class), _) => {
+                let got = match receiver {
+                    Value::Instance(instance) => {
+                        format!("{} instance", instance.get_class().name())
+                    }
+                    receiver => receiver.type_name().to_string(),
+                };
+                Err(NativeError::new(format!(
+                    "Expected an instance of {} but got {got}.",
+                    class.name()
+                )))
+            }
+            _ => Err(NativeError::new("Method is already bound.")),
+        }
+    }
 }
 
 impl Display for BoundMethod {
@@ -0,0 +1,312 @@
+use std::time::Duration;
+
+use crate::config::*;
+use crate::debugger::*;
+use crate::diagnostic::*;
+use crate::hooks::*;
+use crate::native::*;
+use crate::shared::*;
+use crate::trace::*;
+use crate::value::*;
+use crate::vm::*;
+
+/// Collects a VM's settings along with the sinks and hooks it talks to,
+/// then makes the VM. Anything left unset is what `VM::new()` uses.
+///
+/// ```
+/// use std::time::Duration;
+/// use lox_bytecode::{InterpretResult, VmBuilder};
+///
+/// let mut vm = VmBuilder::new()
+///     .stdlib(false)
+///     .fuel(1_000)
+///     .timeout(Duration::from_secs(1))
+///     .output(std::io::sink())
+///     .build();
+/// let err = vm.interpret("while (true) {}").unwrap_err();
+/// assert_eq!(err.kind(), InterpretResult::BudgetExceeded);
+/// ```
+#[derive(Default)]
+pub struct VmBuilder {
+    config: VmConfig,
+    output: Option<Box<dyn Output>>,
+    input: Option<Box<dyn Input>>,
+    args: Option<Vec<String>>,
+    reporter: Option<Box<dyn Reporter>>,
+    tracer: Option<Tracer>,
+    debugger: Option<Box<dyn Debugger>>,
+    hooks: Option<Box<dyn Hooks>>,
+    breakpoints: Vec<usize>,
+    step: bool,
+    profile: bool,
+    coverage: bool,
+    file: Option<String>,
+    clock: Vec<Rc<dyn NativeFunc>>,
+}
+
+impl VmBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces every setting at once.
+    pub fn config(mut self, config: VmConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// How deep calls may nest.
+    pub fn max_frames(mut self, frames: usize) -> Self {
+        self.config.max_frames = frames;
+        self
+    }
+
+    /// How many values the stack may hold.
+    pub fn max_stack(mut self, slots: usize) -> Self {
+        self.config.max_stack = slots;
+        self
+    }
+
+    /// Roughly how many bytes of objects may be alive at once. Objects are
+    /// freed as soon as nothing refers to them, and stop counting then.
+    ///
+    /// ```
+    /// use lox_bytecode::VmBuilder;
+    ///
+    /// let mut vm = VmBuilder::new().max_memory(10_000).build();
+    /// vm.interpret("class A {} for (var i = 0; i < 10000; i = i + 1) { var a = A(); a.x = i; }")
+    ///     .unwrap();
+    /// let err = vm
+    ///     .interpret("class B {} var list = nil; while (true) { var b = B(); b.next = list; list = b; }")
+    ///     .unwrap_err();
+    /// assert!(err.to_string().contains("Out of memory."));
+    /// ```
+    pub fn max_memory(mut self, bytes: usize) -> Self {
+        self.config.max_memory = bytes;
+        self
+    }
+
+    pub fn optimize(mut self, optimize: bool) -> Self {
+        self.config.optimize = optimize;
+        self
+    }
+
+    /// Whether to print the bytecode of everything compiled.
+    pub fn print_code(mut self, print_code: bool) -> Self {
+        self.config.print_code = print_code;
+        self
+    }
+
+    pub fn division_by_zero(mut self, behaviour: DivisionByZero) -> Self {
+        self.config.division_by_zero = behaviour;
+        self
+    }
+
+    pub fn warnings(mut self, warnings: Warnings) -> Self {
+        self.config.warnings = warnings;
+        self
+    }
+
+    /// Whether to define the built-in natives. On by default.
+    pub fn stdlib(mut self, stdlib: bool) -> Self {
+        self.config.stdlib = stdlib;
+        self
+    }
+
+    /// Instructions each script may run.
+    pub fn fuel(mut self, fuel: usize) -> Self {
+        self.config.fuel = Some(fuel);
+        self
+    }
+
+    /// How long each script may run, sleeping included.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use lox_bytecode::VmBuilder;
+    ///
+    /// let mut vm = VmBuilder::new().timeout(Duration::from_millis(50)).build();
+    /// let err = vm.interpret("sleep(1000000000);").unwrap_err();
+    /// assert!(err.to_string().contains("Script timed out."));
+    /// ```
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.config.timeout = Some(timeout);
+        self
+    }
+
+    /// Makes `clock()` count calls and seeds `random()` the same way every
+    /// time.
+    ///
+    /// ```
+    /// use lox_bytecode::{CapturedOutput, VmBuilder};
+    ///
+    /// let output = CapturedOutput::default();
+    /// let mut vm = VmBuilder::new().deterministic().output(output.clone()).build();
+    /// vm.interpret("print clock(); print clock();").unwrap();
+    /// assert_eq!(output.text(), "0\n1\n");
+    /// ```
+    pub fn deterministic(mut self) -> Self {
+        self.config.deterministic = true;
+        self
+    }
+
+    /// Where `print` writes.
+    pub fn output(mut self, out: impl Output + 'static) -> Self {
+        self.output = Some(Box::new(out));
+        self
+    }
+
+    /// Where `readLine` reads.
+    ///
+    /// ```
+    /// use lox_bytecode::{Value, VmBuilder};
+    ///
+    /// let mut vm = VmBuilder::new().input("Ada\n".as_bytes()).build();
+    /// vm.interpret("var name = readLine(); var end = readLine();").unwrap();
+    /// assert_eq!(vm.get_global("name"), Some(Value::from("Ada")));
+    /// assert_eq!(vm.get_global("end"), Some(Value::Nil));
+    /// ```
+    pub fn input(mut self, input: impl Input + 'static) -> Self {
+        self.input = Some(Box::new(input));
+        self
+    }
+
+    /// The arguments scripts see through `argCount()` and `arg(n)`.
+    ///
+    /// ```
+    /// use lox_bytecode::{Value, VmBuilder};
+    ///
+    /// let mut vm = VmBuilder::new().args(vec!["-v".to_string()]).build();
+    /// vm.interpret("var count = argCount(); var first = arg(0);").unwrap();
+    /// assert_eq!(vm.get_global("count"), Some(Value::Number(1.0)));
+    /// assert_eq!(vm.get_global("first"), Some(Value::from("-v")));
+    /// ```
+    pub fn args(mut self, args: Vec<String>) -> Self {
+        self.args = Some(args);
+        self
+    }
+
+    /// Replaces the `clock` and `now` natives with `clock`, which returns
+    /// milliseconds since the Unix epoch.
+    /// Needed where the VM can't read the system time, as in a browser, and
+    /// handy for scripts that should see the same time on every run.
+    ///
+    /// ```
+    /// use lox_bytecode::{Value, VmBuilder};
+    ///
+    /// let mut vm = VmBuilder::new().clock(|| 1234.0).build();
+    /// vm.interpret("var now = clock();").unwrap();
+    /// assert_eq!(vm.get_global("now"), Some(Value::Number(1234.0)));
+    /// ```
+    pub fn clock<F>(mut self, clock: F) -> Self
+    where
+        F: Fn() -> f64 + MaybeSend + MaybeSync + 'static,
+    {
+        let clock = Rc::new(clock);
+        self.clock = ["clock", "now"]
+            .into_iter()
+            .map(|name| {
+                let clock = Rc::clone(&clock);
+                let native: Rc<dyn NativeFunc> = Rc::new(NativeClosure::new(name, 0, move |_| {
+                    Ok(Value::Number(clock()))
+                }));
+                native
+            })
+            .collect();
+        self
+    }
+
+    /// Where compile errors and warnings go.
+    pub fn reporter(mut self, reporter: impl Reporter + 'static) -> Self {
+        self.reporter = Some(Box::new(reporter));
+        self
+    }
+
+    pub fn tracer(mut self, tracer: Tracer) -> Self {
+        self.tracer = Some(tracer);
+        self
+    }
+
+    pub fn debugger(mut self, debugger: impl Debugger + 'static) -> Self {
+        self.debugger = Some(Box::new(debugger));
+        self
+    }
+
+    /// Callbacks for calls, returns and global writes.
+    pub fn hooks(mut self, hooks: impl Hooks + 'static) -> Self {
+        self.hooks = Some(Box::new(hooks));
+        self
+    }
+
+    pub fn breakpoint(mut self, line: usize) -> Self {
+        self.breakpoints.push(line);
+        self
+    }
+
+    /// Pauses the debugger on the first line that runs.
+    pub fn step(mut self) -> Self {
+        self.step = true;
+        self
+    }
+
+    pub fn profile(mut self) -> Self {
+        self.profile = true;
+        self
+    }
+
+    /// Counts which lines run, for [`VM::coverage`].
+    pub fn coverage(mut self) -> Self {
+        self.coverage = true;
+        self
+    }
+
+    /// The file scripts come from, for stack traces.
+    pub fn file(mut self, file: &str) -> Self {
+        self.file = Some(file.to_string());
+        self
+    }
+
+    pub fn build(self) -> VM {
+        let mut vm = VM::with_config(self.config);
+        if let Some(out) = self.output {
+            vm.set_output(out);
+        }
+        if let Some(args) = self.args {
+            vm.set_args(args);
+        }
+        if let Some(input) = self.input {
+            vm.set_input(input);
+        }
+        if let Some(reporter) = self.reporter {
+            vm.set_reporter(reporter);
+        }
+        if let Some(tracer) = self.tracer {
+            vm.set_tracer(tracer);
+        }
+        if let Some(debugger) = self.debugger {
+            vm.set_debugger(debugger);
+        }
+        if let Some(hooks) = self.hooks {
+            vm.set_hooks(hooks);
+        }
+        for line in self.breakpoints {
+            vm.add_breakpoint(line);
+        }
+        if self.step {
+            vm.step();
+        }
+        if self.profile {
+            vm.enable_profiling();
+        }
+        if self.coverage {
+            vm.enable_coverage();
+        }
+        if let Some(file) = &self.file {
+            vm.set_file(file);
+        }
+        for clock in &self.clock {
+            vm.define_native(clock);
+        }
+        vm
+    }
+}
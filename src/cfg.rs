@@ -0,0 +1,129 @@
+use std::fmt::Write;
+
+use crate::chunk::*;
+use crate::function::*;
+use crate::value::*;
+
+// Renders the control-flow graph of `function`, and of every function nested
+// in it, as a Graphviz DOT graph. Each function is a cluster of basic blocks
+// listing their disassembled instructions. A failed conditional jump falls
+// through on the edge labelled "true"; the jump itself is labelled "false".
+pub fn to_dot(function: &Function) -> String {
+    let mut functions = Vec::new();
+    collect(function, &mut functions);
+
+    let mut dot = String::from("digraph cfg {\n    node [shape=box, fontname=monospace];\n");
+    for (index, function) in functions.iter().enumerate() {
+        let name = match function.name() {
+            "" => "<script>",
+            name => name,
+        };
+        let _ = writeln!(dot, "    subgraph cluster_{index} {{");
+        let _ = writeln!(dot, "        label=\"{}\";", escape(name));
+        write_blocks(&mut dot, &format!("f{index}"), &function.chunk);
+        dot.push_str("    }\n");
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+fn collect<'a>(function: &'a Function, functions: &mut Vec<&'a Function>) {
+    functions.push(function);
+    for constant in function.chunk.constants() {
+        if let Value::Func(nested) = constant {
+            collect(nested, functions);
+        }
+    }
+}
+
+fn is_conditional(op: OpCode) -> bool {
+    matches!(
+        op,
+        OpCode::JumpIfFalse | OpCode::JumpIfFalseLong | OpCode::JumpIfFalsePop
+    )
+}
+
+fn write_blocks(dot: &mut String, prefix: &str, chunk: &Chunk) {
+    // A block starts at the entry, at every jump target and after every
+    // jump or return.
+    let mut offsets = Vec::new();
+    let mut leaders = vec![0];
+    let mut offset = 0;
+    while offset < chunk.count() {
+        offsets.push(offset);
+        let Some(len) = chunk.instruction_len(offset) else {
+            break;
+        };
+        if let Some(target) = chunk.jump_target(offset) {
+            leaders.push(target);
+            leaders.push(offset + len);
+        } else if chunk.read(offset) == u8::from(OpCode::Return) {
+            leaders.push(offset + len);
+        }
+        offset += len;
+    }
+    leaders.retain(|&leader| leader < chunk.count());
+    leaders.sort_unstable();
+    leaders.dedup();
+
+    let mut edges = Vec::new();
+    for (block, &start) in leaders.iter().enumerate() {
+        let end = leaders.get(block + 1).copied().unwrap_or(chunk.count());
+        let mut label = String::new();
+        let mut last = start;
+        for &offset in offsets.iter().filter(|&&o| o >= start && o < end) {
+            let (text, _) = chunk.format_instruction(offset);
+            for line in text.lines() {
+                label += &escape(line);
+                label += "\\l";
+            }
+            last = offset;
+        }
+        let _ = writeln!(dot, "        {prefix}_{start} [label=\"{label}\"];");
+
+        let Ok(op) = OpCode::try_from(chunk.read(last)) else {
+            continue;
+        };
+        let falls_through = end < chunk.count();
+        match chunk.jump_target(last) {
+            Some(target) if is_conditional(op) => {
+                edges.push((start, target, Some("false")));
+                if falls_through {
+                    edges.push((start, end, Some("true")));
+                }
+            }
+            Some(target) => edges.push((start, target, None)),
+            None if op == OpCode::Return => {}
+            None if falls_through => edges.push((start, end, None)),
+            None => {}
+        }
+    }
+    for (from, to, label) in edges {
+        match label {
+            Some(label) => {
+                let _ = writeln!(
+                    dot,
+                    "        {prefix}_{from} -> {prefix}_{to} [label=\"{label}\"];"
+                );
+            }
+            None => {
+                let _ = writeln!(dot, "        {prefix}_{from} -> {prefix}_{to};");
+            }
+        }
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
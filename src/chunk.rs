@@ -1,5 +1,8 @@
+use crate::inline_cache::*;
+use crate::shared::*;
 use crate::value::*;
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum OpCode {
     Constant = 0,
     Return,
@@ -38,16 +41,191 @@ pub enum OpCode {
     Inherit,
     GetSuper,
     SuperInvoke,
+    ConstantLong,
+    DefineGlobalLong,
+    GetGlobalLong,
+    SetGlobalLong,
+    ClassLong,
+    GetPropertyLong,
+    SetPropertyLong,
+    MethodLong,
+    InvokeLong,
+    GetSuperLong,
+    SuperInvokeLong,
+    ClosureLong,
+    JumpIfFalseLong,
+    JumpLong,
+    LoopLong,
+    GetLocalLong,
+    SetLocalLong,
+    NotEqual,
+    NotGreater,
+    NotLess,
+    PopN,
+    AddLocals,
+    ConstantCall,
+    JumpIfFalsePop,
+    Extend,
+    GreaterEqual,
+    LessEqual,
+}
+
+// Indices that don't fit in a byte are encoded as a 24-bit operand.
+pub const MAX_LONG_INDEX: usize = 0xff_ffff;
+
+impl OpCode {
+    pub fn long(self) -> Option<OpCode> {
+        match self {
+            OpCode::Constant => Some(OpCode::ConstantLong),
+            OpCode::DefineGlobal => Some(OpCode::DefineGlobalLong),
+            OpCode::GetGlobal => Some(OpCode::GetGlobalLong),
+            OpCode::SetGlobal => Some(OpCode::SetGlobalLong),
+            OpCode::Class => Some(OpCode::ClassLong),
+            OpCode::GetProperty => Some(OpCode::GetPropertyLong),
+            OpCode::SetProperty => Some(OpCode::SetPropertyLong),
+            OpCode::Method => Some(OpCode::MethodLong),
+            OpCode::Invoke => Some(OpCode::InvokeLong),
+            OpCode::GetSuper => Some(OpCode::GetSuperLong),
+            OpCode::SuperInvoke => Some(OpCode::SuperInvokeLong),
+            OpCode::Closure => Some(OpCode::ClosureLong),
+            OpCode::GetLocal => Some(OpCode::GetLocalLong),
+            OpCode::SetLocal => Some(OpCode::SetLocalLong),
+            _ => None,
+        }
+    }
+
+    pub fn long_jump(self) -> OpCode {
+        match self {
+            OpCode::JumpIfFalse => OpCode::JumpIfFalseLong,
+            OpCode::Jump => OpCode::JumpLong,
+            OpCode::Loop => OpCode::LoopLong,
+            _ => panic!("{self:?} is not a jump"),
+        }
+    }
+
+    // The name the disassembler prints and the assembler reads.
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            OpCode::Constant => "OP_CONSTANT",
+            OpCode::Return => "OP_RETURN",
+            OpCode::Negate => "OP_NEGATE",
+            OpCode::Add => "OP_ADD",
+            OpCode::Subtract => "OP_SUBTRACT",
+            OpCode::Multiply => "OP_MULTIPLY",
+            OpCode::Divide => "OP_DIVIDE",
+            OpCode::Nil => "OP_NIL",
+            OpCode::True => "OP_TRUE",
+            OpCode::False => "OP_FALSE",
+            OpCode::Not => "OP_NOT",
+            OpCode::Equal => "OP_EQUAL",
+            OpCode::Greater => "OP_GREATER",
+            OpCode::Less => "OP_LESS",
+            OpCode::Print => "OP_PRINT",
+            OpCode::Pop => "OP_POP",
+            OpCode::DefineGlobal => "OP_DEFINE_GLOBAL",
+            OpCode::GetGlobal => "OP_GET_GLOBAL",
+            OpCode::SetGlobal => "OP_SET_GLOBAL",
+            OpCode::GetLocal => "OP_GET_LOCAL",
+            OpCode::SetLocal => "OP_SET_LOCAL",
+            OpCode::JumpIfFalse => "OP_JUMP_IF_FALSE",
+            OpCode::Jump => "OP_JUMP",
+            OpCode::Loop => "OP_LOOP",
+            OpCode::Call => "OP_CALL",
+            OpCode::Closure => "OP_CLOSURE",
+            OpCode::GetUpvalue => "OP_GET_UPVALUE",
+            OpCode::SetUpvalue => "OP_SET_UPVALUE",
+            OpCode::CloseUpvalue => "OP_CLOSE_UPVALUE",
+            OpCode::Class => "OP_CLASS",
+            OpCode::GetProperty => "OP_GET_PROPERTY",
+            OpCode::SetProperty => "OP_SET_PROPERTY",
+            OpCode::Method => "OP_METHOD",
+            OpCode::Invoke => "OP_INVOKE",
+            OpCode::Inherit => "OP_INHERIT",
+            OpCode::GetSuper => "OP_GET_SUPER",
+            OpCode::SuperInvoke => "OP_SUPER_INVOKE",
+            OpCode::ConstantLong => "OP_CONSTANT_LONG",
+            OpCode::DefineGlobalLong => "OP_DEFINE_GLOBAL_LONG",
+            OpCode::GetGlobalLong => "OP_GET_GLOBAL_LONG",
+            OpCode::SetGlobalLong => "OP_SET_GLOBAL_LONG",
+            OpCode::ClassLong => "OP_CLASS_LONG",
+            OpCode::GetPropertyLong => "OP_GET_PROPERTY_LONG",
+            OpCode::SetPropertyLong => "OP_SET_PROPERTY_LONG",
+            OpCode::MethodLong => "OP_METHOD_LONG",
+            OpCode::InvokeLong => "OP_INVOKE_LONG",
+            OpCode::GetSuperLong => "OP_GET_SUPER_LONG",
+            OpCode::SuperInvokeLong => "OP_SUPER_INVOKE_LONG",
+            OpCode::ClosureLong => "OP_CLOSURE_LONG",
+            OpCode::JumpIfFalseLong => "OP_JUMP_IF_FALSE_LONG",
+            OpCode::JumpLong => "OP_JUMP_LONG",
+            OpCode::LoopLong => "OP_LOOP_LONG",
+            OpCode::GetLocalLong => "OP_GET_LOCAL_LONG",
+            OpCode::SetLocalLong => "OP_SET_LOCAL_LONG",
+            OpCode::NotEqual => "OP_NOT_EQUAL",
+            OpCode::NotGreater => "OP_NOT_GREATER",
+            OpCode::NotLess => "OP_NOT_LESS",
+            OpCode::PopN => "OP_POP_N",
+            OpCode::AddLocals => "OP_ADD_LOCALS",
+            OpCode::ConstantCall => "OP_CONSTANT_CALL",
+            OpCode::JumpIfFalsePop => "OP_JUMP_IF_FALSE_POP",
+            OpCode::Extend => "OP_EXTEND",
+            OpCode::GreaterEqual => "OP_GREATER_EQUAL",
+            OpCode::LessEqual => "OP_LESS_EQUAL",
+        }
+    }
+
+    pub fn is_long(self) -> bool {
+        matches!(
+            self,
+            OpCode::ConstantLong
+                | OpCode::DefineGlobalLong
+                | OpCode::GetGlobalLong
+                | OpCode::SetGlobalLong
+                | OpCode::ClassLong
+                | OpCode::GetPropertyLong
+                | OpCode::SetPropertyLong
+                | OpCode::MethodLong
+                | OpCode::InvokeLong
+                | OpCode::GetSuperLong
+                | OpCode::SuperInvokeLong
+                | OpCode::ClosureLong
+                | OpCode::GetLocalLong
+                | OpCode::SetLocalLong
+        )
+    }
+}
+
+// Source positions are stored as runs: each entry gives the line and column
+// for every byte from `start` up to the start of the next run.
+#[derive(Clone, Copy, Debug)]
+struct LineRun {
+    start: usize,
+    line: usize,
+    column: usize,
+}
+
+// A local variable for debuggers: the stack slot of its frame it lives in,
+// and the code offsets it's in scope for.
+#[derive(Clone, Debug)]
+pub struct LocalName {
+    pub name: String,
+    pub slot: usize,
+    start: usize,
+    end: usize,
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct Chunk {
     code: Vec<u8>,
-    lines: Vec<usize>,
+    lines: Vec<LineRun>,
     constants: ValueArray,
+    caches: Vec<InlineCache>,
+    // The source file the code came from, when there is one.
+    file: Option<Rc<str>>,
+    // Only kept as the compiler wrote it. Anything that replaces the code
+    // drops them rather than leave them pointing at the wrong offsets.
+    local_names: Vec<LocalName>,
 }
 
-#[cfg(any(feature = "debug_trace_execution", feature = "debug_print_code"))]
 #[derive(PartialEq)]
 enum JumpStyle {
     Forwards,
@@ -60,100 +238,363 @@ impl Chunk {
             code: Vec::new(),
             lines: Vec::new(),
             constants: ValueArray::new(),
+            caches: Vec::new(),
+            file: None,
+            local_names: Vec::new(),
+        }
+    }
+
+    pub fn set_file(&mut self, file: Option<Rc<str>>) {
+        self.file = file;
+    }
+
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    // Rebuilds a chunk from the pieces `code`, `line_runs`, `constants` and
+    // `cache_count` return, giving it fresh inline caches.
+    pub fn from_parts(
+        code: Vec<u8>,
+        lines: Vec<(usize, usize, usize)>,
+        constants: Vec<Value>,
+        caches: usize,
+    ) -> Self {
+        let mut chunk = Self::new();
+        chunk.replace_code(code, lines);
+        for value in constants {
+            chunk.constants.write(value);
         }
+        chunk.caches = vec![InlineCache::default(); caches];
+        chunk
     }
 
-    pub fn write(&mut self, byte: u8, line: usize) {
+    pub fn write(&mut self, byte: u8, line: usize, column: usize) {
+        self.add_line(self.code.len(), line, column);
         self.code.push(byte);
-        self.lines.push(line);
+    }
+
+    fn add_line(&mut self, start: usize, line: usize, column: usize) {
+        if self.lines.last().map(|run| (run.line, run.column)) != Some((line, column)) {
+            self.lines.push(LineRun {
+                start,
+                line,
+                column,
+            });
+        }
+    }
+
+    // Names `slot` from the next instruction on, until `end_local`.
+    pub fn begin_local(&mut self, name: &str, slot: usize) {
+        self.local_names.push(LocalName {
+            name: name.to_string(),
+            slot,
+            start: self.code.len(),
+            end: usize::MAX,
+        });
+    }
+
+    pub fn end_local(&mut self, slot: usize) {
+        let end = self.code.len();
+        let open = self
+            .local_names
+            .iter_mut()
+            .rev()
+            .find(|local| local.slot == slot && local.end == usize::MAX);
+        if let Some(local) = open {
+            local.end = end;
+        }
+    }
+
+    // The locals in scope at `offset`, by slot.
+    pub fn locals_at(&self, offset: usize) -> Vec<&LocalName> {
+        let mut locals: Vec<&LocalName> = self
+            .local_names
+            .iter()
+            .filter(|local| (local.start..local.end).contains(&offset))
+            .collect();
+        locals.sort_by_key(|local| local.slot);
+        locals
     }
 
     pub fn write_at(&mut self, offset: usize, byte: u8) {
         self.code[offset] = byte;
     }
 
+    // `lines` holds the starting offset, line and column of each instruction
+    // in `code`.
+    pub fn replace_code(&mut self, code: Vec<u8>, lines: Vec<(usize, usize, usize)>) {
+        self.code = code;
+        self.local_names.clear();
+        self.lines.clear();
+        for (start, line, column) in lines {
+            self.add_line(start, line, column);
+        }
+    }
+
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    // The (start, line, column) of each run of bytes with the same position.
+    pub fn line_runs(&self) -> impl Iterator<Item = (usize, usize, usize)> + '_ {
+        self.lines
+            .iter()
+            .map(|run| (run.start, run.line, run.column))
+    }
+
+    pub fn constants(&self) -> &[Value] {
+        self.constants.values()
+    }
+
+    pub fn cache_count(&self) -> usize {
+        self.caches.len()
+    }
+
     pub fn read(&self, ip: usize) -> u8 {
         self.code[ip]
     }
 
     pub fn get_line(&self, ip: usize) -> usize {
-        self.lines[ip]
+        self.line_run(ip).line
+    }
+
+    pub fn get_column(&self, ip: usize) -> usize {
+        self.line_run(ip).column
+    }
+
+    fn line_run(&self, ip: usize) -> &LineRun {
+        let run = self.lines.partition_point(|run| run.start <= ip);
+        &self.lines[run - 1]
     }
 
-    pub fn add_constant(&mut self, value: Value) -> Option<u8> {
+    pub fn add_constant(&mut self, value: Value) -> Option<usize> {
         let idx = self.constants.write(value);
-        u8::try_from(idx).ok()
+        if idx > MAX_LONG_INDEX {
+            None
+        } else {
+            Some(idx)
+        }
     }
 
     pub fn get_constant(&self, index: usize) -> &Value {
         self.constants.read_value(index)
     }
 
+    pub fn add_cache(&mut self) -> usize {
+        self.caches.push(InlineCache::default());
+        self.caches.len() - 1
+    }
+
+    pub fn get_cache(&self, index: usize) -> &InlineCache {
+        &self.caches[index]
+    }
+
     pub fn count(&self) -> usize {
-        self.lines.len()
+        self.code.len()
     }
 
-    pub fn get_jump_offset(&self, offset: usize) -> usize {
+    pub fn read_short(&self, offset: usize) -> usize {
         ((self.code[offset] as usize) << 8) | self.code[offset + 1] as usize
     }
 
-    #[cfg(any(feature = "debug_trace_execution", feature = "debug_print_code"))]
+    pub fn read_int(&self, offset: usize) -> usize {
+        ((self.code[offset] as usize) << 24) | self.read_long(offset + 1)
+    }
+
+    pub fn read_long(&self, offset: usize) -> usize {
+        ((self.code[offset] as usize) << 16)
+            | ((self.code[offset + 1] as usize) << 8)
+            | self.code[offset + 2] as usize
+    }
+
+    // Size in bytes of the instruction at `offset`, including its operands.
+    pub fn instruction_len(&self, offset: usize) -> Option<usize> {
+        let instruction = OpCode::try_from(self.code[offset]).ok()?;
+        let index = if instruction.is_long() { 3 } else { 1 };
+        let len = match instruction {
+            OpCode::Return
+            | OpCode::Negate
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::Not
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::GreaterEqual
+            | OpCode::LessEqual
+            | OpCode::Print
+            | OpCode::Pop
+            | OpCode::CloseUpvalue
+            | OpCode::Inherit
+            | OpCode::Extend
+            | OpCode::NotEqual
+            | OpCode::NotGreater
+            | OpCode::NotLess => 1,
+            OpCode::Call | OpCode::GetUpvalue | OpCode::SetUpvalue | OpCode::PopN => 2,
+            OpCode::Constant
+            | OpCode::ConstantLong
+            | OpCode::DefineGlobal
+            | OpCode::DefineGlobalLong
+            | OpCode::GetGlobal
+            | OpCode::GetGlobalLong
+            | OpCode::SetGlobal
+            | OpCode::SetGlobalLong
+            | OpCode::GetLocal
+            | OpCode::GetLocalLong
+            | OpCode::SetLocal
+            | OpCode::SetLocalLong
+            | OpCode::Class
+            | OpCode::ClassLong
+            | OpCode::SetProperty
+            | OpCode::SetPropertyLong
+            | OpCode::Method
+            | OpCode::MethodLong
+            | OpCode::GetSuper
+            | OpCode::GetSuperLong => 1 + index,
+            OpCode::GetProperty | OpCode::GetPropertyLong => 3 + index,
+            OpCode::Invoke | OpCode::InvokeLong => 4 + index,
+            OpCode::SuperInvoke | OpCode::SuperInvokeLong => 2 + index,
+            OpCode::JumpIfFalse
+            | OpCode::Jump
+            | OpCode::Loop
+            | OpCode::AddLocals
+            | OpCode::ConstantCall
+            | OpCode::JumpIfFalsePop => 3,
+            OpCode::JumpIfFalseLong | OpCode::JumpLong | OpCode::LoopLong => 5,
+            OpCode::Closure | OpCode::ClosureLong => {
+                let constant = if instruction.is_long() {
+                    self.read_long(offset + 1)
+                } else {
+                    self.code[offset + 1] as usize
+                };
+                match self.get_constant(constant) {
+                    Value::Func(function) => 1 + index + 3 * function.upvalues(),
+                    _ => return None,
+                }
+            }
+        };
+        Some(len)
+    }
+
+    // Where the jump or loop at `offset` lands, or None for anything else.
+    pub fn jump_target(&self, offset: usize) -> Option<usize> {
+        match OpCode::try_from(self.code[offset]).ok()? {
+            OpCode::JumpIfFalse | OpCode::JumpIfFalsePop | OpCode::Jump => {
+                Some(offset + 3 + self.read_short(offset + 1))
+            }
+            OpCode::Loop => Some(offset + 3 - self.read_short(offset + 1)),
+            OpCode::JumpIfFalseLong | OpCode::JumpLong => {
+                Some(offset + 5 + self.read_int(offset + 1))
+            }
+            OpCode::LoopLong => Some(offset + 5 - self.read_int(offset + 1)),
+            _ => None,
+        }
+    }
+
+    // Returns the index operand of the instruction at `offset` along with the
+    // offset just past it, honouring the long encoding.
+    fn index_operand(&self, offset: usize) -> (usize, usize) {
+        if OpCode::try_from(self.code[offset]).is_ok_and(OpCode::is_long) {
+            (self.read_long(offset + 1), offset + 4)
+        } else {
+            (self.code[offset + 1] as usize, offset + 2)
+        }
+    }
+
     pub fn disassemble(&self, name: &str) {
-        println!("== {} ==", name);
+        println!("{}", self.disassembly(name));
+    }
 
+    // What `disassemble` prints, without the trailing newline.
+    pub fn disassembly(&self, name: &str) -> String {
+        let mut lines = vec![format!("== {name} ==")];
         let mut offset = 0;
         while offset < self.code.len() {
-            offset = self.disassemble_instruction(offset);
+            let (text, next) = self.format_instruction(offset);
+            lines.push(text);
+            offset = next;
         }
+        lines.join("\n")
     }
 
-    #[cfg(any(feature = "debug_trace_execution", feature = "debug_print_code"))]
-    pub fn disassemble_instruction(&self, offset: usize) -> usize {
-        use JumpStyle::*;
+    // Disassembles the chunk and lists its constant table, then does the same
+    // for every function among the constants.
+    pub fn disassemble_all(&self, name: &str) {
+        self.disassemble(name);
+        println!("-- constants --");
+        for (index, constant) in self.constants().iter().enumerate() {
+            println!("{index:4} {constant}");
+        }
+        for constant in self.constants() {
+            if let Value::Func(function) = constant {
+                println!();
+                function.chunk.disassemble_all(function.name());
+            }
+        }
+    }
 
-        print!("{offset:04} ");
+    pub fn disassemble_instruction(&self, offset: usize) -> usize {
+        let (text, next) = self.format_instruction(offset);
+        println!("{text}");
+        next
+    }
 
-        if offset > 0 && self.lines[offset] == self.lines[offset - 1] {
-            print!("   | ");
+    // Renders the instruction at `offset` the way `disassemble` prints it,
+    // without a trailing newline, and returns the offset of the next one.
+    pub fn format_instruction(&self, offset: usize) -> (String, usize) {
+        let line = self.get_line(offset);
+        let position = if offset > 0 && line == self.get_line(offset - 1) {
+            "   |".to_string()
         } else {
-            print!("{:4} ", self.lines[offset]);
-        }
+            format!("{line:4}")
+        };
+        let (text, next) = self.format_operation(offset);
+        (format!("{offset:04} {position} {text}"), next)
+    }
 
-        let instruction: OpCode = self.code[offset].into();
+    fn format_operation(&self, offset: usize) -> (String, usize) {
+        use JumpStyle::*;
+
+        let Ok(instruction) = OpCode::try_from(self.code[offset]) else {
+            return (format!("Unknown opcode {}", self.code[offset]), offset + 1);
+        };
+        let name = instruction.mnemonic();
         match instruction {
-            OpCode::Constant => self.constant_instruction("OP_CONSTANT", offset),
-            OpCode::Return => self.simple_instruction("OP_RETURN", offset),
-            OpCode::Negate => self.simple_instruction("OP_NEGATE", offset),
-            OpCode::Add => self.simple_instruction("OP_ADD", offset),
-            OpCode::Subtract => self.simple_instruction("OP_SUBTRACT", offset),
-            OpCode::Multiply => self.simple_instruction("OP_MULTIPLY", offset),
-            OpCode::Divide => self.simple_instruction("OP_DIVIDE", offset),
-            OpCode::Nil => self.simple_instruction("OP_NIL", offset),
-            OpCode::True => self.simple_instruction("OP_TRUE", offset),
-            OpCode::False => self.simple_instruction("OP_FALSE", offset),
-            OpCode::Not => self.simple_instruction("OP_NOT", offset),
-            OpCode::Equal => self.simple_instruction("OP_EQUAL", offset),
-            OpCode::Greater => self.simple_instruction("OP_GREATER", offset),
-            OpCode::Less => self.simple_instruction("OP_LESS", offset),
-            OpCode::Print => self.simple_instruction("OP_PRINT", offset),
-            OpCode::Pop => self.simple_instruction("OP_POP", offset),
-            OpCode::DefineGlobal => self.constant_instruction("OP_DEFINE_GLOBAL", offset),
-            OpCode::GetGlobal => self.constant_instruction("OP_GET_GLOBAL", offset),
-            OpCode::SetGlobal => self.constant_instruction("OP_SET_GLOBAL", offset),
-            OpCode::GetLocal => self.byte_instruction("OP_GET_LOCAL", offset),
-            OpCode::SetLocal => self.byte_instruction("OP_SET_LOCAL", offset),
-            OpCode::JumpIfFalse => self.jump_instruction("OP_JUMP_IF_FALSE", Forwards, offset),
-            OpCode::Jump => self.jump_instruction("OP_JUMP", Forwards, offset),
-            OpCode::Loop => self.jump_instruction("OP_LOOP", Backwards, offset),
-            OpCode::Call => self.byte_instruction("OP_CALL", offset),
-            OpCode::Closure => {
-                let mut i = offset + 1;
-                let constant = self.code[i];
-                i += 1;
-                print!("{:-16} {constant:4} ", "OP_CLOSURE");
-                self.constants.print_value(constant as usize);
-                println!();
-                if let Value::Func(function) = self.constants.read_value(constant as usize) {
+            OpCode::Constant => self.constant_instruction(name, offset),
+            OpCode::Return => self.simple_instruction(name, offset),
+            OpCode::Negate => self.simple_instruction(name, offset),
+            OpCode::Add => self.simple_instruction(name, offset),
+            OpCode::Subtract => self.simple_instruction(name, offset),
+            OpCode::Multiply => self.simple_instruction(name, offset),
+            OpCode::Divide => self.simple_instruction(name, offset),
+            OpCode::Nil => self.simple_instruction(name, offset),
+            OpCode::True => self.simple_instruction(name, offset),
+            OpCode::False => self.simple_instruction(name, offset),
+            OpCode::Not => self.simple_instruction(name, offset),
+            OpCode::Equal => self.simple_instruction(name, offset),
+            OpCode::Greater => self.simple_instruction(name, offset),
+            OpCode::Less => self.simple_instruction(name, offset),
+            OpCode::Print => self.simple_instruction(name, offset),
+            OpCode::Pop => self.simple_instruction(name, offset),
+            OpCode::DefineGlobal => self.slot_instruction(name, offset),
+            OpCode::GetGlobal => self.slot_instruction(name, offset),
+            OpCode::SetGlobal => self.slot_instruction(name, offset),
+            OpCode::GetLocal => self.byte_instruction(name, offset),
+            OpCode::SetLocal => self.byte_instruction(name, offset),
+            OpCode::JumpIfFalse => self.jump_instruction(name, Forwards, offset),
+            OpCode::Jump => self.jump_instruction(name, Forwards, offset),
+            OpCode::Loop => self.jump_instruction(name, Backwards, offset),
+            OpCode::Call => self.byte_instruction(name, offset),
+            OpCode::Closure | OpCode::ClosureLong => {
+                let (constant, mut i) = self.index_operand(offset);
+                let mut text = format!("{name:-16} {constant:4} {}", self.get_constant(constant));
+                if let Value::Func(function) = self.get_constant(constant) {
                     for _ in 0..function.upvalues() {
                         let is_local = if self.code[i] == 0 {
                             "upvalue"
@@ -161,77 +602,161 @@ impl Chunk {
                             "local"
                         };
                         i += 1;
-                        let index = self.code[i];
-                        i += 1;
-                        println!("{:04}      |                     {is_local} {index}", i - 2);
+                        let index = self.read_short(i);
+                        i += 2;
+                        text += &format!(
+                            "\n{:04}      |                     {is_local} {index}",
+                            i - 3
+                        );
                     }
                 } else {
-                    panic!("No function at position {constant}");
+                    text += &format!("\nNo function at position {constant}");
                 }
-                i
+                (text, i)
             }
-            OpCode::GetUpvalue => self.byte_instruction("OP_GET_UPVALUE", offset),
-            OpCode::SetUpvalue => self.byte_instruction("OP_SET_UPVALUE", offset),
-            OpCode::CloseUpvalue => self.simple_instruction("OP_CLOSE_UPVALUE", offset),
-            OpCode::Class => self.constant_instruction("OP_CLASS", offset),
-            OpCode::GetProperty => self.constant_instruction("OP_GET_PROPERTY", offset),
-            OpCode::SetProperty => self.constant_instruction("OP_SET_PROPERTY", offset),
-            OpCode::Method => self.constant_instruction("OP_METHOD", offset),
-            OpCode::Invoke => self.invoke_instruction("OP_INVOKE", offset),
-            OpCode::Inherit => self.simple_instruction("OP_INHERIT", offset),
-            OpCode::GetSuper => self.constant_instruction("OP_GET_SUPER", offset),
-            OpCode::SuperInvoke => self.invoke_instruction("OP_SUPER_INVOKE", offset),
+            OpCode::GetUpvalue => self.byte_instruction(name, offset),
+            OpCode::SetUpvalue => self.byte_instruction(name, offset),
+            OpCode::CloseUpvalue => self.simple_instruction(name, offset),
+            OpCode::Class => self.constant_instruction(name, offset),
+            OpCode::GetProperty => self.property_instruction(name, offset),
+            OpCode::SetProperty => self.constant_instruction(name, offset),
+            OpCode::Method => self.constant_instruction(name, offset),
+            OpCode::Invoke => self.cached_invoke_instruction(name, offset),
+            OpCode::Inherit => self.simple_instruction(name, offset),
+            OpCode::Extend => self.simple_instruction(name, offset),
+            OpCode::GreaterEqual => self.simple_instruction(name, offset),
+            OpCode::LessEqual => self.simple_instruction(name, offset),
+            OpCode::GetSuper => self.constant_instruction(name, offset),
+            OpCode::SuperInvoke => self.invoke_instruction(name, offset),
+            OpCode::ConstantLong => self.constant_instruction(name, offset),
+            OpCode::DefineGlobalLong => self.slot_instruction(name, offset),
+            OpCode::GetGlobalLong => self.slot_instruction(name, offset),
+            OpCode::SetGlobalLong => self.slot_instruction(name, offset),
+            OpCode::ClassLong => self.constant_instruction(name, offset),
+            OpCode::GetPropertyLong => self.property_instruction(name, offset),
+            OpCode::SetPropertyLong => self.constant_instruction(name, offset),
+            OpCode::MethodLong => self.constant_instruction(name, offset),
+            OpCode::InvokeLong => self.cached_invoke_instruction(name, offset),
+            OpCode::GetSuperLong => self.constant_instruction(name, offset),
+            OpCode::SuperInvokeLong => self.invoke_instruction(name, offset),
+            OpCode::JumpIfFalseLong => self.long_jump_instruction(name, Forwards, offset),
+            OpCode::JumpLong => self.long_jump_instruction(name, Forwards, offset),
+            OpCode::LoopLong => self.long_jump_instruction(name, Backwards, offset),
+            OpCode::GetLocalLong => self.slot_instruction(name, offset),
+            OpCode::SetLocalLong => self.slot_instruction(name, offset),
+            OpCode::NotEqual => self.simple_instruction(name, offset),
+            OpCode::NotGreater => self.simple_instruction(name, offset),
+            OpCode::NotLess => self.simple_instruction(name, offset),
+            OpCode::PopN => self.byte_instruction(name, offset),
+            OpCode::AddLocals => self.two_byte_instruction(name, offset),
+            OpCode::ConstantCall => self.constant_call_instruction(name, offset),
+            OpCode::JumpIfFalsePop => self.jump_instruction(name, Forwards, offset),
         }
     }
 
-    #[cfg(any(feature = "debug_trace_execution", feature = "debug_print_code"))]
-    fn simple_instruction(&self, name: &str, offset: usize) -> usize {
-        println!("{name}");
-        offset + 1
+    fn simple_instruction(&self, name: &str, offset: usize) -> (String, usize) {
+        (name.to_string(), offset + 1)
     }
 
-    #[cfg(any(feature = "debug_trace_execution", feature = "debug_print_code"))]
-    fn byte_instruction(&self, name: &str, offset: usize) -> usize {
+    fn byte_instruction(&self, name: &str, offset: usize) -> (String, usize) {
         let slot = self.code[offset + 1];
-        println!("{name:-16} {slot:4}");
-        offset + 2
+        (format!("{name:-16} {slot:4}"), offset + 2)
+    }
+
+    fn two_byte_instruction(&self, name: &str, offset: usize) -> (String, usize) {
+        let a = self.code[offset + 1];
+        let b = self.code[offset + 2];
+        (format!("{name:-16} {a:4} {b:4}"), offset + 3)
     }
 
-    #[cfg(any(feature = "debug_trace_execution", feature = "debug_print_code"))]
-    fn jump_instruction(&self, name: &str, forward_jump: JumpStyle, offset: usize) -> usize {
-        let jump = self.get_jump_offset(offset + 1);
+    fn constant_call_instruction(&self, name: &str, offset: usize) -> (String, usize) {
+        let constant = self.code[offset + 1] as usize;
+        let arg_count = self.code[offset + 2];
+        let text = format!(
+            "{name:-16} ({arg_count} args) {constant:4} '{}'",
+            self.get_constant(constant)
+        );
+        (text, offset + 3)
+    }
+
+    fn jump_instruction(
+        &self,
+        name: &str,
+        forward_jump: JumpStyle,
+        offset: usize,
+    ) -> (String, usize) {
+        let jump = self.read_short(offset + 1);
         let jump_to = if forward_jump == JumpStyle::Forwards {
             offset + 3 + jump
         } else {
             offset + 3 - jump
         };
-        println!("{name:-16} {offset:4} -> {jump_to}");
-        offset + 3
+        (format!("{name:-16} {offset:4} -> {jump_to}"), offset + 3)
     }
 
-    #[cfg(any(feature = "debug_trace_execution", feature = "debug_print_code"))]
-    fn constant_instruction(&self, name: &str, offset: usize) -> usize {
-        let constant = self.code[offset + 1];
-        print!("{name:-16} {constant:4} '");
-        self.constants.print_value(constant as usize);
-        println!("'");
-        offset + 2
+    fn long_jump_instruction(
+        &self,
+        name: &str,
+        forward_jump: JumpStyle,
+        offset: usize,
+    ) -> (String, usize) {
+        let jump = self.read_int(offset + 1);
+        let jump_to = if forward_jump == JumpStyle::Forwards {
+            offset + 5 + jump
+        } else {
+            offset + 5 - jump
+        };
+        (format!("{name:-16} {offset:4} -> {jump_to}"), offset + 5)
     }
 
-    #[cfg(any(feature = "debug_trace_execution", feature = "debug_print_code"))]
-    fn invoke_instruction(&self, name: &str, offset: usize) -> usize {
-        let constant = self.code[offset + 1];
-        let arg_count = self.code[offset + 2];
-        print!("{name:-16} ({arg_count} args) {constant:4} '");
-        self.constants.print_value(constant as usize);
-        println!("'");
-        offset + 3
+    fn slot_instruction(&self, name: &str, offset: usize) -> (String, usize) {
+        let (slot, next) = self.index_operand(offset);
+        (format!("{name:-16} {slot:4}"), next)
+    }
+
+    fn constant_instruction(&self, name: &str, offset: usize) -> (String, usize) {
+        let (constant, next) = self.index_operand(offset);
+        let text = format!("{name:-16} {constant:4} '{}'", self.get_constant(constant));
+        (text, next)
+    }
+
+    fn property_instruction(&self, name: &str, offset: usize) -> (String, usize) {
+        let (constant, next) = self.index_operand(offset);
+        let cache = self.read_short(next);
+        let text = format!(
+            "{name:-16} {constant:4} '{}' [cache {cache}]",
+            self.get_constant(constant)
+        );
+        (text, next + 2)
+    }
+
+    fn cached_invoke_instruction(&self, name: &str, offset: usize) -> (String, usize) {
+        let (constant, next) = self.index_operand(offset);
+        let arg_count = self.code[next];
+        let cache = self.read_short(next + 1);
+        let text = format!(
+            "{name:-16} ({arg_count} args) {constant:4} '{}' [cache {cache}]",
+            self.get_constant(constant)
+        );
+        (text, next + 3)
+    }
+
+    fn invoke_instruction(&self, name: &str, offset: usize) -> (String, usize) {
+        let (constant, next) = self.index_operand(offset);
+        let arg_count = self.code[next];
+        let text = format!(
+            "{name:-16} ({arg_count} args) {constant:4} '{}'",
+            self.get_constant(constant)
+        );
+        (text, next + 1)
     }
 }
 
-impl From<u8> for OpCode {
-    fn from(code: u8) -> Self {
-        match code {
+impl TryFrom<u8> for OpCode {
+    type Error = u8;
+
+    fn try_from(code: u8) -> Result<Self, u8> {
+        Ok(match code {
             0 => OpCode::Constant,
             1 => OpCode::Return,
             2 => OpCode::Negate,
@@ -269,8 +794,35 @@ impl From<u8> for OpCode {
             34 => OpCode::Inherit,
             35 => OpCode::GetSuper,
             36 => OpCode::SuperInvoke,
-            _ => unimplemented!("Invalid opcode"),
-        }
+            37 => OpCode::ConstantLong,
+            38 => OpCode::DefineGlobalLong,
+            39 => OpCode::GetGlobalLong,
+            40 => OpCode::SetGlobalLong,
+            41 => OpCode::ClassLong,
+            42 => OpCode::GetPropertyLong,
+            43 => OpCode::SetPropertyLong,
+            44 => OpCode::MethodLong,
+            45 => OpCode::InvokeLong,
+            46 => OpCode::GetSuperLong,
+            47 => OpCode::SuperInvokeLong,
+            48 => OpCode::ClosureLong,
+            49 => OpCode::JumpIfFalseLong,
+            50 => OpCode::JumpLong,
+            51 => OpCode::LoopLong,
+            52 => OpCode::GetLocalLong,
+            53 => OpCode::SetLocalLong,
+            54 => OpCode::NotEqual,
+            55 => OpCode::NotGreater,
+            56 => OpCode::NotLess,
+            57 => OpCode::PopN,
+            58 => OpCode::AddLocals,
+            59 => OpCode::ConstantCall,
+            60 => OpCode::JumpIfFalsePop,
+            61 => OpCode::Extend,
+            62 => OpCode::GreaterEqual,
+            63 => OpCode::LessEqual,
+            _ => return Err(code),
+        })
     }
 }
 
@@ -1,44 +1,141 @@
-use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fmt::{Debug, Display, Formatter};
-use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
 
 use crate::closure::*;
+use crate::error::*;
+use crate::instance::*;
+use crate::memory::*;
+use crate::shared::*;
 use crate::value::*;
 
-#[derive(Debug)]
+// A method of a class registered from Rust, called with the receiver and the
+// arguments.
+#[cfg(not(feature = "sync"))]
+pub type HostMethod = dyn Fn(&Value, &[Value]) -> Result<Value, NativeError>;
+#[cfg(feature = "sync")]
+pub type HostMethod = dyn Fn(&Value, &[Value]) -> Result<Value, NativeError> + Send + Sync;
+
 pub struct Class {
     name: String,
+    // Methods and fields not found here are looked up in the superclass, so
+    // a subclass sees what its superclass gains later on. It is set once,
+    // and reading it takes no lock, so an instance being dropped can.
+    superclass: OnceLock<Rc<Class>>,
     methods: RefCell<HashMap<String, Rc<Closure>>>,
     init: RefCell<Option<Rc<Closure>>>,
+    // Only classes registered from Rust have these. Lox methods of the same
+    // name win, and the fields are the constructor's parameters unless there
+    // is an initializer.
+    host_methods: RefCell<HashMap<String, (usize, Rc<HostMethod>)>>,
+    fields: RefCell<Vec<String>>,
+    // `static` fields, which belong to the class itself rather than to its
+    // instances.
+    statics: RefCell<HashMap<String, Value>>,
+    // Where instances with a `__drop()` method go when they are dropped, for
+    // the VM that made the class to finalize.
+    drops: Weak<DropQueue>,
+    // Whether this class itself defines `__drop()`. Dropping an instance
+    // checks this rather than borrowing `methods`, which may be borrowed
+    // already: replacing a method can drop the last reference to one.
+    has_drop: AtomicBool,
+    // What the VM counted for this class and its static fields.
+    allocation: Option<Allocation>,
 }
 
 impl Class {
-    pub fn new(name: String) -> Self {
+    pub fn new(name: String, drops: &Rc<DropQueue>) -> Self {
         Self {
             name,
+            superclass: OnceLock::new(),
             methods: RefCell::new(HashMap::new()),
             init: RefCell::new(None),
+            host_methods: RefCell::new(HashMap::new()),
+            fields: RefCell::new(Vec::new()),
+            statics: RefCell::new(HashMap::new()),
+            drops: Rc::downgrade(drops),
+            has_drop: AtomicBool::new(false),
+            allocation: None,
         }
     }
 
+    pub fn with_allocation(mut self, allocation: Allocation) -> Self {
+        self.allocation = Some(allocation);
+        self
+    }
+
+    // Counts a static field the VM has added.
+    pub fn grow_allocation(&self, bytes: usize) {
+        if let Some(allocation) = &self.allocation {
+            allocation.grow(bytes);
+        }
+    }
+
+    pub fn drop_queue(&self) -> Option<Rc<DropQueue>> {
+        self.drops.upgrade()
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     pub fn set_init_method(&self, closure: Rc<Closure>) {
         self.init.replace(Some(closure));
     }
 
+    // A class inherits from one superclass at most, set when it is defined,
+    // so a second one is ignored.
+    pub fn set_superclass(&self, superclass: &Rc<Class>) {
+        let _ = self.superclass.set(Rc::clone(superclass));
+    }
+
+    pub fn superclass(&self) -> Option<Rc<Class>> {
+        self.superclass.get().cloned()
+    }
+
+    // Whether this class or one it inherits from defines `__drop()`. Safe to
+    // call while an instance is dropped, as it borrows nothing.
+    pub fn has_drop(&self) -> bool {
+        self.has_drop.load(Ordering::Relaxed)
+            || self
+                .superclass
+                .get()
+                .is_some_and(|superclass| superclass.has_drop())
+    }
+
+    // Whether this is `ancestor` or inherits from it.
+    pub fn is_subclass_of(&self, ancestor: &Class) -> bool {
+        std::ptr::eq(self, ancestor)
+            || self
+                .superclass()
+                .is_some_and(|superclass| superclass.is_subclass_of(ancestor))
+    }
+
+    // The initializer defined in this class itself, if any.
+    pub fn own_init_method(&self) -> Option<Rc<Closure>> {
+        self.init.borrow().clone()
+    }
+
     pub fn get_init_method(&self) -> Option<Rc<Closure>> {
-        if self.init.borrow().is_none() {
-            None
-        } else {
-            Some(self.init.borrow().as_ref().unwrap().clone())
+        match self.init.borrow().as_ref() {
+            Some(init) => Some(Rc::clone(init)),
+            None => self.superclass()?.get_init_method(),
         }
     }
 
     pub fn add_method(&self, name: &str, value: &Value) {
         if let Value::Closure(closure) = value {
-            self.methods
+            if name == "__drop" {
+                self.has_drop.store(true, Ordering::Relaxed);
+            }
+            // The method this replaces goes once the borrow has ended, as
+            // dropping it can drop an instance.
+            let old = self
+                .methods
                 .borrow_mut()
                 .insert(name.to_string(), closure.clone());
+            drop(old);
         }
     }
 
@@ -46,15 +143,134 @@ impl Class {
         if name == "init" {
             self.get_init_method()
         } else {
-            self.methods.borrow().get(name).cloned()
+            match self.methods.borrow().get(name) {
+                Some(method) => Some(Rc::clone(method)),
+                None => self.superclass()?.get_method(name),
+            }
         }
     }
 
-    pub fn copy_methods(&self, superclass: &Self) {
-        for (k, v) in superclass.methods.borrow().iter() {
-            self.methods.borrow_mut().insert(k.clone(), Rc::clone(v));
+    // The methods defined in this class itself, without those it inherits.
+    pub fn methods(&self) -> Vec<(String, Rc<Closure>)> {
+        let methods = self.methods.borrow();
+        methods
+            .iter()
+            .map(|(name, method)| (name.clone(), Rc::clone(method)))
+            .collect()
+    }
+
+    pub fn add_host_method(&self, name: &str, arity: usize, method: Rc<HostMethod>) {
+        let old = self
+            .host_methods
+            .borrow_mut()
+            .insert(name.to_string(), (arity, method));
+        drop(old);
+    }
+
+    pub fn get_host_method(&self, name: &str) -> Option<(usize, Rc<HostMethod>)> {
+        match self.host_methods.borrow().get(name) {
+            Some(method) => Some(method.clone()),
+            None => self.superclass()?.get_host_method(name),
         }
-        self.init.replace(superclass.get_init_method());
+    }
+
+    // Whether this class itself was registered from Rust.
+    pub fn has_host_methods(&self) -> bool {
+        !self.host_methods.borrow().is_empty()
+    }
+
+    pub fn add_field(&self, name: &str) {
+        self.fields.borrow_mut().push(name.to_string());
+    }
+
+    // The fields every instance starts with, the superclass's first.
+    pub fn fields(&self) -> Vec<String> {
+        let mut fields = self
+            .superclass()
+            .map_or_else(Vec::new, |superclass| superclass.fields());
+        fields.extend(self.fields.borrow().iter().cloned());
+        fields
+    }
+
+    // Reading a static field finds the superclass's if this class has none
+    // of its own, but setting one always sets this class's.
+    pub fn get_static(&self, name: &str) -> Option<Value> {
+        match self.statics.borrow().get(name) {
+            Some(value) => Some(value.clone()),
+            None => self.superclass()?.get_static(name),
+        }
+    }
+
+    // Returns whether the field is new, which is what the VM counts as an
+    // allocation.
+    pub fn set_static(&self, name: String, value: &Value) -> bool {
+        let old = self.statics.borrow_mut().insert(name, value.clone());
+        old.is_none()
+    }
+
+    pub fn statics(&self) -> Vec<(String, Value)> {
+        let statics = self.statics.borrow();
+        statics
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect()
+    }
+}
+
+/// Adds fields and methods to a class registered with `VM::register_class`.
+/// Without an `init` method, calling the class takes one argument per field,
+/// in the order they were added, and stores them in the new instance.
+/// Either way, `instance.init(...)` initializes an instance again and, like
+/// a Lox initializer, returns it whatever `init` returned.
+pub struct ClassBuilder {
+    class: Rc<Class>,
+}
+
+impl ClassBuilder {
+    pub fn new(class: &Rc<Class>) -> Self {
+        Self {
+            class: Rc::clone(class),
+        }
+    }
+
+    /// A field every instance starts with, set to nil.
+    pub fn field(self, name: &str) -> Self {
+        self.class.add_field(name);
+        self
+    }
+
+    /// A method written in Rust, called with the instance and `arity`
+    /// arguments. Errors it returns become runtime errors.
+    pub fn method<F, E>(self, name: &str, arity: usize, method: F) -> Self
+    where
+        F: Fn(&Value, &[Value]) -> Result<Value, E> + MaybeSend + MaybeSync + 'static,
+        E: Into<NativeError>,
+    {
+        let method: Rc<HostMethod> =
+            Rc::new(move |this: &Value, args: &[Value]| method(this, args).map_err(Into::into));
+        self.class.add_host_method(name, arity, method);
+        self
+    }
+
+    /// The class, as scripts see it.
+    pub fn class(&self) -> Value {
+        Value::Class(Rc::clone(&self.class))
+    }
+}
+
+impl Debug for Class {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        f.debug_struct("Class")
+            .field("name", &self.name)
+            .field(
+                "superclass",
+                &self.superclass().map(|superclass| superclass.name.clone()),
+            )
+            .field("methods", &self.methods)
+            .field("init", &self.init)
+            .field("fields", &self.fields)
+            .field("statics", &self.statics)
+            .finish_non_exhaustive()
     }
 }
 
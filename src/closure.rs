@@ -1,16 +1,16 @@
-use std::cell::RefCell;
 use std::fmt::Display;
-use std::rc::Rc;
 
 use crate::chunk::*;
 use crate::function::*;
+use crate::memory::*;
+use crate::shared::*;
 use crate::upvalues::*;
-use crate::value::*;
 
 #[derive(Debug)]
 pub struct Closure {
     function: Rc<Function>,
     upvalues: RefCell<Vec<Rc<Upvalue>>>,
+    allocation: Option<Allocation>,
 }
 
 impl Display for Closure {
@@ -24,9 +24,15 @@ impl Closure {
         Self {
             function: Rc::clone(&function),
             upvalues: RefCell::new(Vec::new()),
+            allocation: None,
         }
     }
 
+    pub fn with_allocation(mut self, allocation: Allocation) -> Self {
+        self.allocation = Some(allocation);
+        self
+    }
+
     pub fn arity(&self) -> usize {
         self.function.arity()
     }
@@ -39,17 +45,19 @@ impl Closure {
         self.function.stack_name()
     }
 
-    pub fn push_upvalue(&self, value: &Rc<RefCell<Value>>) {
-        self.upvalues
-            .borrow_mut()
-            .push(Rc::new(Upvalue::new(value)));
+    pub fn function(&self) -> Rc<Function> {
+        Rc::clone(&self.function)
+    }
+
+    pub fn upvalues(&self) -> Vec<Rc<Upvalue>> {
+        self.upvalues.borrow().clone()
     }
 
-    pub fn get_upvalue(&self, offset: usize) -> Rc<RefCell<Value>> {
-        self.upvalues.borrow()[offset].value()
+    pub fn push_upvalue(&self, upvalue: &Rc<Upvalue>) {
+        self.upvalues.borrow_mut().push(Rc::clone(upvalue));
     }
 
-    pub fn modify(&self, offset: usize, value: &Rc<RefCell<Value>>) {
-        self.upvalues.borrow_mut()[offset].set(value)
+    pub fn get_upvalue(&self, offset: usize) -> Rc<Upvalue> {
+        Rc::clone(&self.upvalues.borrow()[offset])
     }
 }
@@ -1,45 +1,63 @@
-use std::cell::RefCell;
-use std::rc::Rc;
-
 use crate::chunk::*;
-use crate::error::*;
+use crate::config::*;
+use crate::diagnostic::*;
 use crate::function::*;
+use crate::globals::*;
+use crate::native::*;
+use crate::optimizer::*;
 use crate::scanner::*;
+use crate::shared::*;
 use crate::token::*;
 use crate::value::*;
 
+use std::collections::HashSet;
+
 pub struct Compiler {
     rules: Vec<ParseRule>,
+    globals: Rc<RefCell<GlobalTable>>,
+    natives: Rc<NativeTable>,
+    rebound: HashSet<String>,
     parser: Parser,
     scanner: Scanner,
     result: RefCell<Rc<CompileResult>>,
     current_class: RefCell<Option<Rc<ClassCompiler>>>,
+    long_jumps: bool,
+    jump_overflow: bool,
+    optimize: bool,
+    print_code: bool,
+    warnings: Warnings,
+    file: Option<Rc<str>>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Default, PartialEq)]
 enum ChunkType {
+    #[default]
     Script,
     Function,
     Method,
     Initializer,
 }
 
-impl Default for ChunkType {
-    fn default() -> Self {
-        ChunkType::Script
-    }
-}
-
 #[derive(Debug, PartialEq)]
 struct UpvalueData {
     is_local: bool,
-    index: u8,
+    index: usize,
 }
 
+// Locals past the first 256 are addressed with the long form of
+// GetLocal/SetLocal; closures encode captured slots in two bytes.
+const MAX_LOCALS: usize = u16::MAX as usize + 1;
+
 #[derive(Debug, Default)]
 struct ClassCompiler {
     enclosing: RefCell<Option<Rc<ClassCompiler>>>,
     has_superclass: RefCell<bool>,
+    // Set while compiling the value of a static field, where there is no
+    // `this` to refer to.
+    in_static: RefCell<bool>,
+    // Set for an `extend` block, whose methods can't see the class's
+    // superclass.
+    is_extension: RefCell<bool>,
 }
 
 impl ClassCompiler {
@@ -47,6 +65,8 @@ impl ClassCompiler {
         Self {
             enclosing: RefCell::new(None),
             has_superclass: RefCell::new(false),
+            in_static: RefCell::new(false),
+            is_extension: RefCell::new(false),
         }
     }
 }
@@ -68,7 +88,7 @@ enum FindResult {
     TooManyVariables,
     Uninitialized,
     NotFound,
-    Depth(u8),
+    Depth(usize),
 }
 
 impl CompileResult {
@@ -79,24 +99,32 @@ impl CompileResult {
                 name: Token {
                     ttype: TokenType::This,
                     lexeme: String::from("this"),
-                    line: 0,
+                    ..Default::default()
                 },
                 depth: Some(0),
                 is_captured: false,
+                is_used: false,
+                is_param: false,
             }
         } else {
             Local {
                 name: Token::default(),
                 depth: Some(0),
                 is_captured: false,
+                is_used: false,
+                is_param: false,
             }
         });
-        Self {
+        let result = Self {
             locals,
             current_function: RefCell::new(name.into()),
             ctype,
             ..Default::default()
+        };
+        if matches!(result.ctype, ChunkType::Method | ChunkType::Initializer) {
+            result.chunk.borrow_mut().begin_local("this", 0);
         }
+        result
     }
 
     fn arity(&self) -> usize {
@@ -118,19 +146,44 @@ impl CompileResult {
                 if v.depth.is_none() {
                     return FindResult::Uninitialized;
                 }
-                return FindResult::Depth((self.locals.borrow().len() - e - 1) as u8);
+                return FindResult::Depth(self.locals.borrow().len() - e - 1);
             }
         }
         FindResult::NotFound
     }
 
+    // A captured variable counts as used; the closure may well read it.
     fn capture(&self, index: usize) {
         let mut new_local = self.locals.borrow()[index].clone();
         new_local.is_captured = true;
+        new_local.is_used = true;
         self.locals.borrow_mut()[index] = new_local;
     }
 
-    fn resolve_local(&self, name: &Token) -> Result<Option<u8>, FindResult> {
+    fn mark_used(&self, index: usize) {
+        self.locals.borrow_mut()[index].is_used = true;
+    }
+
+    fn mark_param(&self) {
+        if let Some(local) = self.locals.borrow_mut().last_mut() {
+            local.is_param = true;
+        }
+    }
+
+    fn declared_in_scope(&self, name: &str) -> bool {
+        let scope_depth = *self.scope_depth.borrow();
+        for local in self.locals.borrow().iter().rev() {
+            if matches!(local.depth, Some(depth) if depth < scope_depth) {
+                break;
+            }
+            if local.name.lexeme == name {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn resolve_local(&self, name: &Token) -> Result<Option<usize>, FindResult> {
         let find_result = self.find_variable(&name.lexeme);
         match find_result {
             FindResult::Uninitialized | FindResult::TooManyVariables => Err(find_result),
@@ -151,11 +204,7 @@ impl CompileResult {
             .unwrap()
             .resolve_local(name)?
         {
-            self.enclosing
-                .borrow()
-                .as_ref()
-                .unwrap()
-                .capture(depth as usize);
+            self.enclosing.borrow().as_ref().unwrap().capture(depth);
             return Ok(Some(self.add_upvalue(depth, true)?));
         }
 
@@ -167,11 +216,11 @@ impl CompileResult {
             .resolve_upvalue(name)?
         {
             None => Ok(None),
-            Some(depth) => Ok(Some(self.add_upvalue(depth, false)?)),
+            Some(depth) => Ok(Some(self.add_upvalue(depth as usize, false)?)),
         }
     }
 
-    fn add_upvalue(&self, index: u8, is_local: bool) -> Result<u8, FindResult> {
+    fn add_upvalue(&self, index: usize, is_local: bool) -> Result<u8, FindResult> {
         let upvalue = UpvalueData { index, is_local };
         if let Some(pos) = self.upvalues.borrow().iter().position(|x| x == &upvalue) {
             return Ok(pos as u8);
@@ -193,10 +242,13 @@ impl CompileResult {
         let last = self.locals.borrow().len() - 1;
         let mut locals = self.locals.borrow_mut();
         locals[last].depth = Some(*self.scope_depth.borrow());
+        self.chunk
+            .borrow_mut()
+            .begin_local(&locals[last].name.lexeme, last);
     }
 
     fn is_scope_poppable(&self) -> bool {
-        self.locals.borrow().len() > 0
+        !self.locals.borrow().is_empty()
             && self.locals.borrow().last().unwrap().depth.unwrap() > *self.scope_depth.borrow()
     }
 
@@ -212,31 +264,37 @@ impl CompileResult {
         *self.scope_depth.borrow_mut() -= 1;
     }
 
-    fn pop(&self) {
-        self.locals.borrow_mut().pop();
+    fn pop(&self) -> Option<Local> {
+        let local = self.locals.borrow_mut().pop();
+        let slot = self.locals.borrow().len();
+        self.chunk.borrow_mut().end_local(slot);
+        local
     }
 
     fn push(&self, local: Local) {
         self.locals.borrow_mut().push(local);
     }
 
-    fn write(&self, byte: u8, line: usize) {
-        self.chunk.borrow_mut().write(byte, line);
+    fn write(&self, byte: u8, line: usize, column: usize) {
+        self.chunk.borrow_mut().write(byte, line, column);
     }
 
     fn count(&self) -> usize {
         self.chunk.borrow().count()
     }
 
-    fn add_constant(&self, value: Value) -> Option<u8> {
+    fn add_constant(&self, value: Value) -> Option<usize> {
         self.chunk.borrow_mut().add_constant(value)
     }
 
+    fn add_cache(&self) -> usize {
+        self.chunk.borrow_mut().add_cache()
+    }
+
     fn write_at(&self, offset: usize, byte: u8) {
         self.chunk.borrow_mut().write_at(offset, byte);
     }
 
-    #[cfg(feature = "debug_print_code")]
     fn disassemble(&self, name: &str) {
         self.chunk.borrow().disassemble(name);
     }
@@ -248,6 +306,7 @@ pub struct Parser {
     previous: Token,
     had_error: RefCell<bool>,
     panic_mode: RefCell<bool>,
+    diagnostics: RefCell<Vec<Diagnostic>>,
 }
 
 #[derive(Copy, Clone)]
@@ -277,6 +336,8 @@ struct Local {
     name: Token,
     depth: Option<usize>,
     is_captured: bool,
+    is_used: bool,
+    is_param: bool,
 }
 
 impl From<usize> for Precedence {
@@ -309,7 +370,7 @@ impl Precedence {
 }
 
 impl Compiler {
-    pub fn new() -> Self {
+    pub fn new(globals: &Rc<RefCell<GlobalTable>>) -> Self {
         // lazy_static instead?
         let mut rules = vec![
             ParseRule {
@@ -388,18 +449,76 @@ impl Compiler {
 
         Self {
             rules,
+            globals: Rc::clone(globals),
+            natives: Rc::new(NativeTable::new()),
+            rebound: HashSet::new(),
             parser: Parser::default(),
-            scanner: Scanner::new(&"".to_string()),
+            scanner: Scanner::new(""),
             result: RefCell::new(Rc::new(CompileResult::default())),
             current_class: RefCell::new(None),
+            long_jumps: false,
+            jump_overflow: false,
+            optimize: false,
+            print_code: cfg!(feature = "debug_print_code"),
+            warnings: Warnings::default(),
+            file: None,
+        }
+    }
+
+    /// Names the file being compiled in every chunk, for stack traces.
+    pub fn set_file(&mut self, file: Option<Rc<str>>) {
+        self.file = file;
+    }
+
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    pub fn set_print_code(&mut self, print_code: bool) {
+        self.print_code = print_code;
+    }
+
+    pub fn set_warnings(&mut self, warnings: Warnings) {
+        self.warnings = warnings;
+    }
+
+    /// Warnings from the last successful compile. A failed compile returns
+    /// them along with its errors.
+    pub fn take_warnings(&mut self) -> Vec<Diagnostic> {
+        self.parser.diagnostics.take()
+    }
+
+    pub fn set_natives(&mut self, natives: &Rc<NativeTable>) {
+        self.natives = Rc::clone(natives);
+    }
+
+    /// Compiles the whole script, recovering after each error so every
+    /// problem in the source is reported at once.
+    pub fn compile(&mut self, source: &str) -> Result<Function, Vec<Diagnostic>> {
+        self.rebound = rebound_names(source);
+        let result = self.compile_pass(source);
+
+        // A forward jump didn't fit in 16 bits. Rather than relocating code,
+        // compile the whole script again using long forward jumps throughout.
+        if self.jump_overflow && !*self.parser.had_error.borrow() {
+            self.parser = Parser::default();
+            self.result.replace(Rc::new(CompileResult::default()));
+            self.current_class.replace(None);
+            self.long_jumps = true;
+            self.jump_overflow = false;
+            return self.compile_pass(source);
         }
+
+        result
     }
 
-    pub fn compile(&mut self, source: &str) -> Result<Function, InterpretResult> {
+    fn compile_pass(&mut self, source: &str) -> Result<Function, Vec<Diagnostic>> {
         self.result.borrow().push(Local {
             name: Token::default(),
             depth: Some(0),
             is_captured: false,
+            is_used: false,
+            is_param: false,
         });
 
         self.scanner = Scanner::new(source);
@@ -411,11 +530,12 @@ impl Compiler {
 
         self.end_compiler();
 
-        if *self.parser.had_error.borrow() {
-            Err(InterpretResult::CompileError)
+        if *self.parser.had_error.borrow() || self.jump_overflow {
+            Err(self.parser.diagnostics.take())
         } else {
             let result = self.result.replace(Rc::new(CompileResult::default()));
-            let chunk = result.chunk.replace(Chunk::new());
+            let mut chunk = result.chunk.replace(Chunk::new());
+            chunk.set_file(self.file.clone());
             Ok(Function::toplevel(&Rc::new(chunk)))
         }
     }
@@ -457,9 +577,16 @@ impl Compiler {
     }
 
     fn emit_byte<T: Into<u8>>(&mut self, byte: T) {
+        let token = self.parser.previous.clone();
+        self.emit_at(byte, &token);
+    }
+
+    // Emits `byte` attributed to `token`, so runtime errors point at an
+    // operator rather than at the last token of its operands.
+    fn emit_at<T: Into<u8>>(&mut self, byte: T, token: &Token) {
         self.result
             .borrow()
-            .write(byte.into(), self.parser.previous.line);
+            .write(byte.into(), token.line, token.column);
     }
 
     fn emit_bytes<T: Into<u8>, U: Into<u8>>(&mut self, byte1: T, byte2: U) {
@@ -468,22 +595,39 @@ impl Compiler {
     }
 
     fn emit_loop(&mut self, loop_start: usize) {
-        self.emit_byte(OpCode::Loop);
+        let offset = self.result.borrow().count() + 3 - loop_start;
+        if offset <= u16::MAX as usize {
+            self.emit_byte(OpCode::Loop);
+            self.emit_byte(((offset >> 8) & 0xff) as u8);
+            self.emit_byte((offset & 0xff) as u8);
+            return;
+        }
 
-        let offset = self.result.borrow().count() + 2 - loop_start;
-        if offset > u16::MAX as usize {
+        let offset = offset + 2;
+        if offset > u32::MAX as usize {
             self.error("Loop body too large.");
         }
 
+        self.emit_byte(OpCode::LoopLong);
+        self.emit_byte(((offset >> 24) & 0xff) as u8);
+        self.emit_byte(((offset >> 16) & 0xff) as u8);
         self.emit_byte(((offset >> 8) & 0xff) as u8);
         self.emit_byte((offset & 0xff) as u8);
     }
 
     fn emit_jump(&mut self, instruction: OpCode) -> usize {
-        self.emit_byte(instruction);
-        self.emit_byte(0xff);
-        self.emit_byte(0xff);
-        self.result.borrow().count() - 2
+        if self.long_jumps {
+            self.emit_byte(instruction.long_jump());
+            for _ in 0..4 {
+                self.emit_byte(0xff);
+            }
+            self.result.borrow().count() - 4
+        } else {
+            self.emit_byte(instruction);
+            self.emit_byte(0xff);
+            self.emit_byte(0xff);
+            self.result.borrow().count() - 2
+        }
     }
 
     fn emit_return(&mut self) {
@@ -495,7 +639,7 @@ impl Compiler {
         self.emit_byte(OpCode::Return);
     }
 
-    fn make_constant(&mut self, value: Value) -> u8 {
+    fn make_constant(&mut self, value: Value) -> usize {
         if let Some(constant) = self.result.borrow().add_constant(value) {
             constant
         } else {
@@ -504,16 +648,57 @@ impl Compiler {
         }
     }
 
+    fn emit_cache(&mut self) {
+        let cache = self.result.borrow().add_cache();
+        if cache > u16::MAX as usize {
+            self.error("Too many property accesses in one chunk.");
+        }
+
+        self.emit_byte(((cache >> 8) & 0xff) as u8);
+        self.emit_byte((cache & 0xff) as u8);
+    }
+
     fn emit_constant(&mut self, value: Value) {
         let constant = self.make_constant(value);
-        self.emit_bytes(OpCode::Constant, constant);
+        self.emit_indexed(OpCode::Constant, constant);
+    }
+
+    // Emits `op` with a one byte index, switching to the op's long form when
+    // the index doesn't fit.
+    fn emit_indexed(&mut self, op: OpCode, index: usize) {
+        if let Ok(byte) = u8::try_from(index) {
+            self.emit_bytes(op, byte);
+        } else if let Some(long) = op.long() {
+            self.emit_byte(long);
+            self.emit_byte(((index >> 16) & 0xff) as u8);
+            self.emit_byte(((index >> 8) & 0xff) as u8);
+            self.emit_byte((index & 0xff) as u8);
+        } else {
+            panic!("{op:?} has no long form for index {index}");
+        }
     }
 
     fn patch_jump(&mut self, offset: usize) {
+        if self.long_jumps {
+            let jump = self.result.borrow().count() - offset - 4;
+
+            if jump > u32::MAX as usize {
+                self.error("Too much code to jump over.");
+            }
+
+            for (i, shift) in [24, 16, 8, 0].iter().enumerate() {
+                self.result
+                    .borrow()
+                    .write_at(offset + i, ((jump >> shift) & 0xff) as u8);
+            }
+            return;
+        }
+
         let jump = self.result.borrow().count() - offset - 2;
 
         if jump > u16::MAX as usize {
-            self.error("Too much code to jump over.");
+            self.jump_overflow = true;
+            return;
         }
 
         self.result
@@ -526,16 +711,16 @@ impl Compiler {
 
     fn end_compiler(&mut self) {
         self.emit_return();
-        #[cfg(feature = "debug_print_code")]
-        {
+        if self.optimize && !*self.parser.had_error.borrow() && !self.jump_overflow {
+            optimize(&mut self.result.borrow().chunk.borrow_mut());
+        }
+        if self.print_code && !*self.parser.had_error.borrow() && !self.jump_overflow {
             let name = if self.result.borrow().current_function.borrow().is_empty() {
                 "<script>".to_string()
             } else {
                 self.result.borrow().current_function.borrow().clone()
             };
-            if !*self.parser.had_error.borrow() {
-                self.result.borrow().disassemble(&name)
-            }
+            self.result.borrow().disassemble(&name)
         }
     }
 
@@ -552,28 +737,55 @@ impl Compiler {
             } else {
                 self.emit_byte(OpCode::Pop);
             }
-            self.result.borrow().pop();
+            let local = self.result.borrow().pop();
+            if let Some(local) = local {
+                self.warn_unused(&local);
+            }
+        }
+    }
+
+    // Names starting with an underscore are exempt, as are the implicit
+    // `this` and `super` slots.
+    fn warn_unused(&self, local: &Local) {
+        let name = &local.name.lexeme;
+        if local.is_used || name.is_empty() || name.starts_with('_') {
+            return;
+        }
+        if local.name.ttype == TokenType::This || name == "super" {
+            return;
+        }
+        if local.is_param {
+            if self.warnings.unused_parameters {
+                self.warning_at(&local.name, &format!("Unused parameter '{name}'."));
+            }
+        } else if self.warnings.unused_variables {
+            self.warning_at(&local.name, &format!("Unused variable '{name}'."));
         }
     }
 
     fn binary(&mut self, _: bool) {
-        let operator_type = self.parser.previous.ttype;
+        let operator = self.parser.previous.clone();
+        let operator_type = operator.ttype;
         let rule = self.rules[operator_type as usize].precedence.next();
 
         self.parse_precedence(rule);
 
-        match operator_type {
-            TokenType::BangEqual => self.emit_bytes(OpCode::Equal, OpCode::Not),
-            TokenType::Equals => self.emit_byte(OpCode::Equal),
-            TokenType::Greater => self.emit_byte(OpCode::Greater),
-            TokenType::GreaterEqual => self.emit_bytes(OpCode::Less, OpCode::Not),
-            TokenType::Less => self.emit_byte(OpCode::Less),
-            TokenType::LessEqual => self.emit_bytes(OpCode::Greater, OpCode::Not),
-            TokenType::Plus => self.emit_byte(OpCode::Add),
-            TokenType::Minus => self.emit_byte(OpCode::Subtract),
-            TokenType::Star => self.emit_byte(OpCode::Multiply),
-            TokenType::Slash => self.emit_byte(OpCode::Divide),
+        let (op, negate) = match operator_type {
+            TokenType::BangEqual => (OpCode::Equal, true),
+            TokenType::Equals => (OpCode::Equal, false),
+            TokenType::Greater => (OpCode::Greater, false),
+            TokenType::GreaterEqual => (OpCode::GreaterEqual, false),
+            TokenType::Less => (OpCode::Less, false),
+            TokenType::LessEqual => (OpCode::LessEqual, false),
+            TokenType::Plus => (OpCode::Add, false),
+            TokenType::Minus => (OpCode::Subtract, false),
+            TokenType::Star => (OpCode::Multiply, false),
+            TokenType::Slash => (OpCode::Divide, false),
             _ => todo!(),
+        };
+        self.emit_at(op, &operator);
+        if negate {
+            self.emit_at(OpCode::Not, &operator);
         }
     }
 
@@ -589,13 +801,15 @@ impl Compiler {
 
         if can_assign && self.is_match(TokenType::Assign) {
             self.expression();
-            self.emit_bytes(OpCode::SetProperty, name);
+            self.emit_indexed(OpCode::SetProperty, name);
         } else if self.is_match(TokenType::LeftParen) {
             let arg_count = self.argument_list();
-            self.emit_bytes(OpCode::Invoke, name);
+            self.emit_indexed(OpCode::Invoke, name);
             self.emit_byte(arg_count);
+            self.emit_cache();
         } else {
-            self.emit_bytes(OpCode::GetProperty, name);
+            self.emit_indexed(OpCode::GetProperty, name);
+            self.emit_cache();
         }
     }
 
@@ -635,7 +849,7 @@ impl Compiler {
         self.emit_constant(Value::Str(string));
     }
 
-    fn resolve_local(&self, name: &Token) -> Option<u8> {
+    fn resolve_local(&self, name: &Token) -> Option<usize> {
         match self.result.borrow().resolve_local(name) {
             Err(FindResult::Uninitialized) => {
                 self.error("Can't read local variable in its own initializer.");
@@ -659,22 +873,55 @@ impl Compiler {
 
     fn named_variable(&mut self, name: &Token, can_assign: bool) {
         let (arg, get_op, set_op) = if let Some(local_arg) = self.resolve_local(name) {
+            if !(can_assign && self.check(TokenType::Assign)) {
+                self.result.borrow().mark_used(local_arg);
+            }
             (local_arg, OpCode::GetLocal, OpCode::SetLocal)
         } else if let Some(upvalue_arg) = self.resolve_upvalue(name) {
-            (upvalue_arg, OpCode::GetUpvalue, OpCode::SetUpvalue)
+            (upvalue_arg as usize, OpCode::GetUpvalue, OpCode::SetUpvalue)
         } else {
-            (
-                self.identifier_constant(name),
-                OpCode::GetGlobal,
-                OpCode::SetGlobal,
-            )
+            let slot = self.global_variable(name);
+            let assigning = can_assign && self.check(TokenType::Assign);
+            if assigning {
+                self.globals.borrow_mut().mark_assigned(slot);
+            } else if let Some(native) = self.known_native(name, slot) {
+                self.native_call(native);
+                return;
+            }
+            (slot, OpCode::GetGlobal, OpCode::SetGlobal)
         };
 
         if can_assign && self.is_match(TokenType::Assign) {
             self.expression();
-            self.emit_bytes(set_op, arg);
+            self.emit_indexed(set_op, arg);
         } else {
-            self.emit_bytes(get_op, arg);
+            self.emit_indexed(get_op, arg);
+        }
+    }
+
+    // A global still holds its native as long as nothing has declared or
+    // assigned it, and nothing anywhere in this source does either, since a
+    // function can call a global declared further down. Code compiled in an
+    // earlier call keeps calling the native, so redefining one in the REPL
+    // only affects code compiled after it.
+    fn known_native(&self, name: &Token, slot: usize) -> Option<Rc<dyn NativeFunc>> {
+        if self.globals.borrow().is_assigned(slot) || self.rebound.contains(&name.lexeme) {
+            return None;
+        }
+        self.natives.get(&name.lexeme).cloned()
+    }
+
+    // Loads the native from the constant table. When it's called right away
+    // the call is folded into a single ConstantCall.
+    fn native_call(&mut self, native: Rc<dyn NativeFunc>) {
+        let constant = self.make_constant(Value::Native(native));
+        match u8::try_from(constant) {
+            Ok(byte) if self.is_match(TokenType::LeftParen) => {
+                let arg_count = self.argument_list();
+                self.emit_byte(OpCode::ConstantCall);
+                self.emit_bytes(byte, arg_count);
+            }
+            _ => self.emit_indexed(OpCode::Constant, constant),
         }
     }
 
@@ -684,9 +931,16 @@ impl Compiler {
     }
 
     fn this_(&mut self, _: bool) {
-        if self.current_class.borrow().is_none() {
-            self.error("Can't use 'this' outside of a class.");
-            return;
+        match self.current_class.borrow().as_ref() {
+            None => {
+                self.error("Can't use 'this' outside of a class.");
+                return;
+            }
+            Some(cc) if *cc.in_static.borrow() => {
+                self.error("Can't use 'this' in a static field.");
+                return;
+            }
+            Some(_) => {}
         }
         self.variable(false);
     }
@@ -695,7 +949,11 @@ impl Compiler {
         match self.current_class.borrow().as_ref() {
             None => self.error("Can't use 'super' outside of a class."),
             Some(cc) => {
-                if !*cc.has_superclass.borrow() {
+                if *cc.in_static.borrow() {
+                    self.error("Can't use 'super' in a static field.");
+                } else if *cc.is_extension.borrow() {
+                    self.error("Can't use 'super' in an extension.");
+                } else if !*cc.has_superclass.borrow() {
                     self.error("Can't use 'super' in a class with no superclass.");
                 }
             }
@@ -711,22 +969,22 @@ impl Compiler {
         if self.is_match(TokenType::LeftParen) {
             let arg_count = self.argument_list();
             self.named_variable(&Token::new("super"), false);
-            self.emit_bytes(OpCode::SuperInvoke, name);
+            self.emit_indexed(OpCode::SuperInvoke, name);
             self.emit_byte(arg_count);
         } else {
             self.named_variable(&Token::new("super"), false);
-            self.emit_bytes(OpCode::GetSuper, name);
+            self.emit_indexed(OpCode::GetSuper, name);
         }
     }
 
     fn unary(&mut self, _: bool) {
-        let operator_type = self.parser.previous.ttype;
+        let operator = self.parser.previous.clone();
 
         self.parse_precedence(Precedence::Unary);
 
-        match operator_type {
-            TokenType::Minus => self.emit_byte(OpCode::Negate),
-            TokenType::Bang => self.emit_byte(OpCode::Not),
+        match operator.ttype {
+            TokenType::Minus => self.emit_at(OpCode::Negate, &operator),
+            TokenType::Bang => self.emit_at(OpCode::Not, &operator),
             _ => unimplemented!("nope"),
         }
     }
@@ -751,12 +1009,22 @@ impl Compiler {
         }
     }
 
-    fn identifier_constant(&mut self, name: &Token) -> u8 {
+    fn identifier_constant(&mut self, name: &Token) -> usize {
         self.make_constant(Value::Str(name.lexeme.clone()))
     }
 
+    fn global_variable(&mut self, name: &Token) -> usize {
+        let slot = self.globals.borrow_mut().resolve(&name.lexeme);
+        if slot > MAX_LONG_INDEX {
+            self.error("Too many global variables.");
+            0
+        } else {
+            slot
+        }
+    }
+
     fn add_local(&self, name: &Token) {
-        if self.result.borrow().locals() >= 256 {
+        if self.result.borrow().locals() >= MAX_LOCALS {
             self.error("Too many local variables in function.");
             return;
         }
@@ -765,6 +1033,8 @@ impl Compiler {
             name: name.clone(),
             depth: None,
             is_captured: false,
+            is_used: false,
+            is_param: false,
         };
         self.result.borrow().push(loc);
     }
@@ -772,26 +1042,24 @@ impl Compiler {
     fn declare_variable(&mut self) {
         if self.result.borrow().in_scope() {
             let name = &self.parser.previous.lexeme;
-            if let FindResult::Depth(d) = self.result.borrow().find_variable(name) {
-                if d < *self.result.borrow().scope_depth.borrow() as u8 {
-                    self.add_local(&self.parser.previous);
-                } else {
-                    self.error("Already a variable with this name in this scope.");
-                }
+            if self.result.borrow().declared_in_scope(name) {
+                self.error("Already a variable with this name in this scope.");
             } else {
                 self.add_local(&self.parser.previous);
             }
         }
     }
 
-    fn parse_variable(&mut self, error_message: &str) -> u8 {
+    fn parse_variable(&mut self, error_message: &str) -> usize {
         self.consume(TokenType::Identifier, error_message);
 
         self.declare_variable();
 
         if !self.result.borrow().in_scope() {
             let name = self.parser.previous.clone();
-            self.identifier_constant(&name)
+            let slot = self.global_variable(&name);
+            self.globals.borrow_mut().mark_assigned(slot);
+            slot
         } else {
             0
         }
@@ -803,9 +1071,9 @@ impl Compiler {
         }
     }
 
-    fn define_variable(&mut self, global: u8) {
+    fn define_variable(&mut self, global: usize) {
         if !self.result.borrow().in_scope() {
-            self.emit_bytes(OpCode::DefineGlobal, global);
+            self.emit_indexed(OpCode::DefineGlobal, global);
         } else {
             self.mark_initialized();
         }
@@ -842,7 +1110,15 @@ impl Compiler {
     }
 
     fn block(&mut self) {
+        let mut returned = false;
         while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            if returned && self.warnings.unreachable_code {
+                self.warning_at(&self.parser.current, "Unreachable code.");
+                returned = false;
+            }
+            if self.check(TokenType::Return) {
+                returned = true;
+            }
             self.declaration();
         }
 
@@ -866,6 +1142,7 @@ impl Compiler {
 
                 let constant = self.parse_variable("Expect parameter name.");
                 self.define_variable(constant);
+                self.result.borrow().mark_param();
                 if !self.is_match(TokenType::Comma) {
                     break;
                 }
@@ -876,13 +1153,20 @@ impl Compiler {
 
         self.block();
 
+        // The body's own scope is never ended, so check its locals here.
+        let locals = self.result.borrow().locals.borrow().clone();
+        for local in &locals {
+            self.warn_unused(local);
+        }
+
         self.end_compiler();
         let arity = self.result.borrow().arity();
         let prev_compiler = self.result.borrow().enclosing.replace(None).unwrap();
         let result = self.result.replace(prev_compiler);
 
         if !*self.parser.had_error.borrow() {
-            let chunk = result.chunk.replace(Chunk::new());
+            let mut chunk = result.chunk.replace(Chunk::new());
+            chunk.set_file(self.file.clone());
             let func = Function::new(
                 arity,
                 &Rc::new(chunk),
@@ -891,17 +1175,50 @@ impl Compiler {
             );
 
             let constant = self.make_constant(Value::Func(Rc::new(func)));
-            self.emit_bytes(OpCode::Closure, constant);
+            self.emit_indexed(OpCode::Closure, constant);
 
             for upvalue in result.upvalues.borrow().iter() {
                 self.emit_byte(if upvalue.is_local { 1 } else { 0 });
-                self.emit_byte(upvalue.index);
+                self.emit_byte(((upvalue.index >> 8) & 0xff) as u8);
+                self.emit_byte((upvalue.index & 0xff) as u8);
             }
         }
     }
 
-    fn method(&mut self) {
+    // A method, or a static field if the name is `static` and another name
+    // follows it. `static` is only a keyword there, so methods and variables
+    // can still be called that.
+    fn class_member(&mut self, class_name: &Token) {
         self.consume(TokenType::Identifier, "Expect method name.");
+        if self.parser.previous.lexeme == "static" && self.check(TokenType::Identifier) {
+            self.static_field(class_name);
+        } else {
+            self.method();
+        }
+    }
+
+    // `static name = value;` is compiled like `Class.name = value;`. The
+    // value is optional and defaults to nil, like a variable's.
+    fn static_field(&mut self, class_name: &Token) {
+        self.consume(TokenType::Identifier, "Expect static field name.");
+        let name = self.parser.previous.clone();
+        let constant = self.identifier_constant(&name);
+
+        self.named_variable(class_name, false);
+        if self.is_match(TokenType::Assign) {
+            let class = self.current_class.borrow().clone().unwrap();
+            class.in_static.replace(true);
+            self.expression();
+            class.in_static.replace(false);
+        } else {
+            self.emit_byte(OpCode::Nil);
+        }
+        self.consume(TokenType::SemiColon, "Expect ';' after static field.");
+        self.emit_indexed(OpCode::SetProperty, constant);
+        self.emit_byte(OpCode::Pop);
+    }
+
+    fn method(&mut self) {
         let parse_token = self.parser.previous.clone();
         let constant = self.identifier_constant(&parse_token);
 
@@ -910,7 +1227,7 @@ impl Compiler {
         } else {
             ChunkType::Method
         });
-        self.emit_bytes(OpCode::Method, constant);
+        self.emit_indexed(OpCode::Method, constant);
     }
 
     fn class_declaration(&mut self) {
@@ -919,8 +1236,15 @@ impl Compiler {
         let name_constant = self.identifier_constant(&class_name);
 
         self.declare_variable();
-        self.emit_bytes(OpCode::Class, name_constant);
-        self.define_variable(name_constant);
+        let global = if self.result.borrow().in_scope() {
+            0
+        } else {
+            let slot = self.global_variable(&class_name);
+            self.globals.borrow_mut().mark_assigned(slot);
+            slot
+        };
+        self.emit_indexed(OpCode::Class, name_constant);
+        self.define_variable(global);
 
         let prev = self
             .current_class
@@ -959,7 +1283,7 @@ impl Compiler {
         self.consume(TokenType::LeftBrace, "Expect '{{' before class body.");
 
         while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
-            self.method();
+            self.class_member(&class_name);
         }
 
         self.consume(TokenType::RightBrace, "Expect '}' after class body.");
@@ -986,6 +1310,43 @@ impl Compiler {
         self.current_class.replace(prev);
     }
 
+    // `extend Name { ... }` adds methods and static fields to a class that
+    // already exists, which may be one the host registered. `extend` is
+    // only a keyword when a name follows it, so it is still free for
+    // variables.
+    fn extend_declaration(&mut self) {
+        self.consume(TokenType::Identifier, "Expect class name.");
+        let class_name = self.parser.previous.clone();
+        self.named_variable(&class_name, false);
+        self.emit_byte(OpCode::Extend);
+
+        let class = Rc::new(ClassCompiler::new());
+        class.is_extension.replace(true);
+        let prev = self.current_class.replace(Some(class));
+        self.current_class
+            .borrow()
+            .as_ref()
+            .unwrap()
+            .enclosing
+            .replace(prev);
+
+        self.consume(TokenType::LeftBrace, "Expect '{' before extension body.");
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.class_member(&class_name);
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after extension body.");
+        self.emit_byte(OpCode::Pop);
+
+        let prev = self
+            .current_class
+            .borrow()
+            .as_ref()
+            .unwrap()
+            .enclosing
+            .replace(None);
+        self.current_class.replace(prev);
+    }
+
     fn fun_declaration(&mut self) {
         let global = self.parse_variable("Expect function name.");
         self.mark_initialized();
@@ -1024,6 +1385,15 @@ impl Compiler {
         if self.is_match(TokenType::SemiColon) {
             // No initializer
         } else if self.is_match(TokenType::Var) {
+            let next = self.scanner.peek_token();
+            if self.check(TokenType::Identifier)
+                && next.ttype == TokenType::Identifier
+                && next.lexeme == "in"
+            {
+                self.for_in_statement();
+                self.end_scope();
+                return;
+            }
             self.var_declaration();
         } else {
             self.expression_statement(); // consumes semicolon
@@ -1068,6 +1438,49 @@ impl Compiler {
         self.end_scope();
     }
 
+    // `for (var x in items) body` asks `items.iterate()` for an iterator and
+    // runs the body with each value the iterator's `next()` returns, until it
+    // returns nil. Each time round, `x` is a new variable.
+    fn for_in_statement(&mut self) {
+        self.consume(TokenType::Identifier, "Expect variable name.");
+        let name = self.parser.previous.clone();
+        self.advance(); // in
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+
+        let iterate = self.identifier_constant(&Token::new("iterate"));
+        self.emit_indexed(OpCode::Invoke, iterate);
+        self.emit_byte(0);
+        self.emit_cache();
+        self.add_local(&Token::new(""));
+        self.mark_initialized();
+        let iterator = self.result.borrow().locals() - 1;
+
+        let loop_start = self.result.borrow().count();
+        self.begin_scope();
+        self.emit_indexed(OpCode::GetLocal, iterator);
+        let next = self.identifier_constant(&Token::new("next"));
+        self.emit_indexed(OpCode::Invoke, next);
+        self.emit_byte(0);
+        self.emit_cache();
+        self.add_local(&name);
+        self.mark_initialized();
+
+        // Nil goes on the left so that an instance's equals() isn't asked.
+        self.emit_byte(OpCode::Nil);
+        self.emit_indexed(OpCode::GetLocal, iterator + 1);
+        self.emit_byte(OpCode::NotEqual);
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop);
+        self.statement();
+        self.end_scope();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::Pop); // Condition
+        self.emit_byte(OpCode::Pop); // The nil that ended the loop
+    }
+
     fn if_statement(&mut self) {
         self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
         self.expression();
@@ -1154,6 +1567,12 @@ impl Compiler {
     fn declaration(&mut self) {
         if self.is_match(TokenType::Class) {
             self.class_declaration();
+        } else if self.check(TokenType::Identifier)
+            && self.parser.current.lexeme == "extend"
+            && self.scanner.peek_token().ttype == TokenType::Identifier
+        {
+            self.advance();
+            self.extend_declaration();
         } else if self.is_match(TokenType::Fun) {
             self.fun_declaration();
         } else if self.is_match(TokenType::Var) {
@@ -1202,17 +1621,40 @@ impl Compiler {
 
         self.parser.panic_mode.replace(true);
 
-        eprint!("[line {}] Error", token.line);
+        self.parser
+            .diagnostics
+            .borrow_mut()
+            .push(Diagnostic::error_at(token, message));
+        self.parser.had_error.replace(true);
+    }
 
-        if token.ttype == TokenType::Eof {
-            eprint!(" at end");
-        } else if token.ttype == TokenType::Error {
-            // ignore
-        } else {
-            eprint!(" at '{}'", token.lexeme);
-        }
+    fn warning_at(&self, token: &Token, message: &str) {
+        self.parser
+            .diagnostics
+            .borrow_mut()
+            .push(Diagnostic::warning_at(token, message));
+    }
+}
 
-        eprintln!(": {message}");
-        self.parser.had_error.replace(true);
+// Every name the source declares or assigns, wherever it does so. Locals
+// are included too; they only cost a native the folding it would otherwise
+// get.
+fn rebound_names(source: &str) -> HashSet<String> {
+    let mut scanner = Scanner::new(source);
+    let mut names = HashSet::new();
+    let mut previous = scanner.scan_token();
+    while previous.ttype != TokenType::Eof {
+        let token = scanner.scan_token();
+        let declares = matches!(
+            previous.ttype,
+            TokenType::Fun | TokenType::Var | TokenType::Class
+        );
+        if token.ttype == TokenType::Identifier && declares {
+            names.insert(token.lexeme.clone());
+        } else if previous.ttype == TokenType::Identifier && token.ttype == TokenType::Assign {
+            names.insert(previous.lexeme.clone());
+        }
+        previous = token;
     }
+    names
 }
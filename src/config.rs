@@ -0,0 +1,91 @@
+use std::time::Duration;
+
+/// Limits and settings an embedder can tune when creating a VM. Start from
+/// `VmConfig::default()` and override the fields that matter.
+#[derive(Clone, Debug)]
+pub struct VmConfig {
+    pub max_frames: usize,
+    pub max_stack: usize,
+    pub max_memory: usize,
+    pub optimize: bool,
+    pub division_by_zero: DivisionByZero,
+    pub warnings: Warnings,
+    /// Whether to define the built-in natives such as `clock`.
+    pub stdlib: bool,
+    /// Instructions each script may run before it fails with
+    /// `BudgetExceeded`.
+    pub fuel: Option<usize>,
+    /// How long each script may run before it fails with a runtime error.
+    pub timeout: Option<Duration>,
+    /// Whether `clock()` and friends count calls instead of reading the
+    /// time and `random()` starts from a fixed seed, so output is the same
+    /// on every run.
+    pub deterministic: bool,
+    /// Whether the compiler prints the bytecode of everything it compiles.
+    /// On by default in builds with the `debug_print_code` feature.
+    pub print_code: bool,
+}
+
+/// What `x / 0` does. Either way NaN follows IEEE 754: it is never equal to
+/// anything, itself included, and every ordered comparison with it is false,
+/// `>=` and `<=` included.
+///
+/// ```
+/// use lox_bytecode::{CapturedOutput, DivisionByZero, VmBuilder};
+///
+/// let script = "print 1 / 0; var n = 0 / 0; print n == n; print n >= 1; print n <= 1;";
+/// let output = CapturedOutput::default();
+/// let mut vm = VmBuilder::new().output(output.clone()).build();
+/// vm.interpret(script).unwrap();
+/// assert_eq!(output.text(), "inf\nfalse\nfalse\nfalse\n");
+///
+/// let mut vm = VmBuilder::new()
+///     .division_by_zero(DivisionByZero::Error)
+///     .output(std::io::sink())
+///     .build();
+/// let err = vm.interpret("print 1 / 0;").unwrap_err();
+/// assert!(err.to_string().contains("Division by zero."));
+/// vm.interpret("print 0.5 / 2 >= 0.25;").unwrap();
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DivisionByZero {
+    #[default]
+    Ieee,
+    Error,
+}
+
+/// Which compiler warnings to report. All of them are off by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Warnings {
+    pub unused_variables: bool,
+    pub unused_parameters: bool,
+    pub unreachable_code: bool,
+}
+
+impl Warnings {
+    pub fn all() -> Self {
+        Self {
+            unused_variables: true,
+            unused_parameters: true,
+            unreachable_code: true,
+        }
+    }
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        Self {
+            max_frames: 256,
+            max_stack: usize::MAX,
+            max_memory: usize::MAX,
+            optimize: false,
+            division_by_zero: DivisionByZero::Ieee,
+            warnings: Warnings::default(),
+            stdlib: true,
+            fuel: None,
+            timeout: None,
+            deterministic: false,
+            print_code: cfg!(feature = "debug_print_code"),
+        }
+    }
+}
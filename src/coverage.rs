@@ -0,0 +1,144 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, Write};
+
+use crate::chunk::*;
+use crate::shared::*;
+use crate::value::*;
+
+/// Counts how often each instruction ran, to report which lines of a script
+/// its tests reach. Functions that never ran still count against it: they
+/// are found among the constants of the chunks that did.
+#[derive(Debug, Default)]
+pub struct Coverage {
+    chunks: Vec<ChunkHits>,
+    index: HashMap<usize, usize>,
+    // The chunk of the last instruction, which is nearly always the chunk of
+    // the next one too.
+    last: Option<(usize, usize)>,
+}
+
+#[derive(Debug)]
+struct ChunkHits {
+    chunk: Rc<Chunk>,
+    hits: Vec<usize>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, chunk: &Rc<Chunk>, offset: usize) {
+        let key = Rc::as_ptr(chunk) as usize;
+        let index = match self.last {
+            Some((last, index)) if last == key => index,
+            _ => {
+                let index = *self.index.entry(key).or_insert_with(|| {
+                    self.chunks.push(ChunkHits {
+                        chunk: Rc::clone(chunk),
+                        hits: vec![0; chunk.count()],
+                    });
+                    self.chunks.len() - 1
+                });
+                self.last = Some((key, index));
+                index
+            }
+        };
+        if let Some(hits) = self.chunks[index].hits.get_mut(offset) {
+            *hits += 1;
+        }
+    }
+
+    /// Every line with code on it, by file, with how many times it ran. A
+    /// line whose instructions ran different numbers of times counts the
+    /// most any of them ran.
+    pub fn lines(&self) -> BTreeMap<Option<String>, BTreeMap<usize, usize>> {
+        let mut lines: BTreeMap<Option<String>, BTreeMap<usize, usize>> = BTreeMap::new();
+        let mut seen = HashSet::new();
+        let mut pending: Vec<Rc<Chunk>> = self
+            .chunks
+            .iter()
+            .map(|hits| Rc::clone(&hits.chunk))
+            .collect();
+        while let Some(chunk) = pending.pop() {
+            if !seen.insert(Rc::as_ptr(&chunk) as usize) {
+                continue;
+            }
+            let hits = self
+                .index
+                .get(&(Rc::as_ptr(&chunk) as usize))
+                .map(|index| &self.chunks[*index].hits);
+            let file = lines.entry(chunk.file().map(str::to_string)).or_default();
+            let mut offset = 0;
+            while offset < chunk.count() {
+                let line = chunk.get_line(offset);
+                let count = hits.map_or(0, |hits| hits[offset]);
+                let entry = file.entry(line).or_default();
+                *entry = (*entry).max(count);
+                let Some(len) = chunk.instruction_len(offset) else {
+                    break;
+                };
+                offset += len;
+            }
+            for constant in chunk.constants() {
+                if let Value::Func(function) = constant {
+                    pending.push(function.get_chunk());
+                }
+            }
+        }
+        for file in lines.values_mut() {
+            file.remove(&0);
+        }
+        lines
+    }
+
+    /// Writes the coverage as an lcov tracefile, which `genhtml` and most
+    /// editors and CI services read. Chunks compiled without a file name are
+    /// put down to `script`.
+    pub fn write_lcov(&self, script: &str, out: &mut dyn Write) -> io::Result<()> {
+        for (file, lines) in self.lines() {
+            writeln!(out, "TN:")?;
+            writeln!(out, "SF:{}", file.as_deref().unwrap_or(script))?;
+            for (line, count) in &lines {
+                writeln!(out, "DA:{line},{count}")?;
+            }
+            writeln!(out, "LF:{}", lines.len())?;
+            writeln!(
+                out,
+                "LH:{}",
+                lines.values().filter(|count| **count > 0).count()
+            )?;
+            writeln!(out, "end_of_record")?;
+        }
+        Ok(())
+    }
+
+    /// The source with each line prefixed by how many times it ran, in the
+    /// style of gcov: `#####` marks a line that never ran and `-` one with no
+    /// code on it.
+    ///
+    /// ```
+    /// use lox_bytecode::VmBuilder;
+    ///
+    /// let source = "fun f() {\n  print 1;\n}\nprint 2;\n";
+    /// let mut vm = VmBuilder::new().coverage().build();
+    /// vm.interpret(source).unwrap();
+    /// let annotated = vm.coverage().unwrap().annotate(source);
+    /// assert!(annotated.contains("#####:    2:  print 1;"));
+    /// assert!(annotated.contains("    1:    4:print 2;"));
+    /// ```
+    pub fn annotate(&self, source: &str) -> String {
+        let lines: BTreeMap<usize, usize> = self.lines().into_values().flatten().collect();
+        let mut out = String::new();
+        for (index, text) in source.lines().enumerate() {
+            let line = index + 1;
+            let count = match lines.get(&line) {
+                Some(0) => "#####".to_string(),
+                Some(count) => count.to_string(),
+                None => "-".to_string(),
+            };
+            out.push_str(&format!("{count:>9}:{line:>5}:{text}\n"));
+        }
+        out
+    }
+}
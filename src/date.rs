@@ -0,0 +1,184 @@
+// `dateFormat(ms, format)` and `dateParse(text, format)`, which turn
+// milliseconds since the Unix epoch into text and back. Times are UTC, and
+// the formats understand `%Y`, `%m`, `%d`, `%H`, `%M`, `%S` and `%%`, as
+// strftime does.
+
+use crate::error::*;
+use crate::value::*;
+
+const MS_PER_DAY: i64 = 86_400_000;
+
+// The same range JavaScript dates cover, which keeps every year in i64 and
+// every millisecond exact in an f64.
+const MAX_MS: f64 = 8.64e15;
+
+struct DateTime {
+    year: i64,
+    month: i64,
+    day: i64,
+    hour: i64,
+    minute: i64,
+    second: i64,
+}
+
+pub fn date_format(args: &[Value]) -> Result<Value, NativeError> {
+    let ms: f64 = (&args[0]).try_into()?;
+    let format: &str = (&args[1]).try_into()?;
+    if !ms.is_finite() || ms.abs() > MAX_MS {
+        return Err(NativeError::new("Date is out of range."));
+    }
+
+    let ms = ms.floor() as i64;
+    let (year, month, day) = civil_from_days(ms.div_euclid(MS_PER_DAY));
+    let seconds = ms.rem_euclid(MS_PER_DAY) / 1000;
+    let date = DateTime {
+        year,
+        month,
+        day,
+        hour: seconds / 3600,
+        minute: seconds / 60 % 60,
+        second: seconds % 60,
+    };
+
+    let mut out = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => out.push_str(&format!("{:04}", date.year)),
+            Some('m') => out.push_str(&format!("{:02}", date.month)),
+            Some('d') => out.push_str(&format!("{:02}", date.day)),
+            Some('H') => out.push_str(&format!("{:02}", date.hour)),
+            Some('M') => out.push_str(&format!("{:02}", date.minute)),
+            Some('S') => out.push_str(&format!("{:02}", date.second)),
+            Some('%') => out.push('%'),
+            directive => return Err(unknown_directive(directive)),
+        }
+    }
+    Ok(out.into())
+}
+
+// The milliseconds `text` stands for, or nil if it doesn't match `format`
+// or names a date that doesn't exist. Fields the format leaves out default
+// to the start of 1970.
+pub fn date_parse(args: &[Value]) -> Result<Value, NativeError> {
+    let text: &str = (&args[0]).try_into()?;
+    let format: &str = (&args[1]).try_into()?;
+
+    let mut date = DateTime {
+        year: 1970,
+        month: 1,
+        day: 1,
+        hour: 0,
+        minute: 0,
+        second: 0,
+    };
+    let mut text = text;
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            match text.strip_prefix(c) {
+                Some(rest) => text = rest,
+                None => return Ok(Value::Nil),
+            }
+            continue;
+        }
+        let (field, digits) = match chars.next() {
+            Some('Y') => (&mut date.year, 4),
+            Some('m') => (&mut date.month, 2),
+            Some('d') => (&mut date.day, 2),
+            Some('H') => (&mut date.hour, 2),
+            Some('M') => (&mut date.minute, 2),
+            Some('S') => (&mut date.second, 2),
+            Some('%') => match text.strip_prefix('%') {
+                Some(rest) => {
+                    text = rest;
+                    continue;
+                }
+                None => return Ok(Value::Nil),
+            },
+            directive => return Err(unknown_directive(directive)),
+        };
+        match take_number(text, digits) {
+            Some((n, rest)) => {
+                *field = n;
+                text = rest;
+            }
+            None => return Ok(Value::Nil),
+        }
+    }
+
+    let valid = text.is_empty()
+        && (1..=12).contains(&date.month)
+        && (1..=days_in_month(date.year, date.month)).contains(&date.day)
+        && date.hour < 24
+        && date.minute < 60
+        && date.second < 60;
+    if !valid {
+        return Ok(Value::Nil);
+    }
+    let days = days_from_civil(date.year, date.month, date.day);
+    let seconds = date.hour * 3600 + date.minute * 60 + date.second;
+    Ok(Value::Number((days * MS_PER_DAY + seconds * 1000) as f64))
+}
+
+fn unknown_directive(directive: Option<char>) -> NativeError {
+    match directive {
+        Some(c) => NativeError::new(format!("Unknown date directive '%{c}'.")),
+        None => NativeError::new("Incomplete date directive at end of format."),
+    }
+}
+
+// Reads up to `digits` digits from the start of `text`.
+fn take_number(text: &str, digits: usize) -> Option<(i64, &str)> {
+    let len = text
+        .bytes()
+        .take(digits)
+        .take_while(u8::is_ascii_digit)
+        .count();
+    let n = text[..len].parse().ok()?;
+    Some((n, &text[len..]))
+}
+
+fn is_leap_year(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Days since 1970-01-01 in the proleptic Gregorian calendar, after Howard
+// Hinnant's `days_from_civil`.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_from_march = (month + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// The inverse of `days_from_civil`.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = (month_from_march + 2) % 12 + 1;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
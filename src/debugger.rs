@@ -0,0 +1,199 @@
+use std::collections::HashSet;
+use std::io::{self, Write};
+
+use crate::chunk::*;
+use crate::shared::*;
+use crate::value::*;
+use crate::vm::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DebugAction {
+    Continue,
+    Step,
+}
+
+/// Hooks the VM calls while a debugger is attached. Every hook gets the VM so
+/// it can inspect the stack and globals. `on_line` is where execution pauses:
+/// it runs when a breakpoint line is reached, and on every new line after it
+/// returns `DebugAction::Step`.
+pub trait Debugger: MaybeSend {
+    fn on_instruction(&mut self, _vm: &VM, _instruction: OpCode) {}
+
+    fn on_call(&mut self, _vm: &VM, _name: &str) {}
+
+    fn on_return(&mut self, _vm: &VM, _name: &str) {}
+
+    fn on_line(&mut self, _vm: &VM, _line: usize) -> DebugAction {
+        DebugAction::Continue
+    }
+}
+
+/// Prints the stack and globals whenever execution pauses. With `step` set it
+/// pauses on every line instead of only at breakpoints.
+pub struct PrintDebugger {
+    step: bool,
+}
+
+impl PrintDebugger {
+    pub fn new(step: bool) -> Self {
+        Self { step }
+    }
+}
+
+impl Debugger for PrintDebugger {
+    fn on_line(&mut self, vm: &VM, line: usize) -> DebugAction {
+        eprintln!("[line {line}] in {}", vm.current_function());
+        eprint!("  stack:");
+        for value in vm.stack_values() {
+            eprint!(" [ {value} ]");
+        }
+        eprintln!();
+        for (name, value) in vm.global_values() {
+            eprintln!("  {name} = {value}");
+        }
+
+        if self.step {
+            DebugAction::Step
+        } else {
+            DebugAction::Continue
+        }
+    }
+}
+
+/// A debugger driven from the terminal, as `lox-bytecode debug` runs it. It
+/// pauses before the first line and reads commands from stdin, answering on
+/// stderr: `break [FILE:]LINE`, `step`, `next`, `continue`, `print NAME`
+/// (with `.field` lookups), `backtrace`, `locals` and `globals`. Attach it
+/// with stepping on, since it decides for itself where to stop.
+pub struct ConsoleDebugger {
+    source: Vec<String>,
+    breakpoints: HashSet<usize>,
+    mode: Mode,
+}
+
+enum Mode {
+    Step,
+    // Stops once the call depth is back down to this.
+    Next(usize),
+    Continue,
+}
+
+impl ConsoleDebugger {
+    pub fn new(source: &str) -> Self {
+        Self {
+            source: source.lines().map(str::to_string).collect(),
+            breakpoints: HashSet::new(),
+            mode: Mode::Step,
+        }
+    }
+
+    // Runs one command, returning whether the script should carry on.
+    fn command(&mut self, vm: &VM, line: &str) -> bool {
+        let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+        let argument = argument.trim();
+        match command {
+            "s" | "step" => self.mode = Mode::Step,
+            "n" | "next" => self.mode = Mode::Next(vm.call_depth()),
+            "c" | "continue" => self.mode = Mode::Continue,
+            "b" | "break" => {
+                let line = argument.rsplit(':').next().unwrap_or_default();
+                match line.parse() {
+                    Ok(line) => {
+                        self.breakpoints.insert(line);
+                        eprintln!("Breakpoint at line {line}.");
+                    }
+                    Err(_) => eprintln!("Expected a line number."),
+                }
+                return false;
+            }
+            "p" | "print" => {
+                match lookup(vm, argument) {
+                    Some(value) => eprintln!("{argument} = {value}"),
+                    None => eprintln!("No variable '{argument}' here."),
+                }
+                return false;
+            }
+            "bt" | "backtrace" => {
+                for frame in vm.stack_trace() {
+                    eprintln!("  {frame}");
+                }
+                return false;
+            }
+            "locals" => {
+                for (name, value) in vm.locals() {
+                    eprintln!("  {name} = {value}");
+                }
+                return false;
+            }
+            "globals" => {
+                for (name, value) in vm.global_values() {
+                    eprintln!("  {name} = {value}");
+                }
+                return false;
+            }
+            "" => return false,
+            _ => {
+                eprintln!(
+                    "Commands: break [FILE:]LINE, step, next, continue, print NAME, \
+                     backtrace, locals, globals."
+                );
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl Debugger for ConsoleDebugger {
+    fn on_line(&mut self, vm: &VM, line: usize) -> DebugAction {
+        let pause = match self.mode {
+            Mode::Step => true,
+            Mode::Next(depth) => vm.call_depth() <= depth,
+            Mode::Continue => false,
+        };
+        if !pause && !self.breakpoints.contains(&line) {
+            return DebugAction::Step;
+        }
+
+        eprintln!("[line {line}] in {}", vm.current_function());
+        if let Some(text) = self.source.get(line.saturating_sub(1)) {
+            eprintln!("    {text}");
+        }
+        loop {
+            eprint!("(debug) ");
+            let _ = io::stderr().flush();
+            let mut input = String::new();
+            // Without anyone to type commands, let the script finish.
+            if !matches!(io::stdin().read_line(&mut input), Ok(n) if n > 0) {
+                self.mode = Mode::Continue;
+                self.breakpoints.clear();
+                break;
+            }
+            if self.command(vm, input.trim()) {
+                break;
+            }
+        }
+        DebugAction::Step
+    }
+}
+
+// A variable in scope, innermost local first and then globals, followed by
+// any `.field` lookups on instances.
+fn lookup(vm: &VM, path: &str) -> Option<Value> {
+    let mut names = path.split('.');
+    let name = names.next()?;
+    let mut value = vm
+        .locals()
+        .into_iter()
+        .rev()
+        .chain(vm.global_values())
+        .find(|(local, _)| local == name)?
+        .1;
+    for field in names {
+        let Value::Instance(instance) = &value else {
+            return None;
+        };
+        value = instance.get_field(field)?;
+    }
+    Some(value)
+}
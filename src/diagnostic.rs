@@ -0,0 +1,214 @@
+use std::fmt::{self, Display};
+use std::io::IsTerminal;
+
+use crate::json::*;
+use crate::shared::*;
+use crate::token::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A range of the source, counted in characters from its start.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub len: usize,
+}
+
+/// One compile error or warning. `line` and `column` locate the start of
+/// `span`, which covers the token it was reported at; `at` names that token
+/// the way the message is printed.
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub at: Option<String>,
+    pub line: usize,
+    pub column: usize,
+    pub span: Span,
+}
+
+impl Diagnostic {
+    pub fn error_at(token: &Token, message: &str) -> Self {
+        let at = match token.ttype {
+            TokenType::Eof => Some("end".to_string()),
+            TokenType::Error => None,
+            _ => Some(format!("'{}'", token.lexeme)),
+        };
+        Self {
+            severity: Severity::Error,
+            message: message.to_string(),
+            at,
+            line: token.line,
+            column: token.column,
+            span: token.span,
+        }
+    }
+
+    /// The diagnostic as one line of JSON, for editors and CI:
+    /// `{"severity", "message", "file", "line", "column", "length"}`.
+    pub fn to_json(&self, file: Option<&str>) -> String {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        Json::object([
+            ("severity", severity.into()),
+            ("message", self.message.as_str().into()),
+            ("file", file.into()),
+            ("line", self.line.into()),
+            ("column", self.column.into()),
+            ("length", self.span.len.into()),
+        ])
+        .to_string()
+    }
+
+    pub fn warning_at(token: &Token, message: &str) -> Self {
+        Self {
+            severity: Severity::Warning,
+            message: message.to_string(),
+            at: None,
+            line: token.line,
+            column: token.column,
+            span: token.span,
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "Error",
+            Severity::Warning => "Warning",
+        };
+        write!(f, "[line {}:{}] {severity}", self.line, self.column)?;
+        if let Some(at) = &self.at {
+            write!(f, " at {at}")?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// Receives every diagnostic as soon as a compile finishes, before the
+/// script runs, along with the source it was compiled from.
+pub trait Reporter: MaybeSend {
+    fn report(&mut self, source: &str, diagnostic: &Diagnostic);
+}
+
+impl<R: Reporter + ?Sized> Reporter for Box<R> {
+    fn report(&mut self, source: &str, diagnostic: &Diagnostic) {
+        (**self).report(source, diagnostic);
+    }
+}
+
+/// Prints each diagnostic to stderr with the offending line and carets under
+/// its span.
+pub struct StderrReporter;
+
+impl Reporter for StderrReporter {
+    fn report(&mut self, source: &str, diagnostic: &Diagnostic) {
+        eprintln!("{diagnostic}");
+        if let Some(text) = source.lines().nth(diagnostic.line.saturating_sub(1)) {
+            eprint!(
+                "{}",
+                caret_snippet(text, diagnostic.column, diagnostic.span.len)
+            );
+        }
+    }
+}
+
+/// Prints each diagnostic to stderr as a line of JSON; see
+/// [`Diagnostic::to_json`].
+pub struct JsonReporter {
+    file: Option<String>,
+}
+
+impl JsonReporter {
+    pub fn new(file: Option<&str>) -> Self {
+        Self {
+            file: file.map(str::to_string),
+        }
+    }
+}
+
+impl Reporter for JsonReporter {
+    fn report(&mut self, _source: &str, diagnostic: &Diagnostic) {
+        eprintln!("{}", diagnostic.to_json(self.file.as_deref()));
+    }
+}
+
+/// Like [`StderrReporter`], with the severity and the carets in color for a
+/// terminal.
+pub struct ColorReporter;
+
+impl Reporter for ColorReporter {
+    fn report(&mut self, source: &str, diagnostic: &Diagnostic) {
+        let severity = diagnostic.severity;
+        let label = match severity {
+            Severity::Error => "Error",
+            Severity::Warning => "Warning",
+        };
+        let mut header = format!(
+            "[line {}:{}] {}",
+            diagnostic.line,
+            diagnostic.column,
+            paint(label, severity)
+        );
+        if let Some(at) = &diagnostic.at {
+            header += &format!(" at {at}");
+        }
+        eprintln!("{header}: {}", bold(&diagnostic.message));
+        if let Some(text) = source.lines().nth(diagnostic.line.saturating_sub(1)) {
+            eprint!(
+                "{}",
+                color_caret_snippet(text, diagnostic.column, diagnostic.span.len, severity)
+            );
+        }
+    }
+}
+
+/// Whether stderr should get colors: it's a terminal and `NO_COLOR` isn't
+/// set, as <https://no-color.org> asks.
+pub fn color_enabled() -> bool {
+    std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+        && std::io::stderr().is_terminal()
+}
+
+/// Wraps `text` in the bold color [`ColorReporter`] uses for `severity`:
+/// red for errors and yellow for warnings.
+pub fn paint(text: &str, severity: Severity) -> String {
+    let color = match severity {
+        Severity::Error => 31,
+        Severity::Warning => 33,
+    };
+    format!("\x1b[1;{color}m{text}\x1b[0m")
+}
+
+fn bold(text: &str) -> String {
+    format!("\x1b[1m{text}\x1b[0m")
+}
+
+/// Renders a source line with carets under `length` characters starting at
+/// the 1-based `column`, for pointing at the span an error is about.
+pub fn caret_snippet(text: &str, column: usize, length: usize) -> String {
+    let (indent, carets) = carets(text, column, length);
+    format!("    {text}\n    {indent}{carets}\n")
+}
+
+/// [`caret_snippet`] with the carets painted for `severity`.
+pub fn color_caret_snippet(text: &str, column: usize, length: usize, severity: Severity) -> String {
+    let (indent, carets) = carets(text, column, length);
+    format!("    {text}\n    {indent}{}\n", paint(&carets, severity))
+}
+
+fn carets(text: &str, column: usize, length: usize) -> (String, String) {
+    let indent = text
+        .chars()
+        .take(column.saturating_sub(1))
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    (indent, "^".repeat(length.max(1)))
+}
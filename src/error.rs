@@ -1,4 +1,265 @@
+use std::fmt::{self, Display};
+
+use crate::diagnostic::*;
+use crate::json::*;
+
+/// Which way a script failed; see [`LoxError::kind`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum InterpretResult {
     CompileError,
     RuntimeError,
+    BudgetExceeded,
+    Exit,
 }
+
+/// Returned by native functions; the VM reports it as a runtime error,
+/// unless it was made with `exit`.
+#[derive(Debug)]
+pub struct NativeError {
+    message: String,
+    exit: Option<i32>,
+}
+
+impl NativeError {
+    pub fn new<T: Into<String>>(message: T) -> Self {
+        Self {
+            message: message.into(),
+            exit: None,
+        }
+    }
+
+    /// Stops the script, which fails with `LoxError::Exit(code)`.
+    pub fn exit(code: i32) -> Self {
+        Self {
+            message: format!("Exited with code {code}."),
+            exit: Some(code),
+        }
+    }
+
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit
+    }
+}
+
+impl From<String> for NativeError {
+    fn from(message: String) -> Self {
+        Self::new(message)
+    }
+}
+
+impl From<&str> for NativeError {
+    fn from(message: &str) -> Self {
+        Self::new(message)
+    }
+}
+
+impl Display for NativeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for NativeError {}
+
+/// What `VM::interpret` and the rest of the embedding API hand back when a
+/// script fails. Displaying it gives the same messages and stack trace the
+/// CLI prints, and it is a `std::error::Error`, so hosts can pass it on
+/// with `?`.
+///
+/// ```
+/// use lox_bytecode::{LoxError, VM};
+///
+/// fn run(source: &str) -> Result<(), Box<dyn std::error::Error>> {
+///     VM::new().interpret(source)?;
+///     Ok(())
+/// }
+///
+/// let err = VM::new().interpret("print -nil;").unwrap_err();
+/// let LoxError::Runtime(err) = err else { unreachable!() };
+/// assert_eq!(err.message, "Operand must be a number.");
+/// assert_eq!(err.trace[0].line, Some(1));
+/// assert!(run("print;").is_err());
+/// ```
+#[derive(Clone, Debug)]
+pub enum LoxError {
+    Compile(CompileError),
+    Runtime(RuntimeError),
+    /// The script used up its fuel.
+    BudgetExceeded(RuntimeError),
+    /// The script called `exit` with this code.
+    Exit(i32),
+}
+
+/// A script that didn't compile. It carries every diagnostic the compiler
+/// reported; programs that fail to load, such as a broken .loxb file, have
+/// only a message.
+#[derive(Clone, Debug)]
+pub struct CompileError {
+    pub message: String,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// A script that failed while running, with a trace of the frames that were
+/// active.
+#[derive(Clone, Debug)]
+pub struct RuntimeError {
+    pub message: String,
+    pub trace: Vec<TraceFrame>,
+}
+
+/// One entry in a stack trace, innermost first. Natives have no position,
+/// and code compiled from a string rather than a file has no file.
+#[derive(Clone, Debug)]
+pub struct TraceFrame {
+    pub function: String,
+    pub file: Option<String>,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+}
+
+impl LoxError {
+    pub fn kind(&self) -> InterpretResult {
+        match self {
+            LoxError::Compile(_) => InterpretResult::CompileError,
+            LoxError::Runtime(_) => InterpretResult::RuntimeError,
+            LoxError::BudgetExceeded(_) => InterpretResult::BudgetExceeded,
+            LoxError::Exit(_) => InterpretResult::Exit,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            LoxError::Compile(err) => &err.message,
+            LoxError::Runtime(err) | LoxError::BudgetExceeded(err) => &err.message,
+            LoxError::Exit(_) => "Exited.",
+        }
+    }
+
+    /// The error as one line of JSON, shaped like [`Diagnostic::to_json`].
+    /// The position is that of the innermost frame with one, and runtime
+    /// errors add their `trace`.
+    pub fn to_json(&self) -> String {
+        let position = self.trace().iter().find(|frame| frame.line.is_some());
+        let mut json = Json::object([
+            ("severity", "error".into()),
+            ("message", self.message().into()),
+            (
+                "file",
+                position.and_then(|frame| frame.file.as_deref()).into(),
+            ),
+            ("line", position.and_then(|frame| frame.line).into()),
+            ("column", position.and_then(|frame| frame.column).into()),
+        ]);
+        if let (Json::Object(fields), LoxError::Runtime(_) | LoxError::BudgetExceeded(_)) =
+            (&mut json, self)
+        {
+            let trace = self.trace().iter().map(|frame| {
+                Json::object([
+                    ("function", frame.function.as_str().into()),
+                    ("file", frame.file.as_deref().into()),
+                    ("line", frame.line.into()),
+                    ("column", frame.column.into()),
+                ])
+            });
+            fields.push(("trace".to_string(), Json::Array(trace.collect())));
+        }
+        json.to_string()
+    }
+
+    /// The stack trace of a runtime error; compile errors and exits have
+    /// none.
+    pub fn trace(&self) -> &[TraceFrame] {
+        match self {
+            LoxError::Compile(_) | LoxError::Exit(_) => &[],
+            LoxError::Runtime(err) | LoxError::BudgetExceeded(err) => &err.trace,
+        }
+    }
+}
+
+impl CompileError {
+    pub fn new<T: Into<String>>(message: T) -> Self {
+        Self {
+            message: message.into(),
+            diagnostics: Vec::new(),
+        }
+    }
+
+    pub fn with_diagnostics(diagnostics: Vec<Diagnostic>) -> Self {
+        Self {
+            diagnostics,
+            ..Self::new("Compile error.")
+        }
+    }
+}
+
+impl RuntimeError {
+    pub fn new<T: Into<String>>(message: T) -> Self {
+        Self {
+            message: message.into(),
+            trace: Vec::new(),
+        }
+    }
+}
+
+impl From<CompileError> for LoxError {
+    fn from(err: CompileError) -> Self {
+        LoxError::Compile(err)
+    }
+}
+
+impl From<RuntimeError> for LoxError {
+    fn from(err: RuntimeError) -> Self {
+        LoxError::Runtime(err)
+    }
+}
+
+impl Display for LoxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoxError::Compile(err) => write!(f, "{err}"),
+            LoxError::Runtime(err) | LoxError::BudgetExceeded(err) => write!(f, "{err}"),
+            LoxError::Exit(code) => writeln!(f, "Exited with code {code}."),
+        }
+    }
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.diagnostics.is_empty() {
+            return writeln!(f, "{}", self.message);
+        }
+        for diagnostic in &self.diagnostics {
+            writeln!(f, "{diagnostic}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.message)?;
+        for frame in &self.trace {
+            writeln!(f, "{frame}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let location = match (self.line, self.column, &self.file) {
+            (Some(line), Some(column), Some(file)) => format!("{file}:{line}:{column}"),
+            (Some(line), Some(column), None) => format!("line {line}:{column}"),
+            (Some(line), None, Some(file)) => format!("{file}:{line}"),
+            (Some(line), None, None) => format!("line {line}"),
+            _ => "native".to_string(),
+        };
+        write!(f, "[{location}] in {}", self.function)
+    }
+}
+
+impl std::error::Error for LoxError {}
+
+impl std::error::Error for CompileError {}
+
+impl std::error::Error for RuntimeError {}
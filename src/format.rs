@@ -0,0 +1,181 @@
+use crate::ast::*;
+use crate::diagnostic::*;
+use crate::scanner::*;
+use crate::token::*;
+
+const INDENT: &str = "    ";
+
+// Re-prints a program with four-space indentation, one statement per line,
+// single spaces around binary operators and opening braces on the same line.
+// The source is parsed first so that only valid programs get reformatted; the
+// output itself is driven by the token stream, which still has the comments.
+pub fn format_source(source: &str) -> Result<String, Vec<Diagnostic>> {
+    parse_to_ast(source)?;
+    let tokens: Vec<Token> = Scanner::with_comments(source).collect();
+    let mut printer = Printer::default();
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = &tokens[i];
+        let next = tokens.get(i + 1).map(|t| t.ttype);
+        if token.ttype == TokenType::LeftBrace && next == Some(TokenType::RightBrace) {
+            // Empty blocks and class bodies stay on one line.
+            printer.token(token, next);
+            printer.out.push('}');
+            printer.prev = Some(tokens[i + 1].clone());
+            printer.after_right_brace(tokens.get(i + 2).map(|t| t.ttype));
+            i += 2;
+        } else {
+            printer.token(token, next);
+            i += 1;
+        }
+    }
+    printer.finish();
+    Ok(printer.out)
+}
+
+#[derive(Default)]
+struct Printer {
+    out: String,
+    depth: usize,
+    parens: usize,
+    at_line_start: bool,
+    prev: Option<Token>,
+    // Whether the previous token was a prefix `-` or `!`.
+    prev_unary: bool,
+}
+
+impl Printer {
+    fn token(&mut self, token: &Token, next: Option<TokenType>) {
+        if token.ttype == TokenType::Comment {
+            self.comment(token);
+            return;
+        }
+
+        let unary = matches!(token.ttype, TokenType::Minus | TokenType::Bang)
+            && !self.prev.as_ref().is_some_and(ends_operand);
+        if self.at_line_start || self.out.is_empty() {
+            if token.ttype == TokenType::RightBrace {
+                self.depth = self.depth.saturating_sub(1);
+            }
+            self.start_line(token);
+        } else if self.needs_space(token) {
+            self.out.push(' ');
+        }
+        self.out.push_str(&token.lexeme);
+        self.prev_unary = unary;
+        self.prev = Some(token.clone());
+
+        match token.ttype {
+            TokenType::LeftParen => self.parens += 1,
+            TokenType::RightParen => self.parens = self.parens.saturating_sub(1),
+            TokenType::LeftBrace if next != Some(TokenType::RightBrace) => {
+                self.depth += 1;
+                self.newline();
+            }
+            TokenType::RightBrace => self.after_right_brace(next),
+            TokenType::SemiColon if self.parens == 0 => self.newline(),
+            _ => {}
+        }
+    }
+
+    // `} else` stays together; anything else after a closing brace starts a
+    // new line.
+    fn after_right_brace(&mut self, next: Option<TokenType>) {
+        if next != Some(TokenType::Else) {
+            self.newline();
+        }
+    }
+
+    // Comments that shared a line with code stay at the end of it; the rest
+    // get a line of their own at the current indentation.
+    fn comment(&mut self, token: &Token) {
+        let trailing = self
+            .prev
+            .as_ref()
+            .is_some_and(|prev| prev.line == token.line && prev.ttype != TokenType::Comment);
+        if trailing {
+            if self.at_line_start {
+                self.out.pop();
+                self.at_line_start = false;
+            }
+            self.out.push(' ');
+        } else {
+            if !self.at_line_start && !self.out.is_empty() {
+                self.newline();
+            }
+            self.start_line(token);
+        }
+        self.out.push_str(token.lexeme.trim_end());
+        self.prev = Some(token.clone());
+        self.newline();
+    }
+
+    // Indents a fresh line, keeping at most one blank line from the source
+    // between statements.
+    fn start_line(&mut self, token: &Token) {
+        if let Some(prev) = &self.prev {
+            let first_in_block = prev.ttype == TokenType::LeftBrace;
+            let closes_block = token.ttype == TokenType::RightBrace;
+            if token.line > prev.line + 1 && !first_in_block && !closes_block {
+                self.out.push('\n');
+            }
+        }
+        for _ in 0..self.depth {
+            self.out.push_str(INDENT);
+        }
+        self.at_line_start = false;
+    }
+
+    fn needs_space(&self, token: &Token) -> bool {
+        let Some(prev) = &self.prev else {
+            return false;
+        };
+        if self.prev_unary {
+            return false;
+        }
+        match token.ttype {
+            TokenType::SemiColon | TokenType::Comma | TokenType::RightParen | TokenType::Dot => {
+                return false
+            }
+            // Calls and function names hug their parameter lists.
+            TokenType::LeftParen => {
+                return !matches!(
+                    prev.ttype,
+                    TokenType::Identifier | TokenType::RightParen | TokenType::This
+                )
+            }
+            _ => {}
+        }
+        !matches!(prev.ttype, TokenType::LeftParen | TokenType::Dot)
+    }
+
+    fn newline(&mut self) {
+        if !self.at_line_start {
+            self.out.push('\n');
+            self.at_line_start = true;
+        }
+    }
+
+    fn finish(&mut self) {
+        let trimmed = self.out.trim_end().len();
+        self.out.truncate(trimmed);
+        if !self.out.is_empty() {
+            self.out.push('\n');
+        }
+    }
+}
+
+// Tokens after which a `-` is a subtraction rather than a negation.
+fn ends_operand(token: &Token) -> bool {
+    matches!(
+        token.ttype,
+        TokenType::Identifier
+            | TokenType::Number
+            | TokenType::String
+            | TokenType::RightParen
+            | TokenType::True
+            | TokenType::False
+            | TokenType::Nil
+            | TokenType::This
+    )
+}
@@ -1,8 +1,7 @@
 use crate::chunk::*;
+use crate::shared::*;
 use std::fmt::Display;
 
-use std::rc::Rc;
-
 #[derive(Debug, Default)]
 pub struct Function {
     arity: usize,
@@ -11,15 +10,10 @@ pub struct Function {
     upvalue_count: usize,
 }
 
-impl PartialOrd for Function {
-    fn partial_cmp(&self, _: &Self) -> Option<std::cmp::Ordering> {
-        panic!("comparing the ord of two functions");
-    }
-}
-
+// A function is only ever equal to itself.
 impl PartialEq for Function {
-    fn eq(&self, _other: &Self) -> bool {
-        todo!()
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
     }
 }
 
@@ -76,6 +70,20 @@ impl Function {
         self.arity
     }
 
+    /// Empty for the top-level script.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Prints the function's bytecode and constants, followed by those of
+    /// every function nested in it.
+    pub fn disassemble(&self) {
+        match self.name.as_str() {
+            "" => self.chunk.disassemble_all("<script>"),
+            name => self.chunk.disassemble_all(name),
+        }
+    }
+
     pub fn stack_name(&self) -> &str {
         if self.name.is_empty() {
             "script"
@@ -0,0 +1,54 @@
+// Entry points for fuzzers, which feed them arbitrary bytes and treat any
+// panic as a bug. `fuzz/` has the cargo-fuzz targets that call them.
+
+use crate::builder::*;
+use crate::error::*;
+use crate::hooks::*;
+
+// Enough for most small programs to finish, while an endless loop still
+// gives up in a few milliseconds.
+const FUEL: usize = 100_000;
+const MAX_MEMORY: usize = 16 * 1024 * 1024;
+
+/// Compiles `data` as a script, which must fail cleanly rather than panic
+/// however malformed it is. Bytes that aren't UTF-8 are replaced, so the
+/// scanner still sees the rest.
+///
+/// ```
+/// lox_bytecode::fuzz_compile(b"fun (((;");
+/// ```
+pub fn fuzz_compile(data: &[u8]) {
+    let source = String::from_utf8_lossy(data);
+    let _ = VmBuilder::new().build().compile(&source);
+}
+
+/// Runs `data` as a script with limited fuel and memory, deterministically
+/// and with nothing to read, and without letting it sleep, so every input
+/// ends quickly whatever it does. Like [`fuzz_compile`], it must not panic.
+///
+/// ```
+/// lox_bytecode::fuzz_interpret(b"while (true) { print clock(); }");
+/// ```
+pub fn fuzz_interpret(data: &[u8]) {
+    let source = String::from_utf8_lossy(data);
+    let mut vm = VmBuilder::new()
+        .fuel(FUEL)
+        .max_memory(MAX_MEMORY)
+        .deterministic()
+        .output(std::io::sink())
+        .input(std::io::empty())
+        .hooks(NoSleep)
+        .build();
+    let _ = vm.interpret(&source);
+}
+
+struct NoSleep;
+
+impl Hooks for NoSleep {
+    fn on_call(&mut self, function: &str) -> Result<(), NativeError> {
+        match function {
+            "sleep" => Err("sleep() is not allowed while fuzzing.".into()),
+            _ => Ok(()),
+        }
+    }
+}
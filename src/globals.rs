@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+
+/// Maps global variable names to the slots the VM stores their values in.
+/// The compiler resolves names while emitting code so the VM never has to
+/// hash a name at runtime; `name` is kept around for error messages.
+/// `assigned` records globals that script code has declared or assigned, so
+/// the compiler knows a native of the same name may have been replaced.
+#[derive(Clone, Debug, Default)]
+pub struct GlobalTable {
+    indices: HashMap<String, usize>,
+    names: Vec<String>,
+    assigned: Vec<bool>,
+}
+
+impl GlobalTable {
+    pub fn new() -> Self {
+        Self {
+            indices: HashMap::new(),
+            names: Vec::new(),
+            assigned: Vec::new(),
+        }
+    }
+
+    pub fn resolve(&mut self, name: &str) -> usize {
+        if let Some(index) = self.indices.get(name) {
+            return *index;
+        }
+
+        let index = self.names.len();
+        self.indices.insert(name.to_string(), index);
+        self.names.push(name.to_string());
+        self.assigned.push(false);
+        index
+    }
+
+    // Like `resolve`, but doesn't create a slot for a name it hasn't seen.
+    pub fn lookup(&self, name: &str) -> Option<usize> {
+        self.indices.get(name).copied()
+    }
+
+    pub fn mark_assigned(&mut self, index: usize) {
+        self.assigned[index] = true;
+    }
+
+    pub fn is_assigned(&self, index: usize) -> bool {
+        self.assigned[index]
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn name(&self, index: usize) -> &str {
+        self.names[index].as_str()
+    }
+}
@@ -0,0 +1,24 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A cheap, cloneable way to ask a running VM to stop. The VM polls the flag
+/// at every instruction boundary, so this is safe to call from another thread
+/// or from a signal handler.
+#[derive(Clone, Debug, Default)]
+pub struct VmHandle {
+    interrupted: Arc<AtomicBool>,
+}
+
+impl VmHandle {
+    pub fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::Relaxed)
+    }
+
+    pub fn clear(&self) {
+        self.interrupted.store(false, Ordering::Relaxed);
+    }
+}
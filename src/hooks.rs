@@ -0,0 +1,38 @@
+use crate::error::*;
+use crate::shared::*;
+
+/// Callbacks the VM makes as a script runs, for hosts that audit, measure or
+/// restrict what scripts do. `on_call` sees every call, natives included,
+/// before it happens, and `on_global_write` sees every definition of or
+/// assignment to a global. Either can refuse with an error, which fails the
+/// script like any runtime error.
+///
+/// ```
+/// use lox_bytecode::{Hooks, NativeError, VmBuilder};
+///
+/// struct NoClock;
+///
+/// impl Hooks for NoClock {
+///     fn on_call(&mut self, function: &str) -> Result<(), NativeError> {
+///         match function {
+///             "clock" => Err("clock() is not allowed here.".into()),
+///             _ => Ok(()),
+///         }
+///     }
+/// }
+///
+/// let mut vm = VmBuilder::new().hooks(NoClock).build();
+/// let err = vm.interpret("print clock();").unwrap_err();
+/// assert_eq!(err.message(), "clock() is not allowed here.");
+/// ```
+pub trait Hooks: MaybeSend {
+    fn on_call(&mut self, _function: &str) -> Result<(), NativeError> {
+        Ok(())
+    }
+
+    fn on_return(&mut self, _function: &str) {}
+
+    fn on_global_write(&mut self, _name: &str) -> Result<(), NativeError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,42 @@
+use crate::class::*;
+use crate::closure::*;
+use crate::shared::*;
+
+// Remembers the last class seen at a property access or invoke site together
+// with the method it resolved to. The VM bumps its method epoch whenever any
+// class gains a method, which invalidates every cache at once.
+#[derive(Clone, Debug, Default)]
+pub struct InlineCache {
+    entry: RefCell<Option<CacheEntry>>,
+}
+
+#[derive(Clone, Debug)]
+struct CacheEntry {
+    klass: Weak<Class>,
+    epoch: usize,
+    method: Weak<Closure>,
+}
+
+impl InlineCache {
+    // Caches belong to a chunk, which VMs on other threads may be running
+    // too, so one that is busy is skipped rather than waited for.
+    pub fn lookup(&self, klass: &Rc<Class>, epoch: usize) -> Option<Rc<Closure>> {
+        let entry = self.entry.try_borrow().ok()?;
+        let entry = entry.as_ref()?;
+        if entry.epoch == epoch && Weak::as_ptr(&entry.klass) == Rc::as_ptr(klass) {
+            entry.method.upgrade()
+        } else {
+            None
+        }
+    }
+
+    pub fn update(&self, klass: &Rc<Class>, epoch: usize, method: &Rc<Closure>) {
+        if let Ok(mut entry) = self.entry.try_borrow_mut() {
+            *entry = Some(CacheEntry {
+                klass: Rc::downgrade(klass),
+                epoch,
+                method: Rc::downgrade(method),
+            });
+        }
+    }
+}
@@ -1,15 +1,23 @@
-use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fmt::{Debug, Display, Formatter};
-use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use crate::class::*;
+use crate::memory::*;
+use crate::shared::*;
 use crate::value::*;
 
 #[derive(Debug)]
 pub struct Instance {
     klass: Rc<Class>,
     fields: RefCell<HashMap<String, Value>>,
+    // Set by `freeze()`, after which scripts can no longer set fields.
+    frozen: AtomicBool,
+    // Set on the instance `__drop()` is called on, so it isn't finalized a
+    // second time when it goes in turn.
+    finalized: bool,
+    // What the VM counted for this instance and its fields.
+    allocation: Option<Allocation>,
 }
 
 impl Display for Instance {
@@ -23,18 +31,106 @@ impl Instance {
         Self {
             klass: Rc::clone(&klass),
             fields: RefCell::new(HashMap::new()),
+            frozen: AtomicBool::new(false),
+            finalized: false,
+            allocation: None,
         }
     }
 
+    pub fn with_allocation(mut self, allocation: Allocation) -> Self {
+        self.allocation = Some(allocation);
+        self
+    }
+
+    // Counts a field the VM has added.
+    pub fn grow_allocation(&self, bytes: usize) {
+        if let Some(allocation) = &self.allocation {
+            allocation.grow(bytes);
+        }
+    }
+
+    pub fn freeze(&self) {
+        self.frozen.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen.load(Ordering::Relaxed)
+    }
+
     pub fn get_field(&self, name: &str) -> Option<Value> {
         self.fields.borrow().get(&name.to_string()).cloned()
     }
 
-    pub fn set_field<T: Into<String>>(&self, name: T, value: &Value) {
-        self.fields.borrow_mut().insert(name.into(), value.clone());
+    // Returns true if this added a new field rather than overwriting one.
+    pub fn set_field<T: Into<String>>(&self, name: T, value: &Value) -> bool {
+        // The old value goes once the borrow has ended.
+        let old = self.fields.borrow_mut().insert(name.into(), value.clone());
+        old.is_none()
+    }
+
+    pub fn fields(&self) -> Vec<(String, Value)> {
+        let fields = self.fields.borrow();
+        fields
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect()
     }
 
     pub fn get_class(&self) -> Rc<Class> {
         Rc::clone(&self.klass)
     }
 }
+
+// An instance can't run Lox code as it is dropped, as it has no VM to run it
+// on and is on its way out. So if its class has a `__drop()` method, its
+// fields move to a new instance of the class that waits here for the VM to
+// call `__drop()` on, between instructions.
+impl Drop for Instance {
+    fn drop(&mut self) {
+        if self.finalized || !self.klass.has_drop() {
+            return;
+        }
+        let Some(drops) = self.klass.drop_queue() else {
+            return;
+        };
+        let fields = std::mem::take(&mut *self.fields.borrow_mut());
+        drops.push(Instance {
+            klass: Rc::clone(&self.klass),
+            fields: RefCell::new(fields),
+            frozen: AtomicBool::new(self.is_frozen()),
+            finalized: true,
+            allocation: self.allocation.take(),
+        });
+    }
+}
+
+/// Instances waiting for their `__drop()` method to be called. Values are
+/// reference counted, so an instance is dropped as soon as nothing refers to
+/// it any more, and the VM finalizes it before the next instruction, or
+/// when the script finishes. Instances still referred to when the VM goes
+/// away, such as those in globals, and those only referred to by each other
+/// in a cycle are never finalized. `this` in `__drop()` has the fields of
+/// the dropped instance but isn't the same instance, and an error in
+/// `__drop()` stops the script like any other.
+#[derive(Debug, Default)]
+pub struct DropQueue {
+    pending: RefCell<Vec<Instance>>,
+    queued: AtomicBool,
+}
+
+impl DropQueue {
+    fn push(&self, instance: Instance) {
+        self.pending.borrow_mut().push(instance);
+        self.queued.store(true, Ordering::Relaxed);
+    }
+
+    // Cheap enough to check before every instruction.
+    pub fn is_pending(&self) -> bool {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    pub fn take(&self) -> Vec<Instance> {
+        self.queued.store(false, Ordering::Relaxed);
+        std::mem::take(&mut *self.pending.borrow_mut())
+    }
+}
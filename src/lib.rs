@@ -0,0 +1,159 @@
+//! A bytecode compiler and virtual machine for Lox, the language from
+//! *Crafting Interpreters*.
+//!
+//! Embedding the interpreter only takes a [`VM`]:
+//!
+//! ```
+//! use lox_bytecode::{InterpretResult, VM};
+//!
+//! let mut vm = VM::new();
+//! vm.interpret("var greeting = \"hello\"; print greeting;").unwrap();
+//!
+//! let err = vm.interpret("print 1 + nil;").unwrap_err();
+//! assert_eq!(err.kind(), InterpretResult::RuntimeError);
+//! ```
+//!
+//! A VM keeps its globals between calls to [`VM::interpret`], so a host can
+//! feed it a script in pieces, as the REPL does. [`VmConfig`] sets limits
+//! and language options, [`VmBuilder`] sets those and the VM's output and
+//! hooks in one go, and [`NativeFunc`] adds functions written in Rust.
+//! [`Hooks`] let a host watch or veto the calls and global writes a script
+//! makes. [`compile`] turns a script into a [`Program`] once, for any number
+//! of VMs to [`VM::execute`].
+//!
+//! The default `debug_print_code` feature prints the bytecode of everything
+//! compiled, so embedders will usually want `default-features = false`, or
+//! to turn [`VmConfig::print_code`] off.
+//!
+//! A VM is single threaded by default. With the `sync` feature its objects
+//! are shared through `Arc` and locks instead of `Rc` and `RefCell`, which
+//! makes [`VM`] and [`Value`] `Send`, so a host can build a VM on one thread
+//! and run it on another. Everything the VM is given then has to be `Send`
+//! as well, and natives `Sync` too; [`MaybeSend`] and [`MaybeSync`] are those
+//! bounds, and they hold for any type when the feature is off.
+//!
+//! ```
+//! # #[cfg(feature = "sync")]
+//! # {
+//! use lox_bytecode::{Value, VM};
+//!
+//! let mut vm = VM::new();
+//! vm.interpret("fun add(a, b) { return a + b; }").unwrap();
+//! let sum = std::thread::spawn(move || {
+//!     vm.call_global("add", &[Value::from(1), Value::from(2)])
+//! });
+//! assert_eq!(f64::try_from(sum.join().unwrap().unwrap()).unwrap(), 3.0);
+//! # }
+//! ```
+//!
+//! The `serde` feature implements `Serialize` and `Deserialize` for the
+//! plain data a [`Value`] can hold: nil, booleans, numbers and strings. That
+//! is enough for a host to hand scripts settings read from JSON or TOML and
+//! to read their results back out. Other values fail to serialize.
+//!
+//! ```
+//! # #[cfg(feature = "serde")]
+//! # {
+//! use serde::de::{value::Error, IntoDeserializer};
+//! use serde::Deserialize;
+//! use lox_bytecode::{Value, VM};
+//!
+//! let name: Result<Value, Error> = Value::deserialize("lox".into_deserializer());
+//! let mut vm = VM::new();
+//! vm.set_global("name", name.unwrap());
+//! vm.interpret("var greeting = \"hello \" + name;").unwrap();
+//! assert_eq!(vm.get_global("greeting"), Some(Value::from("hello lox")));
+//! # }
+//! ```
+//!
+//! The crate builds for `wasm32-unknown-unknown`. Nothing there can read the
+//! system time, so either give the VM a clock with [`VmBuilder::clock`] or
+//! leave `clock()` and `now()` failing, and use fuel rather than timeouts or
+//! profiling. `clockMonotonic()` and `sleep()` always fail there.
+//! `print` goes wherever [`VmBuilder::output`] says, and `readLine` reads
+//! from [`VmBuilder::input`], finding nothing to read otherwise. Built as a
+//! `cdylib` for that target, the crate also exports a small C-style API
+//! (`lox_alloc`, `lox_interpret`, `lox_free`, `lox_reset`) that imports its
+//! output and clock from the host, enough to drive a browser playground
+//! without any bindings crate; `src/wasm.rs` shows the JavaScript side.
+//!
+//! [`fuzz_compile`] and [`fuzz_interpret`] take arbitrary bytes and must
+//! never panic; `fuzz/` has cargo-fuzz targets for both.
+//!
+//! The `ast`, `format`, `lint`, `lsp`, `cfg`, `scanner` and `token` modules
+//! hold the tooling behind the command-line subcommands.
+
+mod asm;
+pub mod ast;
+mod bound_method;
+mod builder;
+pub mod cfg;
+mod chunk;
+mod class;
+mod closure;
+mod compiler;
+mod config;
+mod coverage;
+mod date;
+mod debugger;
+mod diagnostic;
+mod error;
+pub mod format;
+mod function;
+mod fuzz;
+mod globals;
+mod handle;
+mod hooks;
+mod inline_cache;
+mod instance;
+mod json;
+pub mod lint;
+pub mod lsp;
+mod memory;
+#[cfg(feature = "nan_boxing")]
+mod nanbox;
+mod native;
+mod optimizer;
+mod profile;
+mod program;
+mod reflect;
+pub mod scanner;
+mod serialize;
+mod shared;
+mod snapshot;
+mod testing;
+pub mod token;
+mod trace;
+mod upvalues;
+mod user_data;
+mod value;
+#[cfg(feature = "serde")]
+mod value_serde;
+mod vm;
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+mod wasm;
+
+pub use builder::VmBuilder;
+pub use class::ClassBuilder;
+pub use compiler::Compiler;
+pub use config::{DivisionByZero, VmConfig, Warnings};
+pub use coverage::Coverage;
+pub use debugger::{ConsoleDebugger, DebugAction, Debugger, PrintDebugger};
+pub use diagnostic::{
+    caret_snippet, color_caret_snippet, color_enabled, paint, ColorReporter, Diagnostic,
+    JsonReporter, Reporter, Severity, Span, StderrReporter,
+};
+pub use error::{CompileError, InterpretResult, LoxError, NativeError, RuntimeError, TraceFrame};
+pub use function::Function;
+pub use fuzz::{fuzz_compile, fuzz_interpret};
+pub use globals::GlobalTable;
+pub use handle::VmHandle;
+pub use hooks::Hooks;
+pub use profile::Profile;
+pub use program::{compile, Program};
+pub use shared::{Input, MaybeSend, MaybeSync, Output};
+pub use testing::{run_test, run_tests, CapturedOutput, TestResult};
+pub use trace::Tracer;
+pub use user_data::UserData;
+pub use value::{NativeFunc, Value};
+pub use vm::VM;
@@ -0,0 +1,365 @@
+use crate::ast::*;
+use crate::diagnostic::*;
+use crate::token::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+// What each lint reports as. Denied lints come out as errors so the caller
+// can fail on them.
+#[derive(Clone, Copy, Debug)]
+pub struct LintRules {
+    pub shadowed_variable: Level,
+    pub empty_block: Level,
+    pub constant_comparison: Level,
+    pub unused_function: Level,
+}
+
+impl Default for LintRules {
+    fn default() -> Self {
+        Self {
+            shadowed_variable: Level::Warn,
+            empty_block: Level::Warn,
+            constant_comparison: Level::Warn,
+            unused_function: Level::Warn,
+        }
+    }
+}
+
+impl LintRules {
+    pub const NAMES: [&'static str; 4] = [
+        "shadowed-variable",
+        "empty-block",
+        "constant-comparison",
+        "unused-function",
+    ];
+
+    // Returns false for an unknown rule name.
+    pub fn set(&mut self, rule: &str, level: Level) -> bool {
+        let field = match rule {
+            "shadowed-variable" => &mut self.shadowed_variable,
+            "empty-block" => &mut self.empty_block,
+            "constant-comparison" => &mut self.constant_comparison,
+            "unused-function" => &mut self.unused_function,
+            _ => return false,
+        };
+        *field = level;
+        true
+    }
+}
+
+struct Decl {
+    name: Token,
+    is_function: bool,
+    is_used: bool,
+}
+
+// Walks a parsed script with the same scoping rules as the compiler. The
+// top-level scope is filled in up front, since functions can refer to
+// globals that are declared after them.
+pub fn lint(ast: &Ast, rules: LintRules) -> Vec<Diagnostic> {
+    let mut linter = Linter {
+        rules,
+        scopes: vec![Vec::new()],
+        defining: Vec::new(),
+        diagnostics: Vec::new(),
+    };
+    for statement in &ast.statements {
+        match statement {
+            Stmt::Var { name, .. } | Stmt::Class { name, .. } => linter.declare(name, false),
+            Stmt::Function(function) => linter.declare(&function.name, true),
+            _ => {}
+        }
+    }
+    for statement in &ast.statements {
+        linter.statement(statement, true);
+    }
+    linter.end_scope();
+    linter
+        .diagnostics
+        .sort_by_key(|diagnostic| (diagnostic.line, diagnostic.column));
+    linter.diagnostics
+}
+
+struct Linter {
+    rules: LintRules,
+    scopes: Vec<Vec<Decl>>,
+    // Functions whose bodies are being walked, as (scope, index) pairs, so
+    // that recursive calls don't count as uses.
+    defining: Vec<(usize, usize)>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Linter {
+    fn report(&mut self, level: Level, token: &Token, message: &str) {
+        let mut diagnostic = Diagnostic::warning_at(token, message);
+        match level {
+            Level::Allow => return,
+            Level::Warn => {}
+            Level::Deny => diagnostic.severity = Severity::Error,
+        }
+        self.diagnostics.push(diagnostic);
+    }
+
+    fn declare(&mut self, name: &Token, is_function: bool) {
+        if self.scopes.len() > 1 {
+            let (_, outer) = self.scopes.split_last().unwrap();
+            let shadowed = outer
+                .iter()
+                .rev()
+                .find_map(|scope| scope.iter().find(|decl| decl.name.lexeme == name.lexeme));
+            if let Some(decl) = shadowed {
+                let message = format!(
+                    "'{}' shadows the declaration on line {}.",
+                    name.lexeme, decl.name.line
+                );
+                self.report(self.rules.shadowed_variable, name, &message);
+            }
+        }
+        self.scopes.last_mut().unwrap().push(Decl {
+            name: name.clone(),
+            is_function,
+            is_used: false,
+        });
+    }
+
+    fn resolve(&mut self, name: &Token) {
+        for (depth, scope) in self.scopes.iter_mut().enumerate().rev() {
+            if let Some(index) = scope.iter().rposition(|d| d.name.lexeme == name.lexeme) {
+                if !self.defining.contains(&(depth, index)) {
+                    scope[index].is_used = true;
+                }
+                return;
+            }
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.scopes.push(Vec::new());
+    }
+
+    fn end_scope(&mut self) {
+        let scope = self.scopes.pop().unwrap();
+        for decl in scope {
+            if decl.is_function && !decl.is_used && !decl.name.lexeme.starts_with('_') {
+                let message = format!("Unused function '{}'.", decl.name.lexeme);
+                self.report(self.rules.unused_function, &decl.name, &message);
+            }
+        }
+    }
+
+    // `top_level` declarations were already added to the global scope.
+    fn statement(&mut self, statement: &Stmt, top_level: bool) {
+        match statement {
+            Stmt::Expression(expr) | Stmt::Print(expr) => self.expression(expr),
+            Stmt::Var { name, initializer } => {
+                if let Some(initializer) = initializer {
+                    self.expression(initializer);
+                }
+                if !top_level {
+                    self.declare(name, false);
+                }
+            }
+            Stmt::Block { brace, statements } => {
+                if statements.is_empty() {
+                    self.report(self.rules.empty_block, brace, "Empty block.");
+                }
+                self.begin_scope();
+                for statement in statements {
+                    self.statement(statement, false);
+                }
+                self.end_scope();
+            }
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.expression(condition);
+                self.statement(then_branch, false);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch, false);
+                }
+            }
+            Stmt::While { condition, body } => {
+                self.expression(condition);
+                self.statement(body, false);
+            }
+            Stmt::For {
+                initializer,
+                condition,
+                increment,
+                body,
+            } => {
+                self.begin_scope();
+                if let Some(initializer) = initializer {
+                    self.statement(initializer, false);
+                }
+                for expr in condition.iter().chain(increment) {
+                    self.expression(expr);
+                }
+                self.statement(body, false);
+                self.end_scope();
+            }
+            Stmt::ForIn {
+                name,
+                iterable,
+                body,
+            } => {
+                self.expression(iterable);
+                self.begin_scope();
+                self.declare(name, false);
+                self.statement(body, false);
+                self.end_scope();
+            }
+            Stmt::Function(function) => {
+                if !top_level {
+                    self.declare(&function.name, true);
+                }
+                let depth = self.scopes.len() - 1;
+                let index = self.scopes[depth]
+                    .iter()
+                    .rposition(|d| d.name.lexeme == function.name.lexeme)
+                    .unwrap();
+                self.defining.push((depth, index));
+                self.function(function);
+                self.defining.pop();
+            }
+            Stmt::Return { value, .. } => {
+                if let Some(value) = value {
+                    self.expression(value);
+                }
+            }
+            Stmt::Class {
+                name,
+                superclass,
+                methods,
+                statics,
+            } => {
+                if !top_level {
+                    self.declare(name, false);
+                }
+                if let Some(superclass) = superclass {
+                    self.resolve(superclass);
+                }
+                for value in statics.iter().filter_map(|field| field.value.as_ref()) {
+                    self.expression(value);
+                }
+                for method in methods {
+                    self.function(method);
+                }
+            }
+            Stmt::Extend {
+                name,
+                methods,
+                statics,
+            } => {
+                self.resolve(name);
+                for value in statics.iter().filter_map(|field| field.value.as_ref()) {
+                    self.expression(value);
+                }
+                for method in methods {
+                    self.function(method);
+                }
+            }
+        }
+    }
+
+    fn function(&mut self, function: &FunctionDecl) {
+        self.begin_scope();
+        for param in &function.params {
+            self.declare(param, false);
+        }
+        for statement in &function.body {
+            self.statement(statement, false);
+        }
+        self.end_scope();
+    }
+
+    fn expression(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Literal(_) | Expr::This(_) | Expr::Super { .. } => {}
+            Expr::Variable(name) => self.resolve(name),
+            Expr::Assign { value, .. } => self.expression(value),
+            Expr::Unary { right, .. } => self.expression(right),
+            Expr::Binary {
+                left,
+                operator,
+                right,
+            } => {
+                if let Some(result) = constant_comparison(left, operator, right) {
+                    let message = format!("Comparison is always {result}.");
+                    self.report(self.rules.constant_comparison, operator, &message);
+                }
+                self.expression(left);
+                self.expression(right);
+            }
+            Expr::Logical { left, right, .. } => {
+                self.expression(left);
+                self.expression(right);
+            }
+            Expr::Grouping(expr) => self.expression(expr),
+            Expr::Call {
+                callee, arguments, ..
+            } => {
+                self.expression(callee);
+                for argument in arguments {
+                    self.expression(argument);
+                }
+            }
+            Expr::Get { object, .. } => self.expression(object),
+            Expr::Set { object, value, .. } => {
+                self.expression(object);
+                self.expression(value);
+            }
+        }
+    }
+}
+
+// The result of a comparison whose outcome doesn't depend on any runtime
+// value: a variable compared with itself, or two literals. A variable
+// holding NaN would break the first case, which is rare enough to ignore.
+fn constant_comparison(left: &Expr, operator: &Token, right: &Expr) -> Option<bool> {
+    let ordering = match (ungroup(left), ungroup(right)) {
+        (Expr::Variable(a), Expr::Variable(b)) if a.lexeme == b.lexeme => {
+            Some(std::cmp::Ordering::Equal)
+        }
+        (Expr::Literal(a), Expr::Literal(b))
+            if a.ttype == TokenType::Number && b.ttype == TokenType::Number =>
+        {
+            let a: f64 = a.lexeme.parse().ok()?;
+            let b: f64 = b.lexeme.parse().ok()?;
+            a.partial_cmp(&b)
+        }
+        (Expr::Literal(a), Expr::Literal(b)) => {
+            let equal = a.ttype == b.ttype && a.lexeme == b.lexeme;
+            return match operator.ttype {
+                TokenType::Equals => Some(equal),
+                TokenType::BangEqual => Some(!equal),
+                _ => None,
+            };
+        }
+        _ => return None,
+    }?;
+
+    match operator.ttype {
+        TokenType::Equals => Some(ordering.is_eq()),
+        TokenType::BangEqual => Some(ordering.is_ne()),
+        TokenType::Less => Some(ordering.is_lt()),
+        TokenType::LessEqual => Some(ordering.is_le()),
+        TokenType::Greater => Some(ordering.is_gt()),
+        TokenType::GreaterEqual => Some(ordering.is_ge()),
+        _ => None,
+    }
+}
+
+fn ungroup(expr: &Expr) -> &Expr {
+    match expr {
+        Expr::Grouping(inner) => ungroup(inner),
+        expr => expr,
+    }
+}
@@ -0,0 +1,518 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use crate::ast::*;
+use crate::config::*;
+use crate::diagnostic::*;
+use crate::json::*;
+use crate::shared::*;
+use crate::token::*;
+use crate::vm::*;
+
+// A small language server speaking JSON-RPC over stdin and stdout. Documents
+// are synced in full; every change recompiles the document to publish its
+// diagnostics and rebuilds the symbol index used for go-to-definition and
+// hover. Positions are sent as characters rather than UTF-16 code units,
+// which only differs outside the Basic Multilingual Plane.
+//
+// Returns the exit status the client asked for: 0 after a shutdown request,
+// 1 if it went away without one.
+pub fn run() -> io::Result<i32> {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let mut server = Server::default();
+    while let Some(body) = read_message(&mut input)? {
+        match Json::parse(&body) {
+            Ok(message) => {
+                if let Some(status) = server.handle(&message)? {
+                    return Ok(status);
+                }
+            }
+            Err(err) => send(&error_response(Json::Null, -32700, &err))?,
+        }
+    }
+    Ok(if server.shutdown { 0 } else { 1 })
+}
+
+#[derive(Default)]
+struct Server {
+    documents: HashMap<String, Document>,
+    shutdown: bool,
+}
+
+struct Document {
+    // Built from whatever parses, so it stays useful around syntax errors.
+    index: Index,
+}
+
+impl Server {
+    // Returns the exit status once the client sends `exit`.
+    fn handle(&mut self, message: &Json) -> io::Result<Option<i32>> {
+        let id = message.get("id").cloned();
+        let params = message.get("params").unwrap_or(&Json::Null);
+        let uri = params
+            .get("textDocument")
+            .and_then(|doc| doc.get("uri"))
+            .and_then(Json::as_str)
+            .unwrap_or_default()
+            .to_string();
+
+        let result = match message.get("method").and_then(Json::as_str) {
+            Some("initialize") => Json::object([
+                (
+                    "capabilities",
+                    Json::object([
+                        ("textDocumentSync", Json::Number(1.0)),
+                        ("definitionProvider", true.into()),
+                        ("hoverProvider", true.into()),
+                    ]),
+                ),
+                (
+                    "serverInfo",
+                    Json::object([("name", "lox-bytecode".into())]),
+                ),
+            ]),
+            Some("shutdown") => {
+                self.shutdown = true;
+                Json::Null
+            }
+            Some("exit") => return Ok(Some(if self.shutdown { 0 } else { 1 })),
+            Some("textDocument/didOpen") => {
+                let text = params
+                    .get("textDocument")
+                    .and_then(|doc| doc.get("text"))
+                    .and_then(Json::as_str);
+                self.update(uri, text.unwrap_or_default())?;
+                return Ok(None);
+            }
+            Some("textDocument/didChange") => {
+                let text = match params.get("contentChanges") {
+                    Some(Json::Array(changes)) => changes
+                        .last()
+                        .and_then(|change| change.get("text"))
+                        .and_then(Json::as_str),
+                    _ => None,
+                };
+                if let Some(text) = text {
+                    self.update(uri, text)?;
+                }
+                return Ok(None);
+            }
+            Some("textDocument/didClose") => {
+                self.documents.remove(&uri);
+                publish_diagnostics(&uri, &[])?;
+                return Ok(None);
+            }
+            Some("textDocument/definition") => match self.symbol_at(&uri, params) {
+                Some(symbol) => {
+                    Json::object([("uri", uri.as_str().into()), ("range", range(&symbol.name))])
+                }
+                None => Json::Null,
+            },
+            Some("textDocument/hover") => match self.symbol_at(&uri, params) {
+                Some(symbol) => {
+                    let text = format!(
+                        "{}\n\nDeclared on line {}.",
+                        symbol.detail, symbol.name.line
+                    );
+                    Json::object([(
+                        "contents",
+                        Json::object([
+                            ("kind", "plaintext".into()),
+                            ("value", text.as_str().into()),
+                        ]),
+                    )])
+                }
+                None => Json::Null,
+            },
+            _ => {
+                // Unknown notifications are ignored; unknown requests still
+                // need an answer.
+                if let Some(id) = id {
+                    send(&error_response(id, -32601, "Method not found"))?;
+                }
+                return Ok(None);
+            }
+        };
+
+        if let Some(id) = id {
+            send(&Json::object([
+                ("jsonrpc", "2.0".into()),
+                ("id", id),
+                ("result", result),
+            ]))?;
+        }
+        Ok(None)
+    }
+
+    fn update(&mut self, uri: String, text: &str) -> io::Result<()> {
+        publish_diagnostics(&uri, &compile_diagnostics(text))?;
+        let (ast, _) = parse_partial(text);
+        let index = Index::build(&ast);
+        self.documents.insert(uri, Document { index });
+        Ok(())
+    }
+
+    fn symbol_at(&self, uri: &str, params: &Json) -> Option<&Symbol> {
+        let position = params.get("position")?;
+        let line = position.get("line")?.as_f64()? as usize;
+        let character = position.get("character")?.as_f64()? as usize;
+        self.documents.get(uri)?.index.symbol_at(line, character)
+    }
+}
+
+// Compiles with every warning turned on, collecting what the compiler would
+// have reported.
+fn compile_diagnostics(source: &str) -> Vec<Diagnostic> {
+    struct Collect(Rc<RefCell<Vec<Diagnostic>>>);
+
+    impl Reporter for Collect {
+        fn report(&mut self, _source: &str, diagnostic: &Diagnostic) {
+            self.0.borrow_mut().push(diagnostic.clone());
+        }
+    }
+
+    let collected = Rc::new(RefCell::new(Vec::new()));
+    let mut vm = VM::with_config(VmConfig {
+        warnings: Warnings::all(),
+        // The disassembly would end up in the protocol stream.
+        print_code: false,
+        ..VmConfig::default()
+    });
+    vm.set_reporter(Box::new(Collect(Rc::clone(&collected))));
+    let _ = vm.check(source);
+    collected.take()
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: &[Diagnostic]) -> io::Result<()> {
+    let diagnostics = diagnostics
+        .iter()
+        .map(|diagnostic| {
+            let severity = match diagnostic.severity {
+                Severity::Error => 1.0,
+                Severity::Warning => 2.0,
+            };
+            let line = diagnostic.line.saturating_sub(1);
+            let start = diagnostic.column.saturating_sub(1);
+            Json::object([
+                (
+                    "range",
+                    position_range(line, start, start + diagnostic.span.len),
+                ),
+                ("severity", Json::Number(severity)),
+                ("source", "lox".into()),
+                ("message", diagnostic.message.as_str().into()),
+            ])
+        })
+        .collect();
+    send(&Json::object([
+        ("jsonrpc", "2.0".into()),
+        ("method", "textDocument/publishDiagnostics".into()),
+        (
+            "params",
+            Json::object([
+                ("uri", uri.into()),
+                ("diagnostics", Json::Array(diagnostics)),
+            ]),
+        ),
+    ]))
+}
+
+fn range(token: &Token) -> Json {
+    let start = token.column.saturating_sub(1);
+    position_range(token.line.saturating_sub(1), start, start + token.span.len)
+}
+
+fn position_range(line: usize, start: usize, end: usize) -> Json {
+    let position =
+        |character: usize| Json::object([("line", line.into()), ("character", character.into())]);
+    Json::object([("start", position(start)), ("end", position(end))])
+}
+
+fn error_response(id: Json, code: i32, message: &str) -> Json {
+    Json::object([
+        ("jsonrpc", "2.0".into()),
+        ("id", id),
+        (
+            "error",
+            Json::object([
+                ("code", Json::Number(code.into())),
+                ("message", message.into()),
+            ]),
+        ),
+    ])
+}
+
+// Reads one `Content-Length` framed message, or None at the end of input.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        }
+    }
+    let Some(length) = length else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "message without a Content-Length header",
+        ));
+    };
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    String::from_utf8(body)
+        .map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+fn send(message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    let mut out = io::stdout().lock();
+    write!(out, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+    out.flush()
+}
+
+struct Symbol {
+    name: Token,
+    detail: String,
+}
+
+// Every declaration in a document and every name that refers to one,
+// resolved with the compiler's scoping rules. Globals are declared before
+// anything is walked, because functions may use globals defined after them.
+struct Index {
+    symbols: Vec<Symbol>,
+    references: Vec<(Token, usize)>,
+    scopes: Vec<Vec<usize>>,
+}
+
+impl Index {
+    fn build(ast: &Ast) -> Self {
+        let mut index = Index {
+            symbols: Vec::new(),
+            references: Vec::new(),
+            scopes: vec![Vec::new()],
+        };
+        for statement in &ast.statements {
+            match statement {
+                Stmt::Var { name, .. } => index.declare(name, format!("var {}", name.lexeme)),
+                Stmt::Function(function) => index.declare(&function.name, signature(function)),
+                Stmt::Class {
+                    name, superclass, ..
+                } => index.declare(name, class_detail(name, superclass)),
+                _ => {}
+            }
+        }
+        for statement in &ast.statements {
+            index.statement(statement, true);
+        }
+        index
+    }
+
+    // The declaration named, or referred to, at a zero-based position.
+    fn symbol_at(&self, line: usize, character: usize) -> Option<&Symbol> {
+        let covers = |token: &Token| {
+            let start = token.column.saturating_sub(1);
+            token.line == line + 1 && (start..=start + token.span.len).contains(&character)
+        };
+        let symbol = self
+            .symbols
+            .iter()
+            .position(|symbol| covers(&symbol.name))
+            .or_else(|| {
+                self.references
+                    .iter()
+                    .find(|(token, _)| covers(token))
+                    .map(|(_, symbol)| *symbol)
+            })?;
+        self.symbols.get(symbol)
+    }
+
+    fn declare(&mut self, name: &Token, detail: String) {
+        self.scopes.last_mut().unwrap().push(self.symbols.len());
+        self.symbols.push(Symbol {
+            name: name.clone(),
+            detail,
+        });
+    }
+
+    fn resolve(&mut self, name: &Token) {
+        let symbol = self.scopes.iter().rev().find_map(|scope| {
+            scope
+                .iter()
+                .rev()
+                .find(|&&symbol| self.symbols[symbol].name.lexeme == name.lexeme)
+        });
+        if let Some(&symbol) = symbol {
+            self.references.push((name.clone(), symbol));
+        }
+    }
+
+    // `top_level` declarations were already added to the global scope.
+    fn statement(&mut self, statement: &Stmt, top_level: bool) {
+        match statement {
+            Stmt::Expression(expr) | Stmt::Print(expr) => self.expression(expr),
+            Stmt::Var { name, initializer } => {
+                if let Some(initializer) = initializer {
+                    self.expression(initializer);
+                }
+                if !top_level {
+                    self.declare(name, format!("var {}", name.lexeme));
+                }
+            }
+            Stmt::Block { statements, .. } => {
+                self.scopes.push(Vec::new());
+                for statement in statements {
+                    self.statement(statement, false);
+                }
+                self.scopes.pop();
+            }
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.expression(condition);
+                self.statement(then_branch, false);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch, false);
+                }
+            }
+            Stmt::While { condition, body } => {
+                self.expression(condition);
+                self.statement(body, false);
+            }
+            Stmt::For {
+                initializer,
+                condition,
+                increment,
+                body,
+            } => {
+                self.scopes.push(Vec::new());
+                if let Some(initializer) = initializer {
+                    self.statement(initializer, false);
+                }
+                for expr in condition.iter().chain(increment) {
+                    self.expression(expr);
+                }
+                self.statement(body, false);
+                self.scopes.pop();
+            }
+            Stmt::ForIn {
+                name,
+                iterable,
+                body,
+            } => {
+                self.expression(iterable);
+                self.scopes.push(Vec::new());
+                self.declare(name, format!("var {}", name.lexeme));
+                self.statement(body, false);
+                self.scopes.pop();
+            }
+            Stmt::Function(function) => {
+                if !top_level {
+                    self.declare(&function.name, signature(function));
+                }
+                self.function(function);
+            }
+            Stmt::Return { value, .. } => {
+                if let Some(value) = value {
+                    self.expression(value);
+                }
+            }
+            Stmt::Class {
+                name,
+                superclass,
+                methods,
+                statics,
+            } => {
+                if !top_level {
+                    self.declare(name, class_detail(name, superclass));
+                }
+                if let Some(superclass) = superclass {
+                    self.resolve(superclass);
+                }
+                for value in statics.iter().filter_map(|field| field.value.as_ref()) {
+                    self.expression(value);
+                }
+                for method in methods {
+                    self.function(method);
+                }
+            }
+            Stmt::Extend {
+                name,
+                methods,
+                statics,
+            } => {
+                self.resolve(name);
+                for value in statics.iter().filter_map(|field| field.value.as_ref()) {
+                    self.expression(value);
+                }
+                for method in methods {
+                    self.function(method);
+                }
+            }
+        }
+    }
+
+    fn function(&mut self, function: &FunctionDecl) {
+        self.scopes.push(Vec::new());
+        for param in &function.params {
+            let detail = format!("parameter {} of {}", param.lexeme, function.name.lexeme);
+            self.declare(param, detail);
+        }
+        for statement in &function.body {
+            self.statement(statement, false);
+        }
+        self.scopes.pop();
+    }
+
+    fn expression(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Literal(_) | Expr::This(_) | Expr::Super { .. } => {}
+            Expr::Variable(name) => self.resolve(name),
+            Expr::Assign { name, value } => {
+                self.expression(value);
+                self.resolve(name);
+            }
+            Expr::Unary { right, .. } => self.expression(right),
+            Expr::Binary { left, right, .. } | Expr::Logical { left, right, .. } => {
+                self.expression(left);
+                self.expression(right);
+            }
+            Expr::Grouping(expr) => self.expression(expr),
+            Expr::Call {
+                callee, arguments, ..
+            } => {
+                self.expression(callee);
+                for argument in arguments {
+                    self.expression(argument);
+                }
+            }
+            Expr::Get { object, .. } => self.expression(object),
+            Expr::Set { object, value, .. } => {
+                self.expression(object);
+                self.expression(value);
+            }
+        }
+    }
+}
+
+fn signature(function: &FunctionDecl) -> String {
+    let params: Vec<&str> = function.params.iter().map(|p| p.lexeme.as_str()).collect();
+    format!("fun {}({})", function.name.lexeme, params.join(", "))
+}
+
+fn class_detail(name: &Token, superclass: &Option<Token>) -> String {
+    match superclass {
+        Some(superclass) => format!("class {} < {}", name.lexeme, superclass.lexeme),
+        None => format!("class {}", name.lexeme),
+    }
+}
@@ -1,62 +1,953 @@
 use std::env::args;
-use std::io::{self, stdout, BufRead, Write};
-
-mod bound_method;
-mod chunk;
-mod class;
-mod closure;
-mod compiler;
-mod function;
-mod instance;
-mod native;
-mod scanner;
-mod token;
-mod upvalues;
-mod value;
-
-mod error;
-use error::*;
-
-mod vm;
-use vm::*;
+use std::io::{self, stdout, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use lox_bytecode::lint::{Level, LintRules};
+use lox_bytecode::scanner::Scanner;
+use lox_bytecode::token::TokenType;
+use lox_bytecode::*;
+
+#[derive(Default)]
+struct Options {
+    config: Option<VmConfig>,
+    breakpoints: Vec<usize>,
+    step: bool,
+    profile: bool,
+    bench: Option<usize>,
+    bench_suite: Option<usize>,
+    diff: Option<String>,
+    callgrind: Option<String>,
+    coverage: bool,
+    lcov: Option<String>,
+    trace: bool,
+    trace_file: Option<String>,
+    trace_functions: Vec<String>,
+    tokens: bool,
+    dump_ast: bool,
+    dump_cfg: bool,
+    check: bool,
+    command: Option<Command>,
+    lint_rules: LintRules,
+    output: Option<String>,
+    script: Option<String>,
+    eval: Option<String>,
+    script_args: Vec<String>,
+    color: bool,
+    json_diagnostics: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Command {
+    Fmt,
+    Lint,
+    Lsp,
+    Compile,
+    Run,
+    Disasm,
+    Asm,
+    Debug,
+    Test,
+}
+
+// Whether the command runs the script, which then gets the arguments after
+// it.
+fn runs_script(command: Option<Command>) -> bool {
+    matches!(
+        command,
+        None | Some(Command::Run | Command::Asm | Command::Debug)
+    )
+}
+
+impl Options {
+    fn config(&mut self) -> &mut VmConfig {
+        self.config.get_or_insert_with(VmConfig::default)
+    }
+}
 
 fn main() {
-    let args: Vec<String> = args().collect();
-    let mut vm = VM::new();
+    let options = parse_args();
+    if options.tokens {
+        let Some(path) = &options.script else {
+            usage();
+        };
+        print_tokens(path).expect("Could not read file");
+    }
+    if let Some(runs) = options.bench_suite {
+        bench_suite(runs, &options);
+    }
+    match (options.command, &options.script) {
+        (Some(Command::Lsp), _) => {
+            let status = lsp::run().expect("Could not talk to the client");
+            std::process::exit(status);
+        }
+        (Some(Command::Fmt), Some(path)) => {
+            format_file(path, options.check, &mut reporter(&options)).expect("Could not read file")
+        }
+        (Some(Command::Test), Some(dir)) => test_dir(dir).expect("Could not read tests"),
+        (Some(Command::Lint), Some(path)) => {
+            lint_file(path, options.lint_rules, &mut reporter(&options))
+                .expect("Could not read file")
+        }
+        (Some(_), None) => usage(),
+        _ => {}
+    }
+    if options.dump_ast {
+        let Some(path) = &options.script else {
+            usage();
+        };
+        dump_ast(path, &mut reporter(&options)).expect("Could not read file");
+    }
+    let mut builder = VmBuilder::new()
+        .reporter(reporter(&options))
+        .args(options.script_args.clone());
+    if let Some(config) = &options.config {
+        builder = builder.config(config.clone());
+    }
+    if let Some(path) = options.script.as_ref().filter(|path| *path != "-") {
+        builder = builder.file(path);
+    }
+    if options.step || !options.breakpoints.is_empty() {
+        builder = builder.debugger(PrintDebugger::new(options.step));
+        for line in &options.breakpoints {
+            builder = builder.breakpoint(*line);
+        }
+        if options.step {
+            builder = builder.step();
+        }
+    }
+    if let (Some(Command::Debug), Some(path)) = (options.command, &options.script) {
+        let source = std::fs::read_to_string(path).expect("Could not read file");
+        builder = builder.debugger(ConsoleDebugger::new(&source)).step();
+    }
+    if options.trace {
+        let out: Box<dyn Output> = match &options.trace_file {
+            Some(path) => {
+                let file = std::fs::File::create(path).expect("Could not create trace file");
+                Box::new(io::BufWriter::new(file))
+            }
+            None => Box::new(stdout()),
+        };
+        let mut tracer = Tracer::new(out);
+        for name in &options.trace_functions {
+            tracer.add_function(name);
+        }
+        builder = builder.tracer(tracer);
+    }
+    if options.profile || options.callgrind.is_some() {
+        builder = builder.profile();
+    }
+    if options.coverage || options.lcov.is_some() {
+        builder = builder.coverage();
+    }
+    let mut vm = builder.build();
+    #[cfg(unix)]
+    sigint::install(vm.handle());
+
+    if let Some(source) = &options.eval {
+        if options.bench.is_some() {
+            bench(source, None, &options);
+        }
+        run_source(&mut vm, source, &options);
+    }
+    match (options.command, &options.script) {
+        (Some(Command::Compile), Some(path)) => {
+            compile_file(&mut vm, path, options.output.as_deref()).expect("Could not compile file")
+        }
+        (Some(Command::Disasm), Some(path)) => {
+            disassemble_file(&mut vm, path).expect("Could not read file")
+        }
+        (Some(Command::Asm), Some(path)) => {
+            run_assembly(&mut vm, path, &options).expect("Could not run file")
+        }
+        (Some(Command::Debug), Some(path)) => {
+            run_file(&mut vm, path, &options).expect("Could not run file")
+        }
+        (Some(Command::Run), Some(path)) => {
+            run_binary(&mut vm, path, &options).expect("Could not run file")
+        }
+        (_, Some(path)) if options.dump_cfg => {
+            dump_cfg(&mut vm, path).expect("Could not read file")
+        }
+        (_, Some(path)) if options.check => check_file(&mut vm, path).expect("Could not read file"),
+        (_, Some(path)) if options.diff.is_some() => {
+            diff_file(path, &options).expect("Could not run reference")
+        }
+        (_, Some(path)) if options.bench.is_some() => {
+            let source = std::fs::read_to_string(path).expect("Could not read file");
+            bench(&source, Some(path), &options)
+        }
+        (_, None) => repl(&mut vm, &options),
+        (_, Some(path)) => run_file(&mut vm, path, &options).expect("Could not run file"),
+    }
+}
+
+// Ctrl-C interrupts the running script instead of killing the process. A
+// second Ctrl-C before the VM has noticed the first, or while nothing is
+// running to notice it, such as at the REPL prompt or while a script waits
+// in readLine(), exits as Ctrl-C usually would.
+#[cfg(unix)]
+mod sigint {
+    use std::sync::OnceLock;
+
+    use lox_bytecode::VmHandle;
+
+    const SIGINT: i32 = 2;
+
+    static HANDLE: OnceLock<VmHandle> = OnceLock::new();
+
+    extern "C" {
+        fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+        fn _exit(status: i32) -> !;
+    }
+
+    extern "C" fn on_sigint(_: i32) {
+        if let Some(handle) = HANDLE.get() {
+            if handle.is_interrupted() {
+                // SAFETY: `_exit` is async-signal-safe; unlike `exit` it
+                // runs no handlers and flushes nothing.
+                unsafe { _exit(128 + SIGINT) }
+            }
+            handle.interrupt();
+        }
+    }
 
-    match args.len() {
-        1 => repl(&mut vm),
-        2 => run_file(&mut vm, &args[1]).expect("Could not run file"),
-        _ => {
-            println!("Usage: lox-bytecode [script]");
-            std::process::exit(64);
+    pub fn install(handle: VmHandle) {
+        if HANDLE.set(handle).is_ok() {
+            // SAFETY: `on_sigint` is async-signal-safe. HANDLE was set above,
+            // before the handler can run, so it only reads the initialized
+            // cell, uses an atomic and may call `_exit`. It doesn't allocate,
+            // lock or print.
+            unsafe {
+                signal(SIGINT, on_sigint);
+            }
+        }
+    }
+}
+
+fn parse_args() -> Options {
+    let mut options = Options {
+        color: color_enabled(),
+        ..Options::default()
+    };
+    let mut args = args().skip(1).peekable();
+    let subcommands = [
+        "fmt", "lint", "lsp", "compile", "run", "disasm", "asm", "debug", "test",
+    ];
+    options.command = match args
+        .next_if(|arg| subcommands.contains(&arg.as_str()))
+        .as_deref()
+    {
+        Some("fmt") => Some(Command::Fmt),
+        Some("lint") => Some(Command::Lint),
+        Some("lsp") => Some(Command::Lsp),
+        Some("compile") => Some(Command::Compile),
+        Some("run") => Some(Command::Run),
+        Some("disasm") => Some(Command::Disasm),
+        Some("asm") => Some(Command::Asm),
+        Some("debug") => Some(Command::Debug),
+        Some("test") => Some(Command::Test),
+        _ => None,
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--fuel" => {
+                let fuel = args.next().and_then(|n| n.parse().ok());
+                options.config().fuel = Some(fuel.unwrap_or_else(|| usage()));
+            }
+            "--timeout" => {
+                let ms = args.next().and_then(|n| n.parse().ok());
+                options.config().timeout =
+                    Some(Duration::from_millis(ms.unwrap_or_else(|| usage())));
+            }
+            "--max-memory" => {
+                let bytes = args.next().and_then(|n| n.parse().ok());
+                options.config().max_memory = bytes.unwrap_or_else(|| usage());
+            }
+            "--break" => {
+                let line = args.next().and_then(|n| n.parse().ok());
+                options.breakpoints.push(line.unwrap_or_else(|| usage()));
+            }
+            "-h" | "--help" => {
+                print_usage();
+                std::process::exit(0);
+            }
+            "--no-stdlib" => options.config().stdlib = false,
+            "--deterministic" => options.config().deterministic = true,
+            "--diagnostics=json" => options.json_diagnostics = true,
+            "--diagnostics=text" => options.json_diagnostics = false,
+            "--color" => {
+                options.color = match args.next().as_deref() {
+                    Some("auto") => color_enabled(),
+                    Some("always") => true,
+                    Some("never") => false,
+                    _ => usage(),
+                }
+            }
+            "--disasm" if options.command.is_none() => options.command = Some(Command::Disasm),
+            "--step" => options.step = true,
+            "-O" | "--optimize" => options.config().optimize = true,
+            "--division-by-zero" => {
+                options.config().division_by_zero = match args.next().as_deref() {
+                    Some("ieee") => DivisionByZero::Ieee,
+                    Some("error") => DivisionByZero::Error,
+                    _ => usage(),
+                }
+            }
+            "-Wall" => options.config().warnings = Warnings::all(),
+            "-Wunused-variable" => options.config().warnings.unused_variables = true,
+            "-Wunused-parameter" => options.config().warnings.unused_parameters = true,
+            "-Wunreachable-code" => options.config().warnings.unreachable_code = true,
+            "--tokens" => options.tokens = true,
+            "--check" => options.check = true,
+            "--dump-ast" => options.dump_ast = true,
+            "--dump-cfg" => options.dump_cfg = true,
+            "-o" if options.command == Some(Command::Compile) => {
+                options.output = Some(args.next().unwrap_or_else(|| usage()));
+            }
+            "--allow" | "--deny" if options.command == Some(Command::Lint) => {
+                let level = if arg == "--allow" {
+                    Level::Allow
+                } else {
+                    Level::Deny
+                };
+                let rule = args.next().unwrap_or_else(|| usage());
+                if !options.lint_rules.set(&rule, level) {
+                    usage();
+                }
+            }
+            "--trace" => options.trace = true,
+            "--trace-file" => {
+                options.trace = true;
+                options.trace_file = Some(args.next().unwrap_or_else(|| usage()));
+            }
+            "--trace-function" => {
+                options.trace = true;
+                let name = args.next().unwrap_or_else(|| usage());
+                options.trace_functions.push(name);
+            }
+            "--profile" => options.profile = true,
+            "--diff" => options.diff = Some(args.next().unwrap_or_else(|| usage())),
+            "--bench" => {
+                let runs = args.next_if(|n| n.parse::<usize>().is_ok());
+                options.bench = Some(runs.map_or(10, |n| n.parse().unwrap()));
+            }
+            "--bench-suite" => {
+                let runs = args.next_if(|n| n.parse::<usize>().is_ok());
+                options.bench_suite = Some(runs.map_or(10, |n| n.parse().unwrap()));
+            }
+            "--callgrind" => options.callgrind = Some(args.next().unwrap_or_else(|| usage())),
+            "--coverage" => options.coverage = true,
+            "--lcov" => options.lcov = Some(args.next().unwrap_or_else(|| usage())),
+            "-e" if options.command.is_none() && options.script.is_none() => {
+                options.eval = Some(args.next().unwrap_or_else(|| usage()));
+                args.next_if_eq("--");
+                options.script_args = args.by_ref().collect();
+            }
+            // Ends the options, so a script can be named like one.
+            "--" if options.script.is_none() => {
+                options.script = Some(args.next().unwrap_or_else(|| usage()));
+                if runs_script(options.command) {
+                    args.next_if_eq("--");
+                    options.script_args = args.by_ref().collect();
+                }
+            }
+            _ if arg.starts_with('-') && arg != "-" => {
+                eprintln!("Unknown option '{arg}'.");
+                usage();
+            }
+            // Everything after a script that is going to run is for it, but
+            // for a `--` that only marks where its arguments start.
+            _ if options.script.is_none() => {
+                options.script = Some(arg);
+                if runs_script(options.command) {
+                    args.next_if_eq("--");
+                    options.script_args = args.by_ref().collect();
+                }
+            }
+            _ => usage(),
+        }
+    }
+    if let Some(config) = &options.config {
+        if config.fuel.is_some() && config.timeout.is_some() {
+            usage();
         }
     }
+    options
+}
+
+fn usage() -> ! {
+    print_usage();
+    std::process::exit(64);
 }
 
-fn repl(vm: &mut VM) {
-    let stdin = io::stdin();
-    print!("> ");
+fn print_usage() {
+    println!("Usage: lox-bytecode [--fuel N | --timeout MS] [--max-memory BYTES] [-O]");
+    println!("                    [--division-by-zero ieee|error] [--no-stdlib] [--deterministic]");
+    println!("                    [--color auto|always|never] [--diagnostics=text|json]");
+    println!("                    [-Wall | -Wunused-variable | -Wunused-parameter |");
+    println!("                     -Wunreachable-code]...");
+    println!("                    [--break LINE]... [--step] [--profile] [--bench [N]]");
+    println!("                    [--trace] [--trace-file FILE] [--trace-function NAME]...");
+    println!("                    [--callgrind FILE] [--coverage] [--lcov FILE]");
+    println!("                    [script | -e SOURCE] [--] [ARG...]");
+    println!("       lox-bytecode --tokens script");
+    println!("       lox-bytecode --dump-ast script");
+    println!("       lox-bytecode --dump-cfg [-O] script");
+    println!("       lox-bytecode --check [-W...] script");
+    println!("       lox-bytecode --diff REFERENCE script [ARG...]");
+    println!("       lox-bytecode --bench-suite [N] [-O]");
+    println!("       lox-bytecode fmt [--check] script");
+    println!("       lox-bytecode lint [--allow RULE | --deny RULE]... script");
+    println!("       lox-bytecode lsp");
+    println!("       lox-bytecode compile [-O] script [-o FILE]");
+    println!("       lox-bytecode run FILE.loxb [ARG...]");
+    println!("       lox-bytecode disasm [-O] script");
+    println!("       lox-bytecode --disasm [-O] script");
+    println!("       lox-bytecode asm FILE.loxasm [ARG...]");
+    println!("       lox-bytecode debug script [ARG...]");
+    println!("       lox-bytecode test DIR");
+    println!();
+    println!("A script of - is read from standard input, and -- ends the options.");
+    println!("Lint rules: {}", LintRules::NAMES.join(", "));
+}
+
+// Where compile errors and warnings go: JSON lines for `--diagnostics=json`,
+// otherwise text, colored unless `--color` or the terminal says not to.
+fn reporter(options: &Options) -> Box<dyn Reporter> {
+    if options.json_diagnostics {
+        let file = options.script.as_deref().filter(|path| *path != "-");
+        Box::new(JsonReporter::new(file))
+    } else if options.color {
+        Box::new(ColorReporter)
+    } else {
+        Box::new(StderrReporter)
+    }
+}
+
+// Prints a runtime error and its trace, and with the `source` at hand points
+// at where it happened. Compile errors have already gone to the reporter
+// unless they are about something other than the source, like a program
+// that didn't load.
+fn report_error(err: &LoxError, source: Option<&str>, options: &Options) {
+    match err {
+        LoxError::Compile(compile) if !compile.diagnostics.is_empty() => {}
+        LoxError::Exit(_) => {}
+        _ if options.json_diagnostics => eprintln!("{}", err.to_json()),
+        LoxError::Compile(compile) => eprint!("{compile}"),
+        LoxError::Runtime(runtime) | LoxError::BudgetExceeded(runtime) => {
+            if options.color {
+                eprintln!("{}", paint(&runtime.message, Severity::Error));
+                for frame in &runtime.trace {
+                    eprintln!("{frame}");
+                }
+            } else {
+                eprint!("{runtime}");
+            }
+            if let Some(source) = source {
+                print_caret(source, err, options.color);
+            }
+        }
+    }
+}
+
+fn repl(vm: &mut VM, options: &Options) {
+    prompt("> ");
+    // Stdin isn't kept locked between lines, so a script can call readLine.
+    let mut line = String::new();
+    let mut source = String::new();
+    while matches!(io::stdin().read_line(&mut line), Ok(n) if n > 0) {
+        source.push_str(&line);
+        line.clear();
+        if !is_complete(&source) {
+            prompt("..> ");
+            continue;
+        }
+        if !source.trim().is_empty() {
+            let entry = source.trim_end();
+            let result = match as_print(vm, entry) {
+                Some(program) => vm.execute(&program),
+                None => vm.interpret(entry),
+            };
+            if let Err(err) = &result {
+                report_error(err, None, options);
+            }
+            if let Err(LoxError::Exit(code)) = result {
+                print_profile(vm, options);
+                std::process::exit(code);
+            }
+        }
+        source.clear();
+        prompt("> ");
+    }
+    print_profile(vm, options);
+}
+
+fn prompt(text: &str) {
+    print!("{text}");
     let _ = stdout().flush();
-    for line in stdin.lock().lines() {
-        if let Ok(line) = line {
-            if line.is_empty() {
-                break;
+}
+
+// An entry that's a bare expression, with no semicolon, has its value
+// printed. The semicolon goes on a line of its own in case the entry ends in
+// a comment. The trial is compiled against the REPL's own globals and
+// settings, and is what runs when it works.
+fn as_print(vm: &mut VM, source: &str) -> Option<Program> {
+    if source.ends_with([';', '}']) {
+        return None;
+    }
+    vm.try_compile_program(&format!("print {source}\n;")).ok()
+}
+
+// Whether the REPL has read a whole statement yet: every paren and brace it
+// opened is closed and no string is left open. Anything else wrong with it
+// is left for the compiler to report.
+fn is_complete(source: &str) -> bool {
+    let mut depth = 0;
+    for token in Scanner::new(source) {
+        match token.ttype {
+            TokenType::LeftParen | TokenType::LeftBrace => depth += 1,
+            TokenType::RightParen | TokenType::RightBrace => depth -= 1,
+            TokenType::Error if token.lexeme == "Unterminated string." => return false,
+            _ => {}
+        }
+    }
+    depth <= 0
+}
+
+// `--coverage` annotates the source, when there is any, and `--lcov` writes
+// a tracefile for other tools.
+fn print_coverage(vm: &VM, source: Option<&str>, options: &Options) {
+    let Some(coverage) = vm.coverage() else {
+        return;
+    };
+    if options.coverage {
+        let lines = coverage.lines();
+        let total: usize = lines.values().map(|file| file.len()).sum();
+        let hit: usize = lines
+            .values()
+            .map(|file| file.values().filter(|count| **count > 0).count())
+            .sum();
+        eprintln!("== coverage: {hit} of {total} lines ==");
+        if let Some(source) = source {
+            eprint!("{}", coverage.annotate(source));
+        }
+    }
+    if let Some(path) = &options.lcov {
+        let script = options.script.as_deref().unwrap_or("script");
+        let written =
+            std::fs::File::create(path).and_then(|mut file| coverage.write_lcov(script, &mut file));
+        if let Err(err) = written {
+            eprintln!("Could not write {path}: {err}");
+        }
+    }
+}
+
+fn print_profile(vm: &VM, options: &Options) {
+    let Some(profile) = vm.profile() else {
+        return;
+    };
+    if options.profile {
+        eprint!("{profile}");
+    }
+    if let Some(path) = &options.callgrind {
+        let written =
+            std::fs::File::create(path).and_then(|mut file| profile.write_callgrind(&mut file));
+        if let Err(err) = written {
+            eprintln!("Could not write {path}: {err}");
+        }
+    }
+}
+
+// Points at the innermost Lox frame of a runtime error. Only done for whole
+// files: REPL lines are compiled separately, so a trace line could belong to
+// any of them.
+fn print_caret(source: &str, err: &LoxError, color: bool) {
+    let position = err
+        .trace()
+        .iter()
+        .find_map(|frame| Some((frame.line?, frame.column?)));
+    if let Some((line, column)) = position {
+        if let Some(text) = source.lines().nth(line.saturating_sub(1)) {
+            if color {
+                eprint!("{}", color_caret_snippet(text, column, 1, Severity::Error));
+            } else {
+                eprint!("{}", caret_snippet(text, column, 1));
             }
-            let _ = vm.interpret(&line);
+        }
+    }
+}
+
+// Lexes the file and prints one token per line without compiling it.
+fn print_tokens(path: &str) -> io::Result<()> {
+    let buf = std::fs::read_to_string(path)?;
+    let mut had_error = false;
+    for token in Scanner::new(&buf) {
+        let position = format!("{}:{}", token.line, token.column);
+        if token.ttype == TokenType::Error {
+            had_error = true;
+            println!("{position:<8} {:<14} {}", "Error", token.lexeme);
         } else {
-            break;
+            let ttype = format!("{:?}", token.ttype);
+            println!("{position:<8} {ttype:<14} '{}'", token.lexeme);
         }
-        print!("> ");
-        let _ = stdout().flush();
     }
+    std::process::exit(if had_error { 65 } else { 0 });
 }
 
-fn run_file(vm: &mut VM, path: &str) -> io::Result<()> {
+// Runs every script under the directory against its `// expect` comments.
+// Exits with 1 if any of them failed.
+fn test_dir(dir: &str) -> io::Result<()> {
+    let results = run_tests(Path::new(dir))?;
+    let failed: Vec<&TestResult> = results.iter().filter(|result| !result.passed()).collect();
+    for result in &failed {
+        println!("FAIL {}", result.path.display());
+        for failure in &result.failures {
+            println!("    {failure}");
+        }
+    }
+    println!(
+        "{} passed, {} failed.",
+        results.len() - failed.len(),
+        failed.len()
+    );
+    std::process::exit(if failed.is_empty() { 0 } else { 1 });
+}
+
+// Compiles the file, letting the reporter print its diagnostics, and exits
+// without running it.
+fn check_file(vm: &mut VM, path: &str) -> io::Result<()> {
     let buf = std::fs::read_to_string(path)?;
-    match vm.interpret(&buf) {
-        Err(InterpretResult::CompileError) => std::process::exit(65),
-        Err(InterpretResult::RuntimeError) => std::process::exit(70),
-        Ok(_) => std::process::exit(0),
+    match vm.check(&buf) {
+        Ok(()) => std::process::exit(0),
+        Err(_) => std::process::exit(65),
+    }
+}
+
+// Parses the file and prints its syntax tree as JSON.
+fn dump_ast(path: &str, reporter: &mut dyn Reporter) -> io::Result<()> {
+    let buf = std::fs::read_to_string(path)?;
+    match ast::parse_to_ast(&buf) {
+        Ok(ast) => {
+            println!("{:#}", ast.to_json());
+            std::process::exit(0);
+        }
+        Err(diagnostics) => {
+            for diagnostic in &diagnostics {
+                reporter.report(&buf, diagnostic);
+            }
+            std::process::exit(65);
+        }
+    }
+}
+
+// Prints the file with its formatting normalised. With `--check` nothing is
+// printed and the exit status says whether the file is already formatted.
+fn format_file(path: &str, check: bool, reporter: &mut dyn Reporter) -> io::Result<()> {
+    let buf = std::fs::read_to_string(path)?;
+    match format::format_source(&buf) {
+        Ok(formatted) if check => {
+            if formatted != buf {
+                eprintln!("{path} is not formatted");
+                std::process::exit(1);
+            }
+            std::process::exit(0);
+        }
+        Ok(formatted) => {
+            print!("{formatted}");
+            std::process::exit(0);
+        }
+        Err(diagnostics) => {
+            for diagnostic in &diagnostics {
+                reporter.report(&buf, diagnostic);
+            }
+            std::process::exit(65);
+        }
+    }
+}
+
+// Reports every lint that isn't allowed. Exits with 1 if any of them were
+// denied.
+fn lint_file(path: &str, rules: LintRules, reporter: &mut dyn Reporter) -> io::Result<()> {
+    let buf = std::fs::read_to_string(path)?;
+    let (diagnostics, status) = match ast::parse_to_ast(&buf) {
+        Ok(ast) => {
+            let diagnostics = lint::lint(&ast, rules);
+            let denied = diagnostics.iter().any(|d| d.severity == Severity::Error);
+            (diagnostics, if denied { 1 } else { 0 })
+        }
+        Err(diagnostics) => (diagnostics, 65),
+    };
+    for diagnostic in &diagnostics {
+        reporter.report(&buf, diagnostic);
+    }
+    std::process::exit(status);
+}
+
+// Writes the compiled program next to the script unless `-o` says where.
+fn compile_file(vm: &mut VM, path: &str, output: Option<&str>) -> io::Result<()> {
+    let buf = std::fs::read_to_string(path)?;
+    let bytes = match vm.compile_binary(&buf) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            // Compile errors have already gone to the reporter.
+            if !matches!(&err, LoxError::Compile(err) if !err.diagnostics.is_empty()) {
+                eprint!("{err}");
+            }
+            std::process::exit(65);
+        }
+    };
+    let output = match output {
+        Some(output) => output.to_string(),
+        None => Path::new(path).with_extension("loxb").display().to_string(),
+    };
+    std::fs::write(output, bytes)?;
+    std::process::exit(0);
+}
+
+// Prints the bytecode of the script and of every function in it.
+fn disassemble_file(vm: &mut VM, path: &str) -> io::Result<()> {
+    let buf = std::fs::read_to_string(path)?;
+    match vm.compile(&buf) {
+        Ok(function) => {
+            function.disassemble();
+            std::process::exit(0);
+        }
+        Err(_) => std::process::exit(65),
+    }
+}
+
+// Compiles the file and prints the control-flow graph of each function in
+// Graphviz DOT format.
+fn dump_cfg(vm: &mut VM, path: &str) -> io::Result<()> {
+    let buf = std::fs::read_to_string(path)?;
+    match vm.compile(&buf) {
+        Ok(function) => {
+            print!("{}", cfg::to_dot(&function));
+            std::process::exit(0);
+        }
+        Err(_) => std::process::exit(65),
+    }
+}
+
+fn run_binary(vm: &mut VM, path: &str, options: &Options) -> io::Result<()> {
+    let bytes = std::fs::read(path)?;
+    let result = vm.run_binary(&bytes);
+    if let Err(err) = &result {
+        report_error(err, None, options);
+    }
+    print_profile(vm, options);
+    print_coverage(vm, None, options);
+    exit_with(result)
+}
+
+fn run_assembly(vm: &mut VM, path: &str, options: &Options) -> io::Result<()> {
+    let text = std::fs::read_to_string(path)?;
+    let result = vm.run_assembly(&text);
+    if let Err(err) = &result {
+        report_error(err, Some(&text), options);
+    }
+    print_profile(vm, options);
+    print_coverage(vm, Some(&text), options);
+    exit_with(result)
+}
+
+fn run_file(vm: &mut VM, path: &str, options: &Options) -> io::Result<()> {
+    let buf = if path == "-" {
+        io::read_to_string(io::stdin())?
+    } else {
+        std::fs::read_to_string(path)?
+    };
+    run_source(vm, &buf, options)
+}
+
+fn run_source(vm: &mut VM, source: &str, options: &Options) -> ! {
+    let result = vm.interpret(source);
+    if let Err(err) = &result {
+        report_error(err, Some(source), options);
+    }
+    print_profile(vm, options);
+    print_coverage(vm, Some(source), options);
+    exit_with(result)
+}
+
+// Runs the script here and with the reference interpreter, a command such
+// as `clox` or `java -jar jlox.jar`, and reports where their output or exit
+// status part ways. Error messages are left out since every implementation
+// words them its own way. Exits with 1 if they differ.
+fn diff_file(path: &str, options: &Options) -> io::Result<()> {
+    let mut reference = options
+        .diff
+        .as_deref()
+        .unwrap_or_default()
+        .split_whitespace();
+    let Some(program) = reference.next() else {
+        usage();
+    };
+    let theirs = std::process::Command::new(program)
+        .args(reference)
+        .arg(path)
+        .args(&options.script_args)
+        .stderr(std::process::Stdio::null())
+        .output()?;
+    let source = std::fs::read_to_string(path)?;
+    let output = CapturedOutput::default();
+    let mut builder = VmBuilder::new()
+        .output(output.clone())
+        .args(options.script_args.clone())
+        .file(path);
+    if let Some(config) = &options.config {
+        builder = builder.config(config.clone());
+    }
+    let status = exit_code(&builder.build().interpret(&source));
+
+    let ours_text = output.text();
+    let theirs_text = String::from_utf8_lossy(&theirs.stdout);
+    let mut ours_lines = ours_text.lines();
+    let mut theirs_lines = theirs_text.lines();
+    let mut same = true;
+    for line in 1.. {
+        match (ours_lines.next(), theirs_lines.next()) {
+            (None, None) => break,
+            (a, b) if a == b => {}
+            (a, b) => {
+                println!("Output differs at line {line}:");
+                println!("  lox-bytecode: {}", a.unwrap_or("<end of output>"));
+                println!("  reference:    {}", b.unwrap_or("<end of output>"));
+                same = false;
+                break;
+            }
+        }
+    }
+    if theirs.status.code() != Some(status) {
+        let theirs = theirs.status.code();
+        println!(
+            "Exit status differs: lox-bytecode {status}, reference {}",
+            theirs.map_or("none".to_string(), |code| code.to_string())
+        );
+        same = false;
+    }
+    std::process::exit(if same { 0 } else { 1 });
+}
+
+// Runs the script once to warm up and then as many times as `--bench` says,
+// each time in a fresh VM with its output thrown away, and reports the times.
+// Counts come from one more run with profiling on, which would otherwise
+// skew the times.
+fn bench(source: &str, path: Option<&str>, options: &Options) -> ! {
+    let runs = options.bench.unwrap_or(1).max(1);
+    let run = |profile: bool| {
+        let mut builder = VmBuilder::new()
+            .reporter(reporter(options))
+            .output(io::sink())
+            .args(options.script_args.clone());
+        if let Some(path) = path {
+            builder = builder.file(path);
+        }
+        if let Some(config) = &options.config {
+            builder = builder.config(config.clone());
+        }
+        if profile {
+            builder = builder.profile();
+        }
+        let mut vm = builder.build();
+        let start = Instant::now();
+        let result = vm.interpret(source);
+        let elapsed = start.elapsed();
+        match result {
+            Ok(()) | Err(LoxError::Exit(_)) => {}
+            Err(err) => {
+                report_error(&err, Some(source), options);
+                exit_with(Err(err));
+            }
+        }
+        (vm, elapsed)
+    };
+
+    run(false);
+    let times: Vec<Duration> = (0..runs).map(|_| run(false).1).collect();
+    let (vm, _) = run(true);
+
+    let total: Duration = times.iter().sum();
+    let ms = |time: &Duration| time.as_secs_f64() * 1000.0;
+    println!("== bench: {runs} runs after 1 warmup ==");
+    println!(
+        "wall time     mean {:.3} ms, min {:.3} ms, max {:.3} ms",
+        ms(&(total / runs as u32)),
+        ms(times.iter().min().unwrap()),
+        ms(times.iter().max().unwrap()),
+    );
+    if let Some(profile) = vm.profile() {
+        println!("instructions  {}", profile.instructions());
+        println!("peak stack    {} slots", profile.peak_stack());
+    }
+    let (objects, bytes) = vm.allocations();
+    println!("live objects  {objects} objects, {bytes} bytes");
+    std::process::exit(0);
+}
+
+// The programs in `benches/programs`, which the criterion benches there time
+// as well.
+const BENCH_SUITE: [(&str, &str); 5] = [
+    ("fib", include_str!("../benches/programs/fib.lox")),
+    (
+        "arithmetic",
+        include_str!("../benches/programs/arithmetic.lox"),
+    ),
+    ("strings", include_str!("../benches/programs/strings.lox")),
+    ("methods", include_str!("../benches/programs/methods.lox")),
+    ("closures", include_str!("../benches/programs/closures.lox")),
+];
+
+// Times every program in the suite the way `--bench` times one script, for a
+// quick before-and-after on a change to the VM without needing criterion.
+fn bench_suite(runs: usize, options: &Options) -> ! {
+    let runs = runs.max(1);
+    let run = |source: &str| {
+        let mut builder = VmBuilder::new()
+            .reporter(reporter(options))
+            .output(io::sink());
+        if let Some(config) = &options.config {
+            builder = builder.config(config.clone());
+        }
+        let mut vm = builder.build();
+        let start = Instant::now();
+        if let Err(err) = vm.interpret(source) {
+            report_error(&err, Some(source), options);
+            exit_with(Err(err));
+        }
+        start.elapsed()
+    };
+
+    let ms = |time: &Duration| time.as_secs_f64() * 1000.0;
+    println!("== bench suite: {runs} runs each after 1 warmup ==");
+    println!(
+        "{:<12} {:>10} {:>10} {:>10}",
+        "name", "mean ms", "min ms", "max ms"
+    );
+    for (name, source) in BENCH_SUITE {
+        run(source);
+        let times: Vec<Duration> = (0..runs).map(|_| run(source)).collect();
+        let total: Duration = times.iter().sum();
+        println!(
+            "{name:<12} {:>10.3} {:>10.3} {:>10.3}",
+            ms(&(total / runs as u32)),
+            ms(times.iter().min().unwrap()),
+            ms(times.iter().max().unwrap()),
+        );
+    }
+    std::process::exit(0);
+}
+
+fn exit_with(result: Result<(), LoxError>) -> ! {
+    std::process::exit(exit_code(&result))
+}
+
+fn exit_code(result: &Result<(), LoxError>) -> i32 {
+    match result {
+        Err(LoxError::Compile(_)) => 65,
+        Err(LoxError::Runtime(_) | LoxError::BudgetExceeded(_)) => 70,
+        Err(LoxError::Exit(code)) => *code,
+        Ok(_) => 0,
     }
 }
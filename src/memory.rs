@@ -0,0 +1,59 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// The objects the VM's scripts have made that are still alive, and roughly
+// how many bytes they take. This is what `memoryUsage()` and `objectCount()`
+// report and what the `max_memory` limit bounds. Each object holds an
+// `Allocation` that gives its bytes back when the object is freed.
+//
+// Strings are plain values that are copied rather than shared, so they are
+// checked against the limit when they are made but aren't counted while
+// they live.
+#[derive(Debug, Default)]
+pub struct MemoryStats {
+    bytes: AtomicUsize,
+    objects: AtomicUsize,
+}
+
+impl MemoryStats {
+    // Counts one more object of `bytes`.
+    pub fn allocate(self: &Arc<Self>, bytes: usize) -> Allocation {
+        self.objects.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        Allocation {
+            stats: Arc::clone(self),
+            bytes: AtomicUsize::new(bytes),
+        }
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn objects(&self) -> usize {
+        self.objects.load(Ordering::Relaxed)
+    }
+}
+
+// One object's share of the `MemoryStats` that counted it.
+#[derive(Debug)]
+pub struct Allocation {
+    stats: Arc<MemoryStats>,
+    bytes: AtomicUsize,
+}
+
+impl Allocation {
+    // For an object that gains a field.
+    pub fn grow(&self, bytes: usize) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.stats.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Allocation {
+    fn drop(&mut self) {
+        let bytes = *self.bytes.get_mut();
+        self.stats.bytes.fetch_sub(bytes, Ordering::Relaxed);
+        self.stats.objects.fetch_sub(1, Ordering::Relaxed);
+    }
+}
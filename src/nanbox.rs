@@ -0,0 +1,264 @@
+use std::fmt::{Debug, Display, Formatter};
+
+use crate::bound_method::*;
+use crate::class::*;
+use crate::closure::*;
+use crate::function::*;
+use crate::instance::*;
+use crate::shared::*;
+use crate::user_data::*;
+use crate::value::*;
+
+#[cfg(not(target_pointer_width = "64"))]
+compile_error!("the nan_boxing feature requires a 64-bit target");
+
+// Every value fits in a single u64. Anything that isn't a quiet NaN with all
+// of the QNAN bits set is a plain f64. Nil and the booleans live in the low
+// bits of a quiet NaN. Objects set the sign bit too and keep the object's own
+// `Rc` pointer in the payload, with its kind in the low bits the pointer's
+// alignment leaves free. Strings and natives don't have a thin `Rc` of their
+// own, so they go behind an `Rc<Value>` instead.
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+
+const NIL: u64 = QNAN | 1;
+const FALSE: u64 = QNAN | 2;
+const TRUE: u64 = QNAN | 3;
+const OBJ: u64 = SIGN_BIT | QNAN;
+
+const TAG_MASK: u64 = 0b111;
+const TAG_BOXED: u64 = 0;
+const TAG_FUNC: u64 = 1;
+const TAG_CLOSURE: u64 = 2;
+const TAG_CLASS: u64 = 3;
+const TAG_INSTANCE: u64 = 4;
+const TAG_BOUND: u64 = 5;
+const TAG_USER_DATA: u64 = 6;
+
+// Calls `$f::<T>(bits)` with `T` the type of the object behind `bits`.
+macro_rules! with_object_type {
+    ($bits:expr, $f:ident) => {
+        match $bits & TAG_MASK {
+            TAG_BOXED => $f::<Value>($bits),
+            TAG_FUNC => $f::<Function>($bits),
+            TAG_CLOSURE => $f::<Closure>($bits),
+            TAG_CLASS => $f::<Class>($bits),
+            TAG_INSTANCE => $f::<Instance>($bits),
+            TAG_BOUND => $f::<BoundMethod>($bits),
+            _ => $f::<UserData>($bits),
+        }
+    };
+}
+
+pub struct NanBox(u64);
+
+impl NanBox {
+    #[inline]
+    pub fn number(n: f64) -> Self {
+        if n.is_nan() {
+            Self(f64::NAN.to_bits())
+        } else {
+            Self(n.to_bits())
+        }
+    }
+
+    #[inline]
+    pub fn is_number(&self) -> bool {
+        self.0 & QNAN != QNAN
+    }
+
+    #[inline]
+    pub fn is_falsey(&self) -> bool {
+        self.0 == NIL || self.0 == FALSE
+    }
+
+    #[inline]
+    pub fn is_string(&self) -> bool {
+        matches!(self.as_boxed(), Some(Value::Str(_)))
+    }
+
+    #[inline]
+    pub fn as_number(&self) -> Option<f64> {
+        if self.is_number() {
+            Some(f64::from_bits(self.0))
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    fn object<T>(rc: Rc<T>, tag: u64) -> Self {
+        let ptr = Rc::into_raw(rc) as u64;
+        assert_eq!(
+            ptr & (OBJ | TAG_MASK),
+            0,
+            "pointer does not fit in a NaN box"
+        );
+        NanBox(OBJ | ptr | tag)
+    }
+
+    #[inline]
+    fn is_obj(&self) -> bool {
+        self.0 & OBJ == OBJ
+    }
+
+    #[inline]
+    fn tag(&self) -> Option<u64> {
+        self.is_obj().then_some(self.0 & TAG_MASK)
+    }
+
+    #[inline]
+    fn as_boxed(&self) -> Option<&Value> {
+        if self.tag() == Some(TAG_BOXED) {
+            // SAFETY: the payload was produced by `Rc::into_raw` in `object`
+            // and this box holds one strong reference, so the pointee is
+            // alive for as long as the box is.
+            Some(unsafe { &*pointer::<Value>(self.0) })
+        } else {
+            None
+        }
+    }
+}
+
+fn pointer<T>(bits: u64) -> *const T {
+    (bits & !(OBJ | TAG_MASK)) as *const T
+}
+
+// SAFETY for these and `retain` and `release`: `bits` is an object's NaN box, so its pointer
+// came from `Rc::into_raw` on an `Rc<T>` and the box owns a strong count.
+
+// A new `Rc` to the object, leaving the box's own count alone.
+unsafe fn share<T>(bits: u64) -> Rc<T> {
+    Rc::increment_strong_count(pointer::<T>(bits));
+    Rc::from_raw(pointer::<T>(bits))
+}
+
+unsafe fn increment<T>(bits: u64) {
+    Rc::increment_strong_count(pointer::<T>(bits))
+}
+
+unsafe fn decrement<T>(bits: u64) {
+    Rc::decrement_strong_count(pointer::<T>(bits))
+}
+
+// As with `Clone` and `Drop`, numbers, nil and the booleans convert inline
+// and objects take a call.
+impl From<Value> for NanBox {
+    #[inline]
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Number(n) => NanBox::number(n),
+            Value::Nil => NanBox(NIL),
+            Value::Boolean(b) => NanBox(if b { TRUE } else { FALSE }),
+            object => NanBox::from_object(object),
+        }
+    }
+}
+
+impl From<&NanBox> for Value {
+    #[inline]
+    fn from(value: &NanBox) -> Self {
+        match value.0 {
+            _ if value.is_obj() => value.to_object(),
+            NIL => Value::Nil,
+            FALSE => Value::Boolean(false),
+            TRUE => Value::Boolean(true),
+            bits => Value::Number(f64::from_bits(bits)),
+        }
+    }
+}
+
+impl NanBox {
+    fn from_object(value: Value) -> Self {
+        match value {
+            Value::Func(function) => NanBox::object(function, TAG_FUNC),
+            Value::Closure(closure) => NanBox::object(closure, TAG_CLOSURE),
+            Value::Class(class) => NanBox::object(class, TAG_CLASS),
+            Value::Instance(instance) => NanBox::object(instance, TAG_INSTANCE),
+            Value::Bound(bound) => NanBox::object(bound, TAG_BOUND),
+            Value::UserData(data) => NanBox::object(data, TAG_USER_DATA),
+            value => NanBox::object(Rc::new(value), TAG_BOXED),
+        }
+    }
+
+    fn to_object(&self) -> Value {
+        let bits = self.0;
+        // SAFETY: each arm matches the tag `from_object` gave the object.
+        unsafe {
+            match bits & TAG_MASK {
+                TAG_BOXED => (*pointer::<Value>(bits)).clone(),
+                TAG_FUNC => Value::Func(share(bits)),
+                TAG_CLOSURE => Value::Closure(share(bits)),
+                TAG_CLASS => Value::Class(share(bits)),
+                TAG_INSTANCE => Value::Instance(share(bits)),
+                TAG_BOUND => Value::Bound(share(bits)),
+                _ => Value::UserData(share(bits)),
+            }
+        }
+    }
+}
+
+// Only the check for an object is inlined, so that copying and dropping
+// numbers stays cheap.
+impl Clone for NanBox {
+    #[inline]
+    fn clone(&self) -> Self {
+        if self.is_obj() {
+            // SAFETY: the new box owns the extra strong count.
+            unsafe { retain(self.0) }
+        }
+        NanBox(self.0)
+    }
+}
+
+impl Drop for NanBox {
+    #[inline]
+    fn drop(&mut self) {
+        if self.is_obj() {
+            // SAFETY: releases the strong count owned by this box.
+            unsafe { release(self.0) }
+        }
+    }
+}
+
+unsafe fn retain(bits: u64) {
+    with_object_type!(bits, increment)
+}
+
+unsafe fn release(bits: u64) {
+    with_object_type!(bits, decrement)
+}
+
+impl PartialEq for NanBox {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        match (self.as_number(), other.as_number()) {
+            (Some(a), Some(b)) => a == b,
+            _ => match (self.tag(), other.tag()) {
+                (Some(TAG_BOXED), Some(TAG_BOXED)) => self.as_boxed() == other.as_boxed(),
+                // Bound methods are equal when they bind the same thing.
+                (Some(TAG_BOUND), Some(TAG_BOUND)) => Value::from(self) == Value::from(other),
+                _ => self.0 == other.0,
+            },
+        }
+    }
+}
+
+// A boxed string is printed where it is rather than copied out first.
+impl Display for NanBox {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self.as_boxed() {
+            Some(value) => write!(f, "{value}"),
+            None => write!(f, "{}", Value::from(self)),
+        }
+    }
+}
+
+impl Debug for NanBox {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self.as_boxed() {
+            Some(value) => write!(f, "{value:?}"),
+            None => write!(f, "{:?}", Value::from(self)),
+        }
+    }
+}
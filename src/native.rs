@@ -1,16 +1,502 @@
-use std::cell::RefCell;
-use std::rc::Rc;
-use std::time::SystemTime;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
+use crate::date::*;
+use crate::error::*;
+use crate::handle::*;
+use crate::memory::*;
+use crate::reflect::*;
+use crate::shared::*;
 use crate::value::*;
+use crate::vm::VM;
 
-pub struct NativeClock {}
+// The natives a VM registers, by name. The compiler uses it to call a native
+// straight from the constant table instead of going through its global.
+pub type NativeTable = HashMap<String, Rc<dyn NativeFunc>>;
 
-impl NativeFunc for NativeClock {
-    fn call(&self, _arg_count: usize, _args: &[Rc<RefCell<Value>>]) -> Value {
-        match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
-            Ok(n) => Value::Number(n.as_millis() as f64),
-            Err(_) => panic!("can't get system time"),
+// The natives every VM defines unless its config turns `stdlib` off.
+pub fn stdlib() -> Vec<Rc<dyn NativeFunc>> {
+    let mut natives: Vec<Rc<dyn NativeFunc>> = vec![
+        Rc::new(NativeClosure::new("clock", 0, clock)),
+        Rc::new(NativeClosure::new("now", 0, clock)),
+        Rc::new(NativeClosure::new("dateFormat", 2, date_format)),
+        Rc::new(NativeClosure::new("dateParse", 2, date_parse)),
+        Rc::new(NativeReadLine::stdin()),
+        Rc::new(NativeClosure::new("env", 1, env)),
+        Rc::new(NativeClosure::new("exit", 1, exit)),
+        Rc::new(NativeSleep {}),
+        Rc::new(NativeClosure::new("clockMonotonic", 0, clock_monotonic)),
+        Rc::new(NativeClosure::new("assert", 2, assert)),
+        Rc::new(NativeClosure::new("type", 1, type_of)),
+        Rc::new(NativeClosure::new("className", 1, class_name)),
+        Rc::new(NativeClosure::new("fieldCount", 1, field_count)),
+        Rc::new(NativeClosure::new("fieldName", 2, field_name)),
+        Rc::new(NativeClosure::new("methodCount", 1, method_count)),
+        Rc::new(NativeClosure::new("methodName", 2, method_name)),
+        Rc::new(NativeClosure::new("hasField", 2, has_field)),
+        Rc::new(NativeClosure::new("getattr", 2, getattr)),
+        Rc::new(NativeClosure::new("setattr", 3, setattr)),
+        Rc::new(NativeClosure::new("superclass", 1, superclass)),
+        Rc::new(NativeClosure::new("instanceOf", 2, instance_of)),
+        Rc::new(NativeClosure::new("freeze", 1, freeze)),
+        Rc::new(NativeClosure::new("isFrozen", 1, is_frozen)),
+        Rc::new(NativeClosure::new("format", 1, format).variadic()),
+        Rc::new(NativeStackTrace {}),
+        Rc::new(NativeClosure::new("disassemble", 1, disassemble)),
+        random(time_seed()),
+    ];
+    natives.extend(script_args(Vec::new()));
+    natives
+}
+
+// What a VM defines over `stdlib()` when its config asks for determinism:
+// `clock()`, `now()` and `clockMonotonic()` share a counter that goes up by
+// one on every call, and `random()` always starts from the same seed, so a
+// script prints the same thing on every machine and every run.
+pub fn deterministic_natives() -> Vec<Rc<dyn NativeFunc>> {
+    let ticks = Arc::new(AtomicUsize::new(0));
+    let mut natives: Vec<Rc<dyn NativeFunc>> = ["clock", "now", "clockMonotonic"]
+        .into_iter()
+        .map(|name| {
+            let ticks = Arc::clone(&ticks);
+            Rc::new(NativeClosure::new(name, 0, move |_| {
+                Ok(Value::Number(ticks.fetch_add(1, Ordering::Relaxed) as f64))
+            })) as Rc<dyn NativeFunc>
+        })
+        .collect();
+    natives.push(random(0));
+    natives
+}
+
+// `random()`, a number in [0, 1) from SplitMix64, which is small, fast and
+// happy with any seed, zero included.
+fn random(seed: u64) -> Rc<dyn NativeFunc> {
+    const GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+    let state = AtomicU64::new(seed);
+    Rc::new(NativeClosure::new("random", 0, move |_| {
+        let mut z = state
+            .fetch_add(GAMMA, Ordering::Relaxed)
+            .wrapping_add(GAMMA);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        Ok(Value::Number((z >> 11) as f64 / (1u64 << 53) as f64))
+    }))
+}
+
+// `argCount()` and `arg(n)`, which hand scripts the arguments they were run
+// with. There are no lists to return them all in one go.
+pub fn script_args(args: Vec<String>) -> Vec<Rc<dyn NativeFunc>> {
+    let count = args.len() as f64;
+    let arg = move |values: &[Value]| {
+        let index: f64 = (&values[0]).try_into()?;
+        if index < 0.0 || index.fract() != 0.0 {
+            return Err(NativeError::new(
+                "Argument index must be a non-negative integer.",
+            ));
+        }
+        Ok(args.get(index as usize).cloned().into())
+    };
+    vec![
+        Rc::new(NativeClosure::new("argCount", 0, move |_| {
+            Ok(Value::Number(count))
+        })),
+        Rc::new(NativeClosure::new("arg", 1, arg)),
+    ]
+}
+
+// `gc()`, `memoryUsage()` and `objectCount()`, which report the objects
+// still alive. Objects are freed as soon as nothing refers to them, so there
+// is no collection for `gc()` to force; it is there so scripts written for a
+// tracing collector still run.
+pub fn memory_natives(stats: &Arc<MemoryStats>) -> Vec<Rc<dyn NativeFunc>> {
+    let bytes = Arc::clone(stats);
+    let objects = Arc::clone(stats);
+    vec![
+        Rc::new(NativeClosure::new("gc", 0, |_| Ok(Value::Nil))),
+        Rc::new(NativeClosure::new("memoryUsage", 0, move |_| {
+            Ok(Value::Number(bytes.bytes() as f64))
+        })),
+        Rc::new(NativeClosure::new("objectCount", 0, move |_| {
+            Ok(Value::Number(objects.objects() as f64))
+        })),
+    ]
+}
+
+// The environment variable, or nil if it isn't set or isn't valid Unicode.
+fn env(args: &[Value]) -> Result<Value, NativeError> {
+    let name: &str = (&args[0]).try_into()?;
+    Ok(std::env::var(name).ok().into())
+}
+
+// Fails with the message when the condition is falsey, the way any other
+// runtime error would, so the trace points at the failed assertion.
+fn assert(args: &[Value]) -> Result<Value, NativeError> {
+    if args[0].is_falsey() {
+        return Err(NativeError::new(format!("Assertion failed: {}", args[1])));
+    }
+    Ok(Value::Nil)
+}
+
+// Scripts spell it "bool", the way most languages do; error messages say
+// "boolean".
+fn type_of(args: &[Value]) -> Result<Value, NativeError> {
+    Ok(match &args[0] {
+        Value::Boolean(_) => "bool".into(),
+        value => value.type_name().into(),
+    })
+}
+
+fn class_name(args: &[Value]) -> Result<Value, NativeError> {
+    match &args[0] {
+        Value::Instance(instance) => Ok(instance.get_class().name().into()),
+        Value::Class(class) => Ok(class.name().into()),
+        value => Err(NativeError::new(format!(
+            "Expected an instance or class but got {}.",
+            value.type_name()
+        ))),
+    }
+}
+
+// printf-style formatting: `%s` prints any value the way `print` does, `%d`
+// prints a number truncated to an integer, `%f` prints a number with an
+// optional precision as in `%.2f`, and `%%` is a literal percent sign.
+// Precision goes up to MAX_PRECISION digits, past which an f64 has nothing
+// left to show.
+const MAX_PRECISION: usize = 1074;
+
+fn format(args: &[Value]) -> Result<Value, NativeError> {
+    let template: &str = (&args[0]).try_into()?;
+    let mut values = args[1..].iter();
+    let mut out = String::new();
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+
+        let mut precision = None;
+        if chars.next_if_eq(&'.').is_some() {
+            let mut digits = String::new();
+            while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+                digits.push(digit);
+            }
+            if digits.is_empty() {
+                return Err(NativeError::new("Expected a precision after '%.'."));
+            }
+            match digits.parse::<usize>() {
+                Ok(digits) if digits <= MAX_PRECISION => precision = Some(digits),
+                _ => {
+                    return Err(NativeError::new(format!(
+                        "Precision can't be more than {MAX_PRECISION}."
+                    )))
+                }
+            }
+        }
+
+        let directive = chars
+            .next()
+            .ok_or_else(|| NativeError::new("Incomplete format directive at end of string."))?;
+        if directive == '%' && precision.is_none() {
+            out.push('%');
+            continue;
+        }
+
+        let value = values
+            .next()
+            .ok_or_else(|| NativeError::new("Not enough arguments for format string."))?;
+        match (directive, precision) {
+            ('s', None) => out.push_str(&value.to_string()),
+            ('d', None) => {
+                let n: f64 = value.try_into()?;
+                out.push_str(&n.trunc().to_string());
+            }
+            ('f', None) => {
+                let n: f64 = value.try_into()?;
+                out.push_str(&n.to_string());
+            }
+            ('f', Some(precision)) => {
+                let n: f64 = value.try_into()?;
+                out.push_str(&format!("{n:.precision$}"));
+            }
+            _ => {
+                return Err(NativeError::new(format!(
+                    "Unknown format directive '%{}{directive}'.",
+                    precision.map_or(String::new(), |p| format!(".{p}"))
+                )))
+            }
+        }
+    }
+
+    if values.next().is_some() {
+        return Err(NativeError::new("Too many arguments for format string."));
+    }
+    Ok(out.into())
+}
+
+// The bytecode of a function, as the disassembler prints it.
+fn disassemble(args: &[Value]) -> Result<Value, NativeError> {
+    let function = match &args[0] {
+        Value::Func(function) => Rc::clone(function),
+        Value::Closure(closure) => closure.function(),
+        Value::Bound(bound) => bound.get_closure().function(),
+        Value::Native(_) => return Err(NativeError::new("Can't disassemble a native function.")),
+        value => {
+            return Err(NativeError::new(format!(
+                "Expected a function but got {}.",
+                value.type_name()
+            )))
+        }
+    };
+    Ok(function.chunk.disassembly(function.stack_name()).into())
+}
+
+fn exit(args: &[Value]) -> Result<Value, NativeError> {
+    let code: f64 = (&args[0]).try_into()?;
+    if code.fract() != 0.0 || code < i32::MIN.into() || code > i32::MAX.into() {
+        return Err(NativeError::new("Exit code must be an integer."));
+    }
+    Err(NativeError::exit(code as i32))
+}
+
+#[cfg(not(feature = "sync"))]
+type NativeFn = dyn Fn(&[Value]) -> Result<Value, NativeError>;
+#[cfg(feature = "sync")]
+type NativeFn = dyn Fn(&[Value]) -> Result<Value, NativeError> + Send + Sync;
+
+// A native backed by a Rust closure, as `VM::register` creates.
+pub struct NativeClosure {
+    name: String,
+    arity: usize,
+    variadic: bool,
+    function: Box<NativeFn>,
+}
+
+impl NativeClosure {
+    pub fn new<F>(name: &str, arity: usize, function: F) -> Self
+    where
+        F: Fn(&[Value]) -> Result<Value, NativeError> + MaybeSend + MaybeSync + 'static,
+    {
+        Self {
+            name: name.to_string(),
+            arity,
+            variadic: false,
+            function: Box::new(function),
+        }
+    }
+
+    // Lets the native take any number of arguments past its arity.
+    pub fn variadic(mut self) -> Self {
+        self.variadic = true;
+        self
+    }
+}
+
+impl NativeFunc for NativeClosure {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn arity(&self) -> usize {
+        self.arity
+    }
+
+    fn variadic(&self) -> bool {
+        self.variadic
+    }
+
+    fn call(&self, args: &[Value]) -> Result<Value, NativeError> {
+        (self.function)(args)
+    }
+}
+
+// `stackTrace()`, which returns the calling script's stack the way a runtime
+// error prints it, one line per call.
+struct NativeStackTrace {}
+
+impl NativeFunc for NativeStackTrace {
+    fn name(&self) -> &str {
+        "stackTrace"
+    }
+
+    fn arity(&self) -> usize {
+        0
+    }
+
+    fn call(&self, _args: &[Value]) -> Result<Value, NativeError> {
+        Err(NativeError::new("stackTrace() needs a running script."))
+    }
+
+    fn call_with_vm(&self, vm: &VM, _args: &[Value]) -> Result<Value, NativeError> {
+        let lines: Vec<String> = vm.stack_trace().iter().map(ToString::to_string).collect();
+        Ok(lines.join("\n").into())
+    }
+}
+
+// `sleep(ms)`. It sleeps a slice at a time, so that the VM's handle or its
+// timeout can still end a script while it sleeps. Hosts that would rather
+// it didn't block at all can veto it with `Hooks`.
+struct NativeSleep {}
+
+impl NativeFunc for NativeSleep {
+    fn name(&self) -> &str {
+        "sleep"
+    }
+
+    fn arity(&self) -> usize {
+        1
+    }
+
+    fn call(&self, args: &[Value]) -> Result<Value, NativeError> {
+        sleep(args, &VmHandle::default(), None)
+    }
+
+    fn call_with_vm(&self, vm: &VM, args: &[Value]) -> Result<Value, NativeError> {
+        sleep(args, &vm.handle(), vm.deadline())
+    }
+}
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+fn sleep(
+    args: &[Value],
+    handle: &VmHandle,
+    deadline: Option<Instant>,
+) -> Result<Value, NativeError> {
+    use std::time::Duration;
+    const SLICE: Duration = Duration::from_millis(10);
+    let ms: f64 = (&args[0]).try_into()?;
+    let duration = Duration::try_from_secs_f64(ms / 1000.0)
+        .map_err(|_| NativeError::new("Sleep time must be a non-negative number."))?;
+    let end = Instant::now().checked_add(duration);
+    loop {
+        if handle.is_interrupted() {
+            return Err(NativeError::new("Interrupted."));
+        }
+        let now = Instant::now();
+        if deadline.is_some_and(|deadline| now >= deadline) {
+            return Err(NativeError::new("Script timed out."));
+        }
+        let left = end.map_or(Duration::MAX, |end| end.saturating_duration_since(now));
+        if left.is_zero() {
+            return Ok(Value::Nil);
+        }
+        std::thread::sleep(left.min(SLICE));
+    }
+}
+
+// Milliseconds since some fixed point in the past, from a clock that never
+// goes backwards, unlike `clock`.
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+fn clock_monotonic(_args: &[Value]) -> Result<Value, NativeError> {
+    use std::sync::OnceLock;
+    use std::time::Instant;
+    static START: OnceLock<Instant> = OnceLock::new();
+    let start = START.get_or_init(Instant::now);
+    Ok(Value::Number(start.elapsed().as_secs_f64() * 1000.0))
+}
+
+// Plain WebAssembly can neither block nor read a clock.
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+fn sleep(
+    _args: &[Value],
+    _handle: &VmHandle,
+    _deadline: Option<Instant>,
+) -> Result<Value, NativeError> {
+    Err(NativeError::new("Can't sleep here."))
+}
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+fn clock_monotonic(_args: &[Value]) -> Result<Value, NativeError> {
+    Err(NativeError::new("Can't get monotonic time."))
+}
+
+// Milliseconds since the Unix epoch, for both `clock()` and `now()`.
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+fn clock(_args: &[Value]) -> Result<Value, NativeError> {
+    use std::time::SystemTime;
+    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(n) => Ok(Value::Number(n.as_millis() as f64)),
+        Err(_) => Err(NativeError::new("Can't get system time.")),
+    }
+}
+
+// Asking for the time panics on plain WebAssembly, so hosts there give the
+// VM a clock of their own with `VmBuilder::clock`.
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+fn clock(_args: &[Value]) -> Result<Value, NativeError> {
+    Err(NativeError::new("Can't get system time."))
+}
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+fn time_seed() -> u64 {
+    use std::time::SystemTime;
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |n| n.as_nanos() as u64)
+}
+
+// Without a clock every VM gets the same sequence, as if it were
+// deterministic.
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+fn time_seed() -> u64 {
+    0
+}
+
+// Reads a line without its line ending, or nil at the end of the input.
+// Standard input is read a line at a time rather than through a buffer of
+// our own, so whatever the script leaves unread is still there for the
+// host.
+pub struct NativeReadLine {
+    input: Option<Mutex<Box<dyn Input>>>,
+}
+
+impl NativeReadLine {
+    pub fn stdin() -> Self {
+        Self { input: None }
+    }
+
+    pub fn new(input: Box<dyn Input>) -> Self {
+        Self {
+            input: Some(Mutex::new(input)),
+        }
+    }
+}
+
+impl NativeFunc for NativeReadLine {
+    fn name(&self) -> &str {
+        "readLine"
+    }
+
+    fn arity(&self) -> usize {
+        0
+    }
+
+    fn call(&self, _args: &[Value]) -> Result<Value, NativeError> {
+        let mut line = String::new();
+        let read = match &self.input {
+            Some(input) => input
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner)
+                .read_line(&mut line),
+            None => io::stdin().read_line(&mut line),
+        };
+        match read {
+            Ok(0) => Ok(Value::Nil),
+            Ok(_) => {
+                if line.ends_with('\n') {
+                    line.pop();
+                    if line.ends_with('\r') {
+                        line.pop();
+                    }
+                }
+                Ok(Value::Str(line))
+            }
+            Err(err) => Err(NativeError::new(format!("Can't read input: {err}."))),
         }
     }
 }
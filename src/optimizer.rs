@@ -0,0 +1,374 @@
+use crate::chunk::*;
+use crate::value::*;
+
+// A peephole pass over a finished chunk. The code is decoded into a list of
+// instructions whose jumps point at other instructions rather than byte
+// offsets, rewritten, and then encoded again with fresh jump distances.
+// The starting offset, line and column of each encoded instruction.
+type LineStarts = Vec<(usize, usize, usize)>;
+
+#[derive(Clone, Debug)]
+struct Instruction {
+    op: OpCode,
+    bytes: Vec<u8>,
+    line: usize,
+    column: usize,
+    target: Option<usize>,
+}
+
+pub fn optimize(chunk: &mut Chunk) {
+    let Some(mut code) = decode(chunk) else {
+        return;
+    };
+
+    thread_jumps(&mut code);
+    loop {
+        let before = code.len();
+        remove_dead_code(&mut code);
+        if code.len() == before {
+            break;
+        }
+    }
+    fold_constant_negation(&mut code, chunk);
+    fuse_pairs(&mut code);
+    fuse_pops(&mut code);
+    select_superinstructions(&mut code);
+    fuse_conditional_pops(&mut code);
+
+    if let Some((bytes, lines)) = encode(&code) {
+        chunk.replace_code(bytes, lines);
+    }
+}
+
+fn is_jump(op: OpCode) -> bool {
+    matches!(
+        op,
+        OpCode::JumpIfFalse
+            | OpCode::JumpIfFalseLong
+            | OpCode::JumpIfFalsePop
+            | OpCode::Jump
+            | OpCode::JumpLong
+            | OpCode::Loop
+            | OpCode::LoopLong
+    )
+}
+
+fn is_unconditional(op: OpCode) -> bool {
+    matches!(
+        op,
+        OpCode::Jump | OpCode::JumpLong | OpCode::Loop | OpCode::LoopLong | OpCode::Return
+    )
+}
+
+fn decode(chunk: &Chunk) -> Option<Vec<Instruction>> {
+    let mut code = Vec::new();
+    let mut offsets = Vec::new();
+    let mut targets = Vec::new();
+
+    let mut offset = 0;
+    while offset < chunk.count() {
+        let op = OpCode::try_from(chunk.read(offset)).ok()?;
+        let len = chunk.instruction_len(offset)?;
+        let target = chunk.jump_target(offset);
+        code.push(Instruction {
+            op,
+            bytes: (offset..offset + len).map(|i| chunk.read(i)).collect(),
+            line: chunk.get_line(offset),
+            column: chunk.get_column(offset),
+            target: None,
+        });
+        offsets.push(offset);
+        targets.push(target);
+        offset += len;
+    }
+
+    for (instruction, target) in code.iter_mut().zip(targets) {
+        if let Some(target) = target {
+            instruction.target = Some(offsets.binary_search(&target).ok()?);
+        }
+    }
+    Some(code)
+}
+
+// Jumps that land on an unconditional forward jump can go straight to its
+// destination. A failed JumpIfFalse landing on another JumpIfFalse leaves the
+// same falsey value on the stack, so it will take that jump too.
+fn thread_jumps(code: &mut [Instruction]) {
+    for i in 0..code.len() {
+        let Some(mut target) = code[i].target else {
+            continue;
+        };
+        let conditional = matches!(code[i].op, OpCode::JumpIfFalse | OpCode::JumpIfFalseLong);
+        let mut hops = 0;
+        while hops < code.len() {
+            let next = &code[target];
+            let follow = match next.op {
+                OpCode::Jump | OpCode::JumpLong => true,
+                OpCode::JumpIfFalse | OpCode::JumpIfFalseLong => conditional,
+                _ => false,
+            };
+            match next.target {
+                Some(t) if follow && t > target => target = t,
+                _ => break,
+            }
+            hops += 1;
+        }
+        code[i].target = Some(target);
+    }
+}
+
+fn jump_targets(code: &[Instruction]) -> Vec<bool> {
+    let mut targets = vec![false; code.len()];
+    for instruction in code {
+        if let Some(target) = instruction.target {
+            targets[target] = true;
+        }
+    }
+    targets
+}
+
+// Drops every instruction not marked `keep`. Nothing removed is ever a jump
+// target, but jumps are remapped onto the next surviving instruction anyway.
+fn compact(code: &mut Vec<Instruction>, keep: &[bool]) {
+    let mut remap = Vec::with_capacity(code.len() + 1);
+    let mut next = 0;
+    for kept in keep {
+        remap.push(next);
+        if *kept {
+            next += 1;
+        }
+    }
+    remap.push(next);
+
+    let mut index = 0;
+    code.retain(|_| {
+        index += 1;
+        keep[index - 1]
+    });
+    for instruction in code.iter_mut() {
+        if let Some(target) = instruction.target.as_mut() {
+            *target = remap[*target];
+        }
+    }
+}
+
+fn remove_dead_code(code: &mut Vec<Instruction>) {
+    let targets = jump_targets(code);
+    let mut keep = vec![true; code.len()];
+    let mut reachable = true;
+    for i in 0..code.len() {
+        if targets[i] {
+            reachable = true;
+        }
+        keep[i] = reachable;
+        if is_unconditional(code[i].op) {
+            reachable = false;
+        }
+    }
+    compact(code, &keep);
+}
+
+fn fold_constant_negation(code: &mut Vec<Instruction>, chunk: &mut Chunk) {
+    let targets = jump_targets(code);
+    let mut keep = vec![true; code.len()];
+    let mut i = 0;
+    while i + 1 < code.len() {
+        if code[i + 1].op == OpCode::Negate && !targets[i + 1] {
+            if let Some((op, bytes)) = negated_constant(&code[i], chunk) {
+                code[i].op = op;
+                code[i].bytes = bytes;
+                keep[i + 1] = false;
+                i += 1;
+            }
+        }
+        i += 1;
+    }
+    compact(code, &keep);
+}
+
+fn negated_constant(instruction: &Instruction, chunk: &mut Chunk) -> Option<(OpCode, Vec<u8>)> {
+    let index = match instruction.op {
+        OpCode::Constant => instruction.bytes[1] as usize,
+        OpCode::ConstantLong => {
+            let b = &instruction.bytes;
+            ((b[1] as usize) << 16) | ((b[2] as usize) << 8) | b[3] as usize
+        }
+        _ => return None,
+    };
+    let Value::Number(n) = chunk.get_constant(index) else {
+        return None;
+    };
+    let index = chunk.add_constant(Value::Number(-n))?;
+
+    if index > u8::MAX as usize {
+        let op = OpCode::ConstantLong;
+        Some((
+            op,
+            vec![
+                op.into(),
+                (index >> 16) as u8,
+                (index >> 8) as u8,
+                index as u8,
+            ],
+        ))
+    } else {
+        Some((OpCode::Constant, vec![OpCode::Constant.into(), index as u8]))
+    }
+}
+
+fn fuse_pairs(code: &mut Vec<Instruction>) {
+    let targets = jump_targets(code);
+    let mut keep = vec![true; code.len()];
+    let mut i = 0;
+    while i + 1 < code.len() {
+        let fused = match (code[i].op, code[i + 1].op) {
+            (OpCode::Equal, OpCode::Not) => Some(OpCode::NotEqual),
+            (OpCode::Greater, OpCode::Not) => Some(OpCode::NotGreater),
+            (OpCode::Less, OpCode::Not) => Some(OpCode::NotLess),
+            _ => None,
+        };
+        if let Some(op) = fused.filter(|_| !targets[i + 1]) {
+            code[i].op = op;
+            code[i].bytes = vec![op.into()];
+            keep[i + 1] = false;
+            i += 1;
+        }
+        i += 1;
+    }
+    compact(code, &keep);
+}
+
+fn fuse_pops(code: &mut Vec<Instruction>) {
+    let targets = jump_targets(code);
+    let mut keep = vec![true; code.len()];
+    let mut i = 0;
+    while i < code.len() {
+        let mut count = 1;
+        while code[i].op == OpCode::Pop
+            && i + count < code.len()
+            && code[i + count].op == OpCode::Pop
+            && !targets[i + count]
+            && count < u8::MAX as usize
+        {
+            keep[i + count] = false;
+            count += 1;
+        }
+        if count > 1 {
+            code[i].op = OpCode::PopN;
+            code[i].bytes = vec![OpCode::PopN.into(), count as u8];
+        }
+        i += count;
+    }
+    compact(code, &keep);
+}
+
+fn select_superinstructions(code: &mut Vec<Instruction>) {
+    let targets = jump_targets(code);
+    let mut keep = vec![true; code.len()];
+    let mut i = 0;
+    while i + 1 < code.len() {
+        let ops = (code[i].op, code[i + 1].op, code.get(i + 2).map(|c| c.op));
+        match ops {
+            (OpCode::GetLocal, OpCode::GetLocal, Some(OpCode::Add))
+                if !targets[i + 1] && !targets[i + 2] =>
+            {
+                let a = code[i].bytes[1];
+                let b = code[i + 1].bytes[1];
+                code[i].op = OpCode::AddLocals;
+                code[i].bytes = vec![OpCode::AddLocals.into(), a, b];
+                keep[i + 1] = false;
+                keep[i + 2] = false;
+                i += 3;
+            }
+            // ConstantCall puts the constant underneath the arguments, as the
+            // callee, so it only stands in for a Constant that is the callee:
+            // one called with no arguments.
+            (OpCode::Constant, OpCode::Call, _) if !targets[i + 1] && code[i + 1].bytes[1] == 0 => {
+                let constant = code[i].bytes[1];
+                let arg_count = code[i + 1].bytes[1];
+                code[i].op = OpCode::ConstantCall;
+                code[i].bytes = vec![OpCode::ConstantCall.into(), constant, arg_count];
+                keep[i + 1] = false;
+                i += 2;
+            }
+            _ => i += 1,
+        }
+    }
+    compact(code, &keep);
+}
+
+// `if` and `while` compile to a JumpIfFalse followed by a Pop on both paths.
+// When every jump to a Pop is such a JumpIfFalse, and nothing falls through
+// into it, the jumps can pop the condition themselves and both Pops go.
+fn fuse_conditional_pops(code: &mut Vec<Instruction>) {
+    let targets = jump_targets(code);
+    let mut sources: Vec<Vec<usize>> = vec![Vec::new(); code.len()];
+    for (i, instruction) in code.iter().enumerate() {
+        if let Some(target) = instruction.target {
+            sources[target].push(i);
+        }
+    }
+
+    let mut keep = vec![true; code.len()];
+    for (target, jumps) in sources.iter().enumerate() {
+        let fusable = !jumps.is_empty()
+            && target > 0
+            && code[target].op == OpCode::Pop
+            && is_unconditional(code[target - 1].op)
+            && jumps.iter().all(|&i| {
+                code[i].op == OpCode::JumpIfFalse
+                    && code.get(i + 1).map(|c| c.op) == Some(OpCode::Pop)
+                    && !targets[i + 1]
+            });
+        if fusable {
+            for &i in jumps {
+                code[i].op = OpCode::JumpIfFalsePop;
+                code[i].bytes[0] = OpCode::JumpIfFalsePop.into();
+                keep[i + 1] = false;
+            }
+            keep[target] = false;
+        }
+    }
+    compact(code, &keep);
+}
+
+// Lays the instructions out again and fills in the jump distances. If a
+// jump no longer fits its encoding the chunk is left alone.
+fn encode(code: &[Instruction]) -> Option<(Vec<u8>, LineStarts)> {
+    let mut offsets = Vec::with_capacity(code.len());
+    let mut offset = 0;
+    for instruction in code {
+        offsets.push(offset);
+        offset += instruction.bytes.len();
+    }
+
+    let mut bytes = Vec::with_capacity(offset);
+    let mut lines = Vec::with_capacity(code.len());
+    for (i, instruction) in code.iter().enumerate() {
+        let mut encoded = instruction.bytes.clone();
+        if is_jump(instruction.op) {
+            let end = offsets[i] + encoded.len();
+            let target = offsets[instruction.target?];
+            let distance = if matches!(instruction.op, OpCode::Loop | OpCode::LoopLong) {
+                end.checked_sub(target)?
+            } else {
+                target.checked_sub(end)?
+            };
+            if encoded.len() == 3 {
+                if distance > u16::MAX as usize {
+                    return None;
+                }
+                encoded[1] = (distance >> 8) as u8;
+                encoded[2] = distance as u8;
+            } else {
+                if distance > u32::MAX as usize {
+                    return None;
+                }
+                encoded[1..5].copy_from_slice(&(distance as u32).to_be_bytes());
+            }
+        }
+        lines.push((offsets[i], instruction.line, instruction.column));
+        bytes.extend(encoded);
+    }
+    Some((bytes, lines))
+}
@@ -0,0 +1,225 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use crate::chunk::*;
+use crate::shared::*;
+
+/// Counts executed instructions per opcode, and instructions, calls and time
+/// per function, and notes how deep the stack got. Functions are keyed by their chunk so two functions that
+/// happen to share a name are still reported separately.
+#[derive(Debug)]
+pub struct Profile {
+    opcodes: Vec<usize>,
+    instructions: usize,
+    peak_stack: usize,
+    functions: Vec<FunctionStats>,
+    index: HashMap<usize, usize>,
+    calls: Vec<ActiveCall>,
+    edges: HashMap<(usize, usize), CallStats>,
+}
+
+#[derive(Debug)]
+struct FunctionStats {
+    name: String,
+    // Keeps the chunk alive so its address can't be reused by another function.
+    _chunk: Rc<Chunk>,
+    calls: usize,
+    active: usize,
+    instructions: usize,
+    self_time: Duration,
+    total_time: Duration,
+}
+
+#[derive(Debug)]
+struct ActiveCall {
+    function: usize,
+    start: Instant,
+    instructions: usize,
+    child_time: Duration,
+}
+
+#[derive(Debug, Default)]
+struct CallStats {
+    calls: usize,
+    instructions: usize,
+    time: Duration,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Self {
+            opcodes: vec![0; 256],
+            instructions: 0,
+            peak_stack: 0,
+            functions: Vec::new(),
+            index: HashMap::new(),
+            calls: Vec::new(),
+            edges: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, instruction: OpCode, stack_depth: usize) {
+        self.opcodes[instruction as usize] += 1;
+        self.instructions += 1;
+        self.peak_stack = self.peak_stack.max(stack_depth);
+        if let Some(call) = self.calls.last() {
+            self.functions[call.function].instructions += 1;
+        }
+    }
+
+    pub fn instructions(&self) -> usize {
+        self.instructions
+    }
+
+    /// The most stack slots in use at the start of any instruction.
+    pub fn peak_stack(&self) -> usize {
+        self.peak_stack
+    }
+
+    pub fn enter(&mut self, name: &str, chunk: Rc<Chunk>) {
+        let function = match self.index.get(&(Rc::as_ptr(&chunk) as usize)) {
+            Some(function) => *function,
+            None => {
+                self.index
+                    .insert(Rc::as_ptr(&chunk) as usize, self.functions.len());
+                self.functions.push(FunctionStats {
+                    name: name.to_string(),
+                    _chunk: chunk,
+                    calls: 0,
+                    active: 0,
+                    instructions: 0,
+                    self_time: Duration::ZERO,
+                    total_time: Duration::ZERO,
+                });
+                self.functions.len() - 1
+            }
+        };
+
+        let stats = &mut self.functions[function];
+        stats.calls += 1;
+        stats.active += 1;
+        self.calls.push(ActiveCall {
+            function,
+            start: Instant::now(),
+            instructions: self.instructions,
+            child_time: Duration::ZERO,
+        });
+    }
+
+    pub fn exit(&mut self) {
+        let Some(call) = self.calls.pop() else {
+            return;
+        };
+        let elapsed = call.start.elapsed();
+
+        let stats = &mut self.functions[call.function];
+        stats.active -= 1;
+        stats.self_time += elapsed.saturating_sub(call.child_time);
+        if stats.active == 0 {
+            stats.total_time += elapsed;
+        }
+
+        if let Some(caller) = self.calls.last_mut() {
+            caller.child_time += elapsed;
+            let edge = self
+                .edges
+                .entry((caller.function, call.function))
+                .or_default();
+            edge.calls += 1;
+            edge.instructions += self.instructions - call.instructions;
+            edge.time += elapsed;
+        }
+    }
+
+    /// Closes every call still in progress, e.g. after a runtime error.
+    pub fn unwind(&mut self) {
+        while !self.calls.is_empty() {
+            self.exit();
+        }
+    }
+
+    pub fn write_callgrind(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "# callgrind format")?;
+        writeln!(out, "version: 1")?;
+        writeln!(out, "creator: lox-bytecode")?;
+        writeln!(out, "events: Instructions Nanoseconds")?;
+
+        let mut edges: Vec<(&(usize, usize), &CallStats)> = self.edges.iter().collect();
+        edges.sort_by_key(|(key, _)| **key);
+
+        for (id, stats) in self.functions.iter().enumerate() {
+            writeln!(out)?;
+            writeln!(out, "fn=({}) {}", id + 1, stats.name)?;
+            writeln!(
+                out,
+                "0 {} {}",
+                stats.instructions,
+                stats.self_time.as_nanos()
+            )?;
+            for ((_, callee), call) in edges.iter().filter(|((caller, _), _)| *caller == id) {
+                writeln!(out, "cfn=({}) {}", callee + 1, self.functions[*callee].name)?;
+                writeln!(out, "calls={} 0", call.calls)?;
+                writeln!(out, "0 {} {}", call.instructions, call.time.as_nanos())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total = self.instructions;
+        writeln!(f, "== profile: {total} instructions ==")?;
+
+        let mut opcodes: Vec<(OpCode, usize)> = self
+            .opcodes
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .filter_map(|(code, count)| Some((OpCode::try_from(code as u8).ok()?, *count)))
+            .collect();
+        opcodes.sort_by_key(|(_, count)| Reverse(*count));
+        for (opcode, count) in opcodes {
+            writeln!(
+                f,
+                "{:<16} {count:>12} {:>6.2}%",
+                format!("{opcode:?}"),
+                percent(count, total)
+            )?;
+        }
+
+        writeln!(
+            f,
+            "== functions ==\n{:<16} {:>10} {:>12} {:>7} {:>12} {:>12}",
+            "name", "calls", "instructions", "", "self ms", "total ms"
+        )?;
+        let mut functions: Vec<&FunctionStats> = self.functions.iter().collect();
+        functions.sort_by_key(|stats| Reverse(stats.self_time));
+        for stats in functions {
+            writeln!(
+                f,
+                "{:<16} {:>10} {:>12} {:>6.2}% {:>12.3} {:>12.3}",
+                stats.name,
+                stats.calls,
+                stats.instructions,
+                percent(stats.instructions, total),
+                stats.self_time.as_secs_f64() * 1000.0,
+                stats.total_time.as_secs_f64() * 1000.0,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn percent(count: usize, total: usize) -> f64 {
+    count as f64 * 100.0 / total as f64
+}
@@ -0,0 +1,61 @@
+use crate::error::*;
+use crate::function::*;
+use crate::globals::*;
+use crate::native::*;
+use crate::serialize::*;
+use crate::vm::*;
+
+/// A compiled script that can run in any number of VMs, each with its own
+/// globals. Compiling is the expensive part, so a host that runs the same
+/// script many times can do it once:
+///
+/// ```
+/// use lox_bytecode::{Value, VM};
+///
+/// let program = lox_bytecode::compile("var n = 0; n = n + 1;").unwrap();
+/// for _ in 0..3 {
+///     let mut vm = VM::new();
+///     vm.execute(&program).unwrap();
+///     assert_eq!(vm.get_global("n"), Some(Value::Number(1.0)));
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Program {
+    function: Function,
+    // The slots the code was compiled against.
+    globals: GlobalTable,
+}
+
+impl Program {
+    pub fn new(function: Function, globals: GlobalTable) -> Self {
+        Self { function, globals }
+    }
+
+    /// Prints the bytecode of the program and of every function in it.
+    pub fn disassemble(&self) {
+        self.function.disassemble();
+    }
+
+    // The program's code with its global operands pointing at slots in
+    // `globals`. A VM laid out like the compiling one, such as any fresh VM
+    // with the same natives, runs the code as it is. Otherwise the program
+    // is moved by writing it out and loading it back, the same way .loxb
+    // files are.
+    pub fn load(&self, globals: &mut GlobalTable) -> Result<Function, String> {
+        let mut moved = false;
+        for slot in 0..self.globals.len() {
+            moved |= globals.resolve(self.globals.name(slot)) != slot;
+        }
+        if !moved {
+            return Ok(self.function.clone());
+        }
+        let bytes = serialize(&self.function, &self.globals)?;
+        deserialize(&bytes, &NativeTable::new(), globals)
+    }
+}
+
+/// Compiles `source` with the default settings into a program that any VM
+/// can `execute`. Compile errors come back in the LoxError.
+pub fn compile(source: &str) -> Result<Program, LoxError> {
+    VM::new().compile_program(source)
+}
@@ -0,0 +1,165 @@
+// Natives that look inside instances and classes, for scripts that
+// serialize or print objects generically. There are no lists to return
+// names in, so, like `argCount()` and `arg(n)`, names come one at a time by
+// index, sorted so that they come in the same order every run. An instance's
+// fields are its fields; a class's are its static fields.
+
+use crate::bound_method::*;
+use crate::class::*;
+use crate::error::*;
+use crate::shared::*;
+use crate::value::*;
+
+pub fn field_count(args: &[Value]) -> Result<Value, NativeError> {
+    Ok(Value::Number(field_names(&args[0])?.len() as f64))
+}
+
+pub fn field_name(args: &[Value]) -> Result<Value, NativeError> {
+    let names = field_names(&args[0])?;
+    Ok(names.get(index(&args[1])?).cloned().into())
+}
+
+pub fn method_count(args: &[Value]) -> Result<Value, NativeError> {
+    Ok(Value::Number(method_names(&args[0])?.len() as f64))
+}
+
+pub fn method_name(args: &[Value]) -> Result<Value, NativeError> {
+    let names = method_names(&args[0])?;
+    Ok(names.get(index(&args[1])?).cloned().into())
+}
+
+pub fn has_field(args: &[Value]) -> Result<Value, NativeError> {
+    let name: &str = (&args[1]).try_into()?;
+    let found = match &args[0] {
+        Value::Instance(instance) => instance.get_field(name).is_some(),
+        Value::Class(class) => class.get_static(name).is_some(),
+        value => return Err(not_an_object(value)),
+    };
+    Ok(Value::Boolean(found))
+}
+
+// `obj.name` with the name in a string: a field, or else a method bound to
+// the instance, or for a class, one waiting for an instance.
+pub fn getattr(args: &[Value]) -> Result<Value, NativeError> {
+    let name: &str = (&args[1]).try_into()?;
+    let value = match &args[0] {
+        Value::Instance(instance) => instance.get_field(name).or_else(|| {
+            let method = instance.get_class().get_method(name)?;
+            Some(Value::Bound(Rc::new(BoundMethod::new(&args[0], &method))))
+        }),
+        Value::Class(class) => class.get_static(name).or_else(|| {
+            let method = class.get_method(name)?;
+            Some(Value::Bound(Rc::new(BoundMethod::unbound(
+                &args[0], &method,
+            ))))
+        }),
+        value => return Err(not_an_object(value)),
+    };
+    value.ok_or_else(|| NativeError::new(format!("Undefined property '{name}'.")))
+}
+
+// `obj.name = value` with the name in a string. Returns the value, as the
+// assignment would.
+pub fn setattr(args: &[Value]) -> Result<Value, NativeError> {
+    let name: &str = (&args[1]).try_into()?;
+    match &args[0] {
+        Value::Instance(instance) if instance.is_frozen() => {
+            return Err(NativeError::new(format!(
+                "Can't set '{name}' on a frozen instance."
+            )))
+        }
+        Value::Instance(instance) => instance.set_field(name, &args[2]),
+        Value::Class(class) => class.set_static(name.to_string(), &args[2]),
+        value => return Err(not_an_object(value)),
+    };
+    Ok(args[2].clone())
+}
+
+// The class a class inherits from, or nil.
+pub fn superclass(args: &[Value]) -> Result<Value, NativeError> {
+    match &args[0] {
+        Value::Class(class) => Ok(class.superclass().map_or(Value::Nil, Value::Class)),
+        value => Err(NativeError::new(format!(
+            "Expected a class but got {}.",
+            value.type_name()
+        ))),
+    }
+}
+
+// Whether the value is an instance of the class or of one of its
+// subclasses.
+pub fn instance_of(args: &[Value]) -> Result<Value, NativeError> {
+    let Value::Class(class) = &args[1] else {
+        return Err(NativeError::new(format!(
+            "Expected a class but got {}.",
+            args[1].type_name()
+        )));
+    };
+    let is_instance = match &args[0] {
+        Value::Instance(instance) => instance.get_class().is_subclass_of(class),
+        _ => false,
+    };
+    Ok(Value::Boolean(is_instance))
+}
+
+// Stops scripts setting the instance's fields, and returns it.
+pub fn freeze(args: &[Value]) -> Result<Value, NativeError> {
+    args[0].freeze()?;
+    Ok(args[0].clone())
+}
+
+pub fn is_frozen(args: &[Value]) -> Result<Value, NativeError> {
+    let frozen = matches!(&args[0], Value::Instance(instance) if instance.is_frozen());
+    Ok(Value::Boolean(frozen))
+}
+
+fn field_names(value: &Value) -> Result<Vec<String>, NativeError> {
+    let mut names: Vec<String> = match value {
+        Value::Instance(instance) => instance
+            .fields()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect(),
+        Value::Class(class) => class.statics().into_iter().map(|(name, _)| name).collect(),
+        value => return Err(not_an_object(value)),
+    };
+    names.sort();
+    Ok(names)
+}
+
+// The class's own methods and those it inherits, each name once.
+fn method_names(value: &Value) -> Result<Vec<String>, NativeError> {
+    let mut class: Option<Rc<Class>> = match value {
+        Value::Instance(instance) => Some(instance.get_class()),
+        Value::Class(class) => Some(Rc::clone(class)),
+        value => return Err(not_an_object(value)),
+    };
+    let mut names = Vec::new();
+    while let Some(current) = class {
+        names.extend(current.methods().into_iter().map(|(name, _)| name));
+        if current.own_init_method().is_some() {
+            names.push("init".to_string());
+        }
+        class = current.superclass();
+    }
+    names.sort();
+    names.dedup();
+    Ok(names)
+}
+
+fn index(value: &Value) -> Result<usize, NativeError> {
+    let index: f64 = value.try_into()?;
+    if index < 0.0 || index.fract() != 0.0 {
+        return Err(NativeError::new(
+            "Argument index must be a non-negative integer.",
+        ));
+    }
+    Ok(index as usize)
+}
+
+fn not_an_object(value: &Value) -> NativeError {
+    NativeError::new(format!(
+        "Expected an instance or class but got {}.",
+        value.type_name()
+    ))
+}
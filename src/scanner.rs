@@ -1,10 +1,16 @@
+use crate::diagnostic::Span;
 use crate::token::*;
 
+// Turns source text into tokens on demand. Besides feeding the compiler it
+// can be iterated directly; iteration ends at the end of the source, and
+// lexical errors come through as `TokenType::Error` tokens.
 pub struct Scanner {
     source: Vec<char>,
     start: usize,
     current: usize,
     line: usize,
+    line_start: usize,
+    keep_comments: bool,
 }
 
 impl Scanner {
@@ -14,9 +20,29 @@ impl Scanner {
             start: 0,
             current: 0,
             line: 1,
+            line_start: 0,
+            keep_comments: false,
         }
     }
 
+    // Also produce `TokenType::Comment` tokens, for tools that have to
+    // reproduce the source.
+    pub fn with_comments(source: &str) -> Self {
+        Self {
+            keep_comments: true,
+            ..Self::new(source)
+        }
+    }
+
+    // The token after the one `scan_token` last returned, without moving
+    // past it.
+    pub fn peek_token(&mut self) -> Token {
+        let saved = (self.start, self.current, self.line, self.line_start);
+        let token = self.scan_token();
+        (self.start, self.current, self.line, self.line_start) = saved;
+        token
+    }
+
     pub fn scan_token(&mut self) -> Token {
         self.skip_whitespace();
 
@@ -38,6 +64,7 @@ impl Scanner {
             '.' => self.make_token(TokenType::Dot),
             '-' => self.make_token(TokenType::Minus),
             '+' => self.make_token(TokenType::Plus),
+            '/' if self.keep_comments && self.peek() == '/' => self.comment(),
             '/' => self.make_token(TokenType::Slash),
             '*' => self.make_token(TokenType::Star),
             '!' => {
@@ -84,21 +111,43 @@ impl Scanner {
     }
 
     fn make_token(&self, ttype: TokenType) -> Token {
+        let (column, span) = self.span();
         Token {
             ttype,
             lexeme: self.source[self.start..self.current].iter().collect(),
             line: self.line,
+            column,
+            span,
         }
     }
 
     fn error_token(&self, message: &str) -> Token {
+        let (column, span) = self.span();
         Token {
             ttype: TokenType::Error,
             lexeme: message.to_string(),
             line: self.line,
+            column,
+            span,
         }
     }
 
+    // Column and length of the current lexeme on the line the token is
+    // reported on. A string spanning lines only covers its last line.
+    fn span(&self) -> (usize, Span) {
+        let start = self.start.max(self.line_start);
+        let span = Span {
+            start,
+            len: self.current - start,
+        };
+        (start - self.line_start + 1, span)
+    }
+
+    fn newline(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
+    }
+
     fn skip_whitespace(&mut self) {
         loop {
             match self.peek() {
@@ -106,10 +155,13 @@ impl Scanner {
                     self.advance();
                 }
                 '\n' => {
-                    self.line += 1;
                     self.advance();
+                    self.newline();
                 }
                 '/' => {
+                    if self.keep_comments {
+                        return;
+                    }
                     if let Some('/') = self.peek_next() {
                         // A comment goes until the end of the line
                         while self.peek() != '\n' && !self.is_at_end() {
@@ -126,6 +178,13 @@ impl Scanner {
         }
     }
 
+    fn comment(&mut self) -> Token {
+        while self.peek() != '\n' && !self.is_at_end() {
+            self.advance();
+        }
+        self.make_token(TokenType::Comment)
+    }
+
     fn identifier(&mut self) -> Token {
         while self.peek_is_alphanumeric() {
             self.advance();
@@ -198,15 +257,15 @@ impl Scanner {
     }
 
     fn number(&mut self) -> Token {
-        while self.peek().is_digit(10) {
+        while self.peek().is_ascii_digit() {
             self.advance();
         }
 
         if self.peek() == '.' {
             if let Some(ch) = self.peek_next() {
-                if ch.is_digit(10) {
+                if ch.is_ascii_digit() {
                     self.advance();
-                    while self.peek().is_digit(10) {
+                    while self.peek().is_ascii_digit() {
                         self.advance();
                     }
                 }
@@ -218,10 +277,9 @@ impl Scanner {
 
     fn string(&mut self) -> Token {
         while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
+            if self.advance() == '\n' {
+                self.newline();
             }
-            self.advance();
         }
 
         if self.is_at_end() {
@@ -264,3 +322,16 @@ impl Scanner {
         true
     }
 }
+
+impl Iterator for Scanner {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.scan_token();
+        if token.ttype == TokenType::Eof {
+            None
+        } else {
+            Some(token)
+        }
+    }
+}
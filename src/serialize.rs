@@ -0,0 +1,561 @@
+use crate::chunk::*;
+use crate::function::*;
+use crate::globals::*;
+use crate::native::*;
+use crate::shared::*;
+use crate::value::*;
+
+// Layout of a .loxb file. Integers are little-endian u32s and strings are a
+// length followed by that many bytes of UTF-8.
+//
+//   "LOXB" version
+//   global count, then each global name
+//   the top-level function
+//
+// A function is its name, source file (empty when there is none), arity,
+// upvalue count, code, line runs, inline cache count and constants. Each constant starts with one of the tags
+// below; nested functions are written out in place.
+const MAGIC: &[u8; 4] = b"LOXB";
+const VERSION: u32 = 2;
+
+pub const TAG_NIL: u8 = 0;
+pub const TAG_FALSE: u8 = 1;
+pub const TAG_TRUE: u8 = 2;
+pub const TAG_NUMBER: u8 = 3;
+pub const TAG_STRING: u8 = 4;
+pub const TAG_FUNCTION: u8 = 5;
+pub const TAG_NATIVE: u8 = 6;
+
+// How deeply function constants may nest. Loading them recurses, so a file
+// could otherwise run the loader out of stack before anything is checked.
+const MAX_FUNCTION_DEPTH: usize = 256;
+
+// Global operands are slots in the compiling VM's table, so the names of
+// every slot travel with the code.
+pub fn serialize(function: &Function, globals: &GlobalTable) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    write_u32(&mut out, VERSION);
+    write_usize(&mut out, globals.len())?;
+    for slot in 0..globals.len() {
+        write_str(&mut out, globals.name(slot))?;
+    }
+    write_function(&mut out, function)?;
+    Ok(out)
+}
+
+// Reads a program back, checking every chunk before any of it can run. The
+// file's globals are resolved in `globals`, and operands are rewritten to
+// the slots they get there.
+pub fn deserialize(
+    bytes: &[u8],
+    natives: &NativeTable,
+    globals: &mut GlobalTable,
+) -> Result<Function, String> {
+    let mut reader = Reader::new(bytes);
+    if reader.take(4)? != MAGIC {
+        return Err("not a .loxb file".to_string());
+    }
+    let version = reader.u32()?;
+    if version != VERSION {
+        return Err(format!("unsupported version {version}, expected {VERSION}"));
+    }
+    let count = reader.u32()? as usize;
+    let mut slots = Vec::new();
+    for _ in 0..count {
+        slots.push(globals.resolve(&reader.string()?));
+    }
+
+    let loader = Loader::new(natives, slots);
+    let function = loader.function(&mut reader)?;
+    if !reader.at_end() {
+        return Err("trailing bytes after the program".to_string());
+    }
+    Ok(function)
+}
+
+pub fn write_u32(out: &mut Vec<u8>, n: u32) {
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+pub fn write_usize(out: &mut Vec<u8>, n: usize) -> Result<(), String> {
+    let n = u32::try_from(n).map_err(|_| format!("{n} is too large to encode"))?;
+    write_u32(out, n);
+    Ok(())
+}
+
+pub fn write_str(out: &mut Vec<u8>, s: &str) -> Result<(), String> {
+    write_usize(out, s.len())?;
+    out.extend_from_slice(s.as_bytes());
+    Ok(())
+}
+
+pub fn write_function(out: &mut Vec<u8>, function: &Function) -> Result<(), String> {
+    write_str(out, function.name())?;
+    write_str(out, function.chunk.file().unwrap_or(""))?;
+    write_usize(out, function.arity())?;
+    write_usize(out, function.upvalues())?;
+
+    let chunk = &function.chunk;
+    write_usize(out, chunk.count())?;
+    out.extend_from_slice(chunk.code());
+    let runs: Vec<_> = chunk.line_runs().collect();
+    write_usize(out, runs.len())?;
+    for (start, line, column) in runs {
+        write_usize(out, start)?;
+        write_usize(out, line)?;
+        write_usize(out, column)?;
+    }
+    write_usize(out, chunk.cache_count())?;
+
+    write_usize(out, chunk.constants().len())?;
+    for constant in chunk.constants() {
+        match constant {
+            Value::Nil => out.push(TAG_NIL),
+            Value::Boolean(false) => out.push(TAG_FALSE),
+            Value::Boolean(true) => out.push(TAG_TRUE),
+            Value::Number(n) => {
+                out.push(TAG_NUMBER);
+                out.extend_from_slice(&n.to_le_bytes());
+            }
+            Value::Str(s) => {
+                out.push(TAG_STRING);
+                write_str(out, s)?;
+            }
+            Value::Func(function) => {
+                out.push(TAG_FUNCTION);
+                write_function(out, function)?;
+            }
+            Value::Native(native) => {
+                out.push(TAG_NATIVE);
+                write_str(out, native.name())?;
+            }
+            value => return Err(format!("can't serialize the constant {value}")),
+        }
+    }
+    Ok(())
+}
+
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+
+    pub fn at_end(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or("unexpected end of file")?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn usize(&mut self) -> Result<usize, String> {
+        Ok(self.u32()? as usize)
+    }
+
+    pub fn f64(&mut self) -> Result<f64, String> {
+        let bytes = self.take(8)?;
+        Ok(f64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn string(&mut self) -> Result<String, String> {
+        let len = self.usize()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| "string is not valid UTF-8".to_string())
+    }
+}
+
+pub struct Loader<'a> {
+    natives: &'a NativeTable,
+    // The loading VM's slot for each global slot in the file.
+    slots: Vec<usize>,
+}
+
+impl<'a> Loader<'a> {
+    pub fn new(natives: &'a NativeTable, slots: Vec<usize>) -> Self {
+        Self { natives, slots }
+    }
+
+    pub fn function(&self, reader: &mut Reader) -> Result<Function, String> {
+        self.nested_function(reader, 0)
+    }
+
+    // A function inside `depth` others.
+    fn nested_function(&self, reader: &mut Reader, depth: usize) -> Result<Function, String> {
+        if depth > MAX_FUNCTION_DEPTH {
+            return Err(format!(
+                "functions nested more than {MAX_FUNCTION_DEPTH} deep"
+            ));
+        }
+        let name = reader.string()?;
+        let file = reader.string()?;
+        let arity = reader.usize()?;
+        let upvalues = reader.usize()?;
+
+        let code_len = reader.usize()?;
+        let code = reader.take(code_len)?.to_vec();
+        let run_count = reader.usize()?;
+        let mut lines = Vec::new();
+        for _ in 0..run_count {
+            lines.push((reader.usize()?, reader.usize()?, reader.usize()?));
+        }
+        let caches = reader.usize()?;
+
+        let constant_count = reader.usize()?;
+        let mut constants = Vec::new();
+        for _ in 0..constant_count {
+            let value = match reader.u8()? {
+                TAG_NIL => Value::Nil,
+                TAG_FALSE => Value::Boolean(false),
+                TAG_TRUE => Value::Boolean(true),
+                TAG_NUMBER => Value::Number(reader.f64()?),
+                TAG_STRING => Value::Str(reader.string()?),
+                TAG_FUNCTION => Value::Func(Rc::new(self.nested_function(reader, depth + 1)?)),
+                TAG_NATIVE => {
+                    let name = reader.string()?;
+                    match self.natives.get(&name) {
+                        Some(native) => Value::Native(Rc::clone(native)),
+                        None => return Err(format!("unknown native function '{name}'")),
+                    }
+                }
+                tag => return Err(format!("unknown constant tag {tag}")),
+            };
+            constants.push(value);
+        }
+
+        if code.is_empty() || lines.first().map(|run| run.0) != Some(0) {
+            return Err(format!("<fn {name}> has no code or no line information"));
+        }
+        if lines.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+            return Err(format!("<fn {name}> has unordered line information"));
+        }
+        // Every cache belongs to an instruction, so there can't be more of
+        // them than bytes of code.
+        if caches > code.len() {
+            return Err(format!("<fn {name}> has too many inline caches"));
+        }
+
+        let mut chunk = Chunk::from_parts(code, lines, constants, caches);
+        if !file.is_empty() {
+            chunk.set_file(Some(file.into()));
+        }
+        self.check_code(&mut chunk, arity, upvalues)
+            .map_err(|err| format!("<fn {name}>: {err}"))?;
+        Ok(Function::new(arity, &Rc::new(chunk), name, upvalues))
+    }
+
+    // Makes sure every instruction is known, fits in the chunk, and only
+    // refers to constants, globals, caches and upvalues that exist, and that
+    // every jump lands on an instruction. Global operands are moved to the
+    // loading VM's slots on the way. Then `check_stack` follows the code to
+    // make sure it never reaches past its frame.
+    fn check_code(&self, chunk: &mut Chunk, arity: usize, upvalues: usize) -> Result<(), String> {
+        let len = chunk.count();
+        let constants = chunk.constants().len();
+        let operand = |chunk: &Chunk, offset: usize, long: bool| -> Result<usize, String> {
+            let width = if long { 3 } else { 1 };
+            if offset + width > len {
+                return Err(format!("truncated instruction at {offset}"));
+            }
+            Ok(if long {
+                chunk.read_long(offset)
+            } else {
+                chunk.read(offset) as usize
+            })
+        };
+
+        let mut starts = vec![false; len];
+        let mut jumps = Vec::new();
+        let mut offset = 0;
+        while offset < len {
+            starts[offset] = true;
+            let instruction = OpCode::try_from(chunk.read(offset))
+                .map_err(|code| format!("unknown opcode {code} at {offset}"))?;
+            let long = instruction.is_long();
+            let width = if long { 3 } else { 1 };
+            let index = |chunk: &Chunk| operand(chunk, offset + 1, long);
+
+            match instruction {
+                OpCode::Constant
+                | OpCode::ConstantLong
+                | OpCode::Class
+                | OpCode::ClassLong
+                | OpCode::SetProperty
+                | OpCode::SetPropertyLong
+                | OpCode::Method
+                | OpCode::MethodLong
+                | OpCode::GetSuper
+                | OpCode::GetSuperLong
+                | OpCode::SuperInvoke
+                | OpCode::SuperInvokeLong
+                | OpCode::GetProperty
+                | OpCode::GetPropertyLong
+                | OpCode::Invoke
+                | OpCode::InvokeLong
+                | OpCode::ConstantCall => {
+                    let constant = operand(chunk, offset + 1, long)?;
+                    if constant >= constants {
+                        return Err(format!("constant {constant} out of range at {offset}"));
+                    }
+                }
+                OpCode::Closure | OpCode::ClosureLong => {
+                    let constant = index(chunk)?;
+                    if !matches!(chunk.constants().get(constant), Some(Value::Func(_))) {
+                        return Err(format!("closure of a non-function at {offset}"));
+                    }
+                }
+                OpCode::GetUpvalue | OpCode::SetUpvalue => {
+                    let upvalue = index(chunk)?;
+                    if upvalue >= upvalues {
+                        return Err(format!("upvalue {upvalue} out of range at {offset}"));
+                    }
+                }
+                OpCode::DefineGlobal
+                | OpCode::DefineGlobalLong
+                | OpCode::GetGlobal
+                | OpCode::GetGlobalLong
+                | OpCode::SetGlobal
+                | OpCode::SetGlobalLong => {
+                    let slot = index(chunk)?;
+                    let Some(&target) = self.slots.get(slot) else {
+                        return Err(format!("global {slot} out of range at {offset}"));
+                    };
+                    if long && target <= MAX_LONG_INDEX {
+                        let bytes = (target as u32).to_be_bytes();
+                        for (i, byte) in bytes[1..].iter().enumerate() {
+                            chunk.write_at(offset + 1 + i, *byte);
+                        }
+                    } else if let Ok(byte) = u8::try_from(target) {
+                        chunk.write_at(offset + 1, byte);
+                    } else {
+                        return Err(format!("global {slot} doesn't fit its operand at {offset}"));
+                    }
+                }
+                _ => {}
+            }
+
+            let size = chunk
+                .instruction_len(offset)
+                .ok_or_else(|| format!("malformed instruction at {offset}"))?;
+            if offset + size > len {
+                return Err(format!("truncated instruction at {offset}"));
+            }
+
+            match instruction {
+                OpCode::GetProperty | OpCode::GetPropertyLong => {
+                    Self::check_cache(chunk, offset + 1 + width, offset)?;
+                }
+                OpCode::Invoke | OpCode::InvokeLong => {
+                    Self::check_cache(chunk, offset + 2 + width, offset)?;
+                }
+                OpCode::Closure | OpCode::ClosureLong => {
+                    // Captured locals are checked along with the stack.
+                    for capture in (offset + 1 + width..offset + size).step_by(3) {
+                        let upvalue = chunk.read_short(capture + 1);
+                        if chunk.read(capture) == 0 && upvalue >= upvalues {
+                            return Err(format!("upvalue {upvalue} out of range at {offset}"));
+                        }
+                    }
+                }
+                OpCode::Loop | OpCode::LoopLong => {
+                    let back = if instruction == OpCode::LoopLong {
+                        chunk.read_int(offset + 1)
+                    } else {
+                        chunk.read_short(offset + 1)
+                    };
+                    let target = (offset + size)
+                        .checked_sub(back)
+                        .ok_or_else(|| format!("jump out of range at {offset}"))?;
+                    jumps.push((offset, target));
+                }
+                _ => {
+                    if let Some(target) = chunk.jump_target(offset) {
+                        jumps.push((offset, target));
+                    }
+                }
+            }
+            offset += size;
+        }
+
+        for (offset, target) in jumps {
+            if !starts.get(target).copied().unwrap_or(false) {
+                return Err(format!(
+                    "jump into the middle of an instruction at {offset}"
+                ));
+            }
+        }
+        Self::check_stack(chunk, arity, &starts)
+    }
+
+    // Follows every path through the code from the start of the frame, where
+    // the callee and its arguments are, working out how deep the stack is
+    // before each instruction. Each one must find the values it takes and
+    // the locals it names within the frame, every path into an instruction
+    // must agree on the depth, and no path may run off the end of the code.
+    fn check_stack(chunk: &Chunk, arity: usize, starts: &[bool]) -> Result<(), String> {
+        let len = chunk.count();
+        let mut depths: Vec<Option<usize>> = vec![None; len];
+        let mut pending = vec![(0, arity + 1)];
+        while let Some((offset, depth)) = pending.pop() {
+            if offset >= len || !starts[offset] {
+                return Err(format!("execution runs off the end at {offset}"));
+            }
+            match depths[offset] {
+                Some(seen) if seen == depth => continue,
+                Some(_) => return Err(format!("stack depth differs between paths at {offset}")),
+                None => depths[offset] = Some(depth),
+            }
+
+            let instruction = OpCode::try_from(chunk.read(offset)).unwrap();
+            let size = chunk.instruction_len(offset).unwrap();
+            let (takes, leaves) = stack_effect(chunk, offset, instruction);
+            // The callee's slot at the bottom of the frame is never taken.
+            if takes >= depth {
+                return Err(format!("stack underflow at {offset}"));
+            }
+            // The closure is pushed before it captures, so it can capture
+            // itself.
+            let (locals, frame): (Vec<usize>, usize) = match instruction {
+                OpCode::GetLocal | OpCode::SetLocal => {
+                    (vec![chunk.read(offset + 1) as usize], depth)
+                }
+                OpCode::GetLocalLong | OpCode::SetLocalLong => {
+                    (vec![chunk.read_long(offset + 1)], depth)
+                }
+                OpCode::AddLocals => (
+                    vec![
+                        chunk.read(offset + 1) as usize,
+                        chunk.read(offset + 2) as usize,
+                    ],
+                    depth,
+                ),
+                OpCode::Closure | OpCode::ClosureLong => {
+                    let width = if instruction.is_long() { 3 } else { 1 };
+                    let captures = (offset + 1 + width..offset + size)
+                        .step_by(3)
+                        .filter(|&capture| chunk.read(capture) != 0)
+                        .map(|capture| chunk.read_short(capture + 1));
+                    (captures.collect(), depth + 1)
+                }
+                _ => (Vec::new(), depth),
+            };
+            if let Some(local) = locals.into_iter().find(|&local| local >= frame) {
+                return Err(format!("local {local} out of range at {offset}"));
+            }
+
+            let after = depth - takes + leaves;
+            let target = chunk.jump_target(offset);
+            match instruction {
+                OpCode::Return => {}
+                OpCode::Jump | OpCode::JumpLong | OpCode::Loop | OpCode::LoopLong => {
+                    pending.push((target.unwrap(), after));
+                }
+                _ => {
+                    pending.extend(target.map(|target| (target, after)));
+                    pending.push((offset + size, after));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_cache(chunk: &Chunk, at: usize, offset: usize) -> Result<(), String> {
+        let cache = chunk.read_short(at);
+        if cache >= chunk.cache_count() {
+            return Err(format!("inline cache {cache} out of range at {offset}"));
+        }
+        Ok(())
+    }
+}
+
+// How many values the instruction at `offset` takes off the stack, and how
+// many it leaves in their place.
+fn stack_effect(chunk: &Chunk, offset: usize, instruction: OpCode) -> (usize, usize) {
+    let width = if instruction.is_long() { 3 } else { 1 };
+    match instruction {
+        OpCode::Constant
+        | OpCode::ConstantLong
+        | OpCode::Nil
+        | OpCode::True
+        | OpCode::False
+        | OpCode::GetGlobal
+        | OpCode::GetGlobalLong
+        | OpCode::GetLocal
+        | OpCode::GetLocalLong
+        | OpCode::GetUpvalue
+        | OpCode::Class
+        | OpCode::ClassLong
+        | OpCode::Closure
+        | OpCode::ClosureLong
+        | OpCode::AddLocals => (0, 1),
+        OpCode::Negate
+        | OpCode::Not
+        | OpCode::SetGlobal
+        | OpCode::SetGlobalLong
+        | OpCode::SetLocal
+        | OpCode::SetLocalLong
+        | OpCode::SetUpvalue
+        | OpCode::GetProperty
+        | OpCode::GetPropertyLong
+        | OpCode::Extend
+        | OpCode::JumpIfFalse
+        | OpCode::JumpIfFalseLong => (1, 1),
+        OpCode::Add
+        | OpCode::Subtract
+        | OpCode::Multiply
+        | OpCode::Divide
+        | OpCode::Equal
+        | OpCode::Greater
+        | OpCode::Less
+        | OpCode::GreaterEqual
+        | OpCode::LessEqual
+        | OpCode::NotEqual
+        | OpCode::NotGreater
+        | OpCode::NotLess
+        | OpCode::SetProperty
+        | OpCode::SetPropertyLong
+        | OpCode::Method
+        | OpCode::MethodLong
+        | OpCode::Inherit
+        | OpCode::GetSuper
+        | OpCode::GetSuperLong => (2, 1),
+        OpCode::Return
+        | OpCode::Print
+        | OpCode::Pop
+        | OpCode::DefineGlobal
+        | OpCode::DefineGlobalLong
+        | OpCode::CloseUpvalue
+        | OpCode::JumpIfFalsePop => (1, 0),
+        OpCode::Jump | OpCode::JumpLong | OpCode::Loop | OpCode::LoopLong => (0, 0),
+        OpCode::PopN => (chunk.read(offset + 1) as usize, 0),
+        OpCode::Call => (chunk.read(offset + 1) as usize + 1, 1),
+        // The native goes in underneath the arguments.
+        OpCode::ConstantCall => (chunk.read(offset + 2) as usize, 1),
+        OpCode::Invoke | OpCode::InvokeLong => (chunk.read(offset + 1 + width) as usize + 1, 1),
+        // The superclass sits above the receiver and arguments.
+        OpCode::SuperInvoke | OpCode::SuperInvokeLong => {
+            (chunk.read(offset + 1 + width) as usize + 2, 1)
+        }
+    }
+}
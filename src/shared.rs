@@ -0,0 +1,117 @@
+// The pointers and cells the VM shares objects through. By default they are
+// the single-threaded std ones. The `sync` feature swaps in atomic reference
+// counts and locks, so that a VM, its values and everything it has compiled
+// can move to another thread. The names stay the same either way so the rest
+// of the crate doesn't care which build it's in.
+
+#[cfg(not(feature = "sync"))]
+pub use std::cell::RefCell;
+#[cfg(not(feature = "sync"))]
+pub use std::rc::{Rc, Weak};
+
+#[cfg(feature = "sync")]
+pub use std::sync::{Arc as Rc, Weak};
+
+/// Bound on every host object the VM holds: the natives, the output, the
+/// reporter and the debugger. It is `Send` in builds with the `sync` feature
+/// and holds for everything otherwise.
+#[cfg(feature = "sync")]
+pub trait MaybeSend: Send {}
+#[cfg(feature = "sync")]
+impl<T: Send + ?Sized> MaybeSend for T {}
+
+#[cfg(not(feature = "sync"))]
+pub trait MaybeSend {}
+#[cfg(not(feature = "sync"))]
+impl<T: ?Sized> MaybeSend for T {}
+
+/// Like [`MaybeSend`], for things shared between values, such as natives.
+#[cfg(feature = "sync")]
+pub trait MaybeSync: Sync {}
+#[cfg(feature = "sync")]
+impl<T: Sync + ?Sized> MaybeSync for T {}
+
+#[cfg(not(feature = "sync"))]
+pub trait MaybeSync {}
+#[cfg(not(feature = "sync"))]
+impl<T: ?Sized> MaybeSync for T {}
+
+/// Anything the VM can write to: where `print` and traces go.
+pub trait Output: std::io::Write + MaybeSend {}
+impl<T: std::io::Write + MaybeSend> Output for T {}
+
+/// Anything the VM can read lines from: where `readLine` reads.
+pub trait Input: std::io::BufRead + MaybeSend {}
+impl<T: std::io::BufRead + MaybeSend> Input for T {}
+
+// A `RefCell` lookalike over a lock, there only to make the objects `Sync`.
+// Like a `RefCell`, it panics on a borrow that conflicts with one already
+// held rather than waiting for it: within one VM that can only be a borrow
+// taken again while dropping something, which would otherwise deadlock
+// without a word. Anything threads might genuinely share, such as the
+// inline caches of a program several VMs run, uses the `try_` versions.
+#[cfg(feature = "sync")]
+#[derive(Default)]
+pub struct RefCell<T>(std::sync::RwLock<T>);
+
+#[cfg(feature = "sync")]
+impl<T> RefCell<T> {
+    pub fn new(value: T) -> Self {
+        Self(std::sync::RwLock::new(value))
+    }
+
+    pub fn borrow(&self) -> std::sync::RwLockReadGuard<'_, T> {
+        self.try_borrow().expect("already mutably borrowed")
+    }
+
+    pub fn borrow_mut(&self) -> std::sync::RwLockWriteGuard<'_, T> {
+        self.try_borrow_mut().expect("already borrowed")
+    }
+
+    // Like `RefCell`'s, these give an error where `borrow` and `borrow_mut`
+    // would panic. A lock poisoned by a panic elsewhere is used as it is.
+    pub fn try_borrow(&self) -> Result<std::sync::RwLockReadGuard<'_, T>, BorrowError> {
+        match self.0.try_read() {
+            Ok(guard) => Ok(guard),
+            Err(std::sync::TryLockError::Poisoned(err)) => Ok(err.into_inner()),
+            Err(std::sync::TryLockError::WouldBlock) => Err(BorrowError),
+        }
+    }
+
+    pub fn try_borrow_mut(&self) -> Result<std::sync::RwLockWriteGuard<'_, T>, BorrowError> {
+        match self.0.try_write() {
+            Ok(guard) => Ok(guard),
+            Err(std::sync::TryLockError::Poisoned(err)) => Ok(err.into_inner()),
+            Err(std::sync::TryLockError::WouldBlock) => Err(BorrowError),
+        }
+    }
+
+    pub fn replace(&self, value: T) -> T {
+        std::mem::replace(&mut *self.borrow_mut(), value)
+    }
+
+    pub fn take(&self) -> T
+    where
+        T: Default,
+    {
+        self.replace(T::default())
+    }
+}
+
+#[cfg(feature = "sync")]
+impl<T: Clone> Clone for RefCell<T> {
+    fn clone(&self) -> Self {
+        Self::new(self.borrow().clone())
+    }
+}
+
+#[cfg(feature = "sync")]
+impl<T: std::fmt::Debug> std::fmt::Debug for RefCell<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("RefCell").field(&*self.borrow()).finish()
+    }
+}
+
+#[cfg(feature = "sync")]
+#[derive(Debug)]
+pub struct BorrowError;
@@ -0,0 +1,498 @@
+use std::collections::HashMap;
+
+use crate::bound_method::*;
+use crate::class::*;
+use crate::closure::*;
+use crate::function::*;
+use crate::globals::*;
+use crate::instance::*;
+use crate::native::*;
+use crate::serialize::*;
+use crate::shared::*;
+use crate::upvalues::*;
+use crate::value::*;
+
+// Layout of a snapshot, using the integers and strings of .loxb files:
+//
+//   "LOXS" version
+//   global count, then each global name
+//   function, upvalue, class, closure, instance and bound method counts
+//   each function, written as in .loxb files
+//   each class: 0 and its name, or 1 and its name for a host class
+//   each closure's function
+//   each instance's class
+//   each bound method's receiver, closure and unbound flag
+//   each upvalue's value
+//   each Lox class's superclass, initializer, methods and static fields
+//   each closure's upvalues
+//   each instance's frozen flag and fields
+//   defined global count, then each global's slot, assigned flag and value
+//
+// Objects refer to each other by their index among the objects of their
+// kind, so shared objects and cycles come back as they were. Everything is
+// created before anything is filled in; only a bound method needs what it
+// refers to up front. Classes registered from Rust can't be written out, so
+// a snapshot only names them and the restoring VM must have registered a
+// class of the same name.
+const MAGIC: &[u8; 4] = b"LOXS";
+const VERSION: u32 = 5;
+
+const TAG_CLOSURE: u8 = 7;
+const TAG_CLASS: u8 = 8;
+const TAG_INSTANCE: u8 = 9;
+const TAG_BOUND: u8 = 10;
+
+// A global a snapshot brings back: its slot in the restoring VM, whether
+// scripts assigned it, and its value.
+pub type RestoredGlobal = (usize, bool, Value);
+
+// Writes every defined global and everything it reaches. The VM's own
+// natives are left out: the restoring VM has its own.
+pub fn snapshot(values: &[Option<Value>], globals: &GlobalTable) -> Result<Vec<u8>, String> {
+    let saved: Vec<(usize, &Value)> = values
+        .iter()
+        .enumerate()
+        .filter_map(|(slot, value)| Some((slot, value.as_ref()?)))
+        .filter(|(slot, value)| globals.is_assigned(*slot) || !matches!(value, Value::Native(_)))
+        .collect();
+
+    let mut graph = Graph::default();
+    for (_, value) in &saved {
+        graph.visit(value)?;
+    }
+
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    write_u32(&mut out, VERSION);
+    write_usize(&mut out, globals.len())?;
+    for slot in 0..globals.len() {
+        write_str(&mut out, globals.name(slot))?;
+    }
+    graph.write(&mut out)?;
+
+    write_usize(&mut out, saved.len())?;
+    for (slot, value) in saved {
+        write_usize(&mut out, slot)?;
+        out.push(globals.is_assigned(slot) as u8);
+        graph.write_value(&mut out, value)?;
+    }
+    Ok(out)
+}
+
+// Reads a snapshot back. Global names are resolved in `globals`, and host
+// classes are looked up among the restoring VM's `values`. Functions are
+// checked just as when a .loxb file is loaded, and anything else that
+// doesn't hold together is an error rather than something to trip over
+// once the code runs.
+pub fn restore(
+    bytes: &[u8],
+    natives: &NativeTable,
+    globals: &mut GlobalTable,
+    values: &[Option<Value>],
+    drops: &Rc<DropQueue>,
+) -> Result<Vec<RestoredGlobal>, String> {
+    let mut reader = Reader::new(bytes);
+    if reader.take(4)? != MAGIC {
+        return Err("not a snapshot".to_string());
+    }
+    let version = reader.u32()?;
+    if version != VERSION {
+        return Err(format!("unsupported version {version}, expected {VERSION}"));
+    }
+    let count = reader.usize()?;
+    let mut slots = Vec::new();
+    for _ in 0..count {
+        slots.push(globals.resolve(&reader.string()?));
+    }
+
+    let host_class = |name: &str| match globals.lookup(name).and_then(|slot| values.get(slot)) {
+        Some(Some(Value::Class(class))) if class.has_host_methods() => Ok(Rc::clone(class)),
+        _ => Err(format!("no host class named '{name}'")),
+    };
+    let loader = Loader::new(natives, slots.clone());
+    let mut restorer = Restorer::new(natives, drops);
+    restorer.read(&mut reader, &loader, host_class)?;
+
+    let count = reader.usize()?;
+    let mut restored = Vec::new();
+    for _ in 0..count {
+        let slot = reader.usize()?;
+        let Some(&slot) = slots.get(slot) else {
+            return Err(format!("global {slot} out of range"));
+        };
+        let assigned = reader.u8()? != 0;
+        restored.push((slot, assigned, restorer.value(&mut reader)?));
+    }
+    if !reader.at_end() {
+        return Err("trailing bytes after the snapshot".to_string());
+    }
+    Ok(restored)
+}
+
+// The objects of one kind, numbered in the order they were found.
+struct Objects<T> {
+    ids: HashMap<*const T, usize>,
+    items: Vec<Rc<T>>,
+}
+
+impl<T> Default for Objects<T> {
+    fn default() -> Self {
+        Self {
+            ids: HashMap::new(),
+            items: Vec::new(),
+        }
+    }
+}
+
+impl<T> Objects<T> {
+    // True the first time `item` is added.
+    fn add(&mut self, item: &Rc<T>) -> bool {
+        let ptr = Rc::as_ptr(item);
+        if self.ids.contains_key(&ptr) {
+            return false;
+        }
+        self.ids.insert(ptr, self.items.len());
+        self.items.push(Rc::clone(item));
+        true
+    }
+
+    fn id(&self, item: &Rc<T>) -> usize {
+        self.ids[&Rc::as_ptr(item)]
+    }
+}
+
+#[derive(Default)]
+struct Graph {
+    functions: Objects<Function>,
+    upvalues: Objects<Upvalue>,
+    classes: Objects<Class>,
+    closures: Objects<Closure>,
+    instances: Objects<Instance>,
+    bounds: Objects<BoundMethod>,
+}
+
+impl Graph {
+    fn visit(&mut self, root: &Value) -> Result<(), String> {
+        let mut pending = vec![root.clone()];
+        while let Some(value) = pending.pop() {
+            match &value {
+                Value::Func(function) => {
+                    self.functions.add(function);
+                }
+                Value::Closure(closure) => {
+                    if !self.closures.add(closure) {
+                        continue;
+                    }
+                    self.functions.add(&closure.function());
+                    for upvalue in closure.upvalues() {
+                        if !self.upvalues.add(&upvalue) {
+                            continue;
+                        }
+                        // Nothing is on the stack between runs, so every
+                        // variable a closure captured has been closed over.
+                        let Some(captured) = upvalue.closed_value() else {
+                            return Err("a closure still refers to the stack".to_string());
+                        };
+                        pending.push(Value::from(&captured));
+                    }
+                }
+                Value::Class(class) => {
+                    if self.classes.add(class) && !class.has_host_methods() {
+                        pending.extend(class.superclass().map(Value::Class));
+                        pending.extend(class.own_init_method().map(Value::Closure));
+                        let methods = class.methods().into_iter();
+                        pending.extend(methods.map(|(_, method)| Value::Closure(method)));
+                        let statics = class.statics().into_iter();
+                        pending.extend(statics.map(|(_, value)| value));
+                    }
+                }
+                Value::Instance(instance) => {
+                    if self.instances.add(instance) {
+                        pending.push(Value::Class(instance.get_class()));
+                        let fields = instance.fields().into_iter();
+                        pending.extend(fields.map(|(_, value)| value));
+                    }
+                }
+                Value::Bound(bound) => {
+                    if self.bounds.add(bound) {
+                        pending.push(bound.get_receiver());
+                        pending.push(Value::Closure(bound.get_closure()));
+                    }
+                }
+                Value::UserData(data) => return Err(format!("can't snapshot {data}")),
+                Value::Boolean(_)
+                | Value::Number(_)
+                | Value::Nil
+                | Value::Str(_)
+                | Value::Native(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&self, out: &mut Vec<u8>) -> Result<(), String> {
+        write_usize(out, self.functions.items.len())?;
+        write_usize(out, self.upvalues.items.len())?;
+        write_usize(out, self.classes.items.len())?;
+        write_usize(out, self.closures.items.len())?;
+        write_usize(out, self.instances.items.len())?;
+        write_usize(out, self.bounds.items.len())?;
+
+        for function in &self.functions.items {
+            write_function(out, function)?;
+        }
+        for class in &self.classes.items {
+            out.push(class.has_host_methods() as u8);
+            write_str(out, class.name())?;
+        }
+        for closure in &self.closures.items {
+            write_usize(out, self.functions.id(&closure.function()))?;
+        }
+        for instance in &self.instances.items {
+            write_usize(out, self.classes.id(&instance.get_class()))?;
+        }
+        for bound in &self.bounds.items {
+            self.write_value(out, &bound.get_receiver())?;
+            write_usize(out, self.closures.id(&bound.get_closure()))?;
+            out.push(bound.is_unbound() as u8);
+        }
+
+        for upvalue in &self.upvalues.items {
+            let captured = upvalue
+                .closed_value()
+                .map_or(Value::Nil, |slot| Value::from(&slot));
+            self.write_value(out, &captured)?;
+        }
+        for class in &self.classes.items {
+            if class.has_host_methods() {
+                continue;
+            }
+            match class.superclass() {
+                Some(superclass) => {
+                    out.push(1);
+                    write_usize(out, self.classes.id(&superclass))?;
+                }
+                None => out.push(0),
+            }
+            match class.own_init_method() {
+                Some(init) => {
+                    out.push(1);
+                    write_usize(out, self.closures.id(&init))?;
+                }
+                None => out.push(0),
+            }
+            let methods = class.methods();
+            write_usize(out, methods.len())?;
+            for (name, method) in &methods {
+                write_str(out, name)?;
+                write_usize(out, self.closures.id(method))?;
+            }
+            let statics = class.statics();
+            write_usize(out, statics.len())?;
+            for (name, value) in &statics {
+                write_str(out, name)?;
+                self.write_value(out, value)?;
+            }
+        }
+        for closure in &self.closures.items {
+            let upvalues = closure.upvalues();
+            write_usize(out, upvalues.len())?;
+            for upvalue in &upvalues {
+                write_usize(out, self.upvalues.id(upvalue))?;
+            }
+        }
+        for instance in &self.instances.items {
+            out.push(instance.is_frozen() as u8);
+            let fields = instance.fields();
+            write_usize(out, fields.len())?;
+            for (name, value) in &fields {
+                write_str(out, name)?;
+                self.write_value(out, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_value(&self, out: &mut Vec<u8>, value: &Value) -> Result<(), String> {
+        let (tag, id) = match value {
+            Value::Nil => (TAG_NIL, None),
+            Value::Boolean(false) => (TAG_FALSE, None),
+            Value::Boolean(true) => (TAG_TRUE, None),
+            Value::Number(n) => {
+                out.push(TAG_NUMBER);
+                out.extend_from_slice(&n.to_le_bytes());
+                return Ok(());
+            }
+            Value::Str(s) => {
+                out.push(TAG_STRING);
+                return write_str(out, s);
+            }
+            Value::Native(native) => {
+                out.push(TAG_NATIVE);
+                return write_str(out, native.name());
+            }
+            Value::Func(function) => (TAG_FUNCTION, Some(self.functions.id(function))),
+            Value::Closure(closure) => (TAG_CLOSURE, Some(self.closures.id(closure))),
+            Value::Class(class) => (TAG_CLASS, Some(self.classes.id(class))),
+            Value::Instance(instance) => (TAG_INSTANCE, Some(self.instances.id(instance))),
+            Value::Bound(bound) => (TAG_BOUND, Some(self.bounds.id(bound))),
+            Value::UserData(data) => return Err(format!("can't snapshot {data}")),
+        };
+        out.push(tag);
+        if let Some(id) = id {
+            write_usize(out, id)?;
+        }
+        Ok(())
+    }
+}
+
+struct Restorer<'a> {
+    natives: &'a NativeTable,
+    drops: &'a Rc<DropQueue>,
+    functions: Vec<Rc<Function>>,
+    upvalues: Vec<Rc<Upvalue>>,
+    classes: Vec<Rc<Class>>,
+    closures: Vec<Rc<Closure>>,
+    instances: Vec<Rc<Instance>>,
+    bounds: Vec<Rc<BoundMethod>>,
+}
+
+impl<'a> Restorer<'a> {
+    fn new(natives: &'a NativeTable, drops: &'a Rc<DropQueue>) -> Self {
+        Self {
+            natives,
+            drops,
+            functions: Vec::new(),
+            upvalues: Vec::new(),
+            classes: Vec::new(),
+            closures: Vec::new(),
+            instances: Vec::new(),
+            bounds: Vec::new(),
+        }
+    }
+
+    fn read<F>(&mut self, reader: &mut Reader, loader: &Loader, host_class: F) -> Result<(), String>
+    where
+        F: Fn(&str) -> Result<Rc<Class>, String>,
+    {
+        let functions = reader.usize()?;
+        let upvalues = reader.usize()?;
+        let classes = reader.usize()?;
+        let closures = reader.usize()?;
+        let instances = reader.usize()?;
+        let bounds = reader.usize()?;
+
+        for _ in 0..functions {
+            self.functions.push(Rc::new(loader.function(reader)?));
+        }
+        let mut lox_classes = Vec::new();
+        for _ in 0..classes {
+            let host = reader.u8()? != 0;
+            let name = reader.string()?;
+            let class = if host {
+                host_class(&name)?
+            } else {
+                let class = Rc::new(Class::new(name, self.drops));
+                lox_classes.push(Rc::clone(&class));
+                class
+            };
+            self.classes.push(class);
+        }
+        for _ in 0..closures {
+            let function = Rc::clone(get(&self.functions, reader)?);
+            self.closures.push(Rc::new(Closure::new(function)));
+        }
+        for _ in 0..instances {
+            let class = Rc::clone(get(&self.classes, reader)?);
+            self.instances.push(Rc::new(Instance::new(class)));
+        }
+        for _ in 0..bounds {
+            let receiver = self.value(reader)?;
+            let method = get(&self.closures, reader)?;
+            let bound = if reader.u8()? != 0 {
+                BoundMethod::unbound(&receiver, method)
+            } else {
+                BoundMethod::new(&receiver, method)
+            };
+            self.bounds.push(Rc::new(bound));
+        }
+
+        for _ in 0..upvalues {
+            let upvalue = Upvalue::new(0);
+            upvalue.close(new_slot(self.value(reader)?));
+            self.upvalues.push(Rc::new(upvalue));
+        }
+        for class in &lox_classes {
+            if reader.u8()? != 0 {
+                let superclass = get(&self.classes, reader)?;
+                if superclass.is_subclass_of(class) {
+                    return Err(format!("{class} inherits from itself"));
+                }
+                class.set_superclass(superclass);
+            }
+            if reader.u8()? != 0 {
+                class.set_init_method(Rc::clone(get(&self.closures, reader)?));
+            }
+            for _ in 0..reader.usize()? {
+                let name = reader.string()?;
+                let method = Value::Closure(Rc::clone(get(&self.closures, reader)?));
+                class.add_method(&name, &method);
+            }
+            for _ in 0..reader.usize()? {
+                let name = reader.string()?;
+                class.set_static(name, &self.value(reader)?);
+            }
+        }
+        for closure in &self.closures {
+            let count = reader.usize()?;
+            if count != closure.function().upvalues() {
+                return Err(format!("{closure} has the wrong number of upvalues"));
+            }
+            for _ in 0..count {
+                closure.push_upvalue(get(&self.upvalues, reader)?);
+            }
+        }
+        for instance in &self.instances {
+            if reader.u8()? != 0 {
+                instance.freeze();
+            }
+            for _ in 0..reader.usize()? {
+                let name = reader.string()?;
+                instance.set_field(name, &self.value(reader)?);
+            }
+        }
+        Ok(())
+    }
+
+    fn value(&self, reader: &mut Reader) -> Result<Value, String> {
+        Ok(match reader.u8()? {
+            TAG_NIL => Value::Nil,
+            TAG_FALSE => Value::Boolean(false),
+            TAG_TRUE => Value::Boolean(true),
+            TAG_NUMBER => Value::Number(reader.f64()?),
+            TAG_STRING => Value::Str(reader.string()?),
+            TAG_NATIVE => {
+                let name = reader.string()?;
+                match self.natives.get(&name) {
+                    Some(native) => Value::Native(Rc::clone(native)),
+                    None => return Err(format!("unknown native function '{name}'")),
+                }
+            }
+            TAG_FUNCTION => Value::Func(Rc::clone(get(&self.functions, reader)?)),
+            TAG_CLOSURE => Value::Closure(Rc::clone(get(&self.closures, reader)?)),
+            TAG_CLASS => Value::Class(Rc::clone(get(&self.classes, reader)?)),
+            TAG_INSTANCE => Value::Instance(Rc::clone(get(&self.instances, reader)?)),
+            TAG_BOUND => Value::Bound(Rc::clone(get(&self.bounds, reader)?)),
+            tag => return Err(format!("unknown value tag {tag}")),
+        })
+    }
+}
+
+// The object a reference in the snapshot points at. Bound methods can only
+// refer to objects created before them.
+fn get<'a, T>(objects: &'a [Rc<T>], reader: &mut Reader) -> Result<&'a Rc<T>, String> {
+    let index = reader.usize()?;
+    objects
+        .get(index)
+        .ok_or_else(|| format!("reference to object {index}, which doesn't exist yet"))
+}
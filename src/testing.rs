@@ -0,0 +1,285 @@
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::builder::*;
+use crate::config::*;
+use crate::error::*;
+
+/// What became of one test script: empty `failures` means it passed.
+#[derive(Debug)]
+pub struct TestResult {
+    pub path: PathBuf,
+    pub failures: Vec<String>,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Runs a script written in the style of the Crafting Interpreters test
+/// suite and returns how it fell short of its comments. `// expect: TEXT`
+/// is a line it should print, `// expect runtime error: MESSAGE` is the
+/// runtime error it should stop with on that line, and `// Error at 'x':
+/// MESSAGE` or `// [line N] Error at 'x': MESSAGE` is a compile error it
+/// should be rejected with. Scripts run deterministically, so anything that
+/// reads the clock or calls `random()` prints the same thing every time.
+///
+/// `// options: ...` changes how the script runs: `optimize` turns the
+/// optimizer on, `division-by-zero=error` makes `x / 0` an error, and
+/// `loxb` writes the code out to the .loxb format and runs what loads back.
+/// A line ending in `// repl` runs what follows as a separate entry in the
+/// same VM, the way the REPL does, even after an error. `// snapshot` does
+/// the same in a fresh VM restored from a snapshot of the old one.
+///
+/// ```
+/// let failures = lox_bytecode::run_test("print 1 + 2; // expect: 3\nprint nil; // expect: 4");
+/// assert_eq!(failures, ["Expected output '4' on line 2 and got 'nil'."]);
+///
+/// let script = "var a = 1; // snapshot\nprint a; // expect: 1";
+/// assert!(lox_bytecode::run_test(script).is_empty());
+/// ```
+pub fn run_test(source: &str) -> Vec<String> {
+    let expected = Expectations::parse(source);
+    let output = CapturedOutput::default();
+    let new_vm = || {
+        VmBuilder::new()
+            .config(expected.config.clone())
+            .deterministic()
+            .output(output.clone())
+            .build()
+    };
+    let mut vm = new_vm();
+
+    let mut failures = expected.failures.clone();
+    let mut compile_errors = Vec::new();
+    let mut runtime_errors = Vec::new();
+    for (part, split) in parts(source, &expected.splits) {
+        if split == Some(Split::Snapshot) {
+            let restored = vm.snapshot().and_then(|bytes| {
+                let mut restored = new_vm();
+                restored.restore(&bytes).map(|()| restored)
+            });
+            match restored {
+                Ok(restored) => vm = restored,
+                Err(err) => {
+                    failures.push(format!("Unexpected error: {err}"));
+                    break;
+                }
+            }
+        }
+        let result = if expected.loxb {
+            vm.compile_binary(&part)
+                .and_then(|bytes| vm.run_binary(&bytes))
+        } else {
+            vm.interpret(&part)
+        };
+        match &result {
+            Err(LoxError::Compile(err)) => {
+                for diagnostic in &err.diagnostics {
+                    let mut error = diagnostic.to_string();
+                    // The suite doesn't know about columns.
+                    if let (Some(colon), Some(bracket)) = (error.find(':'), error.find(']')) {
+                        if colon < bracket {
+                            error.replace_range(colon..bracket, "");
+                        }
+                    }
+                    compile_errors.push(error);
+                }
+            }
+            Err(LoxError::Runtime(err)) => {
+                let line = err.trace.iter().find_map(|frame| frame.line);
+                runtime_errors.push((err.message.clone(), line));
+            }
+            Err(LoxError::BudgetExceeded(err)) => {
+                failures.push(format!("Unexpected error: {}", err.message))
+            }
+            Err(LoxError::Exit(_)) => break,
+            Ok(()) => {}
+        }
+    }
+
+    if compile_errors != expected.compile_errors {
+        for error in &expected.compile_errors {
+            if !compile_errors.contains(error) {
+                failures.push(format!("Missing expected error: {error}"));
+            }
+        }
+        for error in &compile_errors {
+            if !expected.compile_errors.contains(error) {
+                failures.push(format!("Unexpected error: {error}"));
+            }
+        }
+    }
+
+    let errors = expected.runtime_errors.len().max(runtime_errors.len());
+    for index in 0..errors {
+        match (
+            expected.runtime_errors.get(index),
+            runtime_errors.get(index),
+        ) {
+            (Some(expected), Some(actual)) if expected != actual => failures.push(format!(
+                "Expected runtime error '{}' on line {} and got '{}' on line {}.",
+                expected.0,
+                expected.1.unwrap_or_default(),
+                actual.0,
+                actual.1.unwrap_or_default()
+            )),
+            (Some((message, _)), None) => {
+                failures.push(format!("Expected runtime error '{message}' and got none."))
+            }
+            (None, Some((message, _))) => {
+                failures.push(format!("Unexpected runtime error '{message}'."))
+            }
+            _ => {}
+        }
+    }
+
+    let printed = output.text();
+    let mut printed = printed.lines();
+    for (line, text) in &expected.output {
+        match printed.next() {
+            Some(actual) if actual == text => {}
+            Some(actual) => failures.push(format!(
+                "Expected output '{text}' on line {line} and got '{actual}'."
+            )),
+            None => failures.push(format!("Missing expected output '{text}' on line {line}.")),
+        }
+    }
+    for extra in printed {
+        failures.push(format!("Got output '{extra}' when none was expected."));
+    }
+    failures
+}
+
+// The pieces of `source` between the `// repl` and `// snapshot` lines,
+// each with the split that comes before it. Every piece keeps the earlier
+// lines as blank ones, so errors are reported on the script's own lines.
+fn parts(source: &str, splits: &[(usize, Split)]) -> Vec<(String, Option<Split>)> {
+    let lines: Vec<&str> = source.split_inclusive('\n').collect();
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut before = None;
+    let ends = splits.iter().map(|&(line, split)| (line, Some(split)));
+    for (end, split) in ends.chain([(lines.len(), None)]) {
+        parts.push(("\n".repeat(start) + &lines[start..end].concat(), before));
+        start = end;
+        before = split;
+    }
+    parts
+}
+
+/// Runs every `.lox` file under `dir`, in sorted order, with [`run_test`].
+/// This is what `lox-bytecode test` runs, and it can equally be called from
+/// an integration test to keep a directory of scripts passing.
+pub fn run_tests(dir: &Path) -> io::Result<Vec<TestResult>> {
+    let mut paths = Vec::new();
+    find_scripts(dir, &mut paths)?;
+    paths.sort();
+    paths
+        .into_iter()
+        .map(|path| {
+            let source = std::fs::read_to_string(&path)?;
+            let failures = run_test(&source);
+            Ok(TestResult { path, failures })
+        })
+        .collect()
+}
+
+fn find_scripts(dir: &Path, paths: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_scripts(&path, paths)?;
+        } else if path.extension().is_some_and(|ext| ext == "lox") {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Split {
+    Repl,
+    Snapshot,
+}
+
+#[derive(Default)]
+struct Expectations {
+    output: Vec<(usize, String)>,
+    compile_errors: Vec<String>,
+    runtime_errors: Vec<(String, Option<usize>)>,
+    config: VmConfig,
+    loxb: bool,
+    // The lines that end a piece of the script, counted from 1.
+    splits: Vec<(usize, Split)>,
+    // Options that weren't understood.
+    failures: Vec<String>,
+}
+
+impl Expectations {
+    fn parse(source: &str) -> Self {
+        let mut expected = Self::default();
+        for (index, text) in source.lines().enumerate() {
+            let line = index + 1;
+            let Some((_, comment)) = text.split_once("// ") else {
+                continue;
+            };
+            if let Some(output) = comment.strip_prefix("expect: ") {
+                expected.output.push((line, output.to_string()));
+            } else if let Some(message) = comment.strip_prefix("expect runtime error: ") {
+                expected
+                    .runtime_errors
+                    .push((message.to_string(), Some(line)));
+            } else if comment.starts_with("Error") {
+                expected
+                    .compile_errors
+                    .push(format!("[line {line}] {comment}"));
+            } else if comment.starts_with("[line ") && comment.contains("] Error") {
+                expected.compile_errors.push(comment.to_string());
+            } else if let Some(options) = comment.strip_prefix("options: ") {
+                for option in options.split_whitespace() {
+                    match option {
+                        "optimize" => expected.config.optimize = true,
+                        "division-by-zero=error" => {
+                            expected.config.division_by_zero = DivisionByZero::Error
+                        }
+                        "loxb" => expected.loxb = true,
+                        _ => expected
+                            .failures
+                            .push(format!("Unknown option '{option}' on line {line}.")),
+                    }
+                }
+            } else if comment.trim_end() == "repl" {
+                expected.splits.push((line, Split::Repl));
+            } else if comment.trim_end() == "snapshot" {
+                expected.splits.push((line, Split::Snapshot));
+            }
+        }
+        expected
+    }
+}
+
+/// An output that keeps what a script prints, for tests and tools to look
+/// at. Clones share the same text, so keep one and give the VM the other.
+#[derive(Clone, Default)]
+pub struct CapturedOutput(Arc<Mutex<Vec<u8>>>);
+
+impl CapturedOutput {
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+impl Write for CapturedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -1,8 +1,14 @@
+use crate::diagnostic::Span;
+
+// `column` is where the token starts on `line`, counting characters from 1,
+// and `span` is the part of the source it covers on that line.
 #[derive(Debug)]
 pub struct Token {
     pub ttype: TokenType,
     pub lexeme: String,
     pub line: usize,
+    pub column: usize,
+    pub span: Span,
 }
 
 impl Default for Token {
@@ -11,6 +17,8 @@ impl Default for Token {
             ttype: TokenType::Undefined,
             lexeme: String::new(),
             line: 0,
+            column: 0,
+            span: Span::default(),
         }
     }
 }
@@ -21,6 +29,8 @@ impl Clone for Token {
             ttype: self.ttype,
             lexeme: self.lexeme.clone(),
             line: self.line,
+            column: self.column,
+            span: self.span,
         }
     }
 }
@@ -31,6 +41,8 @@ impl Token {
             ttype: TokenType::Undefined,
             lexeme: s.to_string(),
             line: 0,
+            column: 0,
+            span: Span::default(),
         }
     }
 }
@@ -75,6 +87,7 @@ pub enum TokenType {
     True,
     Var,
     While,
+    Comment,
     Error,
     Eof,
     Undefined,
@@ -0,0 +1,43 @@
+use std::io::Write;
+
+use crate::chunk::*;
+use crate::shared::*;
+use crate::value::*;
+
+/// Writes the stack and the disassembled instruction before each instruction
+/// the VM executes. With a function filter, only instructions in frames of
+/// those functions are written; the top level is called "script".
+pub struct Tracer {
+    out: Box<dyn Output>,
+    functions: Vec<String>,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Output>) -> Self {
+        Self {
+            out,
+            functions: Vec::new(),
+        }
+    }
+
+    pub fn add_function(&mut self, name: &str) {
+        self.functions.push(name.to_string());
+    }
+
+    pub fn trace(&mut self, function: &str, stack: &[Slot], chunk: &Chunk, ip: usize) {
+        if !self.functions.is_empty() && !self.functions.iter().any(|f| f == function) {
+            return;
+        }
+        let mut line = String::from("          ");
+        for slot in stack {
+            line += &format!("[ {slot} ]");
+        }
+        let (instruction, _) = chunk.format_instruction(ip);
+        // A trace is best effort; a failing writer shouldn't stop the script.
+        let _ = writeln!(self.out, "{line}\n{instruction}");
+    }
+
+    pub fn flush(&mut self) {
+        let _ = self.out.flush();
+    }
+}
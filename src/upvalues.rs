@@ -1,25 +1,35 @@
-use std::cell::RefCell;
-use std::rc::Rc;
-
+use crate::shared::*;
 use crate::value::*;
 
+// An upvalue is open while the variable it captures still lives on the VM
+// stack at `slot`. Once that slot goes away the value is moved into `closed`.
 #[derive(Debug)]
 pub struct Upvalue {
-    location: Rc<RefCell<Value>>,
+    slot: usize,
+    closed: RefCell<Option<Slot>>,
 }
 
 impl Upvalue {
-    pub fn new(value: &Rc<RefCell<Value>>) -> Self {
+    pub fn new(slot: usize) -> Self {
         Self {
-            location: Rc::clone(value),
+            slot,
+            closed: RefCell::new(None),
         }
     }
 
-    pub fn value(&self) -> Rc<RefCell<Value>> {
-        Rc::clone(&self.location)
+    pub fn slot(&self) -> usize {
+        self.slot
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.closed.borrow().is_none()
+    }
+
+    pub fn closed_value(&self) -> Option<Slot> {
+        self.closed.borrow().clone()
     }
 
-    pub fn set(&self, value: &Rc<RefCell<Value>>) {
-        *self.location.borrow_mut() = value.borrow().clone()
+    pub fn close(&self, value: Slot) {
+        self.closed.replace(Some(value));
     }
 }
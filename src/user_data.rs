@@ -0,0 +1,57 @@
+use std::any::{Any, TypeId};
+use std::fmt::{Debug, Display, Formatter};
+
+use crate::error::*;
+use crate::shared::*;
+use crate::value::*;
+
+#[cfg(not(feature = "sync"))]
+type AnyData = dyn Any;
+#[cfg(feature = "sync")]
+type AnyData = dyn Any + Send + Sync;
+
+/// A Rust value handed to scripts, such as a database handle or a game
+/// entity. Scripts can pass it around and call the methods the host has
+/// registered for its type with `VM::register_method`; the host gets it
+/// back with `Value::user_data`. Methods only get `&T`, so state that
+/// scripts change belongs in a `Cell`, `RefCell` or `Mutex`.
+pub struct UserData {
+    data: Box<AnyData>,
+}
+
+impl UserData {
+    pub fn new<T: Any + MaybeSend + MaybeSync>(data: T) -> Self {
+        Self {
+            data: Box::new(data),
+        }
+    }
+
+    /// The wrapped value, if it is a `T`.
+    pub fn get<T: Any>(&self) -> Option<&T> {
+        self.data.downcast_ref()
+    }
+
+    /// The type of the wrapped value, which its methods are keyed by.
+    pub fn data_type(&self) -> TypeId {
+        (*self.data).type_id()
+    }
+}
+
+impl Debug for UserData {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "<userdata>")
+    }
+}
+
+impl Display for UserData {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "<userdata>")
+    }
+}
+
+// A method the host registered for a userdata type, called with the
+// receiver and the arguments.
+#[cfg(not(feature = "sync"))]
+pub type UserMethod = dyn Fn(&UserData, &[Value]) -> Result<Value, NativeError>;
+#[cfg(feature = "sync")]
+pub type UserMethod = dyn Fn(&UserData, &[Value]) -> Result<Value, NativeError> + Send + Sync;
@@ -1,18 +1,31 @@
 use std::any::Any;
-use std::cell::RefCell;
 use std::cmp::Ordering;
 use std::fmt::{Debug, Display, Formatter};
-use std::ops::{Add, Div, Mul, Neg, Sub};
-use std::rc::Rc;
 
 use crate::bound_method::*;
 use crate::class::*;
 use crate::closure::*;
+use crate::error::*;
 use crate::function::*;
 use crate::instance::*;
+use crate::shared::*;
+use crate::user_data::*;
+use crate::vm::VM;
 
-pub trait NativeFunc {
-    fn call(&self, arg_count: usize, args: &[Rc<RefCell<Value>>]) -> Value;
+/// A function written in Rust that scripts can call. The VM checks the
+/// argument count against `arity` before calling it.
+pub trait NativeFunc: MaybeSend + MaybeSync {
+    fn name(&self) -> &str;
+    fn arity(&self) -> usize;
+    /// Whether the native takes any number of arguments past `arity`.
+    fn variadic(&self) -> bool {
+        false
+    }
+    fn call(&self, args: &[Value]) -> Result<Value, NativeError>;
+    /// What the VM actually calls, for natives that need to look at it.
+    fn call_with_vm(&self, _vm: &VM, args: &[Value]) -> Result<Value, NativeError> {
+        self.call(args)
+    }
 }
 
 impl Debug for dyn NativeFunc {
@@ -21,6 +34,42 @@ impl Debug for dyn NativeFunc {
     }
 }
 
+/// What the VM keeps in its stack slots and captured upvalues.
+#[cfg(not(feature = "nan_boxing"))]
+pub type Slot = Value;
+#[cfg(feature = "nan_boxing")]
+pub type Slot = crate::nanbox::NanBox;
+
+#[cfg(not(feature = "nan_boxing"))]
+#[inline]
+pub fn new_slot(value: Value) -> Slot {
+    value
+}
+
+#[cfg(feature = "nan_boxing")]
+#[inline]
+pub fn new_slot(value: Value) -> Slot {
+    Slot::from(value)
+}
+
+/// A Lox value.
+///
+/// Rust numbers, strings and booleans convert into values with `From`, and
+/// back out with `TryFrom`, which fails with a [`NativeError`] naming the
+/// type it found. That lets a native use `?` on its arguments:
+///
+/// ```
+/// use lox_bytecode::{NativeError, Value};
+///
+/// fn repeat(args: &[Value]) -> Result<Value, NativeError> {
+///     let text: &str = (&args[0]).try_into()?;
+///     let times: f64 = (&args[1]).try_into()?;
+///     Ok(text.repeat(times as usize).into())
+/// }
+///
+/// assert_eq!(repeat(&["ab".into(), 2.0.into()]).unwrap(), Value::from("abab"));
+/// assert!(repeat(&[Value::Nil, 2.0.into()]).is_err());
+/// ```
 #[derive(Debug)]
 pub enum Value {
     Boolean(bool),
@@ -33,6 +82,7 @@ pub enum Value {
     Class(Rc<Class>),
     Instance(Rc<Instance>),
     Bound(Rc<BoundMethod>),
+    UserData(Rc<UserData>),
 }
 
 impl PartialOrd for Value {
@@ -53,11 +103,19 @@ impl PartialEq for Value {
             (Value::Str(a), Value::Str(b)) => a.cmp(b) == Ordering::Equal,
             (Value::Func(a), Value::Func(b)) => Rc::ptr_eq(a, b),
             (Value::Class(a), Value::Class(b)) => Rc::ptr_eq(a, b),
-            (Value::Native(a), Value::Native(b)) => a.type_id() == b.type_id(),
+            (Value::Native(a), Value::Native(b)) => Rc::ptr_eq(a, b),
+            (Value::Closure(a), Value::Closure(b)) => Rc::ptr_eq(a, b),
             (Value::Number(a), Value::Number(b)) => a == b,
             (Value::Boolean(a), Value::Boolean(b)) => a == b,
             (Value::Instance(a), Value::Instance(b)) => Rc::ptr_eq(a, b),
-            (Value::Bound(a), Value::Bound(b)) => Rc::ptr_eq(a, b),
+            // `obj.method` binds a new method each time, so two of them are
+            // the same if they call the same closure on the same receiver.
+            (Value::Bound(a), Value::Bound(b)) => {
+                Rc::ptr_eq(&a.get_closure(), &b.get_closure())
+                    && a.is_unbound() == b.is_unbound()
+                    && a.get_receiver() == b.get_receiver()
+            }
+            (Value::UserData(a), Value::UserData(b)) => Rc::ptr_eq(a, b),
             _ => false,
         }
     }
@@ -76,6 +134,123 @@ impl Clone for Value {
             Value::Class(c) => Value::Class(Rc::clone(c)),
             Value::Instance(i) => Value::Instance(Rc::clone(i)),
             Value::Bound(b) => Value::Bound(Rc::clone(b)),
+            Value::UserData(u) => Value::UserData(Rc::clone(u)),
+        }
+    }
+}
+
+impl From<&Value> for Value {
+    fn from(value: &Value) -> Self {
+        value.clone()
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Value::Number(n)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(n: i32) -> Self {
+        Value::Number(n.into())
+    }
+}
+
+impl From<u32> for Value {
+    fn from(n: u32) -> Self {
+        Value::Number(n.into())
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Boolean(b)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Str(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::Str(s)
+    }
+}
+
+// None becomes nil.
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::Nil, Into::into)
+    }
+}
+
+fn type_error(expected: &str, value: &Value) -> NativeError {
+    NativeError::new(format!(
+        "Expected a {expected} but got {}.",
+        value.type_name()
+    ))
+}
+
+impl TryFrom<&Value> for f64 {
+    type Error = NativeError;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Number(n) => Ok(*n),
+            _ => Err(type_error("number", value)),
+        }
+    }
+}
+
+impl TryFrom<&Value> for bool {
+    type Error = NativeError;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Boolean(b) => Ok(*b),
+            _ => Err(type_error("boolean", value)),
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a Value> for &'a str {
+    type Error = NativeError;
+
+    fn try_from(value: &'a Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Str(s) => Ok(s),
+            _ => Err(type_error("string", value)),
+        }
+    }
+}
+
+impl TryFrom<Value> for f64 {
+    type Error = NativeError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        f64::try_from(&value)
+    }
+}
+
+impl TryFrom<Value> for bool {
+    type Error = NativeError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        bool::try_from(&value)
+    }
+}
+
+impl TryFrom<Value> for String {
+    type Error = NativeError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Str(s) => Ok(s),
+            _ => Err(type_error("string", &value)),
         }
     }
 }
@@ -93,74 +268,98 @@ impl Display for Value {
             Value::Class(klass) => write!(f, "{klass}"),
             Value::Instance(instance) => write!(f, "{instance}"),
             Value::Bound(bound_method) => write!(f, "{bound_method}"),
+            Value::UserData(data) => write!(f, "{data}"),
         }
     }
 }
 
-impl Add for &Value {
-    type Output = Value;
-
-    fn add(self, other: &Value) -> Value {
-        match (self, other) {
-            (&Value::Number(a), &Value::Number(b)) => Value::Number(a + b),
-            _ => panic!("Invalid operation"),
+impl Value {
+    #[cfg(not(feature = "nan_boxing"))]
+    pub fn as_number(&self) -> Option<f64> {
+        if let Value::Number(n) = self {
+            Some(*n)
+        } else {
+            None
         }
     }
-}
-
-impl Sub for &Value {
-    type Output = Value;
 
-    fn sub(self, other: &Value) -> Value {
-        match (self, other) {
-            (&Value::Number(a), &Value::Number(b)) => Value::Number(a - b),
-            _ => panic!("Invalid operation"),
+    /// The name of the value's type, as error messages print it.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Boolean(_) => "boolean",
+            Value::Number(_) => "number",
+            Value::Nil => "nil",
+            Value::Str(_) => "string",
+            Value::Func(_) | Value::Native(_) | Value::Closure(_) | Value::Bound(_) => "function",
+            Value::Class(_) => "class",
+            Value::Instance(_) => "instance",
+            Value::UserData(_) => "userdata",
         }
     }
-}
 
-impl Mul for &Value {
-    type Output = Value;
+    /// Wraps a Rust value for scripts to hold; see [`UserData`].
+    pub fn new_user_data<T: Any + MaybeSend + MaybeSync>(data: T) -> Self {
+        Value::UserData(Rc::new(UserData::new(data)))
+    }
 
-    fn mul(self, other: &Value) -> Value {
-        match (self, other) {
-            (&Value::Number(a), &Value::Number(b)) => Value::Number(a * b),
-            _ => panic!("Invalid operation"),
+    /// The Rust value inside a userdata value, if it is one holding a `T`.
+    pub fn user_data<T: Any>(&self) -> Option<&T> {
+        match self {
+            Value::UserData(data) => data.get(),
+            _ => None,
         }
     }
-}
-
-impl Div for &Value {
-    type Output = Value;
 
-    fn div(self, other: &Value) -> Value {
-        match (self, other) {
-            (&Value::Number(a), &Value::Number(b)) => Value::Number(a / b),
-            _ => panic!("Invalid operation"),
+    /// The field `name` of an instance, or None if this isn't an instance
+    /// or has no such field.
+    pub fn get_field(&self, name: &str) -> Option<Value> {
+        match self {
+            Value::Instance(instance) => instance.get_field(name),
+            _ => None,
         }
     }
-}
-
-impl Neg for &Value {
-    type Output = Value;
 
-    fn neg(self) -> Value {
+    /// Sets the field `name` of an instance. The host can still set fields
+    /// of an instance it has frozen.
+    pub fn set_field(&self, name: &str, value: Value) -> Result<(), NativeError> {
         match self {
-            &Value::Number(a) => Value::Number(-a),
-            _ => panic!("Invalid operation"),
+            Value::Instance(instance) => {
+                instance.set_field(name, &value);
+                Ok(())
+            }
+            _ => Err(NativeError::new("Only instances have fields.")),
         }
     }
-}
 
-impl Value {
-    pub fn is_number(&self) -> bool {
-        matches!(self, Value::Number(_))
+    /// Stops scripts from setting fields of an instance, for handing them
+    /// data they should only read. It can't be undone.
+    ///
+    /// ```
+    /// use lox_bytecode::VM;
+    ///
+    /// let mut vm = VM::new();
+    /// vm.interpret("class Config {} var config = Config(); config.debug = true;").unwrap();
+    /// vm.get_global("config").unwrap().freeze().unwrap();
+    /// assert!(vm.interpret("config.debug = false;").is_err());
+    /// ```
+    pub fn freeze(&self) -> Result<(), NativeError> {
+        match self {
+            Value::Instance(instance) => {
+                instance.freeze();
+                Ok(())
+            }
+            _ => Err(NativeError::new(format!(
+                "Can't freeze {}, only instances.",
+                self.type_name()
+            ))),
+        }
     }
 
     pub fn is_falsey(&self) -> bool {
         matches!(self, Value::Nil | Value::Boolean(false))
     }
 
+    #[cfg(not(feature = "nan_boxing"))]
     pub fn is_string(&self) -> bool {
         matches!(self, Value::Str(_))
     }
@@ -182,12 +381,11 @@ impl ValueArray {
         count
     }
 
-    #[cfg(any(feature = "debug_trace_execution", feature = "debug_print_code"))]
-    pub fn print_value(&self, which: usize) {
-        print!("{}", self.values[which]);
-    }
-
     pub fn read_value(&self, which: usize) -> &Value {
         &self.values[which]
     }
+
+    pub fn values(&self) -> &[Value] {
+        &self.values
+    }
 }
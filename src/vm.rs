@@ -1,562 +1,1514 @@
-use std::cell::RefCell;
-use std::collections::{hash_map::Entry, HashMap};
-use std::rc::Rc;
+use std::any::{Any, TypeId};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::{
-    bound_method::*, chunk::*, class::*, closure::*, compiler::*, error::*, instance::*, native::*,
-    value::*,
+    asm::*, bound_method::*, chunk::*, class::*, closure::*, compiler::*, config::*, coverage::*,
+    debugger::*, diagnostic::*, error::*, function::*, globals::*, handle::*, hooks::*,
+    instance::*, memory::*, native::*, profile::*, program::*, serialize::*, shared::*,
+    snapshot::*, trace::*, upvalues::*, user_data::*, value::*,
 };
 
+const TIMEOUT_CHECK_INTERVAL: usize = 1024;
+
 enum Operands {
     TwoNumbers,
     NumbersOrStrings,
+    // Two numbers, or an instance whose class defines `compareTo(other)` on
+    // the left.
+    Comparable,
 }
 
+// The whole point of the `sync` feature; fails to compile if anything the VM
+// owns stops being `Send`.
+#[cfg(feature = "sync")]
+const _: fn() = || {
+    fn assert_send<T: Send>() {}
+    assert_send::<VM>();
+    assert_send::<Value>();
+    assert_send::<Function>();
+};
+
 pub struct VM {
-    stack: Vec<Rc<RefCell<Value>>>,
+    stack: Vec<Slot>,
     frames: Vec<CallFrame>,
-    globals: HashMap<String, Value>,
+    ip: usize,
+    chunk: Rc<Chunk>,
+    globals: Vec<Option<Slot>>,
+    global_names: Rc<RefCell<GlobalTable>>,
+    natives: Rc<NativeTable>,
+    open_upvalues: Vec<Rc<Upvalue>>,
+    method_epoch: usize,
+    config: VmConfig,
+    fuel: Option<usize>,
+    deadline: Option<Instant>,
+    ticks: usize,
+    handle: VmHandle,
+    memory: Arc<MemoryStats>,
+    debugger: Option<Box<dyn Debugger>>,
+    hooks: Option<Box<dyn Hooks>>,
+    breakpoints: HashSet<usize>,
+    stepping: bool,
+    debug_position: (usize, usize),
+    // How many frames `run` returns at, which is more than none while it
+    // runs a call made from inside an instruction.
+    base_frames: usize,
+    profile: Option<Profile>,
+    coverage: Option<Coverage>,
+    reporter: Option<Box<dyn Reporter>>,
+    tracer: Option<Tracer>,
+    file: Option<Rc<str>>,
+    out: Box<dyn Output>,
+    // Methods registered for userdata, by the type they hold.
+    user_methods: HashMap<TypeId, HashMap<String, (usize, Rc<UserMethod>)>>,
+    // Dropped instances of the classes made here, waiting for `__drop()`.
+    drops: Rc<DropQueue>,
+    finalizing: bool,
 }
 
 #[derive(Debug)]
 struct CallFrame {
     closure: Rc<Closure>, // index into VM.stack
-    ip: RefCell<usize>,
+    ip: usize,
     slots: usize,
 }
 
-impl CallFrame {
-    fn inc(&self, amount: usize) {
-        *self.ip.borrow_mut() += amount;
-    }
-
-    fn dec(&self, amount: usize) {
-        *self.ip.borrow_mut() -= amount;
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl VM {
+    /// A VM with the default configuration and the built-in natives.
     pub fn new() -> Self {
+        Self::with_config(VmConfig::default())
+    }
+
+    /// A VM with the built-in natives, set up as `config` says.
+    pub fn with_config(config: VmConfig) -> Self {
         let mut vm = Self {
             stack: Vec::new(),
             frames: Vec::new(),
-            globals: HashMap::new(),
+            ip: 0,
+            chunk: Rc::new(Chunk::new()),
+            globals: Vec::new(),
+            global_names: Rc::new(RefCell::new(GlobalTable::new())),
+            natives: Rc::new(NativeTable::new()),
+            open_upvalues: Vec::new(),
+            method_epoch: 0,
+            config,
+            fuel: None,
+            deadline: None,
+            ticks: 0,
+            handle: VmHandle::default(),
+            memory: Arc::default(),
+            debugger: None,
+            hooks: None,
+            breakpoints: HashSet::new(),
+            stepping: false,
+            debug_position: (0, 0),
+            base_frames: 0,
+            profile: None,
+            coverage: None,
+            reporter: None,
+            tracer: None,
+            file: None,
+            out: Box::new(io::stdout()),
+            user_methods: HashMap::new(),
+            drops: Rc::default(),
+            finalizing: false,
         };
-        let f: Rc<dyn NativeFunc> = Rc::new(NativeClock {});
-        vm.define_native("clock", &f);
+        vm.register_method("count", 0, |args: &MissingArgs, _| {
+            Ok::<_, NativeError>(Value::Number(args.0.len() as f64))
+        });
+        vm.register_method("get", 1, |args: &MissingArgs, index| {
+            let index: f64 = (&index[0]).try_into()?;
+            if index < 0.0 || index.fract() != 0.0 {
+                return Err(NativeError::new(
+                    "Argument index must be a non-negative integer.",
+                ));
+            }
+            Ok(args.0.get(index as usize).cloned().unwrap_or(Value::Nil))
+        });
+        if vm.config.stdlib {
+            for native in stdlib() {
+                vm.define_native(&native);
+            }
+            for native in memory_natives(&vm.memory) {
+                vm.define_native(&native);
+            }
+            if vm.config.deterministic {
+                for native in deterministic_natives() {
+                    vm.define_native(&native);
+                }
+            }
+        }
         vm
     }
 
-    pub fn interpret(&mut self, source: &str) -> Result<(), InterpretResult> {
-        let mut compiler = Compiler::new();
-        let function = compiler.compile(source)?;
+    /// Compiles and runs `source`. Globals it defines stay around for the
+    /// next call.
+    pub fn interpret(&mut self, source: &str) -> Result<(), LoxError> {
+        let function = self.compile(source)?;
+        self.run_function(function)
+    }
+
+    /// Compiles `source` into the .loxb format that `run_binary` loads.
+    pub fn compile_binary(&mut self, source: &str) -> Result<Vec<u8>, LoxError> {
+        let function = self.compile(source)?;
+        serialize(&function, &self.global_names.borrow())
+            .map_err(|err| CompileError::new(err).into())
+    }
+
+    /// Runs a program written by `compile_binary`. A file that fails to load
+    /// is reported as a compile error, before any of it runs.
+    pub fn run_binary(&mut self, bytes: &[u8]) -> Result<(), LoxError> {
+        let loaded = deserialize(bytes, &self.natives, &mut self.global_names.borrow_mut());
+        let function =
+            loaded.map_err(|err| CompileError::new(format!("Invalid .loxb file: {err}")))?;
+        self.run_function(function)
+    }
 
+    /// Assembles and runs a .loxasm program. Assembly errors are reported like
+    /// compile errors.
+    pub fn run_assembly(&mut self, text: &str) -> Result<(), LoxError> {
+        let assembled = assemble(
+            text,
+            self.file.clone(),
+            &mut self.global_names.borrow_mut(),
+            &self.natives,
+        );
+        let function = assembled.map_err(|diagnostics| {
+            if let Some(reporter) = self.reporter.as_mut() {
+                for diagnostic in &diagnostics {
+                    reporter.report(text, diagnostic);
+                }
+            }
+            CompileError::with_diagnostics(diagnostics)
+        })?;
+        self.run_function(function)
+    }
+
+    /// Calls the function stored in the global `name` with `args` and runs
+    /// it to completion. Classes can be called too, which makes an
+    /// instance. It fails if the global isn't defined or can't be called,
+    /// or if the call fails with a runtime error.
+    ///
+    /// ```
+    /// use lox_bytecode::{Value, VM};
+    ///
+    /// let mut vm = VM::new();
+    /// vm.interpret("fun add(a, b) { return a + b; }").unwrap();
+    /// let sum = vm.call_global("add", &[1.0.into(), 2.0.into()]).unwrap();
+    /// assert_eq!(sum, Value::Number(3.0));
+    /// ```
+    pub fn call_global(&mut self, name: &str, args: &[Value]) -> Result<Value, LoxError> {
+        let Some(callee) = self.get_global(name) else {
+            let message = format!("Undefined variable '{name}'.");
+            return Err(RuntimeError::new(message).into());
+        };
+        self.call_and_run(callee, args)
+    }
+
+    /// The value of the global `name`, or None if it isn't defined.
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        let slot = self.global_names.borrow().lookup(name)?;
+        self.globals.get(slot)?.as_ref().map(Value::from)
+    }
+
+    /// Defines or replaces the global `name`, for scripts run afterwards to
+    /// read. Replacing a native this way works like redefining it in Lox:
+    /// code compiled before keeps calling the native.
+    ///
+    /// ```
+    /// use lox_bytecode::{Value, VM};
+    ///
+    /// let mut vm = VM::new();
+    /// vm.set_global("limit", 10.0.into());
+    /// vm.interpret("var doubled = limit * 2;").unwrap();
+    /// assert_eq!(vm.get_global("doubled"), Some(Value::Number(20.0)));
+    /// ```
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        let slot = self.global_names.borrow_mut().resolve(name);
+        self.global_names.borrow_mut().mark_assigned(slot);
+        self.define_global(slot, value);
+    }
+
+    /// Writes out the VM's globals along with the functions, classes and
+    /// instances they refer to, for `restore` to bring back in this or
+    /// another VM, even in another process. Natives are saved by name, and
+    /// classes registered from Rust only by their name too. It fails if a
+    /// global reaches userdata.
+    ///
+    /// ```
+    /// use lox_bytecode::{Value, VM};
+    ///
+    /// let mut vm = VM::new();
+    /// vm.interpret("fun counter() { var n = 0; fun next() { n = n + 1; return n; } return next; }")
+    ///     .unwrap();
+    /// vm.interpret("var next = counter(); next();").unwrap();
+    /// let snapshot = vm.snapshot().unwrap();
+    ///
+    /// let mut restored = VM::new();
+    /// restored.restore(&snapshot).unwrap();
+    /// assert_eq!(restored.call_global("next", &[]).unwrap(), Value::Number(2.0));
+    /// ```
+    pub fn snapshot(&self) -> Result<Vec<u8>, LoxError> {
+        snapshot(&self.slot_values(), &self.global_names.borrow())
+            .map_err(|err| RuntimeError::new(format!("Can't snapshot: {err}")).into())
+    }
+
+    /// Defines the globals saved by `snapshot`, replacing any of the same
+    /// name. Natives and host classes it names must already be defined
+    /// here. A snapshot that fails to load leaves the globals untouched.
+    pub fn restore(&mut self, bytes: &[u8]) -> Result<(), LoxError> {
+        let restored = restore(
+            bytes,
+            &self.natives,
+            &mut self.global_names.borrow_mut(),
+            &self.slot_values(),
+            &self.drops,
+        );
+        let restored =
+            restored.map_err(|err| RuntimeError::new(format!("Invalid snapshot: {err}")))?;
+        for (slot, assigned, value) in restored {
+            if assigned {
+                self.global_names.borrow_mut().mark_assigned(slot);
+            }
+            self.define_global(slot, value);
+        }
+        self.method_epoch += 1;
+        Ok(())
+    }
+
+    /// Runs a program from `compile` or `compile_program`. Like `interpret`,
+    /// the globals it defines stay around for the next call.
+    pub fn execute(&mut self, program: &Program) -> Result<(), LoxError> {
+        let loaded = program.load(&mut self.global_names.borrow_mut());
+        let function =
+            loaded.map_err(|err| CompileError::new(format!("Can't load program: {err}")))?;
+        self.run_function(function)
+    }
+
+    fn run_function(&mut self, function: Function) -> Result<(), LoxError> {
         let closure = Rc::new(Closure::new(Rc::new(function)));
-        self.stack
-            .push(Rc::new(RefCell::new(Value::Closure(Rc::clone(&closure)))));
-        self.call(closure, 0);
-        let result = self.run();
-        self.stack.pop();
+        self.call_and_run(Value::Closure(closure), &[]).map(|_| ())
+    }
+
+    // Calls `callee` from outside of any running code and returns what it
+    // returns.
+    fn call_and_run(&mut self, callee: Value, args: &[Value]) -> Result<Value, LoxError> {
+        self.handle.clear();
+        self.debug_position = (0, 0);
+        self.fuel = self.config.fuel;
+        self.deadline = self
+            .config
+            .timeout
+            .and_then(|timeout| Instant::now().checked_add(timeout));
+        self.push(callee);
+        for arg in args {
+            self.push(arg.clone());
+        }
+        let mut result = self.call_value(args.len());
+        // Natives and classes without an initializer are done already.
+        if result.is_ok() && !self.frames.is_empty() {
+            result = self.run();
+        }
+        let mut value = result.map(|()| self.pop_value());
+        if value.is_ok() && self.drops.is_pending() {
+            value = self.run_finalizers().and(value);
+        }
+        self.fuel = None;
+        self.deadline = None;
+        let _ = self.out.flush();
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.flush();
+        }
+        value
+    }
+
+    /// Compiles `source` and reports its diagnostics without running it.
+    pub fn check(&mut self, source: &str) -> Result<(), LoxError> {
+        self.compile(source).map(|_| ())
+    }
+
+    /// Compiles `source` against this VM's globals and natives, handing its
+    /// diagnostics to the reporter, and returns the top-level function.
+    pub fn compile(&mut self, source: &str) -> Result<Function, LoxError> {
+        let globals = Rc::clone(&self.global_names);
+        let natives = Rc::clone(&self.natives);
+        self.compile_with(source, &globals, &natives, true)
+    }
 
+    /// Compiles `source` with this VM's settings into a program that can run
+    /// in other VMs. The VM itself is left as it was. Natives are called
+    /// through their globals, so the program calls whichever natives the VM
+    /// running it has.
+    pub fn compile_program(&mut self, source: &str) -> Result<Program, LoxError> {
+        self.compile_program_with(source, true)
+    }
+
+    /// Like `compile_program`, but diagnostics only come back in the error
+    /// rather than going to the reporter too. For trying out a reading of
+    /// some input, the way the REPL checks whether an entry is an expression
+    /// whose value it should print.
+    ///
+    /// ```
+    /// use lox_bytecode::{Value, VM};
+    ///
+    /// let mut vm = VM::new();
+    /// vm.interpret("var x = 40;").unwrap();
+    /// assert!(vm.try_compile_program("var y = x + 2").is_err());
+    /// let program = vm.try_compile_program("var y = x + 2;").unwrap();
+    /// vm.execute(&program).unwrap();
+    /// assert_eq!(vm.get_global("y"), Some(Value::Number(42.0)));
+    /// ```
+    pub fn try_compile_program(&mut self, source: &str) -> Result<Program, LoxError> {
+        self.compile_program_with(source, false)
+    }
+
+    fn compile_program_with(&mut self, source: &str, report: bool) -> Result<Program, LoxError> {
+        let globals = Rc::new(RefCell::new(self.global_names.borrow().clone()));
+        let natives = Rc::new(NativeTable::new());
+        let function = self.compile_with(source, &globals, &natives, report)?;
+        Ok(Program::new(function, globals.take()))
+    }
+
+    fn compile_with(
+        &mut self,
+        source: &str,
+        globals: &Rc<RefCell<GlobalTable>>,
+        natives: &Rc<NativeTable>,
+        report: bool,
+    ) -> Result<Function, LoxError> {
+        let mut compiler = Compiler::new(globals);
+        compiler.set_optimize(self.config.optimize);
+        compiler.set_print_code(self.config.print_code);
+        compiler.set_natives(natives);
+        compiler.set_warnings(self.config.warnings);
+        compiler.set_file(self.file.clone());
+        let compiled = compiler.compile(source);
+        let diagnostics = match &compiled {
+            Ok(_) => compiler.take_warnings(),
+            Err(diagnostics) => diagnostics.clone(),
+        };
+        if let Some(reporter) = self.reporter.as_mut().filter(|_| report) {
+            for diagnostic in &diagnostics {
+                reporter.report(source, diagnostic);
+            }
+        }
+        compiled.map_err(|diagnostics| CompileError::with_diagnostics(diagnostics).into())
+    }
+
+    /// Like `interpret`, but gives up with `BudgetExceeded` once `fuel`
+    /// instructions have been dispatched.
+    pub fn interpret_with_fuel(&mut self, source: &str, fuel: usize) -> Result<(), LoxError> {
+        let saved = self.config.fuel.replace(fuel);
+        let result = self.interpret(source);
+        self.config.fuel = saved;
+        result
+    }
+
+    /// Like `interpret`, but fails with a runtime error if the script is still
+    /// running after `timeout`. The clock is only consulted every
+    /// TIMEOUT_CHECK_INTERVAL instructions to keep the dispatch loop cheap.
+    pub fn interpret_with_timeout(
+        &mut self,
+        source: &str,
+        timeout: Duration,
+    ) -> Result<(), LoxError> {
+        let saved = self.config.timeout.replace(timeout);
+        let result = self.interpret(source);
+        self.config.timeout = saved;
         result
     }
 
+    /// Every `interpret` hands its warnings and errors to the reporter before
+    /// running anything. Compile errors are also returned in the CompileError.
+    pub fn set_reporter(&mut self, reporter: Box<dyn Reporter>) {
+        self.reporter = Some(reporter);
+    }
+
+    /// Names the file that scripts compiled from now on come from, so that
+    /// their stack traces can point into it.
+    pub fn set_file(&mut self, file: &str) {
+        self.file = Some(file.into());
+    }
+
+    /// Where `print` writes. Standard output unless set.
+    pub fn set_output(&mut self, out: Box<dyn Output>) {
+        self.out = out;
+    }
+
+    /// The arguments `argCount()` and `arg(n)` give scripts. None unless
+    /// set.
+    pub fn set_args(&mut self, args: Vec<String>) {
+        for native in script_args(args) {
+            self.define_native(&native);
+        }
+    }
+
+    /// Where `readLine` reads. Standard input unless set.
+    pub fn set_input(&mut self, input: Box<dyn Input>) {
+        let native: Rc<dyn NativeFunc> = Rc::new(NativeReadLine::new(input));
+        self.define_native(&native);
+    }
+
+    /// Writes a trace of every instruction executed from now on.
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Attaches a debugger, which is called at breakpoints and while stepping.
+    pub fn set_debugger(&mut self, debugger: Box<dyn Debugger>) {
+        self.debugger = Some(debugger);
+    }
+
+    /// Installs callbacks for calls, returns and global writes.
+    pub fn set_hooks(&mut self, hooks: Box<dyn Hooks>) {
+        self.hooks = Some(hooks);
+    }
+
+    /// Pauses in the attached debugger whenever `line` is reached.
+    pub fn add_breakpoint(&mut self, line: usize) {
+        self.breakpoints.insert(line);
+    }
+
+    /// Pause at the next line the attached debugger sees.
+    pub fn step(&mut self) {
+        self.stepping = true;
+    }
+
+    /// Starts collecting a [`Profile`] of the scripts run from now on.
+    pub fn enable_profiling(&mut self) {
+        self.profile = Some(Profile::new());
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Starts counting which lines of the scripts run from now on execute.
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(Coverage::new());
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// How many objects the VM's scripts have made that are still alive, and
+    /// roughly how many bytes they take.
+    pub fn allocations(&self) -> (usize, usize) {
+        (self.memory.objects(), self.memory.bytes())
+    }
+
+    /// The values on the stack, bottom first. Meant for debuggers.
+    pub fn stack_values(&self) -> Vec<Value> {
+        self.stack.iter().map(Value::from).collect()
+    }
+
+    /// Every defined global with its value, in the order they were first seen.
+    pub fn global_values(&self) -> Vec<(String, Value)> {
+        let names = self.global_names.borrow();
+        self.globals
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, value)| {
+                Some((names.name(slot).to_string(), Value::from(value.as_ref()?)))
+            })
+            .collect()
+    }
+
+    // The value of each global slot, or None where it isn't defined.
+    fn slot_values(&self) -> Vec<Option<Value>> {
+        self.globals
+            .iter()
+            .map(|value| value.as_ref().map(Value::from))
+            .collect()
+    }
+
+    /// The local variables in scope in the function running now, with their
+    /// values. Code built with the optimizer on has no names to go by.
+    pub fn locals(&self) -> Vec<(String, Value)> {
+        let frame = self.current_frame();
+        let chunk = frame.closure.get_chunk();
+        chunk
+            .locals_at(self.ip.saturating_sub(1))
+            .into_iter()
+            .filter_map(|local| {
+                let slot = self.stack.get(frame.slots + local.slot)?;
+                Some((local.name.clone(), Value::from(slot)))
+            })
+            .collect()
+    }
+
+    /// How many calls deep the running script is; the top level is 1.
+    pub fn call_depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// The name of the function running now; the top level is "script".
+    pub fn current_function(&self) -> &str {
+        self.current_frame().closure.stack_name()
+    }
+
+    /// A handle that can interrupt this VM from elsewhere.
+    pub fn handle(&self) -> VmHandle {
+        self.handle.clone()
+    }
+
+    /// When the running script times out, if it has a timeout.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
     fn current_frame(&self) -> &CallFrame {
         self.frames.last().unwrap()
     }
 
     fn ip(&self) -> usize {
-        *self.current_frame().ip.borrow()
+        self.ip
     }
 
-    fn get_upvalue(&self, offset: usize) -> Rc<RefCell<Value>> {
+    fn get_upvalue(&self, offset: usize) -> Rc<Upvalue> {
         self.current_frame().closure.get_upvalue(offset)
     }
 
-    fn set_upvalue(&self, offset: usize, value: &Rc<RefCell<Value>>) {
-        self.current_frame().closure.modify(offset, value);
+    fn read_upvalue(&self, offset: usize) -> Slot {
+        let upvalue = self.get_upvalue(offset);
+        match upvalue.closed_value() {
+            Some(value) => value,
+            None => self.stack[upvalue.slot()].clone(),
+        }
     }
 
-    fn capture_upvalue(&self, offset: usize) -> Rc<RefCell<Value>> {
-        Rc::clone(&self.stack[offset])
+    fn write_upvalue(&mut self, offset: usize, value: Slot) {
+        let upvalue = self.get_upvalue(offset);
+        if upvalue.is_open() {
+            self.stack[upvalue.slot()] = value;
+        } else {
+            upvalue.close(value);
+        }
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<Upvalue> {
+        let position = self.open_upvalues.partition_point(|u| u.slot() < slot);
+        if let Some(upvalue) = self.open_upvalues.get(position) {
+            if upvalue.slot() == slot {
+                return Rc::clone(upvalue);
+            }
+        }
+
+        let upvalue = Rc::new(Upvalue::new(slot));
+        self.open_upvalues.insert(position, Rc::clone(&upvalue));
+        upvalue
+    }
+
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            if upvalue.slot() < last {
+                break;
+            }
+            upvalue.close(self.stack[upvalue.slot()].clone());
+            self.open_upvalues.pop();
+        }
     }
 
-    fn chunk(&self) -> Rc<Chunk> {
-        self.current_frame().closure.get_chunk()
+    fn chunk(&self) -> &Chunk {
+        &self.chunk
     }
 
-    fn run(&mut self) -> Result<(), InterpretResult> {
+    fn run(&mut self) -> Result<(), LoxError> {
         loop {
-            #[cfg(feature = "debug_trace_execution")]
-            {
-                print!("          ");
-                for slot in &self.stack {
-                    print!("[ {} ]", slot.borrow());
+            if self.ip >= self.chunk.count() {
+                return self.internal_error("instruction pointer out of range");
+            }
+
+            if let Some(tracer) = self.tracer.as_mut() {
+                let function = self.frames.last().unwrap().closure.stack_name();
+                tracer.trace(function, &self.stack, &self.chunk, self.ip);
+            }
+
+            if self.drops.is_pending() && !self.finalizing {
+                self.run_finalizers()?;
+            }
+
+            if let Some(fuel) = self.fuel.as_mut() {
+                if *fuel == 0 {
+                    let err = self.unwind("Instruction budget exceeded.", None);
+                    return Err(LoxError::BudgetExceeded(err));
                 }
-                println!();
-                self.chunk().disassemble_instruction(self.ip());
+                *fuel -= 1;
             }
 
-            let instruction: OpCode = self.read_byte().into();
-            match instruction {
-                OpCode::SuperInvoke => {
-                    let constant = self.read_constant().clone();
-                    let method_name = if let Value::Str(s) = constant {
-                        s
-                    } else {
-                        panic!("Unable to get field name from table");
-                    };
+            let Ok(instruction) = OpCode::try_from(self.read_byte()) else {
+                return self.internal_error("unknown opcode");
+            };
+
+            if let Some(profile) = self.profile.as_mut() {
+                profile.record(instruction, self.stack.len());
+            }
+
+            if let Some(coverage) = self.coverage.as_mut() {
+                coverage.record(&self.chunk, self.ip - 1);
+            }
 
+            if self.debugger.is_some() {
+                self.debug_instruction(instruction);
+            }
+
+            if self.handle.is_interrupted() {
+                return self.runtime_error("Interrupted.");
+            }
+
+            if let Some(deadline) = self.deadline {
+                self.ticks = self.ticks.wrapping_add(1);
+                if self.ticks.is_multiple_of(TIMEOUT_CHECK_INTERVAL) && Instant::now() >= deadline {
+                    return self.runtime_error("Script timed out.");
+                }
+            }
+
+            match instruction {
+                OpCode::SuperInvoke | OpCode::SuperInvokeLong => {
+                    let method_name = self.read_string(instruction.is_long())?;
                     let arg_count = self.read_byte() as usize;
-                    let popped_value = self.pop().borrow().clone();
-                    let superclass = if let Value::Class(klass) = popped_value {
-                        klass
-                    } else {
-                        return Err(InterpretResult::RuntimeError);
+                    let Value::Class(superclass) = self.pop_value() else {
+                        return self.internal_error("superclass is not a class");
                     };
 
-                    if !self.invoke_from_class(superclass, &method_name, arg_count) {
-                        return Err(InterpretResult::RuntimeError);
-                    }
+                    self.invoke_from_class(superclass, &method_name, arg_count)?;
                 }
-                OpCode::GetSuper => {
-                    let constant = self.read_constant().clone();
-                    let name = if let Value::Str(s) = constant {
-                        s
-                    } else {
-                        panic!("Unable to get field name from table");
-                    };
-                    let popped_value = self.pop().borrow().clone();
-                    let superclass = if let Value::Class(klass) = popped_value {
-                        klass
-                    } else {
-                        panic!("no superclass");
+                OpCode::GetSuper | OpCode::GetSuperLong => {
+                    let name = self.read_string(instruction.is_long())?;
+                    let Value::Class(superclass) = self.pop_value() else {
+                        return self.internal_error("superclass is not a class");
                     };
-                    if !self.bind_method(superclass, &name) {
-                        return Err(InterpretResult::RuntimeError);
-                    }
+                    self.bind_method(&superclass, superclass.get_method(&name), &name)?;
                 }
                 OpCode::Inherit => {
-                    let superclass_value = self.peek(1).borrow().clone();
+                    let superclass_value = self.peek_value(1);
                     let superclass = if let Value::Class(c) = superclass_value {
                         c
                     } else {
                         return self.runtime_error("Superclass must be a class.");
                     };
-                    let subclass = if let Value::Class(c) = self.peek(0).borrow().clone() {
-                        c
-                    } else {
-                        panic!("No subclass found on stack.");
+                    let Value::Class(subclass) = self.peek_value(0) else {
+                        return self.internal_error("subclass is not a class");
                     };
 
-                    subclass.copy_methods(&superclass);
+                    subclass.set_superclass(&superclass);
+                    self.method_epoch += 1;
 
                     self.pop();
                 }
-                OpCode::Invoke => {
-                    let constant = self.read_constant().clone();
-                    let method_name = if let Value::Str(s) = constant {
-                        s
-                    } else {
-                        panic!("Unable to get field name from table");
-                    };
+                OpCode::Extend => {
+                    if !matches!(self.peek_value(0), Value::Class(_)) {
+                        return self.runtime_error("Can only extend a class.");
+                    }
+                }
+                OpCode::Invoke | OpCode::InvokeLong => {
+                    let method_name = self.read_string(instruction.is_long())?;
 
                     let arg_count = self.read_byte() as usize;
-                    if !self.invoke(method_name.as_str(), arg_count) {
-                        return Err(InterpretResult::RuntimeError);
-                    }
+                    let cache = self.read_short();
+                    self.invoke(method_name.as_str(), arg_count, cache)?;
                 }
-                OpCode::Method => {
-                    let constant = self.read_constant().clone();
-                    let method_name = if let Value::Str(s) = constant {
-                        s
-                    } else {
-                        panic!("Unable to get class name from table");
-                    };
-                    self.define_method(&method_name);
+                OpCode::Method | OpCode::MethodLong => {
+                    let method_name = self.read_string(instruction.is_long())?;
+                    self.define_method(&method_name)?;
                 }
-                OpCode::SetProperty => {
-                    let instance = if let Value::Instance(i) = self.peek(1).borrow().clone() {
-                        Some(i)
-                    } else {
-                        None
+                OpCode::SetProperty | OpCode::SetPropertyLong => {
+                    let field_name = self.read_string(instruction.is_long())?;
+                    let size = field_name.len() + std::mem::size_of::<Value>();
+                    let value = self.peek_value(0);
+                    let added = match self.peek_value(1) {
+                        Value::Instance(instance) if instance.is_frozen() => {
+                            return self.runtime_error(format!(
+                                "Can't set '{field_name}' on a frozen instance."
+                            ));
+                        }
+                        Value::Instance(instance) => instance.set_field(field_name, &value),
+                        Value::Class(class) => class.set_static(field_name, &value),
+                        _ => return self.runtime_error("Only instances have fields."),
                     };
-
-                    if instance.is_none() {
-                        return self.runtime_error("Only instances have fields.");
+                    if added {
+                        self.reserve(size)?;
+                        match self.peek_value(1) {
+                            Value::Instance(instance) => instance.grow_allocation(size),
+                            Value::Class(class) => class.grow_allocation(size),
+                            _ => {}
+                        }
                     }
 
-                    let constant = self.read_constant().clone();
-                    let field_name = if let Value::Str(s) = constant {
-                        s
-                    } else {
-                        panic!("Unable to get class name from table");
-                    };
-
-                    let value = self.pop();
-                    instance
-                        .unwrap()
-                        .set_field(field_name, &value.borrow().clone());
-
-                    self.pop(); // Instance
-                    self.push(value.borrow().clone());
+                    self.pop(); // Value
+                    self.pop(); // Instance or class
+                    self.push(value);
                 }
-                OpCode::GetProperty => {
-                    let instance = if let Value::Instance(i) = self.peek(0).borrow().clone() {
-                        Some(i)
-                    } else {
-                        None
-                    };
-                    // xyzzy
-
-                    if instance.is_none() {
-                        return self.runtime_error("Only instances have properties.");
-                    }
-
-                    let constant = self.read_constant().clone();
-                    let field_name = if let Value::Str(s) = constant {
-                        s
-                    } else {
-                        panic!("Unable to get field name from table");
-                    };
-
-                    if let Some(value) = instance.as_ref().unwrap().get_field(&field_name) {
-                        self.pop(); // Instance
-                        self.push(value.clone());
-                    } else if !self.bind_method(instance.unwrap().get_class(), &field_name) {
-                        return self.runtime_error(&format!("Undefined property '{field_name}'."));
+                OpCode::GetProperty | OpCode::GetPropertyLong => {
+                    let field_name = self.read_string(instruction.is_long())?;
+                    let cache = self.read_short();
+                    match self.peek_value(0) {
+                        Value::Instance(instance) => {
+                            if let Some(value) = instance.get_field(&field_name) {
+                                self.pop(); // Instance
+                                self.push(value);
+                            } else {
+                                let klass = instance.get_class();
+                                let method = self.find_method(&klass, &field_name, cache);
+                                self.bind_method(&klass, method, &field_name)?;
+                            }
+                        }
+                        Value::UserData(data) => {
+                            let Some(method) = self.bind_user_method(&data, &field_name) else {
+                                return self
+                                    .runtime_error(format!("Undefined property '{field_name}'."));
+                            };
+                            self.pop(); // Userdata
+                            self.push(method);
+                        }
+                        Value::Class(class) => {
+                            let Some(value) = self.class_property(&class, &field_name)? else {
+                                return self
+                                    .runtime_error(format!("Undefined property '{field_name}'."));
+                            };
+                            self.pop(); // Class
+                            self.push(value);
+                        }
+                        Value::Bound(method) if field_name == "bind" => {
+                            self.pop(); // Method
+                            self.push(bind_native(&method));
+                        }
+                        _ => return self.runtime_error("Only instances have properties."),
                     }
                 }
-                OpCode::Class => {
-                    let constant = self.read_constant().clone();
-                    let class_string = if let Value::Str(s) = constant {
-                        s
-                    } else {
-                        panic!("Unable to get class name from table");
-                    };
-                    self.push(Value::Class(Rc::new(Class::new(class_string))));
+                OpCode::Class | OpCode::ClassLong => {
+                    let class_string = self.read_string(instruction.is_long())?;
+                    let allocation =
+                        self.allocate(std::mem::size_of::<Class>() + class_string.len())?;
+                    let class = Class::new(class_string, &self.drops).with_allocation(allocation);
+                    self.push(Value::Class(Rc::new(class)));
                 }
                 OpCode::GetUpvalue => {
                     let slot = self.read_byte() as usize;
-                    self.stack.push(Rc::clone(&self.get_upvalue(slot)));
+                    let value = self.read_upvalue(slot);
+                    self.stack.push(value);
                 }
                 OpCode::SetUpvalue => {
                     let slot = self.read_byte() as usize;
-                    let value = self.peek(0);
-                    self.set_upvalue(slot, value);
-                }
-                OpCode::Closure => {
-                    let constant = self.read_constant().clone();
-                    if let Value::Func(function) = constant {
-                        let upvalue_count = function.upvalues();
-                        let closure = Rc::new(Closure::new(function));
-                        self.push(Value::Closure(Rc::clone(&closure)));
-                        for _ in 0..upvalue_count {
-                            let is_local = self.read_byte() != 0;
-                            let index = self.read_byte() as usize;
-                            let captured = if is_local {
-                                let offset = self.current_frame().slots + index;
-                                self.capture_upvalue(offset)
-                            } else {
-                                self.get_upvalue(index)
-                            };
-                            closure.push_upvalue(&captured);
-                        }
-                    } else {
-                        panic!("Tried to read function from constant table but got {constant:?}");
+                    let value = self.peek(0).clone();
+                    self.write_upvalue(slot, value);
+                }
+                OpCode::Closure | OpCode::ClosureLong => {
+                    let constant = self.read_constant(instruction.is_long());
+                    let Value::Func(function) = constant else {
+                        return self.internal_error("closure constant is not a function");
+                    };
+                    let upvalue_count = function.upvalues();
+                    let allocation = self.allocate(
+                        std::mem::size_of::<Closure>()
+                            + upvalue_count * std::mem::size_of::<Rc<Upvalue>>(),
+                    )?;
+                    let closure = Rc::new(Closure::new(function).with_allocation(allocation));
+                    self.push(Value::Closure(Rc::clone(&closure)));
+                    for _ in 0..upvalue_count {
+                        let is_local = self.read_byte() != 0;
+                        let index = self.read_short();
+                        let captured = if is_local {
+                            let offset = self.current_frame().slots + index;
+                            self.capture_upvalue(offset)
+                        } else {
+                            self.get_upvalue(index)
+                        };
+                        closure.push_upvalue(&captured);
                     }
                 }
                 OpCode::Call => {
                     let arg_count = self.read_byte() as usize;
-                    if !self.call_value(arg_count) {
-                        return Err(InterpretResult::RuntimeError);
-                    }
+                    self.call_value(arg_count)?;
+                }
+                OpCode::ConstantCall => {
+                    // The arguments are already on the stack, so the native
+                    // goes in underneath them where a callee would be.
+                    let constant = self.read_constant(false);
+                    let arg_count = self.read_byte() as usize;
+                    let callee = self.stack.len() - arg_count;
+                    self.stack.insert(callee, new_slot(constant));
+                    self.call_value(arg_count)?;
                 }
                 OpCode::Loop => {
                     let offset = self.read_short();
-                    self.current_frame().dec(offset);
+                    self.ip -= offset;
+                }
+                OpCode::LoopLong => {
+                    let offset = self.read_int();
+                    self.ip -= offset;
                 }
                 OpCode::Jump => {
                     let offset = self.read_short();
-                    self.current_frame().inc(offset);
+                    self.ip += offset;
+                }
+                OpCode::JumpLong => {
+                    let offset = self.read_int();
+                    self.ip += offset;
                 }
                 OpCode::JumpIfFalse => {
                     let offset = self.read_short();
-                    if self.peek(0).borrow().is_falsey() {
-                        self.current_frame().inc(offset);
+                    if self.peek(0).is_falsey() {
+                        self.ip += offset;
                     }
                 }
-                OpCode::DefineGlobal => {
-                    let constant = self.read_constant().clone();
-                    if let Value::Str(s) = constant {
-                        let p = self.pop();
-                        self.globals.insert(s, p.borrow().clone());
-                    } else {
-                        panic!("Unable to read constant from table");
+                OpCode::JumpIfFalsePop => {
+                    let offset = self.read_short();
+                    if self.pop().is_falsey() {
+                        self.ip += offset;
                     }
                 }
-                OpCode::GetGlobal => {
-                    let constant = self.read_constant().clone();
-                    if let Value::Str(s) = constant {
-                        if let Some(v) = self.globals.get(&s) {
-                            let u = v.clone();
-                            self.push(u);
-                        } else {
-                            return self.runtime_error(&format!("Undefined variable '{s}'."));
-                        }
+                OpCode::JumpIfFalseLong => {
+                    let offset = self.read_int();
+                    if self.peek(0).is_falsey() {
+                        self.ip += offset;
+                    }
+                }
+                OpCode::DefineGlobal | OpCode::DefineGlobalLong => {
+                    let slot = self.read_index(instruction.is_long());
+                    self.check_global_write(slot)?;
+                    let p = self.pop_value();
+                    self.define_global(slot, p);
+                }
+                OpCode::GetGlobal | OpCode::GetGlobalLong => {
+                    let slot = self.read_index(instruction.is_long());
+                    if let Some(Some(value)) = self.globals.get(slot) {
+                        self.stack.push(value.clone());
                     } else {
-                        panic!("Unable to read constant from table");
+                        return self.undefined_variable(slot);
                     }
                 }
-                OpCode::SetGlobal => {
-                    let constant = self.read_constant().clone();
-                    if let Value::Str(s) = constant {
-                        let p = self.peek(0).borrow().clone();
-                        if let Entry::Occupied(mut o) = self.globals.entry(s.clone()) {
-                            *o.get_mut() = p;
-                        } else {
-                            return self.runtime_error(&format!("Undefined variable '{s}'."));
-                        }
+                OpCode::SetGlobal | OpCode::SetGlobalLong => {
+                    let slot = self.read_index(instruction.is_long());
+                    if !matches!(self.globals.get(slot), Some(Some(_))) {
+                        return self.undefined_variable(slot);
                     }
+                    self.check_global_write(slot)?;
+                    self.globals[slot] = Some(self.peek(0).clone());
                 }
-                OpCode::CloseUpvalue | OpCode::Pop => {
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
                     self.pop();
                 }
-                OpCode::GetLocal => {
-                    let slot = self.read_byte() as usize;
+                OpCode::Pop => {
+                    self.pop();
+                }
+                OpCode::PopN => {
+                    let count = self.read_byte() as usize;
+                    self.stack.truncate(self.stack.len() - count);
+                }
+                OpCode::GetLocal | OpCode::GetLocalLong => {
+                    let slot = self.read_index(instruction.is_long());
                     let slot_offset = self.current_frame().slots;
                     self.stack.push(self.stack[slot_offset + slot].clone());
                 }
-                OpCode::SetLocal => {
-                    let slot = self.read_byte() as usize;
+                OpCode::AddLocals => {
+                    let a = self.read_byte() as usize;
+                    let b = self.read_byte() as usize;
+                    let slot_offset = self.current_frame().slots;
+                    self.stack.push(self.stack[slot_offset + a].clone());
+                    self.stack.push(self.stack[slot_offset + b].clone());
+                    self.binary_op(Operands::NumbersOrStrings, |a, b| Value::Number(a + b))?
+                }
+                OpCode::SetLocal | OpCode::SetLocalLong => {
+                    let slot = self.read_index(instruction.is_long());
                     let slot_offset = self.current_frame().slots;
                     self.stack[slot_offset + slot] = self.peek(0).clone();
                 }
                 OpCode::Print => {
-                    println!("{}", self.pop().borrow());
+                    // Like a trace, output is best effort.
+                    let value = self.pop();
+                    match to_string_method(&Value::from(&value)) {
+                        Some(method) => {
+                            let text = self.call_to_string(Value::from(&value), method)?;
+                            let _ = writeln!(self.out, "{text}");
+                        }
+                        None => {
+                            let _ = writeln!(self.out, "{value}");
+                        }
+                    }
                 }
                 OpCode::Return => {
+                    if let Some(mut debugger) = self.debugger.take() {
+                        debugger.on_return(self, self.current_function());
+                        self.debugger = Some(debugger);
+                    }
+                    if let Some(hooks) = self.hooks.as_mut() {
+                        hooks.on_return(self.frames.last().unwrap().closure.stack_name());
+                    }
+                    if let Some(profile) = self.profile.as_mut() {
+                        profile.exit();
+                    }
                     let result = self.pop();
                     let prev_frame = self.frames.pop().unwrap();
-                    if self.frames.is_empty() {
-                        self.pop();
-                        return Ok(());
-                    }
+                    self.close_upvalues(prev_frame.slots);
                     self.stack.truncate(prev_frame.slots);
                     self.stack.push(result);
+                    if let Some(frame) = self.frames.last() {
+                        self.ip = frame.ip;
+                        self.chunk = frame.closure.get_chunk();
+                    }
+                    // The outermost call, or one made from inside an
+                    // instruction, leaves its result for its caller.
+                    if self.frames.len() == self.base_frames {
+                        return Ok(());
+                    }
                 }
-                OpCode::Constant => {
-                    let constant = self.read_constant().clone();
+                OpCode::Constant | OpCode::ConstantLong => {
+                    let constant = self.read_constant(instruction.is_long());
                     self.push(constant);
                 }
                 OpCode::Nil => self.push(Value::Nil),
                 OpCode::True => self.push(Value::Boolean(true)),
                 OpCode::False => self.push(Value::Boolean(false)),
                 OpCode::Equal => {
-                    let b = self.pop();
-                    let a = self.pop();
-                    self.push(Value::Boolean(a == b));
+                    let equal = self.values_equal()?;
+                    self.push(Value::Boolean(equal));
                 }
                 OpCode::Greater => {
-                    self.binary_op(Operands::TwoNumbers, |a, b| Value::Boolean(a > b))?
+                    self.binary_op(Operands::Comparable, |a, b| Value::Boolean(a > b))?
                 }
                 OpCode::Less => {
-                    self.binary_op(Operands::TwoNumbers, |a, b| Value::Boolean(a < b))?
+                    self.binary_op(Operands::Comparable, |a, b| Value::Boolean(a < b))?
+                }
+                OpCode::GreaterEqual => {
+                    self.binary_op(Operands::Comparable, |a, b| Value::Boolean(a >= b))?
+                }
+                OpCode::LessEqual => {
+                    self.binary_op(Operands::Comparable, |a, b| Value::Boolean(a <= b))?
+                }
+                OpCode::NotEqual => {
+                    let equal = self.values_equal()?;
+                    self.push(Value::Boolean(!equal));
+                }
+                OpCode::NotGreater => self.binary_op(Operands::Comparable, |a, b| {
+                    Value::Boolean(a.partial_cmp(&b) != Some(Ordering::Greater))
+                })?,
+                OpCode::NotLess => self.binary_op(Operands::Comparable, |a, b| {
+                    Value::Boolean(a.partial_cmp(&b) != Some(Ordering::Less))
+                })?,
+                OpCode::Add => {
+                    self.binary_op(Operands::NumbersOrStrings, |a, b| Value::Number(a + b))?
+                }
+                OpCode::Subtract => {
+                    self.binary_op(Operands::TwoNumbers, |a, b| Value::Number(a - b))?
+                }
+                OpCode::Multiply => {
+                    self.binary_op(Operands::TwoNumbers, |a, b| Value::Number(a * b))?
+                }
+                OpCode::Divide => {
+                    if self.config.division_by_zero == DivisionByZero::Error
+                        && self.peek(0).as_number() == Some(0.0)
+                        && self.peek(1).as_number().is_some()
+                    {
+                        return self.runtime_error("Division by zero.");
+                    }
+                    self.binary_op(Operands::TwoNumbers, |a, b| Value::Number(a / b))?
                 }
-                OpCode::Add => self.binary_op(Operands::NumbersOrStrings, |a, b| a + b)?,
-                OpCode::Subtract => self.binary_op(Operands::TwoNumbers, |a, b| a - b)?,
-                OpCode::Multiply => self.binary_op(Operands::TwoNumbers, |a, b| a * b)?,
-                OpCode::Divide => self.binary_op(Operands::TwoNumbers, |a, b| a / b)?,
                 OpCode::Not => {
-                    let value = self.pop().borrow().clone();
+                    let value = self.pop_value();
                     self.push(Value::Boolean(value.is_falsey()))
                 }
                 OpCode::Negate => {
-                    if !self.peek(0).borrow().is_number() {
+                    let Some(value) = self.peek(0).as_number() else {
                         return self.runtime_error("Operand must be a number.");
-                    }
-
-                    let value = self.pop().borrow().clone();
-                    self.push(-&value);
+                    };
+                    self.pop();
+                    self.push(Value::Number(-value));
                 }
             }
         }
     }
 
-    fn define_method(&mut self, name: &str) {
-        let method = self.peek(0).borrow().clone();
-        let klass = if let Value::Class(klass) = self.peek(1).borrow().clone() {
-            Some(klass)
-        } else {
-            panic!("compiler bug - no class found at stack[-2]");
+    fn define_method(&mut self, name: &str) -> Result<(), LoxError> {
+        let method = self.peek_value(0);
+        let Value::Class(klass) = self.peek_value(1) else {
+            return self.internal_error("method defined outside a class");
         };
 
         if name == "init" {
-            if let Value::Closure(closure) = method {
-                klass.unwrap().set_init_method(closure)
-            } else {
-                panic!("method should have been a closure");
-            }
+            let Value::Closure(closure) = method else {
+                return self.internal_error("initializer is not a closure");
+            };
+            klass.set_init_method(closure);
         } else {
-            klass.unwrap().add_method(name, &method);
+            klass.add_method(name, &method);
         }
+        self.method_epoch += 1;
         self.pop();
+        Ok(())
     }
 
+    // These run for nearly every instruction; with NaN boxing they convert
+    // too, and are then too big to be inlined without asking.
+    #[inline]
     fn push(&mut self, value: Value) {
-        self.stack.push(Rc::new(RefCell::new(value)));
+        self.stack.push(new_slot(value));
     }
 
-    fn pop(&mut self) -> Rc<RefCell<Value>> {
+    #[inline]
+    fn pop(&mut self) -> Slot {
         self.stack.pop().unwrap()
     }
 
-    fn peek(&self, distance: usize) -> &Rc<RefCell<Value>> {
+    #[inline]
+    fn pop_value(&mut self) -> Value {
+        Value::from(&self.pop())
+    }
+
+    #[inline]
+    fn peek(&self, distance: usize) -> &Slot {
         &self.stack[self.stack.len() - distance - 1]
     }
 
-    fn call(&mut self, closure: Rc<Closure>, arg_count: usize) -> bool {
+    #[inline]
+    fn peek_value(&self, distance: usize) -> Value {
+        Value::from(self.peek(distance))
+    }
+
+    fn call(&mut self, closure: Rc<Closure>, arg_count: usize) -> Result<(), LoxError> {
         let arity = closure.arity();
         if arity != arg_count {
-            let _ = self.runtime_error(&format!("Expected {arity} arguments but got {arg_count}."));
-            return false;
+            return self.runtime_error(format!("Expected {arity} arguments but got {arg_count}."));
         }
 
-        if self.frames.len() == 256 {
-            let _ = self.runtime_error("Stack overflow.");
-            return false;
+        // The value stack is only checked on calls; a single frame's use of
+        // it is bounded by the compiler's local and argument limits.
+        if self.frames.len() >= self.config.max_frames || self.stack.len() > self.config.max_stack {
+            return self.runtime_error("Stack overflow.");
         }
 
+        self.check_call(closure.stack_name())?;
+
+        if let Some(frame) = self.frames.last_mut() {
+            frame.ip = self.ip;
+        }
         self.frames.push(CallFrame {
             closure: Rc::clone(&closure),
-            ip: RefCell::new(0),
+            ip: 0,
             slots: self.stack.len() - arg_count - 1,
         });
+        self.ip = 0;
+        self.chunk = closure.get_chunk();
+
+        if let Some(profile) = self.profile.as_mut() {
+            profile.enter(closure.stack_name(), closure.get_chunk());
+        }
+
+        if let Some(mut debugger) = self.debugger.take() {
+            debugger.on_call(self, closure.stack_name());
+            self.debugger = Some(debugger);
+        }
 
-        true
+        Ok(())
     }
 
-    fn call_value(&mut self, arg_count: usize) -> bool {
-        let callee = self.peek(arg_count).borrow().clone();
-        let success = match callee {
+    fn call_value(&mut self, arg_count: usize) -> Result<(), LoxError> {
+        let callee = self.peek_value(arg_count);
+        match callee {
+            Value::Bound(method) if method.is_unbound() => {
+                if arg_count == 0 {
+                    return self.runtime_error("Expected a receiver as the first argument.");
+                }
+                let stack_top = self.stack.len();
+                if let Err(err) = method.bind(&self.peek_value(arg_count - 1)) {
+                    return self.runtime_error(err.to_string());
+                }
+                // The receiver takes the method's place, as if bound.
+                self.stack.remove(stack_top - arg_count - 1);
+                self.call(method.get_closure(), arg_count - 1)
+            }
             Value::Bound(method) => {
                 let stack_top = self.stack.len();
-                self.stack[stack_top - arg_count - 1] =
-                    Rc::new(RefCell::new(method.get_receiver()));
-                return self.call(method.get_closure(), arg_count);
+                self.stack[stack_top - arg_count - 1] = new_slot(method.get_receiver());
+                self.call(method.get_closure(), arg_count)
             }
 
             Value::Class(klass) => {
                 let stack_top = self.stack.len();
                 let init = klass.get_init_method();
-                self.stack[stack_top - arg_count - 1] =
-                    Rc::new(RefCell::new(Value::Instance(Rc::new(Instance::new(klass)))));
+                let allocation = self.allocate(std::mem::size_of::<Instance>())?;
+                let instance =
+                    Rc::new(Instance::new(Rc::clone(&klass)).with_allocation(allocation));
+                let fields = klass.fields();
+                for field in &fields {
+                    instance.set_field(field.as_str(), &Value::Nil);
+                }
+                let receiver = Value::Instance(instance);
+                self.stack[stack_top - arg_count - 1] = new_slot(receiver.clone());
                 if let Some(initializer) = init {
-                    self.call(initializer, arg_count)
-                } else if arg_count != 0 {
-                    let _ =
-                        self.runtime_error(format!("Expected 0 arguments but got {arg_count}."));
-                    false
-                } else {
-                    true
+                    return self.call(initializer, arg_count);
                 }
-            }
 
-            Value::Closure(closure) => {
-                return self.call(closure, arg_count);
+                let host_init = klass.get_host_method("init");
+                let arity = host_init.as_ref().map_or(fields.len(), |(arity, _)| *arity);
+                if arg_count != arity {
+                    return self
+                        .runtime_error(format!("Expected {arity} arguments but got {arg_count}."));
+                }
+                let args: Vec<Value> = self.stack[stack_top - arg_count..stack_top]
+                    .iter()
+                    .map(Value::from)
+                    .collect();
+                self.stack.truncate(stack_top - arg_count);
+                if let Some((_, init)) = host_init {
+                    if let Err(err) = init(&receiver, &args) {
+                        return Err(self.native_error(err, "init"));
+                    }
+                } else if let Value::Instance(instance) = &receiver {
+                    for (field, value) in fields.iter().zip(&args) {
+                        instance.set_field(field.as_str(), value);
+                    }
+                }
+                Ok(())
             }
 
+            Value::Closure(closure) => self.call(closure, arg_count),
+
             Value::Native(f) => {
+                let arity = f.arity();
+                if f.variadic() && arg_count < arity {
+                    return self.runtime_error(format!(
+                        "Expected at least {arity} arguments but got {arg_count}."
+                    ));
+                } else if !f.variadic() && arity != arg_count {
+                    return self
+                        .runtime_error(format!("Expected {arity} arguments but got {arg_count}."));
+                }
+
+                self.check_call(f.name())?;
+
                 let stack_top = self.stack.len();
-                let result = f.call(arg_count, &self.stack[stack_top - arg_count..stack_top]);
-                self.stack.truncate(stack_top - (arg_count + 1));
-                self.push(result);
-                true
+                let args = &self.stack[stack_top - arg_count..stack_top];
+                #[cfg(feature = "nan_boxing")]
+                let args = &args.iter().map(Value::from).collect::<Vec<_>>();
+                match f.call_with_vm(self, args) {
+                    Ok(result) => {
+                        if let Some(hooks) = self.hooks.as_mut() {
+                            hooks.on_return(f.name());
+                        }
+                        self.stack.truncate(stack_top - (arg_count + 1));
+                        self.push(result);
+                        Ok(())
+                    }
+                    Err(err) => Err(self.native_error(err, f.name())),
+                }
             }
-            _ => false,
-        };
-
-        if !success {
-            let _ = self.runtime_error("Can only call functions and classes.");
+            _ => self.runtime_error("Can only call functions and classes."),
         }
+    }
 
-        success
+    fn invoke_from_class(
+        &mut self,
+        klass: Rc<Class>,
+        name: &str,
+        arg_count: usize,
+    ) -> Result<(), LoxError> {
+        let method = klass.get_method(name);
+        self.call_method(&klass, method, name, arg_count)
     }
 
-    fn invoke_from_class(&mut self, klass: Rc<Class>, name: &str, arg_count: usize) -> bool {
-        if let Some(closure) = klass.get_method(name) {
+    fn call_method(
+        &mut self,
+        klass: &Class,
+        method: Option<Rc<Closure>>,
+        name: &str,
+        arg_count: usize,
+    ) -> Result<(), LoxError> {
+        let stack_top = self.stack.len();
+        let receiver = self.peek_value(arg_count);
+        if let Some(closure) = method {
             self.call(closure, arg_count)
+        } else if let Some(bound) = self.bind_host_method(klass, &receiver, name) {
+            self.stack[stack_top - arg_count - 1] = new_slot(bound);
+            self.call_value(arg_count)
+        } else if let Some(missing) = klass.get_method("methodMissing") {
+            let args = self
+                .stack
+                .drain(stack_top - arg_count..)
+                .map(|slot| Value::from(&slot))
+                .collect();
+            self.push(Value::Str(name.to_string()));
+            self.push(Value::new_user_data(MissingArgs(args)));
+            self.call(missing, 2)
+        } else if let Some(missing) = klass.get_method("propertyMissing") {
+            // Without `methodMissing`, `obj.name(...)` calls whatever
+            // `obj.name` would have given.
+            let missing = Value::Bound(Rc::new(BoundMethod::new(&receiver, &missing)));
+            let value = self.call_nested(missing, &[Value::Str(name.to_string())])?;
+            self.stack[stack_top - arg_count - 1] = new_slot(value);
+            self.call_value(arg_count)
         } else {
-            let _ = self.runtime_error(&format!("Undefined property '{name}'."));
-            false
+            self.runtime_error(format!("Undefined property '{name}'."))
         }
     }
 
-    fn invoke(&mut self, name: &str, arg_count: usize) -> bool {
-        let receiver = self.peek(arg_count).borrow().clone();
+    fn find_method(&self, klass: &Rc<Class>, name: &str, cache: usize) -> Option<Rc<Closure>> {
+        let chunk = self.chunk();
+        let cache = chunk.get_cache(cache);
+        if let Some(method) = cache.lookup(klass, self.method_epoch) {
+            return Some(method);
+        }
+
+        let method = klass.get_method(name)?;
+        cache.update(klass, self.method_epoch, &method);
+        Some(method)
+    }
+
+    fn invoke(&mut self, name: &str, arg_count: usize, cache: usize) -> Result<(), LoxError> {
+        let receiver = self.peek_value(arg_count);
         if let Value::Instance(instance) = receiver {
             if let Some(value) = instance.get_field(name) {
                 let stack_top = self.stack.len();
-                self.stack[stack_top - arg_count - 1] = Rc::new(RefCell::new(value));
+                self.stack[stack_top - arg_count - 1] = new_slot(value);
                 self.call_value(arg_count)
             } else {
-                self.invoke_from_class(instance.get_class(), name, arg_count)
+                let klass = instance.get_class();
+                let method = self.find_method(&klass, name, cache);
+                self.call_method(&klass, method, name, arg_count)
             }
+        } else if let Value::UserData(data) = receiver {
+            let Some(method) = self.bind_user_method(&data, name) else {
+                return self.runtime_error(format!("Undefined property '{name}'."));
+            };
+            let stack_top = self.stack.len();
+            self.stack[stack_top - arg_count - 1] = new_slot(method);
+            self.call_value(arg_count)
+        } else if let Value::Class(class) = receiver {
+            let Some(value) = self.class_property(&class, name)? else {
+                return self.runtime_error(format!("Undefined property '{name}'."));
+            };
+            let stack_top = self.stack.len();
+            self.stack[stack_top - arg_count - 1] = new_slot(value);
+            self.call_value(arg_count)
+        } else if let Value::Bound(method) = receiver.clone() {
+            if name != "bind" {
+                return self.runtime_error("Only instances have methods.");
+            }
+            let stack_top = self.stack.len();
+            self.stack[stack_top - arg_count - 1] = new_slot(bind_native(&method));
+            self.call_value(arg_count)
         } else {
-            let _ = self.runtime_error("Only instances have methods.");
-            false
+            self.runtime_error("Only instances have methods.")
         }
     }
 
-    fn bind_method(&mut self, klass: Rc<Class>, name: &str) -> bool {
-        if let Some(method) = klass.get_method(name) {
-            let value = self.peek(0).borrow().clone();
-            let bound = Rc::new(BoundMethod::new(&value, &method));
-            self.pop();
-            self.push(Value::Bound(bound));
-            true
+    // `Class.name`: a static field, or else the method `name` waiting for a
+    // receiver.
+    fn class_property(&mut self, class: &Rc<Class>, name: &str) -> Result<Option<Value>, LoxError> {
+        if let Some(value) = class.get_static(name) {
+            return Ok(Some(value));
+        }
+        let Some(method) = class.get_method(name) else {
+            return Ok(None);
+        };
+        let allocation = self.allocate(std::mem::size_of::<BoundMethod>())?;
+        let class = Value::Class(Rc::clone(class));
+        let method = BoundMethod::unbound(&class, &method).with_allocation(allocation);
+        Ok(Some(Value::Bound(Rc::new(method))))
+    }
+
+    fn bind_method(
+        &mut self,
+        klass: &Class,
+        method: Option<Rc<Closure>>,
+        name: &str,
+    ) -> Result<(), LoxError> {
+        let receiver = self.peek_value(0);
+        let bound = if let Some(method) = method {
+            let allocation = self.allocate(std::mem::size_of::<BoundMethod>())?;
+            let method = BoundMethod::new(&receiver, &method).with_allocation(allocation);
+            Value::Bound(Rc::new(method))
+        } else if let Some(bound) = self.bind_host_method(klass, &receiver, name) {
+            bound
+        } else if let Some(missing) = klass.get_method("propertyMissing") {
+            // Its result takes the receiver's place on the stack.
+            self.push(Value::Str(name.to_string()));
+            return self.call(missing, 1);
         } else {
-            let _ = self.runtime_error(format!("Undefined property '{name}'."));
-            false
+            return self.runtime_error(format!("Undefined property '{name}'."));
+        };
+        self.pop();
+        self.push(bound);
+        Ok(())
+    }
+
+    // Binds the host method `name` of `klass` to `receiver` as a native
+    // function.
+    fn bind_host_method(&self, klass: &Class, receiver: &Value, name: &str) -> Option<Value> {
+        if name == "init" {
+            return self.bind_host_init(klass, receiver);
         }
+        let (arity, method) = klass.get_host_method(name)?;
+        let receiver = receiver.clone();
+        let bound = NativeClosure::new(name, arity, move |args| method(&receiver, args));
+        Some(Value::Native(Rc::new(bound)))
+    }
+
+    // `init` called on an instance of a host class runs its initializer
+    // again, which fills in its fields from the arguments if the host gave it
+    // none, and returns the instance just as a Lox initializer does.
+    fn bind_host_init(&self, klass: &Class, receiver: &Value) -> Option<Value> {
+        let receiver = receiver.clone();
+        let bound = match klass.get_host_method("init") {
+            Some((arity, method)) => NativeClosure::new("init", arity, move |args| {
+                method(&receiver, args)?;
+                Ok(receiver.clone())
+            }),
+            None => {
+                let fields = klass.fields();
+                if fields.is_empty() {
+                    return None;
+                }
+                NativeClosure::new("init", fields.len(), move |args| {
+                    if let Value::Instance(instance) = &receiver {
+                        if instance.is_frozen() {
+                            return Err(NativeError::new("Can't initialize a frozen instance."));
+                        }
+                        for (field, value) in fields.iter().zip(args) {
+                            instance.set_field(field.as_str(), value);
+                        }
+                    }
+                    Ok(receiver.clone())
+                })
+            }
+        };
+        Some(Value::Native(Rc::new(bound)))
+    }
+
+    // Looks `name` up among the methods registered for the type `data`
+    // holds, and binds it to `data` as a native function.
+    fn bind_user_method(&self, data: &Rc<UserData>, name: &str) -> Option<Value> {
+        let (arity, method) = self.user_methods.get(&data.data_type())?.get(name)?;
+        let method = Rc::clone(method);
+        let receiver = Rc::clone(data);
+        let bound = NativeClosure::new(name, *arity, move |args| method(&receiver, args));
+        Some(Value::Native(Rc::new(bound)))
+    }
+
+    // Asks the hooks whether `function` may be called, and fails the script
+    // if it may not.
+    fn check_call(&mut self, function: &str) -> Result<(), LoxError> {
+        let Some(hooks) = self.hooks.as_mut() else {
+            return Ok(());
+        };
+        if let Err(err) = hooks.on_call(function) {
+            return self.runtime_error(err.to_string());
+        }
+        Ok(())
+    }
+
+    fn check_global_write(&mut self, slot: usize) -> Result<(), LoxError> {
+        let Some(hooks) = self.hooks.as_mut() else {
+            return Ok(());
+        };
+        let name = self.global_names.borrow().name(slot).to_string();
+        if let Err(err) = hooks.on_global_write(&name) {
+            return self.runtime_error(err.to_string());
+        }
+        Ok(())
+    }
+
+    fn debug_instruction(&mut self, instruction: OpCode) {
+        let Some(mut debugger) = self.debugger.take() else {
+            return;
+        };
+        debugger.on_instruction(self, instruction);
+
+        let line = self.chunk().get_line(self.ip() - 1);
+        let position = (self.frames.len(), line);
+        if position != self.debug_position {
+            self.debug_position = position;
+            if self.stepping || self.breakpoints.contains(&line) {
+                self.stepping = debugger.on_line(self, line) == DebugAction::Step;
+            }
+        }
+        self.debugger = Some(debugger);
     }
 
     fn reset_stack(&mut self) {
+        // Closures that escaped, into a global say, keep the values they
+        // captured rather than slots that are about to go.
+        self.close_upvalues(0);
         self.stack.clear();
+        self.frames.clear();
+        if let Some(profile) = self.profile.as_mut() {
+            profile.unwind();
+        }
     }
 
     fn read_byte(&mut self) -> u8 {
-        let val: u8 = self.chunk().read(self.ip());
-        self.current_frame().inc(1);
+        let val: u8 = self.chunk.read(self.ip);
+        self.ip += 1;
         val
     }
 
     fn read_short(&mut self) -> usize {
-        self.current_frame().inc(2);
-        self.chunk().get_jump_offset(self.ip() - 2)
+        self.ip += 2;
+        self.chunk.read_short(self.ip - 2)
     }
 
-    fn read_constant(&mut self) -> Value {
-        let index = self.chunk().read(self.ip()) as usize;
-        self.current_frame().inc(1);
-        self.chunk().get_constant(index).clone()
+    fn read_int(&mut self) -> usize {
+        self.ip += 4;
+        self.chunk.read_int(self.ip - 4)
+    }
+
+    fn read_index(&mut self, long: bool) -> usize {
+        if long {
+            self.ip += 3;
+            self.chunk.read_long(self.ip - 3)
+        } else {
+            self.read_byte() as usize
+        }
+    }
+
+    fn read_constant(&mut self, long: bool) -> Value {
+        let index = self.read_index(long);
+        self.chunk.get_constant(index).clone()
+    }
+
+    fn read_string(&mut self, long: bool) -> Result<String, LoxError> {
+        match self.read_constant(long) {
+            Value::Str(s) => Ok(s),
+            _ => self.internal_error("expected a string constant"),
+        }
     }
 
     fn binary_op(
         &mut self,
         op_type: Operands,
-        op: fn(a: &Value, b: &Value) -> Value,
-    ) -> Result<(), InterpretResult> {
-        if self.peek(0).borrow().is_string() && self.peek(1).borrow().is_string() {
+        op: fn(a: f64, b: f64) -> Value,
+    ) -> Result<(), LoxError> {
+        let strings = self.peek(0).is_string() && self.peek(1).is_string();
+        if strings && matches!(op_type, Operands::NumbersOrStrings) {
             self.concatenate()
-        } else if self.peek(0).borrow().is_number() && self.peek(1).borrow().is_number() {
-            let b = self.pop();
-            let a = self.pop();
-            self.push(op(&a.borrow(), &b.borrow()));
+        } else if let (Some(a), Some(b)) = (self.peek(1).as_number(), self.peek(0).as_number()) {
+            self.pop();
+            self.pop();
+            self.push(op(a, b));
             Ok(())
+        } else if matches!(op_type, Operands::NumbersOrStrings) && self.concatenates_object() {
+            self.concatenate_objects()
+        } else if matches!(op_type, Operands::Comparable) {
+            match compare_to_method(&self.peek_value(1)) {
+                Some(method) => self.compare_objects(&method, op),
+                None => self.runtime_error("Operands must be numbers."),
+            }
         } else {
             match op_type {
-                Operands::TwoNumbers => self.runtime_error("Operands must be numbers."),
+                Operands::TwoNumbers | Operands::Comparable => {
+                    self.runtime_error("Operands must be numbers.")
+                }
                 Operands::NumbersOrStrings => {
                     self.runtime_error("Operands must be two numbers or two strings.")
                 }
@@ -564,27 +1516,355 @@ impl VM {
         }
     }
 
-    fn concatenate(&mut self) -> Result<(), InterpretResult> {
+    // Pops two values and compares them with the left one's
+    // `compareTo(other)`, which returns a number less than, equal to or
+    // greater than zero as the left one sorts before, with or after the
+    // right. `op` then compares that number with zero.
+    fn compare_objects(
+        &mut self,
+        method: &Rc<Closure>,
+        op: fn(a: f64, b: f64) -> Value,
+    ) -> Result<(), LoxError> {
+        let b = self.pop_value();
+        let a = self.pop_value();
+        let bound = Value::Bound(Rc::new(BoundMethod::new(&a, method)));
+        let Value::Number(order) = self.call_nested(bound, &[b])? else {
+            return self.runtime_error("compareTo() must return a number.");
+        };
+        self.push(op(order, 0.0));
+        Ok(())
+    }
+
+    fn concatenate(&mut self) -> Result<(), LoxError> {
         let b = self.pop();
         let a = self.pop();
-        self.push(Value::Str(format!("{}{}", a.borrow(), b.borrow())));
+        let result = format!("{a}{b}");
+        self.reserve(result.len())?;
+        self.push(Value::Str(result));
         Ok(())
     }
 
-    fn runtime_error<T: Into<String>>(&mut self, err_msg: T) -> Result<(), InterpretResult> {
-        eprintln!("{}", err_msg.into());
-        for frame in self.frames.iter().rev() {
-            let instruction = *frame.ip.borrow() - 1;
-            let line = frame.closure.get_chunk().get_line(instruction);
-            eprintln!("[line {line}] in {}", frame.closure.stack_name());
+    // Pops two values and compares them: by calling `equals(other)` when the
+    // left one is an instance whose class defines it, and by identity or
+    // value as usual otherwise. What `equals` returns counts by truthiness.
+    fn values_equal(&mut self) -> Result<bool, LoxError> {
+        let b = self.pop();
+        let a = self.pop();
+        // Numbers and strings, the usual case, skip the conversion.
+        let method = if a.as_number().is_some() || a.is_string() {
+            None
+        } else {
+            equals_method(&Value::from(&a))
+        };
+        match method {
+            Some(method) => {
+                let bound = Value::Bound(Rc::new(BoundMethod::new(&Value::from(&a), &method)));
+                let result = self.call_nested(bound, &[Value::from(&b)])?;
+                Ok(!result.is_falsey())
+            }
+            None => Ok(a == b),
+        }
+    }
+
+    // Whether `+` has a string on one side and an instance with a
+    // `toString()` method on the other.
+    fn concatenates_object(&self) -> bool {
+        (self.peek(1).is_string() && to_string_method(&self.peek_value(0)).is_some())
+            || (self.peek(0).is_string() && to_string_method(&self.peek_value(1)).is_some())
+    }
+
+    fn concatenate_objects(&mut self) -> Result<(), LoxError> {
+        let b = self.pop_value();
+        let a = self.pop_value();
+        let mut text = |value: Value| match to_string_method(&value) {
+            Some(method) => self.call_to_string(value, method),
+            None => Ok(value.to_string()),
+        };
+        let result = text(a)? + &text(b)?;
+        self.reserve(result.len())?;
+        self.push(Value::Str(result));
+        Ok(())
+    }
+
+    fn call_to_string(&mut self, receiver: Value, method: Rc<Closure>) -> Result<String, LoxError> {
+        let bound = Value::Bound(Rc::new(BoundMethod::new(&receiver, &method)));
+        match self.call_nested(bound, &[])? {
+            Value::Str(text) => Ok(text),
+            value => Err(self
+                .unwind(
+                    format!(
+                        "toString() must return a string, not {}.",
+                        value.type_name()
+                    ),
+                    None,
+                )
+                .into()),
         }
+    }
+
+    // Calls `callee` from inside an instruction and runs it to completion
+    // before the instruction carries on, for methods the VM calls itself,
+    // such as `toString()`.
+    fn call_nested(&mut self, callee: Value, args: &[Value]) -> Result<Value, LoxError> {
+        let base = self.frames.len();
+        self.push(callee);
+        for arg in args {
+            self.push(arg.clone());
+        }
+        self.call_value(args.len())?;
+        if self.frames.len() > base {
+            let outer = std::mem::replace(&mut self.base_frames, base);
+            let result = self.run();
+            self.base_frames = outer;
+            result?;
+        }
+        Ok(self.pop_value())
+    }
+
+    // Calls `__drop()` on the instances dropped since the last time, and on
+    // any those calls drop in turn.
+    fn run_finalizers(&mut self) -> Result<(), LoxError> {
+        self.finalizing = true;
+        let mut result = Ok(());
+        while result.is_ok() && self.drops.is_pending() {
+            for instance in self.drops.take() {
+                let Some(method) = instance.get_class().get_method("__drop") else {
+                    continue;
+                };
+                let receiver = Value::Instance(Rc::new(instance));
+                let method = Value::Bound(Rc::new(BoundMethod::new(&receiver, &method)));
+                result = self.call_nested(method, &[]).map(|_| ());
+                if result.is_err() {
+                    break;
+                }
+            }
+        }
+        self.finalizing = false;
+        result
+    }
+
+    // Counts a new object of roughly `bytes` for as long as it lives.
+    fn allocate(&mut self, bytes: usize) -> Result<Allocation, LoxError> {
+        self.reserve(bytes)?;
+        Ok(self.memory.allocate(bytes))
+    }
+
+    // Fails if `bytes` more would take the objects alive over `max_memory`.
+    fn reserve(&mut self, bytes: usize) -> Result<(), LoxError> {
+        if self.memory.bytes().saturating_add(bytes) > self.config.max_memory {
+            return self.runtime_error("Out of memory.");
+        }
+        Ok(())
+    }
+
+    fn runtime_error<T: Into<String>>(&mut self, err_msg: T) -> Result<(), LoxError> {
+        Err(self.unwind(err_msg, None).into())
+    }
+
+    // Reports bytecode the compiler should never have produced. This is still
+    // a runtime error rather than a panic so a host embedding the VM survives.
+    fn internal_error<T>(&mut self, err_msg: &str) -> Result<T, LoxError> {
+        let message = format!("Internal error: {err_msg}.");
+        Err(self.unwind(message, None).into())
+    }
+
+    // A native either failed, which is a runtime error in the script, or
+    // asked to exit, which stops it without a trace.
+    fn native_error(&mut self, err: NativeError, native: &str) -> LoxError {
+        match err.exit_code() {
+            Some(code) => {
+                self.reset_stack();
+                LoxError::Exit(code)
+            }
+            None => self.unwind(err.to_string(), Some(native)).into(),
+        }
+    }
+
+    // Builds the error for `interpret` to return, along with a trace of the
+    // frames that were active, and unwinds the VM.
+    fn unwind<S: Into<String>>(&mut self, err_msg: S, native: Option<&str>) -> RuntimeError {
+        let mut error = RuntimeError::new(err_msg);
+        if let Some(name) = native {
+            error.trace.push(TraceFrame {
+                function: name.to_string(),
+                file: None,
+                line: None,
+                column: None,
+            });
+        }
+
+        error.trace.extend(self.stack_trace());
         self.reset_stack();
+        error
+    }
 
-        Err(InterpretResult::RuntimeError)
+    /// Where the running script is, innermost call first.
+    pub fn stack_trace(&self) -> Vec<TraceFrame> {
+        let top = self.frames.len().saturating_sub(1);
+        self.frames
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(depth, frame)| {
+                let ip = if depth == top { self.ip } else { frame.ip };
+                // The fuel check runs before an instruction is read, so a
+                // budget can run out with ip still at the start of the chunk.
+                let instruction = ip.saturating_sub(1);
+                let chunk = frame.closure.get_chunk();
+                TraceFrame {
+                    function: frame.closure.stack_name().to_string(),
+                    file: chunk.file().map(str::to_string),
+                    line: Some(chunk.get_line(instruction)),
+                    column: Some(chunk.get_column(instruction)),
+                }
+            })
+            .collect()
     }
 
-    fn define_native<T: Into<String>>(&mut self, name: T, function: &Rc<dyn NativeFunc>) {
-        self.globals
-            .insert(name.into(), Value::Native(Rc::clone(function)));
+    fn define_global(&mut self, slot: usize, value: Value) {
+        if slot >= self.globals.len() {
+            self.globals.resize(slot + 1, None);
+        }
+        self.globals[slot] = Some(new_slot(value));
+    }
+
+    fn undefined_variable(&mut self, slot: usize) -> Result<(), LoxError> {
+        let name = self.global_names.borrow().name(slot).to_string();
+        self.runtime_error(format!("Undefined variable '{name}'."))
     }
+
+    /// Makes a Rust closure callable from Lox as the global `name`. A call
+    /// with anything but `arity` arguments fails before the closure runs,
+    /// and an error it returns becomes a runtime error in the script.
+    ///
+    /// ```
+    /// use lox_bytecode::{Value, VM};
+    ///
+    /// let mut vm = VM::new();
+    /// vm.register("len", 1, |args| match &args[0] {
+    ///     Value::Str(s) => Ok(Value::Number(s.chars().count() as f64)),
+    ///     _ => Err("len() takes a string."),
+    /// });
+    /// vm.interpret("print len(\"lox\");").unwrap();
+    /// assert!(vm.interpret("len(1);").is_err());
+    /// ```
+    pub fn register<F, E>(&mut self, name: &str, arity: usize, function: F)
+    where
+        F: Fn(&[Value]) -> Result<Value, E> + MaybeSend + MaybeSync + 'static,
+        E: Into<NativeError>,
+    {
+        let native: Rc<dyn NativeFunc> = Rc::new(NativeClosure::new(name, arity, move |args| {
+            function(args).map_err(Into::into)
+        }));
+        self.define_native(&native);
+    }
+
+    /// Lets scripts call `name` on userdata holding a `T`, with `arity`
+    /// arguments. Like `register`, errors become runtime errors.
+    ///
+    /// ```
+    /// use std::sync::Mutex;
+    /// use lox_bytecode::{NativeError, Value, VM};
+    ///
+    /// struct Counter(Mutex<f64>);
+    ///
+    /// let mut vm = VM::new();
+    /// vm.register_method("add", 1, |counter: &Counter, args| {
+    ///     let n: f64 = (&args[0]).try_into()?;
+    ///     *counter.0.lock().unwrap() += n;
+    ///     Ok::<_, NativeError>(Value::Nil)
+    /// });
+    /// vm.set_global("counter", Value::new_user_data(Counter(Mutex::new(0.0))));
+    /// vm.interpret("counter.add(2); var add = counter.add; add(3);").unwrap();
+    ///
+    /// let counter = vm.get_global("counter").unwrap();
+    /// assert_eq!(*counter.user_data::<Counter>().unwrap().0.lock().unwrap(), 5.0);
+    /// ```
+    pub fn register_method<T, F, E>(&mut self, name: &str, arity: usize, method: F)
+    where
+        T: Any,
+        F: Fn(&T, &[Value]) -> Result<Value, E> + MaybeSend + MaybeSync + 'static,
+        E: Into<NativeError>,
+    {
+        let method: Rc<UserMethod> = Rc::new(move |data: &UserData, args: &[Value]| {
+            // Methods are only looked up for the type they were registered for.
+            let this = data.get::<T>().expect("userdata of the wrong type");
+            method(this, args).map_err(Into::into)
+        });
+        self.user_methods
+            .entry(TypeId::of::<T>())
+            .or_default()
+            .insert(name.to_string(), (arity, method));
+    }
+
+    /// Defines a class named `name` whose fields and methods come from Rust,
+    /// which scripts construct and use like any other class.
+    ///
+    /// ```
+    /// use lox_bytecode::{NativeError, Value, VM};
+    ///
+    /// let mut vm = VM::new();
+    /// vm.register_class("Vec2")
+    ///     .field("x")
+    ///     .field("y")
+    ///     .method("length", 0, |this, _| {
+    ///         let x = f64::try_from(this.get_field("x").unwrap_or(Value::Nil))?;
+    ///         let y = f64::try_from(this.get_field("y").unwrap_or(Value::Nil))?;
+    ///         Ok::<_, NativeError>(Value::Number(x.hypot(y)))
+    ///     });
+    /// vm.interpret("var v = Vec2(3, 4); v.y = 0; var length = v.length();").unwrap();
+    /// assert_eq!(vm.get_global("length"), Some(Value::Number(3.0)));
+    /// ```
+    pub fn register_class(&mut self, name: &str) -> ClassBuilder {
+        let class = Rc::new(Class::new(name.to_string(), &self.drops));
+        self.set_global(name, Value::Class(Rc::clone(&class)));
+        ClassBuilder::new(&class)
+    }
+
+    /// Makes `function` callable from Lox as a global under its own name.
+    pub fn define_native(&mut self, function: &Rc<dyn NativeFunc>) {
+        let slot = self.global_names.borrow_mut().resolve(function.name());
+        self.define_global(slot, Value::Native(Rc::clone(function)));
+        Rc::make_mut(&mut self.natives).insert(function.name().to_string(), Rc::clone(function));
+    }
+}
+
+// The arguments a call passed to a method the class doesn't have, which
+// `methodMissing(name, args)` reads with `args.count()` and `args.get(n)`.
+struct MissingArgs(Vec<Value>);
+
+// The `toString()` method an instance's class defines, which `print` and
+// string concatenation use in place of "X instance".
+fn to_string_method(value: &Value) -> Option<Rc<Closure>> {
+    let Value::Instance(instance) = value else {
+        return None;
+    };
+    instance.get_class().get_method("toString")
+}
+
+// The `compareTo(other)` method that `<`, `>`, `<=` and `>=` call for an
+// instance on the left.
+fn compare_to_method(value: &Value) -> Option<Rc<Closure>> {
+    let Value::Instance(instance) = value else {
+        return None;
+    };
+    instance.get_class().get_method("compareTo")
+}
+
+// The `equals(other)` method that `==` and `!=` call for an instance in
+// place of comparing identity.
+fn equals_method(value: &Value) -> Option<Rc<Closure>> {
+    let Value::Instance(instance) = value else {
+        return None;
+    };
+    instance.get_class().get_method("equals")
+}
+
+// `method.bind(obj)`, which binds a method taken from a class to `obj`.
+fn bind_native(method: &Rc<BoundMethod>) -> Value {
+    let method = Rc::clone(method);
+    let bind = NativeClosure::new("bind", 1, move |args| {
+        Ok(Value::Bound(Rc::new(method.bind(&args[0])?)))
+    });
+    Value::Native(Rc::new(bind))
 }
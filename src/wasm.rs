@@ -0,0 +1,122 @@
+use std::cell::RefCell;
+use std::io::{self, Write};
+
+use crate::builder::*;
+use crate::diagnostic::*;
+use crate::error::*;
+use crate::vm::*;
+
+// Entry points for running the interpreter as a plain WebAssembly module,
+// such as behind a browser playground. There is no bindings crate, so text
+// crosses the boundary as a pointer and length into the module's memory,
+// and the host supplies output and the time as imports:
+//
+//   const { instance } = await WebAssembly.instantiate(bytes, { env: {
+//       lox_print: (ptr, len) => output(text(ptr, len)),
+//       lox_error: (ptr, len) => errors(text(ptr, len)),
+//       lox_clock: () => Date.now(),
+//   }});
+//   const lox = instance.exports;
+//   const source = new TextEncoder().encode("print 1 + 2;");
+//   const ptr = lox.lox_alloc(source.length);
+//   new Uint8Array(lox.memory.buffer, ptr, source.length).set(source);
+//   const status = lox.lox_interpret(ptr, source.length);
+//   lox.lox_free(ptr, source.length);
+//
+// where text(ptr, len) decodes that many bytes of lox.memory.buffer. The
+// status is 0 or the exit code the command line would use: 65 for a
+// compile error and 70 for a runtime error. One VM lives across calls so a
+// playground can run a script in pieces; lox_reset starts a new one.
+//
+// Build it with
+//
+//   cargo rustc --lib --release --no-default-features --target wasm32-unknown-unknown --crate-type cdylib
+
+extern "C" {
+    fn lox_print(ptr: *const u8, len: usize);
+    fn lox_error(ptr: *const u8, len: usize);
+    fn lox_clock() -> f64;
+}
+
+thread_local! {
+    static INTERPRETER: RefCell<VM> = RefCell::new(new_vm());
+}
+
+fn new_vm() -> VM {
+    VmBuilder::new()
+        .output(HostOutput)
+        .reporter(HostReporter)
+        .clock(|| unsafe { lox_clock() })
+        .build()
+}
+
+fn print_error(text: &str) {
+    unsafe { lox_error(text.as_ptr(), text.len()) }
+}
+
+struct HostOutput;
+
+impl Write for HostOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        unsafe { lox_print(buf.as_ptr(), buf.len()) };
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct HostReporter;
+
+impl Reporter for HostReporter {
+    fn report(&mut self, source: &str, diagnostic: &Diagnostic) {
+        let mut text = format!("{diagnostic}\n");
+        if let Some(line) = source.lines().nth(diagnostic.line.saturating_sub(1)) {
+            text += &caret_snippet(line, diagnostic.column, diagnostic.span.len);
+        }
+        print_error(&text);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn lox_alloc(len: usize) -> *mut u8 {
+    Box::into_raw(vec![0u8; len].into_boxed_slice()) as *mut u8
+}
+
+/// # Safety
+///
+/// `ptr` and `len` must come from one call to `lox_alloc`.
+#[no_mangle]
+pub unsafe extern "C" fn lox_free(ptr: *mut u8, len: usize) {
+    drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr, len)));
+}
+
+/// # Safety
+///
+/// `ptr` must point at `len` initialized bytes, such as a buffer from
+/// `lox_alloc` the host has written the source into.
+#[no_mangle]
+pub unsafe extern "C" fn lox_interpret(ptr: *const u8, len: usize) -> u32 {
+    let bytes = std::slice::from_raw_parts(ptr, len);
+    let Ok(source) = std::str::from_utf8(bytes) else {
+        print_error("Source is not valid UTF-8.\n");
+        return 65;
+    };
+    let result = INTERPRETER.with(|vm| vm.borrow_mut().interpret(source));
+    match result {
+        Ok(()) => 0,
+        // Compile errors have already gone to the reporter.
+        Err(err) if err.kind() == InterpretResult::CompileError => 65,
+        Err(LoxError::Exit(code)) => code as u32,
+        Err(err) => {
+            print_error(&err.to_string());
+            70
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn lox_reset() {
+    INTERPRETER.with(|vm| *vm.borrow_mut() = new_vm());
+}
@@ -0,0 +1,27 @@
+use lox_bytecode::{LoxError, VM};
+
+fn u32s(bytes: &mut Vec<u8>, values: &[u32]) {
+    for value in values {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+// Each function in this file holds the next one as its only constant, far
+// deeper than the loader will follow.
+#[test]
+fn deeply_nested_functions_are_rejected() {
+    let mut bytes = b"LOXB".to_vec();
+    u32s(&mut bytes, &[2, 0]); // version, globals
+    for _ in 0..200_000 {
+        // name, file, arity, upvalues, code, line runs, caches, constants
+        u32s(&mut bytes, &[0, 0, 0, 0, 0, 0, 0, 1]);
+        bytes.push(5); // a function
+    }
+
+    let err = VM::new().run_binary(&bytes).unwrap_err();
+    assert!(matches!(err, LoxError::Compile(_)));
+    assert!(
+        err.to_string().contains("nested more than 256 deep"),
+        "{err}"
+    );
+}
@@ -0,0 +1,53 @@
+use lox_bytecode::{CapturedOutput, VmBuilder};
+
+// NaN compares false with everything, whichever side it is on, whether it
+// came straight from a division or went through a local, a global, a field
+// or a call, and whether or not the optimizer has fused the comparison with
+// the code around it. With `nan_boxing` the value itself is a NaN too, so
+// these run under that feature as well.
+const SCRIPT: &str = "
+var nan = 0 / 0;
+class Box { init(value) { this.value = value; } }
+fun id(x) { return x; }
+fun compare(n) {
+  print n < 1; print n <= 1; print n > 1; print n >= 1;
+  print 1 < n; print 1 <= n; print 1 > n; print 1 >= n;
+  print n == n; print n != n;
+  print !(n < 1); print !(n >= 1);
+  if (n >= 1) print \"if\"; else print \"else\";
+  var count = 0;
+  while (n <= 1 and count < 3) count = count + 1;
+  print count;
+}
+compare(nan);
+compare(Box(nan).value);
+compare(id(-nan));
+{ var local = 0 / 0; compare(local); }
+";
+
+const EXPECTED: &str = "false\nfalse\nfalse\nfalse\n\
+    false\nfalse\nfalse\nfalse\n\
+    false\ntrue\n\
+    true\ntrue\n\
+    else\n0\n";
+
+fn run(optimize: bool) -> String {
+    let output = CapturedOutput::default();
+    let mut vm = VmBuilder::new()
+        .optimize(optimize)
+        .print_code(false)
+        .output(output.clone())
+        .build();
+    vm.interpret(SCRIPT).unwrap();
+    output.text()
+}
+
+#[test]
+fn comparisons_with_nan_are_false() {
+    assert_eq!(run(false), EXPECTED.repeat(4));
+}
+
+#[test]
+fn comparisons_with_nan_are_false_when_optimized() {
+    assert_eq!(run(true), EXPECTED.repeat(4));
+}
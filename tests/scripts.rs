@@ -0,0 +1,25 @@
+use std::path::Path;
+
+use lox_bytecode::run_tests;
+
+// Runs every script under tests/scripts against the comments in it.
+#[test]
+fn scripts() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/scripts");
+    let results = run_tests(&dir).expect("Could not read tests");
+    assert!(!results.is_empty());
+
+    let failed: Vec<String> = results
+        .iter()
+        .filter(|result| !result.passed())
+        .map(|result| {
+            let path = result.path.strip_prefix(&dir).unwrap_or(&result.path);
+            format!(
+                "{}:\n    {}",
+                path.display(),
+                result.failures.join("\n    ")
+            )
+        })
+        .collect();
+    assert!(failed.is_empty(), "{}", failed.join("\n"));
+}